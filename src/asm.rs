@@ -0,0 +1,569 @@
+use crate::expr::{eval, ExprError};
+use crate::isa::*;
+use crate::memory::{TEXT_BASE, DATA_BASE, KTEXT_BASE, KDATA_BASE, EXCEPTION_HANDLER};
+use crate::macros::{expand_macros, split_args, Position, SourceLine};
+use crate::parser::{parse_line, substitute, Body, Line, ParseError};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+// Largest block .space reserves, and the most padding .align may add to a text section
+const MAX_SPACE: u32 = 1 << 24;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Section {
+    Text,
+    KText,
+    Data,
+    KData,
+}
+impl Section {
+    fn name(&self) -> &'static str {
+        match self {
+            Section::Text => ".text",
+            Section::KText => ".ktext",
+            Section::Data => ".data",
+            Section::KData => ".kdata",
+        }
+    }
+    fn overflow(&self) -> String {
+        format!("The {} section runs past the end of the address space", self.name())
+    }
+}
+
+enum Directive {
+    Section(Section, Option<u32>),
+    Word(Vec<String>),
+    Half(Vec<u32>),
+    Byte(Vec<u32>),
+    Space(u32),
+    Ascii(Vec<u8>),
+    Float(Vec<f32>),
+    Double(Vec<f64>),
+    Align(u32),
+    // Numeric constant usable in expressions, may be redefined
+    Set(String, u32),
+    // Textual substitution applied to the lines that follow
+    Eqv(String, String),
+    // Directives accepted for compatibility that have no effect, e.g. .globl
+    Ignored,
+}
+
+// A label, with where it is defined and used, for symbol tables
+pub struct Symbol {
+    pub name: String,
+    pub addr: u32,
+    pub section: &'static str,
+    pub defined: String,
+    // Locations of the lines that refer to the label
+    pub references: Vec<String>,
+}
+
+// An assembled program, ready to be loaded into a CPU
+pub struct Program {
+    // Instructions keyed by their address
+    pub text: BTreeMap<u32, Instr>,
+    // Source file and line of each instruction, keyed by address
+    pub lines: HashMap<u32, Position>,
+    // Initial contents of the data segments: address, little-endian bytes and the size of each
+    // item in them, so images can be written in the other byte order
+    pub data: Vec<(u32, Vec<u8>, u32)>,
+    // Location and text of the source line each instruction came from, for listings
+    pub source: HashMap<u32, (String, String)>,
+    // Labels in address order
+    pub symbols: Vec<Symbol>,
+}
+impl Program {
+    pub fn fetch(&self, addr: u32) -> Option<Instr> {
+        self.text.get(&addr).copied()
+    }
+    // Address of a label
+    pub fn symbol(&self, name: &str) -> Option<u32> {
+        self.symbols.iter().find(|s| s.name == name).map(|s| s.addr)
+    }
+    // The first code label at addr, e.g. the name of the function starting there
+    pub fn label_at(&self, addr: u32) -> Option<&str> {
+        self.symbols.iter().find(|s| s.addr == addr && matches!(s.section, ".text" | ".ktext")).map(|s| s.name.as_str())
+    }
+    pub fn has_exception_handler(&self) -> bool {
+        self.text.contains_key(&EXCEPTION_HANDLER)
+    }
+    // Lists each instruction with its address and encoding, next to the source line it was
+    // assembled from, followed by a symbol table and a cross-reference of where each label is used.
+    // Pseudo-instructions show their source once, against the first instruction of the expansion.
+    pub fn listing(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "{:<12}{:<12}{:<32}Source", "Address", "Code", "Basic");
+        let mut last = None;
+        for (pc, instr) in &self.text {
+            let source = &self.source[pc];
+            let text = if last != Some(source) {
+                format!("{}: {}", source.0, source.1.trim())
+            } else {
+                String::new()
+            };
+            last = Some(source);
+            let row = format!("0x{:08x}  0x{:08x}  {:<32}{}", pc, instr.encode(), format!("{:?}", instr), text);
+            let _ = writeln!(out, "{}", row.trim_end());
+        }
+        let width = self.symbols.iter().map(|s| s.name.len()).max().unwrap_or(0).max(6) + 2;
+        let _ = writeln!(out, "\nSymbols\n{:<width$}{:<12}{:<9}Defined", "Name", "Address", "Section");
+        for symbol in &self.symbols {
+            let _ = writeln!(out, "{:<width$}0x{:08x}  {:<9}{}", symbol.name, symbol.addr, symbol.section, symbol.defined);
+        }
+        let _ = writeln!(out, "\nCross-reference\n{:<width$}Used at", "Name");
+        for symbol in &self.symbols {
+            let used = if symbol.references.is_empty() { "(unused)".to_string() } else { symbol.references.join("; ") };
+            let _ = writeln!(out, "{:<width$}{}", symbol.name, used);
+        }
+        out
+    }
+}
+
+// Strips a trailing # comment, ignoring any # inside string or character literals
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some(_), '\\') if !escaped => { escaped = true; continue; }
+            (Some(q), _) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '#') => return &line[..i],
+            _ => {}
+        }
+        escaped = false;
+    }
+    line
+}
+
+fn parse_string(s: &str) -> Result<Vec<u8>, String> {
+    let inner = s.trim().strip_prefix('"').and_then(|s| s.strip_suffix('"'))
+        .ok_or(format!("Expected a quoted string, found {}", s))?;
+    let mut bytes = vec![];
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        bytes.push(match chars.next() {
+            Some('n') => b'\n',
+            Some('t') => b'\t',
+            Some('r') => b'\r',
+            Some('0') => 0,
+            Some('\\') => b'\\',
+            Some('"') => b'"',
+            Some('\'') => b'\'',
+            _ => return Err(format!("Invalid escape sequence in {}", s)),
+        });
+    }
+    Ok(bytes)
+}
+
+// Splits directive arguments on commas, or on whitespace between items that are not one expression,
+// so both .word 1, 2 and .word 1 2 work alongside .word SIZE * 4
+fn list_items(args: &str) -> Vec<String> {
+    let mut items = vec![];
+    for item in split_args(args) {
+        match eval(&item, &|_| Some(0)) {
+            Err(ExprError::Invalid(_)) if !item.starts_with(['"', '\'']) => {
+                items.extend(item.split_whitespace().map(|s| s.to_string()));
+            }
+            _ => items.push(item),
+        }
+    }
+    items
+}
+
+fn parse_directive(line: &str, constants: &HashMap<String, u32>) -> Result<Directive, String> {
+    let line = line.trim();
+    let (name, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let args = args.trim();
+    let constant = |expr: &str| -> Result<u32, String> {
+        eval(expr, &|name| constants.get(name).copied()).map_err(|e| match e {
+            ExprError::Unknown(name) => format!("{} must be a constant", name),
+            ExprError::Invalid(message) => message,
+        })
+    };
+    let values = || -> Result<Vec<u32>, String> {
+        list_items(args).iter().map(|s| constant(s)).collect()
+    };
+    let address = || -> Result<Option<u32>, String> {
+        if args.is_empty() { Ok(None) } else { constant(args).map(Some) }
+    };
+    // Name and value of .set and .eqv, separated by a comma or whitespace
+    let definition = || -> Result<(String, String), String> {
+        let (symbol, value) = args.split_once([',', ' ', '\t']).ok_or(format!("Missing value in {}", line))?;
+        if !symbol.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') ||
+            !symbol.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("Invalid symbol name {}", symbol));
+        }
+        Ok((symbol.to_string(), value.trim_start_matches([',', ' ', '\t']).trim().to_string()))
+    };
+    Ok(match name {
+        ".text" => Directive::Section(Section::Text, address()?),
+        ".ktext" => Directive::Section(Section::KText, address()?),
+        ".data" => Directive::Section(Section::Data, address()?),
+        ".kdata" => Directive::Section(Section::KData, address()?),
+        ".word" => Directive::Word(list_items(args)),
+        ".half" => Directive::Half(values()?),
+        ".byte" => Directive::Byte(values()?),
+        ".space" => match constant(args)? {
+            n if n > MAX_SPACE => return Err(format!(".space {} is larger than the maximum of {} bytes", n, MAX_SPACE)),
+            n => Directive::Space(n),
+        },
+        ".ascii" => Directive::Ascii(parse_string(args)?),
+        ".asciiz" => {
+            let mut bytes = parse_string(args)?;
+            bytes.push(0);
+            Directive::Ascii(bytes)
+        }
+        ".float" => Directive::Float(args.split(|c: char| c == ',' || c.is_whitespace()).filter(|s| !s.is_empty())
+            .map(|s| s.parse::<f32>().map_err(|_| format!("Cannot parse float {}", s))).collect::<Result<_, _>>()?),
+        ".double" => Directive::Double(args.split(|c: char| c == ',' || c.is_whitespace()).filter(|s| !s.is_empty())
+            .map(|s| s.parse::<f64>().map_err(|_| format!("Cannot parse double {}", s))).collect::<Result<_, _>>()?),
+        ".align" => match constant(args)? {
+            n if n > 31 => return Err(format!(".align {} is out of range, expected 0 to 31", n)),
+            n => Directive::Align(n),
+        },
+        // .set with a single word is an assembler option such as noreorder, which has no effect here
+        ".set" if !args.contains([',', ' ', '\t']) => Directive::Ignored,
+        ".set" => {
+            let (symbol, value) = definition()?;
+            Directive::Set(symbol, constant(&value)?)
+        }
+        ".eqv" => {
+            let (symbol, value) = definition()?;
+            Directive::Eqv(symbol, value)
+        }
+        ".globl" | ".global" | ".extern" => Directive::Ignored,
+        _ => return Err(format!("Unknown directive {}", name)),
+    })
+}
+
+// Reads the lines of a source file, replacing .include directives with the lines of the named
+// file. Included paths are relative to the including file. `stack` holds the files being read,
+// to catch files that include themselves.
+fn load_source(text: &str, file: Option<String>, path: &Path, stack: &mut Vec<PathBuf>, out: &mut Vec<SourceLine>) -> Result<(), String> {
+    for (i, l) in text.lines().enumerate() {
+        let pos = Position { file: file.clone(), line: i + 1 };
+        let l = strip_comment(l);
+        let Some(name) = l.trim().strip_prefix(".include") else {
+            out.push(SourceLine { text: l.to_string(), pos, expansions: vec![] });
+            continue;
+        };
+        let name = String::from_utf8_lossy(&parse_string(name).map_err(|e| format!("{}: {}", pos, e))?).to_string();
+        let include = path.parent().unwrap_or(Path::new(".")).join(&name);
+        let canonical = include.canonicalize().map_err(|e| format!("{}: Cannot read {}: {}", pos, name, e))?;
+        if stack.contains(&canonical) {
+            let chain: Vec<String> = stack.iter().chain([&canonical]).map(|p| p.display().to_string()).collect();
+            return Err(format!("{}: Include cycle {}", pos, chain.join(" -> ")));
+        }
+        let contents = std::fs::read_to_string(&include).map_err(|e| format!("{}: Cannot read {}: {}", pos, name, e))?;
+        stack.push(canonical);
+        load_source(&contents, Some(name), &include, stack, out)?;
+        stack.pop();
+    }
+    Ok(())
+}
+
+// Assembles MIPS source in two passes: the first lays out sections and maps labels to addresses,
+// the second resolves label references into executable instructions.
+// `path` is the file the source was read from, which includes are found relative to.
+pub fn assemble(source: &str, path: &Path, isa: IsaLevel) -> Result<Program, String> {
+    // Pull in includes and expand macros first, so the passes below only see plain instructions and directives
+    let mut raw_lines = vec![];
+    let mut stack = vec![path.canonicalize().unwrap_or(path.to_path_buf())];
+    load_source(source, None, path, &mut stack, &mut raw_lines)?;
+    let source_lines = expand_macros(raw_lines)?;
+    // Stores parsed instructions along with their address, the index of the source line they came
+    // from and any immediate expression waiting on labels
+    let mut p_instrs: Vec<(u32, ParsedInstr, usize, Option<String>)> = vec![];
+    let mut data: Vec<(u32, Vec<u8>, u32)> = vec![];
+    // .word entries naming a label: (data chunk, byte offset, label, source line index)
+    let mut word_fixups: Vec<(usize, usize, String, usize)> = vec![];
+    // Stores label mappings, along with the section and source line index they are defined in
+    let mut labels: HashMap<String, (u32, Section, usize)> = HashMap::new();
+    // Constants from .set, and .eqv substitutions
+    let mut constants: HashMap<String, u32> = HashMap::new();
+    let mut eqvs: HashMap<String, String> = HashMap::new();
+    // Labels waiting for the next item, so they pick up any alignment applied to it
+    let mut pending: Vec<(String, usize)> = vec![];
+    // Location counter for each section
+    let mut section = Section::Text;
+    let mut counters: HashMap<Section, u32> = HashMap::from([
+        (Section::Text, TEXT_BASE), (Section::KText, KTEXT_BASE),
+        (Section::Data, DATA_BASE), (Section::KData, KDATA_BASE),
+    ]);
+    for (index, source_line) in source_lines.iter().enumerate() {
+        let err = |s: String| format!("{}: {}", source_line.location(), s);
+        // A later .eqv of the same name replaces the earlier one, so its name must not be
+        // substituted; its value is, when it is stored
+        let defines_eqv = matches!(parse_line(&source_line.text), Ok(Line { body: Some(Body::Directive(d)), .. }) if d.starts_with(".eqv"));
+        let text = if defines_eqv { source_line.text.clone() } else { substitute(&source_line.text, &eqvs) };
+        // Parse errors point at a column of the line after .eqv substitution
+        let err_at = |e: ParseError| {
+            let substituted = SourceLine { text: text.clone(), ..source_line.clone() };
+            format!("{}: {}", substituted.location_at(e.span.start), e.message)
+        };
+        let line = parse_line(&text).map_err(err_at)?;
+        for (label, span) in line.labels {
+            let defined = labels.get(&label).map(|l| l.2).or(pending.iter().find(|p| p.0 == label).map(|p| p.1));
+            if let Some(defined) = defined {
+                return Err(err_at(ParseError::new(format!("Label {} is already defined at {}", label, source_lines[defined].location()), span)));
+            }
+            pending.push((label, index));
+        }
+        let Some(body) = line.body else {
+            continue;
+        };
+        let counter = counters.get_mut(&section).unwrap();
+        let statement = match body {
+            Body::Instr(statement) => statement,
+            Body::Directive(directive) => {
+                let directive = parse_directive(&directive, &constants).map_err(err)?;
+                let is_text = section == Section::Text || section == Section::KText;
+                let (align, bytes) = match directive {
+                    Directive::Section(s, addr) => {
+                        for (label, line) in pending.drain(..) {
+                            labels.insert(label, (*counter, section, line));
+                        }
+                        section = s;
+                        if let Some(addr) = addr {
+                            counters.insert(s, addr);
+                        }
+                        continue;
+                    }
+                    Directive::Set(symbol, value) => {
+                        constants.insert(symbol, value);
+                        continue;
+                    }
+                    Directive::Eqv(symbol, value) => {
+                        let value = substitute(&value, &eqvs);
+                        eqvs.insert(symbol, value);
+                        continue;
+                    }
+                    Directive::Ignored => continue,
+                    // Code falls through the padding, so it is filled with nops
+                    Directive::Align(n) if is_text => {
+                        let aligned = counter.checked_next_multiple_of(1 << n).ok_or_else(|| err(section.overflow()))?;
+                        if aligned - *counter > MAX_SPACE {
+                            return Err(err(format!(".align {} would pad the text section by more than {} bytes", n, MAX_SPACE)));
+                        }
+                        while *counter < aligned {
+                            p_instrs.push((*counter, ParsedInstr::I(Instr::Nop), index, None));
+                            *counter += 4;
+                        }
+                        continue;
+                    }
+                    _ if is_text => return Err(err("Data directives are not allowed in a text section".to_string())),
+                    Directive::Word(words) => {
+                        let mut bytes = vec![];
+                        for word in words {
+                            let value = match eval(&word, &|name| constants.get(name).copied()) {
+                                Ok(value) => value,
+                                // Label values are filled in once all labels are known
+                                Err(ExprError::Unknown(_)) => {
+                                    word_fixups.push((data.len(), bytes.len(), word, index));
+                                    0
+                                }
+                                Err(ExprError::Invalid(message)) => return Err(err(message)),
+                            };
+                            bytes.extend_from_slice(&value.to_le_bytes());
+                        }
+                        (4, bytes)
+                    }
+                    Directive::Half(values) => (2, values.iter().flat_map(|v| (*v as u16).to_le_bytes()).collect()),
+                    Directive::Byte(values) => (1, values.iter().map(|v| *v as u8).collect()),
+                    Directive::Space(n) => (1, vec![0; n as usize]),
+                    Directive::Ascii(bytes) => (1, bytes),
+                    Directive::Float(values) => (4, values.iter().flat_map(|v| v.to_le_bytes()).collect()),
+                    Directive::Double(values) => (8, values.iter().flat_map(|v| v.to_le_bytes()).collect()),
+                    Directive::Align(n) => (1 << n, vec![]),
+                };
+                *counter = counter.checked_next_multiple_of(align).ok_or_else(|| err(section.overflow()))?;
+                for (label, line) in pending.drain(..) {
+                    labels.insert(label, (*counter, section, line));
+                }
+                let end = counter.checked_add(bytes.len() as u32).ok_or_else(|| err(section.overflow()))?;
+                data.push((*counter, bytes, align));
+                *counter = end;
+                continue;
+            }
+        };
+        if section == Section::Data || section == Section::KData {
+            return Err(err("Instructions are not allowed in a data section".to_string()));
+        }
+        for (label, line) in pending.drain(..) {
+            labels.insert(label, (*counter, section, line));
+        }
+        let (p_instr, deferred) = ParsedInstr::from_statement(&statement, &text, &|name| constants.get(name).copied())
+            .map_err(err_at)?;
+        let end = counter.checked_add(4 * p_instr.len()).ok_or_else(|| err(section.overflow()))?;
+        p_instrs.push((*counter, p_instr, index, deferred));
+        *counter = end;
+    }
+    let counter = counters[&section];
+    for (label, line) in pending.drain(..) {
+        labels.insert(label, (counter, section, line));
+    }
+    // Source line indices referring to each label, for the cross-reference
+    let references: RefCell<HashMap<&str, Vec<usize>>> = RefCell::new(HashMap::new());
+    // Evaluates an expression that may refer to labels, e.g. table+12
+    let find_label = |expr: &String, index: usize| -> Result<u32, String> {
+        eval(expr, &|name| {
+            if let Some(value) = constants.get(name) {
+                return Some(*value);
+            }
+            let (label, (addr, _, _)) = labels.get_key_value(name)?;
+            references.borrow_mut().entry(label.as_str()).or_default().push(index);
+            Some(*addr)
+        }).map_err(|e| format!("{}: {}", source_lines[index].location(), e.message()))
+    };
+    // Fill in .word directives that referenced labels
+    for (chunk, offset, label, line) in &word_fixups {
+        let addr = find_label(label, *line)?;
+        data[*chunk].1[*offset..*offset + 4].copy_from_slice(&addr.to_le_bytes());
+    }
+    // Map parsed instructions to instructions ready to execute
+    let mut text: BTreeMap<u32, Instr> = BTreeMap::new();
+    let mut lines: HashMap<u32, Position> = HashMap::new();
+    let mut sources: HashMap<u32, (String, String)> = HashMap::new();
+    for (pc, inst, line, deferred) in &p_instrs {
+        let rel_addr = |label: &String| -> Result<i32, String> {
+            Ok((find_label(label, *line)?.wrapping_sub(pc + 4) as i32) / 4)
+        };
+        let instrs = match &inst{
+            // Typical instructions can just be pulled out of any parsed instructions
+            ParsedInstr::I(inner) => match deferred {
+                Some(expr) => {
+                    let instr = inner.with_immediate(find_label(expr, *line)?);
+                    if !instr.immediate_fits() {
+                        return Err(format!("{}: {} does not fit in {:?}", source_lines[*line].location(), expr, instr));
+                    }
+                    vec![instr]
+                }
+                None => vec![*inner],
+            },
+            // Handle labelled instructions
+            // Beq, Bne, etc use addresses relative to the delay slot in words
+            // J, and Jal use absolute addresses
+            ParsedInstr::Beq{rt, rs, label} => vec![Instr::Beq{rt: *rt, rs: *rs, rel_addr: rel_addr(label)?}],
+            ParsedInstr::Bne{rt, rs, label} => vec![Instr::Bne{rt: *rt, rs: *rs, rel_addr: rel_addr(label)?}],
+            ParsedInstr::Bgez{rs, label} | ParsedInstr::Bgtz{rs, label} | ParsedInstr::Blez{rs, label} |
+            ParsedInstr::Bltz{rs, label} | ParsedInstr::Bgezal{rs, label} | ParsedInstr::Bltzal{rs, label} => {
+                let rel_addr = rel_addr(label)?;
+                vec![match &inst {
+                    ParsedInstr::Bgez{..} => Instr::Bgez{rs: *rs, rel_addr},
+                    ParsedInstr::Bgtz{..} => Instr::Bgtz{rs: *rs, rel_addr},
+                    ParsedInstr::Blez{..} => Instr::Blez{rs: *rs, rel_addr},
+                    ParsedInstr::Bltz{..} => Instr::Bltz{rs: *rs, rel_addr},
+                    ParsedInstr::Bgezal{..} => Instr::Bgezal{rs: *rs, rel_addr},
+                    ParsedInstr::Bltzal{..} => Instr::Bltzal{rs: *rs, rel_addr},
+                    _ => unreachable!(),
+                }]
+            }
+            ParsedInstr::Jump{label} => vec![Instr::Jump{addr: find_label(label, *line)?}],
+            ParsedInstr::Jal{label} => vec![Instr::Jal{addr: find_label(label, *line)?}],
+            ParsedInstr::Bc1t{cc, label} => vec![Instr::Bc1t{cc: *cc, rel_addr: rel_addr(label)?}],
+            ParsedInstr::Bc1f{cc, label} => vec![Instr::Bc1f{cc: *cc, rel_addr: rel_addr(label)?}],
+            ParsedInstr::Pseudo(pseudo) => pseudo.expand(*pc, &|label| find_label(label, *line))?,
+        };
+        for (i, instr) in instrs.into_iter().enumerate() {
+            if instr.isa_level() > isa {
+                return Err(format!("{}: {:?} requires {} (assembling for {})", source_lines[*line].location(), instr, instr.isa_level().name(), isa.name()));
+            }
+            let addr = pc + 4 * i as u32;
+            instr.check_encodable(addr).map_err(|e| format!("{}: {}", source_lines[*line].location(), e))?;
+            text.insert(addr, instr);
+            lines.insert(addr, source_lines[*line].pos.clone());
+            sources.insert(addr, (source_lines[*line].location(), source_lines[*line].text.clone()));
+        }
+    }
+    let mut references = references.into_inner();
+    let mut symbols: Vec<Symbol> = labels.iter().map(|(name, (addr, section, line))| {
+        let mut used = references.remove(name.as_str()).unwrap_or_default();
+        used.sort();
+        used.dedup();
+        Symbol {
+            name: name.clone(),
+            addr: *addr,
+            section: section.name(),
+            defined: source_lines[*line].location(),
+            references: used.iter().map(|i| source_lines[*i].location()).collect(),
+        }
+    }).collect();
+    symbols.sort_by(|a, b| (a.addr, &a.name).cmp(&(b.addr, &b.name)));
+    Ok(Program { text, lines, data, source: sources, symbols })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assemble_str(source: &str) -> Result<Program, String> {
+        assemble(source, Path::new("test.s"), IsaLevel::Mips32r2)
+    }
+
+    fn error(source: &str) -> String {
+        assemble_str(source).err().expect("assembled")
+    }
+
+    #[test]
+    fn align_in_text_pads_with_nops() {
+        let program = assemble_str(".text\nli $a0, 1\n.align 3\nf: li $a0, 2\n").unwrap();
+        assert!(matches!(program.text[&0x00400004], Instr::Nop));
+        assert_eq!(program.symbol("f"), Some(0x00400008));
+    }
+
+    #[test]
+    fn align_out_of_range() {
+        assert!(error(".data\n.align 32\n").contains("out of range"));
+        assert!(error(".text\nnop\n.align 31\n").contains("pad"));
+    }
+
+    #[test]
+    fn word_format_only_in_conversions() {
+        assert!(error("add.w $f2, $f0, $f1\n").contains("no word format"));
+        assert!(error("mov.w $f2, $f0\n").contains("no word format"));
+        assert!(error("c.eq.w $f2, $f0\n").contains("Cannot compare words"));
+        assert!(assemble_str("cvt.s.w $f2, $f0\nadd.s $f2, $f0, $f1\n").is_ok());
+    }
+
+    #[test]
+    fn included_lines_keep_their_file() {
+        let dir = std::env::temp_dir().join(format!("mipsemu-include-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("lib.s"), "f: jr $ra\nnop\n").unwrap();
+        let program = assemble("jal f\nnop\n.include \"lib.s\"\n", &dir.join("main.s"), IsaLevel::Mips32r2).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(program.lines[&0x00400004], Position { file: None, line: 2 });
+        assert_eq!(program.lines[&0x00400008], Position { file: Some("lib.s".to_string()), line: 1 });
+        assert_eq!(program.lines[&0x00400008].short(), "lib.s:1");
+    }
+
+    #[test]
+    fn eqv_leaves_strings_alone() {
+        let program = assemble_str(".eqv SIZE 4\n.eqv REG $t1\n.data\n.asciiz \"SIZE\"\n.byte 'S', SIZE\n.text\naddiu REG, $0, SIZE\n").unwrap();
+        assert_eq!(program.data[0].1, b"SIZE\0");
+        assert_eq!(program.data[1].1, [b'S', 4]);
+        assert_eq!(program.text[&0x00400000].encode(), 0x24090004);
+    }
+
+    #[test]
+    fn eqv_in_terms_of_another() {
+        let program = assemble_str(".eqv A 4\n.eqv B (A+1)\n.eqv A B*2\nli $a0, B\nli $a1, A\n").unwrap();
+        assert_eq!(program.text[&0x00400000].encode(), 0x24040005);
+        assert_eq!(program.text[&0x00400004].encode(), 0x2405000a);
+    }
+
+    #[test]
+    fn space_is_bounded() {
+        assert!(error(".data\n.space 0xffffffff\n").contains("larger than"));
+        assert!(error(".data 0xfffffff0\n.space 32\n").contains("past the end"));
+        assert!(error(".data 0xfffffff0\n.word 1, 2, 3, 4, 5\n").contains("past the end"));
+    }
+}
@@ -0,0 +1,250 @@
+// Headless version of the MARS "Bitmap Display" tool. The framebuffer is ordinary memory, one
+// 0x00RRGGBB word per unit, and frames are captured from it to PNG or PPM files.
+use crate::memory::{Memory, DATA_BASE, GP_INIT, HEAP_BASE};
+use crate::mmio::MMIO_BASE;
+
+// Service number of the syscall that writes a frame, above the ones MARS uses
+pub const DUMP_FRAME_SERVICE: u32 = 100;
+// Largest width or height in pixels, well beyond the 1024 MARS allows
+pub const MAX_DIMENSION: u32 = 8192;
+
+#[derive(Clone, Debug)]
+pub struct BitmapConfig {
+    // Display size in pixels
+    pub width: u32,
+    pub height: u32,
+    // Width and height in pixels of each unit, so each word of memory covers unit*unit pixels
+    pub unit: u32,
+    pub base: u32,
+}
+impl BitmapConfig {
+    // Parses a comma separated key=value list, e.g. "width=256,height=256,unit=8,base=gp". Any key
+    // that is left out keeps the MARS default. The base may be an address or one of the choices
+    // MARS offers: global (0x10000000), gp, data, heap or mmio.
+    pub fn from_str(spec: &str) -> Result<BitmapConfig, String> {
+        let mut config = BitmapConfig { width: 512, height: 256, unit: 1, base: DATA_BASE };
+        let parse_num = |s: &str| s.parse::<u32>().map_err(|_| format!("Invalid bitmap parameter value {}", s));
+        for field in spec.split(',').filter(|s| !s.is_empty()) {
+            let (key, value) = field.split_once('=').ok_or(format!("Bitmap parameter {} is not key=value", field))?;
+            match key {
+                "width" => config.width = parse_num(value)?,
+                "height" => config.height = parse_num(value)?,
+                "unit" => config.unit = parse_num(value)?,
+                "base" => config.base = match value {
+                    "global" => 0x10000000,
+                    "gp" => GP_INIT,
+                    "data" => DATA_BASE,
+                    "heap" => HEAP_BASE,
+                    "mmio" => MMIO_BASE,
+                    _ => crate::expr::eval(value, &|_| None).map_err(|_| format!("Cannot parse bitmap base address {}", value))?,
+                },
+                _ => return Err(format!("Unknown bitmap parameter {}", key)),
+            }
+        }
+        if config.unit == 0 || config.width == 0 || config.height == 0 {
+            return Err("Bitmap width, height and unit must be greater than zero".to_string());
+        }
+        if config.width > MAX_DIMENSION || config.height > MAX_DIMENSION {
+            return Err(format!("Bitmap width and height must be at most {}", MAX_DIMENSION));
+        }
+        if !config.width.is_multiple_of(config.unit) || !config.height.is_multiple_of(config.unit) {
+            return Err("Bitmap width and height must be multiples of the unit size".to_string());
+        }
+        if !config.base.is_multiple_of(4) {
+            return Err(format!("Bitmap base address 0x{:08x} must be word aligned", config.base));
+        }
+        Ok(config)
+    }
+}
+
+pub struct Bitmap {
+    config: BitmapConfig,
+    // Frame file name, {} is replaced by the frame number. The extension picks PNG or PPM.
+    output: String,
+    // Capture a frame every this many instructions
+    every: Option<u64>,
+    instructions: u64,
+    frames: u32,
+}
+impl Bitmap {
+    pub fn new(config: BitmapConfig, output: String, every: Option<u64>) -> Bitmap {
+        Bitmap { config, output, every, instructions: 0, frames: 0 }
+    }
+    // Captures a frame if one is due after another instruction
+    pub fn tick(&mut self, mem: &Memory) -> Result<(), String> {
+        self.instructions += 1;
+        match self.every {
+            Some(every) if self.instructions.is_multiple_of(every) => self.dump(mem),
+            _ => Ok(()),
+        }
+    }
+    // The display as rows of RGB bytes, each unit filling unit*unit pixels
+    fn pixels(&self, mem: &Memory) -> Result<Vec<u8>, String> {
+        let BitmapConfig { width, height, unit, base } = self.config;
+        let size = (width as usize).checked_mul(height as usize).and_then(|n| n.checked_mul(3))
+            .ok_or(format!("Bitmap of {}x{} pixels is too large", width, height))?;
+        let mut pixels = Vec::with_capacity(size);
+        for y in 0..height {
+            for x in 0..width {
+                let addr = base.wrapping_add(4 * ((y / unit) * (width / unit) + x / unit));
+                let color = mem.load_word(addr).unwrap_or(0);
+                pixels.extend_from_slice(&color.to_be_bytes()[1..]);
+            }
+        }
+        Ok(pixels)
+    }
+    // Writes the current contents of the display to the next frame file
+    pub fn dump(&mut self, mem: &Memory) -> Result<(), String> {
+        let file = self.output.replace("{}", &self.frames.to_string());
+        self.frames += 1;
+        let pixels = self.pixels(mem)?;
+        let (width, height) = (self.config.width, self.config.height);
+        let image = if file.to_ascii_lowercase().ends_with(".ppm") {
+            let mut image = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+            image.extend_from_slice(&pixels);
+            image
+        } else {
+            png(width, height, &pixels)
+        };
+        std::fs::write(&file, image).map_err(|e| format!("Could not write bitmap frame {}: {}", file, e))
+    }
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (a, b) = bytes.iter().fold((1u32, 0u32), |(a, b), byte| {
+        let a = (a + *byte as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+    b << 16 | a
+}
+
+// An 8 bit RGB PNG. The image data is zlib wrapped but left uncompressed, in stored deflate blocks.
+fn png(width: u32, height: u32, pixels: &[u8]) -> Vec<u8> {
+    let mut raw = vec![];
+    for row in pixels.chunks(width as usize * 3) {
+        // No filter on each scanline
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+    let mut zlib = vec![0x78, 0x01];
+    let blocks: Vec<&[u8]> = raw.chunks(0xffff).collect();
+    for (i, block) in blocks.iter().enumerate() {
+        zlib.push((i == blocks.len() - 1) as u8);
+        zlib.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        zlib.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    let mut out = b"\x89PNG\r\n\x1a\n".to_vec();
+    let mut chunk = |kind: &[u8], data: &[u8]| {
+        out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        let start = out.len();
+        out.extend_from_slice(kind);
+        out.extend_from_slice(data);
+        let crc = crc32(&out[start..]);
+        out.extend_from_slice(&crc.to_be_bytes());
+    };
+    let mut header = vec![];
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // 8 bits per channel, truecolor, then the default compression, filter and interlace methods
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+    chunk(b"IHDR", &header);
+    chunk(b"IDAT", &zlib);
+    chunk(b"IEND", &[]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A 4x2 display of 2x2 units, so two words: red then blue
+    fn display() -> (Bitmap, Memory) {
+        let config = BitmapConfig::from_str("width=4,height=2,unit=2").unwrap();
+        let mut mem = Memory::new();
+        mem.store_word(DATA_BASE, 0x00ff0000).unwrap();
+        mem.store_word(DATA_BASE + 4, 0x000000ff).unwrap();
+        (Bitmap::new(config, String::new(), None), mem)
+    }
+
+    #[test]
+    fn config() {
+        let config = BitmapConfig::from_str("width=256,height=128,unit=8,base=gp").unwrap();
+        assert_eq!((config.width, config.height, config.unit, config.base), (256, 128, 8, GP_INIT));
+        assert!(BitmapConfig::from_str("width=0").is_err());
+        assert!(BitmapConfig::from_str("width=12,unit=8").is_err());
+        assert!(BitmapConfig::from_str("base=0x10010002").is_err());
+        assert!(BitmapConfig::from_str("width=65536,height=65536").is_err());
+        assert!(BitmapConfig::from_str("width=8192,height=8192").is_ok());
+    }
+
+    #[test]
+    fn units_cover_unit_square_pixels() {
+        let (bitmap, mem) = display();
+        let (red, blue) = ([0xff, 0, 0], [0, 0, 0xff]);
+        let row = [red, red, blue, blue].concat();
+        assert_eq!(bitmap.pixels(&mem).unwrap(), [row.clone(), row].concat());
+    }
+
+    #[test]
+    fn ppm() {
+        let (mut bitmap, mem) = display();
+        let file = std::env::temp_dir().join(format!("mipsemu-bitmap-{}.ppm", std::process::id()));
+        bitmap.output = file.display().to_string();
+        bitmap.dump(&mem).unwrap();
+        let image = std::fs::read(&file).unwrap();
+        std::fs::remove_file(&file).unwrap();
+        let mut expected = b"P6\n4 2\n255\n".to_vec();
+        expected.extend_from_slice(&bitmap.pixels(&mem).unwrap());
+        assert_eq!(image, expected);
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+    }
+
+    #[test]
+    fn png_chunks() {
+        let (bitmap, mem) = display();
+        let image = png(4, 2, &bitmap.pixels(&mem).unwrap());
+        assert_eq!(&image[..8], b"\x89PNG\r\n\x1a\n");
+        // Each chunk is a length, a type, the data and a CRC of the type and data
+        let mut chunks = vec![];
+        let mut rest = &image[8..];
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            let crc = u32::from_be_bytes(rest[8 + len..12 + len].try_into().unwrap());
+            assert_eq!(crc, crc32(&rest[4..8 + len]));
+            chunks.push((&rest[4..8], &rest[8..8 + len]));
+            rest = &rest[12 + len..];
+        }
+        let kinds: Vec<&[u8]> = chunks.iter().map(|c| c.0).collect();
+        assert_eq!(kinds, [b"IHDR", b"IDAT", b"IEND"]);
+        assert_eq!(chunks[0].1, [0, 0, 0, 4, 0, 0, 0, 2, 8, 2, 0, 0, 0]);
+        // A zlib header, one final stored block of the two filtered scanlines, then the Adler-32
+        let zlib = chunks[1].1;
+        assert_eq!(&zlib[..2], [0x78, 0x01]);
+        assert_eq!(&zlib[2..7], [1, 26, 0, !26, 0xff]);
+        let raw = &zlib[7..zlib.len() - 4];
+        assert_eq!(raw.len(), 26);
+        assert_eq!((raw[0], raw[13]), (0, 0));
+        assert_eq!(&zlib[zlib.len() - 4..], adler32(raw).to_be_bytes());
+        // The IEND CRC every PNG ends with
+        assert_eq!(&image[image.len() - 4..], [0xae, 0x42, 0x60, 0x82]);
+    }
+}
@@ -0,0 +1,405 @@
+use crate::macros::Position;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+// A direction predictor for conditional branches. predict() is called before the branch
+// resolves and update() once the outcome is known.
+pub trait Predictor {
+    fn name(&self) -> String;
+    fn predict(&mut self, pc: u32, target: u32) -> bool;
+    fn update(&mut self, pc: u32, target: u32, taken: bool);
+}
+
+fn table_index(pc: u32, bits: u32) -> usize {
+    ((pc >> 2) & ((1 << bits) - 1)) as usize
+}
+// Two bit saturating counter helpers, 0-1 predict not taken and 2-3 predict taken
+fn counter_update(counter: &mut u8, taken: bool) {
+    if taken {
+        *counter = (*counter + 1).min(3);
+    } else {
+        *counter = counter.saturating_sub(1);
+    }
+}
+
+pub struct StaticNotTaken;
+impl Predictor for StaticNotTaken {
+    fn name(&self) -> String {
+        "static not-taken".to_string()
+    }
+    fn predict(&mut self, _pc: u32, _target: u32) -> bool {
+        false
+    }
+    fn update(&mut self, _pc: u32, _target: u32, _taken: bool) {}
+}
+
+// Backward taken, forward not taken: loops are predicted taken
+pub struct Btfnt;
+impl Predictor for Btfnt {
+    fn name(&self) -> String {
+        "backward-taken/forward-not-taken".to_string()
+    }
+    fn predict(&mut self, pc: u32, target: u32) -> bool {
+        target <= pc
+    }
+    fn update(&mut self, _pc: u32, _target: u32, _taken: bool) {}
+}
+
+pub struct OneBit {
+    bits: u32,
+    table: Vec<bool>,
+}
+impl OneBit {
+    pub fn new(bits: u32) -> OneBit {
+        OneBit { bits, table: vec![false; 1 << bits] }
+    }
+}
+impl Predictor for OneBit {
+    fn name(&self) -> String {
+        format!("1-bit ({} entries)", self.table.len())
+    }
+    fn predict(&mut self, pc: u32, _target: u32) -> bool {
+        self.table[table_index(pc, self.bits)]
+    }
+    fn update(&mut self, pc: u32, _target: u32, taken: bool) {
+        self.table[table_index(pc, self.bits)] = taken;
+    }
+}
+
+pub struct TwoBit {
+    bits: u32,
+    table: Vec<u8>,
+}
+impl TwoBit {
+    pub fn new(bits: u32) -> TwoBit {
+        TwoBit { bits, table: vec![1; 1 << bits] }
+    }
+}
+impl Predictor for TwoBit {
+    fn name(&self) -> String {
+        format!("2-bit ({} entries)", self.table.len())
+    }
+    fn predict(&mut self, pc: u32, _target: u32) -> bool {
+        self.table[table_index(pc, self.bits)] >= 2
+    }
+    fn update(&mut self, pc: u32, _target: u32, taken: bool) {
+        counter_update(&mut self.table[table_index(pc, self.bits)], taken);
+    }
+}
+
+// Two bit counters indexed by the pc xor'd with the global branch history
+pub struct Gshare {
+    bits: u32,
+    history_bits: u32,
+    history: u32,
+    table: Vec<u8>,
+}
+impl Gshare {
+    pub fn new(bits: u32, history_bits: u32) -> Gshare {
+        Gshare { bits, history_bits, history: 0, table: vec![1; 1 << bits] }
+    }
+    fn index(&self, pc: u32) -> usize {
+        (((pc >> 2) ^ self.history) & ((1 << self.bits) - 1)) as usize
+    }
+}
+impl Predictor for Gshare {
+    fn name(&self) -> String {
+        format!("gshare ({} entries, {} history bits)", self.table.len(), self.history_bits)
+    }
+    fn predict(&mut self, pc: u32, _target: u32) -> bool {
+        self.table[self.index(pc)] >= 2
+    }
+    fn update(&mut self, pc: u32, _target: u32, taken: bool) {
+        let index = self.index(pc);
+        counter_update(&mut self.table[index], taken);
+        self.history = ((self.history << 1) | taken as u32) & ((1 << self.history_bits) - 1);
+    }
+}
+
+// Chooses per branch between a 2-bit (local) and a gshare (global) predictor
+pub struct Tournament {
+    bits: u32,
+    local: TwoBit,
+    global: Gshare,
+    // 0-1 prefer the local predictor, 2-3 prefer the global one
+    chooser: Vec<u8>,
+}
+impl Tournament {
+    pub fn new(bits: u32, history_bits: u32) -> Tournament {
+        Tournament {
+            bits,
+            local: TwoBit::new(bits),
+            global: Gshare::new(bits, history_bits),
+            chooser: vec![1; 1 << bits],
+        }
+    }
+}
+impl Predictor for Tournament {
+    fn name(&self) -> String {
+        format!("tournament ({} entries, {} history bits)", self.chooser.len(), self.global.history_bits)
+    }
+    fn predict(&mut self, pc: u32, target: u32) -> bool {
+        let local = self.local.predict(pc, target);
+        let global = self.global.predict(pc, target);
+        if self.chooser[table_index(pc, self.bits)] >= 2 { global } else { local }
+    }
+    fn update(&mut self, pc: u32, target: u32, taken: bool) {
+        let local_correct = self.local.predict(pc, target) == taken;
+        let global_correct = self.global.predict(pc, target) == taken;
+        if local_correct != global_correct {
+            counter_update(&mut self.chooser[table_index(pc, self.bits)], global_correct);
+        }
+        self.local.update(pc, target, taken);
+        self.global.update(pc, target, taken);
+    }
+}
+
+// Direct mapped branch target buffer
+pub struct Btb {
+    bits: u32,
+    entries: Vec<Option<(u32, u32)>>,
+    pub hits: u64,
+    pub misses: u64,
+}
+impl Btb {
+    pub fn new(bits: u32) -> Btb {
+        Btb { bits, entries: vec![None; 1 << bits], hits: 0, misses: 0 }
+    }
+    pub fn lookup(&mut self, pc: u32) -> Option<u32> {
+        match self.entries[table_index(pc, self.bits)] {
+            Some((tag, target)) if tag == pc => {
+                self.hits += 1;
+                Some(target)
+            }
+            _ => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+    pub fn update(&mut self, pc: u32, target: u32) {
+        self.entries[table_index(pc, self.bits)] = Some((pc, target));
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct BranchStats {
+    pub branches: u64,
+    pub taken: u64,
+    pub correct: u64,
+}
+impl BranchStats {
+    pub fn accuracy(&self) -> f64 {
+        if self.branches == 0 {
+            return 0.0;
+        }
+        self.correct as f64 / self.branches as f64
+    }
+}
+
+// A predictor together with an optional BTB and the statistics gathered while running
+pub struct BranchUnit {
+    pub predictor: Box<dyn Predictor>,
+    pub btb: Option<Btb>,
+    pub stats: BranchStats,
+    pub per_pc: HashMap<u32, BranchStats>,
+}
+impl BranchUnit {
+    // Parses a comma separated spec, e.g. "gshare,bits=10,history=8,btb=6". The first field is the
+    // predictor type: not-taken, btfnt, 1bit, 2bit, gshare or tournament. bits and btb are log2 of the
+    // number of table entries.
+    pub fn from_str(spec: &str) -> Result<BranchUnit, String> {
+        let mut fields = spec.split(',');
+        let kind = fields.next().unwrap_or("");
+        let mut bits = 10;
+        let mut history = 8;
+        let mut btb = None;
+        for field in fields.filter(|s| !s.is_empty()) {
+            let (key, value) = field.split_once('=').ok_or(format!("Predictor parameter {} is not key=value", field))?;
+            let value = value.parse::<u32>().map_err(|_| format!("Invalid predictor parameter value {}", value))?;
+            if value == 0 || value > 24 {
+                return Err(format!("Predictor parameter {} must be between 1 and 24", key));
+            }
+            match key {
+                "bits" => bits = value,
+                "history" => history = value,
+                "btb" => btb = Some(Btb::new(value)),
+                _ => return Err(format!("Unknown predictor parameter {}", key)),
+            }
+        }
+        let predictor: Box<dyn Predictor> = match kind {
+            "not-taken" => Box::new(StaticNotTaken),
+            "btfnt" => Box::new(Btfnt),
+            "1bit" => Box::new(OneBit::new(bits)),
+            "2bit" => Box::new(TwoBit::new(bits)),
+            "gshare" => Box::new(Gshare::new(bits, history)),
+            "tournament" => Box::new(Tournament::new(bits, history)),
+            _ => return Err(format!("Unknown branch predictor {}", kind)),
+        };
+        Ok(BranchUnit::new(predictor, btb))
+    }
+    pub fn new(predictor: Box<dyn Predictor>, btb: Option<Btb>) -> BranchUnit {
+        BranchUnit { predictor, btb, stats: BranchStats::default(), per_pc: HashMap::new() }
+    }
+    // Records a resolved conditional branch, returns whether it was predicted correctly. A taken
+    // prediction only counts as correct when the BTB (if any) also supplied the right target.
+    pub fn record_branch(&mut self, pc: u32, target: u32, taken: bool) -> bool {
+        let predicted = self.predictor.predict(pc, target);
+        let mut correct = predicted == taken;
+        if let Some(btb) = &mut self.btb {
+            if predicted {
+                correct &= btb.lookup(pc) == Some(target);
+            }
+            if taken {
+                btb.update(pc, target);
+            }
+        }
+        self.predictor.update(pc, target, taken);
+        for stats in [&mut self.stats, self.per_pc.entry(pc).or_default()] {
+            stats.branches += 1;
+            stats.taken += taken as u64;
+            stats.correct += correct as u64;
+        }
+        correct
+    }
+    // Unconditional jumps only exercise the BTB
+    pub fn record_jump(&mut self, pc: u32, target: u32) {
+        if let Some(btb) = &mut self.btb {
+            btb.lookup(pc);
+            btb.update(pc, target);
+        }
+    }
+    pub fn report(&self, lines: &HashMap<u32, Position>) -> String {
+        let mut out = String::new();
+        let s = &self.stats;
+        let _ = writeln!(out, "Branch predictor: {}", self.predictor.name());
+        let _ = writeln!(out, "  branches {}, taken {}, correct {} ({:.2}% accuracy)",
+            s.branches, s.taken, s.correct, s.accuracy() * 100.0);
+        if let Some(btb) = &self.btb {
+            let _ = writeln!(out, "  BTB: {} entries, hits {}, misses {}", btb.entries.len(), btb.hits, btb.misses);
+        }
+        let per_pc: BTreeMap<&u32, &BranchStats> = self.per_pc.iter().collect();
+        if !per_pc.is_empty() {
+            let _ = writeln!(out, "  {:>10} {:>6} {:>10} {:>10} {:>10}", "pc", "line", "executed", "taken", "accuracy");
+            for (pc, s) in per_pc {
+                let line = lines.get(pc).map_or("?".to_string(), Position::short);
+                let _ = writeln!(out, "  0x{:08x} {:>6} {:>10} {:>10} {:>9.2}%", pc, line, s.branches, s.taken, s.accuracy() * 100.0);
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PC: u32 = 0x00400020;
+    const LOOP_TOP: u32 = 0x00400000;
+
+    // Runs a branch through a sequence of outcomes, returning how many were predicted correctly
+    fn correct(predictor: &mut dyn Predictor, outcomes: &[bool]) -> usize {
+        outcomes.iter().filter(|taken| {
+            let predicted = predictor.predict(PC, LOOP_TOP);
+            predictor.update(PC, LOOP_TOP, **taken);
+            predicted == **taken
+        }).count()
+    }
+
+    // A loop branch taken three times then falling through, run the given number of times
+    fn loop_exits(runs: usize) -> Vec<bool> {
+        [true, true, true, false].repeat(runs)
+    }
+
+    #[test]
+    fn two_bit_hysteresis_on_loop_exit() {
+        let (mut one, mut two) = (OneBit::new(4), TwoBit::new(4));
+        correct(&mut one, &loop_exits(1));
+        correct(&mut two, &loop_exits(1));
+        // The 1-bit predictor misses the exit and the next entry, the 2-bit one only the exit
+        assert_eq!(correct(&mut one, &loop_exits(10)), 20);
+        assert_eq!(correct(&mut two, &loop_exits(10)), 30);
+    }
+
+    #[test]
+    fn alternating_pattern() {
+        let (mut one, mut two) = (OneBit::new(4), TwoBit::new(4));
+        correct(&mut one, &[true, true]);
+        correct(&mut two, &[true, true]);
+        let alternating = [false, true].repeat(10);
+        assert_eq!(correct(&mut one, &alternating), 0);
+        assert_eq!(correct(&mut two, &alternating), 10);
+    }
+
+    #[test]
+    fn gshare_history_is_masked() {
+        let mut gshare = Gshare::new(8, 2);
+        correct(&mut gshare, &[true, true, true]);
+        assert_eq!(gshare.history, 0b11);
+        correct(&mut gshare, &[false]);
+        assert_eq!(gshare.history, 0b10);
+        // History selects a different counter for the same branch
+        assert_ne!(gshare.index(PC), Gshare::new(8, 2).index(PC));
+    }
+
+    #[test]
+    fn gshare_learns_alternating_pattern() {
+        let mut gshare = Gshare::new(8, 4);
+        let alternating = [false, true].repeat(20);
+        correct(&mut gshare, &alternating);
+        assert_eq!(correct(&mut gshare, &alternating), alternating.len());
+    }
+
+    #[test]
+    fn tournament_chooser_moves_to_the_correct_predictor() {
+        let mut tournament = Tournament::new(8, 4);
+        let index = table_index(PC, 8);
+        assert_eq!(tournament.chooser[index], 1);
+        // The local 2-bit counter gets every alternation wrong, gshare learns it
+        let alternating = [false, true].repeat(20);
+        correct(&mut tournament, &alternating);
+        assert_eq!(tournament.chooser[index], 3);
+        assert_eq!(correct(&mut tournament, &alternating), alternating.len());
+    }
+
+    #[test]
+    fn btb_tags() {
+        let mut btb = Btb::new(2);
+        assert_eq!(btb.lookup(PC), None);
+        btb.update(PC, LOOP_TOP);
+        assert_eq!(btb.lookup(PC), Some(LOOP_TOP));
+        // Same entry, different tag
+        assert_eq!(btb.lookup(PC + 16), None);
+        btb.update(PC + 16, PC);
+        assert_eq!(btb.lookup(PC), None);
+        assert_eq!((btb.hits, btb.misses), (1, 3));
+    }
+
+    #[test]
+    fn taken_prediction_needs_the_right_target() {
+        let mut unit = BranchUnit::from_str("btfnt,btb=4").unwrap();
+        // Predicted taken, but the BTB has no target yet
+        assert!(!unit.record_branch(PC, LOOP_TOP, true));
+        assert!(unit.record_branch(PC, LOOP_TOP, true));
+        // A stale target, e.g. after the entry was replaced, is also a misprediction
+        unit.btb.as_mut().unwrap().update(PC, LOOP_TOP + 4);
+        assert!(!unit.record_branch(PC, LOOP_TOP, true));
+        // Not taken predictions don't need the BTB
+        assert!(unit.record_branch(PC, PC + 8, false));
+        assert_eq!((unit.stats.branches, unit.stats.taken, unit.stats.correct), (4, 3, 2));
+    }
+
+    #[test]
+    fn from_str() {
+        let names = ["not-taken", "btfnt", "1bit,bits=4", "2bit", "gshare,bits=12,history=6", "tournament,btb=6"]
+            .map(|spec| BranchUnit::from_str(spec).unwrap().predictor.name());
+        assert_eq!(names[2], "1-bit (16 entries)");
+        assert_eq!(names[4], "gshare (4096 entries, 6 history bits)");
+        assert!(BranchUnit::from_str("tournament,btb=6").unwrap().btb.is_some());
+        assert!(BranchUnit::from_str("perceptron").is_err());
+        assert!(BranchUnit::from_str("2bit,bits=0").is_err());
+        assert!(BranchUnit::from_str("2bit,bits=25").is_err());
+        assert!(BranchUnit::from_str("2bit,size=4").is_err());
+        assert!(BranchUnit::from_str("2bit,bits").is_err());
+    }
+}
@@ -0,0 +1,412 @@
+use crate::macros::Position;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Replacement {
+    Lru,
+    Fifo,
+    Random,
+}
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WritePolicy {
+    WriteBack,
+    WriteThrough,
+}
+
+#[derive(Clone, Debug)]
+pub struct CacheConfig {
+    pub size: u32,
+    pub associativity: u32,
+    pub block_size: u32,
+    pub replacement: Replacement,
+    pub write_policy: WritePolicy,
+    pub write_allocate: bool,
+}
+impl CacheConfig {
+    // Parses a comma separated key=value list, e.g. "size=4096,assoc=2,block=16,repl=lru,write=back,alloc=yes".
+    // Any key that is left out keeps its default.
+    pub fn from_str(spec: &str) -> Result<CacheConfig, String> {
+        let mut config = CacheConfig {
+            size: 4096,
+            associativity: 1,
+            block_size: 16,
+            replacement: Replacement::Lru,
+            write_policy: WritePolicy::WriteBack,
+            write_allocate: true,
+        };
+        let parse_num = |s: &str| s.parse::<u32>().map_err(|_| format!("Invalid cache parameter value {}", s));
+        for field in spec.split(',').filter(|s| !s.is_empty()) {
+            let (key, value) = field.split_once('=').ok_or(format!("Cache parameter {} is not key=value", field))?;
+            match key {
+                "size" => config.size = parse_num(value)?,
+                "assoc" => config.associativity = parse_num(value)?,
+                "block" => config.block_size = parse_num(value)?,
+                "repl" => config.replacement = match value {
+                    "lru" => Replacement::Lru,
+                    "fifo" => Replacement::Fifo,
+                    "random" => Replacement::Random,
+                    _ => return Err(format!("Unknown replacement policy {}", value)),
+                },
+                "write" => config.write_policy = match value {
+                    "back" => WritePolicy::WriteBack,
+                    "through" => WritePolicy::WriteThrough,
+                    _ => return Err(format!("Unknown write policy {}", value)),
+                },
+                "alloc" => config.write_allocate = match value {
+                    "yes" => true,
+                    "no" => false,
+                    _ => return Err(format!("Unknown write allocate setting {}", value)),
+                },
+                _ => return Err(format!("Unknown cache parameter {}", key)),
+            }
+        }
+        if !config.block_size.is_power_of_two() || config.block_size < 4 {
+            return Err("Cache block size must be a power of two of at least 4 bytes".to_string());
+        }
+        let set_size = config.block_size.checked_mul(config.associativity).filter(|size| *size != 0 && config.size.is_multiple_of(*size))
+            .ok_or("Cache size must be a multiple of block size * associativity")?;
+        if !(config.size / set_size).is_power_of_two() {
+            return Err("Cache must have a power of two number of sets".to_string());
+        }
+        Ok(config)
+    }
+    pub fn num_sets(&self) -> u32 {
+        self.size / (self.block_size * self.associativity)
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Line {
+    valid: bool,
+    dirty: bool,
+    tag: u32,
+    last_used: u64,
+    inserted: u64,
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct AccessStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub writebacks: u64,
+}
+impl AccessStats {
+    pub fn accesses(&self) -> u64 {
+        self.reads + self.writes
+    }
+    pub fn miss_rate(&self) -> f64 {
+        if self.accesses() == 0 {
+            return 0.0;
+        }
+        self.misses as f64 / self.accesses() as f64
+    }
+    fn merge(&mut self, other: &AccessStats) {
+        self.reads += other.reads;
+        self.writes += other.writes;
+        self.hits += other.hits;
+        self.misses += other.misses;
+        self.evictions += other.evictions;
+        self.writebacks += other.writebacks;
+    }
+}
+
+pub struct Access {
+    pub hit: bool,
+    // Block address of a dirty line that was evicted and has to be written to the next level
+    pub writeback: Option<u32>,
+}
+
+pub struct Cache {
+    pub name: String,
+    pub config: CacheConfig,
+    sets: Vec<Vec<Line>>,
+    clock: u64,
+    rng: u32,
+    pub stats: AccessStats,
+    // Statistics keyed by the pc of the instruction that caused the access
+    pub per_pc: HashMap<u32, AccessStats>,
+}
+impl Cache {
+    pub fn new(name: &str, config: CacheConfig) -> Cache {
+        let sets = vec![vec![Line::default(); config.associativity as usize]; config.num_sets() as usize];
+        Cache {
+            name: name.to_string(),
+            config,
+            sets,
+            clock: 0,
+            rng: 0x2545f491,
+            stats: AccessStats::default(),
+            per_pc: HashMap::new(),
+        }
+    }
+    fn victim(&mut self, set: usize) -> usize {
+        let lines = &self.sets[set];
+        if let Some(way) = lines.iter().position(|l| !l.valid) {
+            return way;
+        }
+        match self.config.replacement {
+            Replacement::Lru => (0..lines.len()).min_by_key(|&w| lines[w].last_used).unwrap(),
+            Replacement::Fifo => (0..lines.len()).min_by_key(|&w| lines[w].inserted).unwrap(),
+            Replacement::Random => {
+                // xorshift32, seeded with a constant so runs are reproducible
+                self.rng ^= self.rng << 13;
+                self.rng ^= self.rng >> 17;
+                self.rng ^= self.rng << 5;
+                self.rng as usize % lines.len()
+            }
+        }
+    }
+    pub fn access(&mut self, addr: u32, write: bool, pc: u32) -> Access {
+        self.clock += 1;
+        let block = addr / self.config.block_size;
+        let set = (block % self.config.num_sets()) as usize;
+        let tag = block / self.config.num_sets();
+        let mut stats = AccessStats::default();
+        if write {
+            stats.writes += 1;
+        } else {
+            stats.reads += 1;
+        }
+        let mut access = Access { hit: false, writeback: None };
+        if let Some(way) = self.sets[set].iter().position(|l| l.valid && l.tag == tag) {
+            let line = &mut self.sets[set][way];
+            line.last_used = self.clock;
+            if write && self.config.write_policy == WritePolicy::WriteBack {
+                line.dirty = true;
+            }
+            stats.hits += 1;
+            access.hit = true;
+        } else {
+            stats.misses += 1;
+            if !write || self.config.write_allocate {
+                let way = self.victim(set);
+                let old = self.sets[set][way];
+                if old.valid {
+                    stats.evictions += 1;
+                    if old.dirty {
+                        stats.writebacks += 1;
+                        let old_block = old.tag * self.config.num_sets() + set as u32;
+                        access.writeback = Some(old_block * self.config.block_size);
+                    }
+                }
+                self.sets[set][way] = Line {
+                    valid: true,
+                    dirty: write && self.config.write_policy == WritePolicy::WriteBack,
+                    tag,
+                    last_used: self.clock,
+                    inserted: self.clock,
+                };
+            }
+        }
+        self.stats.merge(&stats);
+        self.per_pc.entry(pc).or_default().merge(&stats);
+        access
+    }
+    pub fn report(&self, lines: &HashMap<u32, Position>) -> String {
+        let c = &self.config;
+        let mut out = String::new();
+        let replacement = match c.replacement {
+            Replacement::Lru => "LRU",
+            Replacement::Fifo => "FIFO",
+            Replacement::Random => "random",
+        };
+        let _ = writeln!(out, "{}: {} bytes, {}-way, {}-byte blocks, {}, {}, {}",
+            self.name, c.size, c.associativity, c.block_size, replacement,
+            if c.write_policy == WritePolicy::WriteBack { "write-back" } else { "write-through" },
+            if c.write_allocate { "write-allocate" } else { "no-write-allocate" });
+        let s = &self.stats;
+        let _ = writeln!(out, "  accesses {} (reads {}, writes {}), hits {}, misses {} ({:.2}%), evictions {}, writebacks {}",
+            s.accesses(), s.reads, s.writes, s.hits, s.misses, s.miss_rate() * 100.0, s.evictions, s.writebacks);
+        // Fold per pc statistics into per source line statistics
+        let mut per_line: BTreeMap<&Position, AccessStats> = BTreeMap::new();
+        for (pc, stats) in &self.per_pc {
+            if let Some(line) = lines.get(pc) {
+                per_line.entry(line).or_default().merge(stats);
+            }
+        }
+        if !per_line.is_empty() {
+            let _ = writeln!(out, "  {:>6} {:>10} {:>10} {:>10} {:>10}", "line", "accesses", "hits", "misses", "evictions");
+            for (line, s) in per_line {
+                let _ = writeln!(out, "  {:>6} {:>10} {:>10} {:>10} {:>10}", line.short(), s.accesses(), s.hits, s.misses, s.evictions);
+            }
+        }
+        out
+    }
+}
+
+// L1 instruction and data caches with an optional unified L2 behind them. Any level that is not
+// configured is skipped and accesses go straight to the next level (or memory).
+#[derive(Default)]
+pub struct CacheHierarchy {
+    pub l1i: Option<Cache>,
+    pub l1d: Option<Cache>,
+    pub l2: Option<Cache>,
+}
+impl CacheHierarchy {
+    pub fn is_enabled(&self) -> bool {
+        self.l1i.is_some() || self.l1d.is_some() || self.l2.is_some()
+    }
+    fn next_level(&mut self, addr: u32, write: bool, pc: u32) {
+        if let Some(l2) = &mut self.l2 {
+            // Writebacks from L2 go to memory, which is not modelled beyond this point
+            l2.access(addr, write, pc);
+        }
+    }
+    pub fn fetch(&mut self, addr: u32, pc: u32) {
+        match &mut self.l1i {
+            Some(l1i) => {
+                if !l1i.access(addr, false, pc).hit {
+                    self.next_level(addr, false, pc);
+                }
+            }
+            None => self.next_level(addr, false, pc),
+        }
+    }
+    pub fn load(&mut self, addr: u32, pc: u32) {
+        match &mut self.l1d {
+            Some(l1d) => {
+                let access = l1d.access(addr, false, pc);
+                if let Some(victim) = access.writeback {
+                    self.next_level(victim, true, pc);
+                }
+                if !access.hit {
+                    self.next_level(addr, false, pc);
+                }
+            }
+            None => self.next_level(addr, false, pc),
+        }
+    }
+    pub fn store(&mut self, addr: u32, pc: u32) {
+        match &mut self.l1d {
+            Some(l1d) => {
+                let access = l1d.access(addr, true, pc);
+                let write_through = l1d.config.write_policy == WritePolicy::WriteThrough;
+                let allocated = !access.hit && l1d.config.write_allocate;
+                if let Some(victim) = access.writeback {
+                    self.next_level(victim, true, pc);
+                }
+                // Allocating a block on a write miss first reads the block from the next level
+                if allocated {
+                    self.next_level(addr, false, pc);
+                }
+                // The write itself reaches the next level when the line is write-through or was not allocated
+                if write_through || (!access.hit && !allocated) {
+                    self.next_level(addr, true, pc);
+                }
+            }
+            None => self.next_level(addr, true, pc),
+        }
+    }
+    pub fn report(&self, lines: &HashMap<u32, Position>) -> String {
+        [&self.l1i, &self.l1d, &self.l2].iter()
+            .filter_map(|c| c.as_ref())
+            .map(|c| c.report(lines))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(spec: &str) -> Cache {
+        Cache::new("test", CacheConfig::from_str(spec).unwrap())
+    }
+
+    // Whether each access hit
+    fn hits(cache: &mut Cache, accesses: &[(u32, bool)]) -> Vec<bool> {
+        accesses.iter().map(|(addr, write)| cache.access(*addr, *write, 0).hit).collect()
+    }
+
+    #[test]
+    fn config() {
+        let config = CacheConfig::from_str("size=1024,assoc=2,block=32,repl=fifo,write=through,alloc=no").unwrap();
+        assert_eq!(config.num_sets(), 16);
+        assert_eq!(config.replacement, Replacement::Fifo);
+        assert_eq!(config.write_policy, WritePolicy::WriteThrough);
+        assert!(!config.write_allocate);
+    }
+
+    #[test]
+    fn invalid_configs() {
+        assert!(CacheConfig::from_str("block=12").is_err());
+        assert!(CacheConfig::from_str("assoc=0").is_err());
+        assert!(CacheConfig::from_str("size=4096,assoc=3").is_err());
+        // block * assoc overflows 32 bits
+        assert!(CacheConfig::from_str("block=65536,assoc=65536").is_err());
+        assert!(CacheConfig::from_str("block=2147483648,assoc=2").is_err());
+    }
+
+    #[test]
+    fn same_and_adjacent_blocks() {
+        let mut cache = cache("size=256,block=16");
+        assert_eq!(hits(&mut cache, &[(0x100, false), (0x10c, false), (0x110, false), (0x100, true)]), [false, true, false, true]);
+        assert_eq!((cache.stats.hits, cache.stats.misses, cache.stats.reads, cache.stats.writes), (2, 2, 3, 1));
+    }
+
+    #[test]
+    fn lru_and_fifo_eviction() {
+        // Two sets of two ways, so 0, 32 and 64 all land in set 0
+        let sequence = [(0, false), (32, false), (0, false), (64, false), (0, false), (64, false)];
+        let mut lru = cache("size=64,assoc=2,block=16,repl=lru");
+        // 64 evicts 32, which was used longest ago
+        assert_eq!(hits(&mut lru, &sequence), [false, false, true, false, true, true]);
+        let mut fifo = cache("size=64,assoc=2,block=16,repl=fifo");
+        // 64 evicts 0, which came in first, although it was used since
+        assert_eq!(hits(&mut fifo, &sequence), [false, false, true, false, false, true]);
+        assert_eq!((lru.stats.evictions, fifo.stats.evictions), (1, 2));
+    }
+
+    #[test]
+    fn write_back_and_write_through() {
+        // Direct mapped with two sets, so 0 and 32 share a line
+        let mut back = cache("size=32,block=16,write=back");
+        assert_eq!(back.access(0, true, 0).writeback, None);
+        assert_eq!(back.access(32, false, 0).writeback, Some(0));
+        // Evicting a clean line writes nothing back
+        assert_eq!(back.access(0, false, 0).writeback, None);
+        assert_eq!((back.stats.evictions, back.stats.writebacks), (2, 1));
+        let mut through = cache("size=32,block=16,write=through");
+        through.access(0, true, 0);
+        assert_eq!(through.access(32, false, 0).writeback, None);
+        assert_eq!((through.stats.evictions, through.stats.writebacks), (1, 0));
+    }
+
+    #[test]
+    fn no_write_allocate() {
+        let mut cache = cache("size=32,block=16,write=through,alloc=no");
+        assert_eq!(hits(&mut cache, &[(0, true), (0, false), (0, true)]), [false, false, true]);
+        assert_eq!((cache.stats.misses, cache.stats.evictions), (2, 0));
+    }
+
+    #[test]
+    fn l1_misses_go_to_l2() {
+        let mut caches = CacheHierarchy {
+            l1i: Some(cache("size=32,block=16")),
+            l1d: Some(cache("size=32,block=16,write=back")),
+            l2: Some(cache("size=256,block=16")),
+        };
+        caches.load(0, 0);
+        caches.load(4, 0);
+        caches.load(32, 0);
+        caches.load(0, 0);
+        let l2 = caches.l2.as_ref().unwrap().stats;
+        // The L1 hit never reaches L2, the reload of 0 after it was evicted hits there
+        assert_eq!((l2.reads, l2.hits, l2.misses), (3, 1, 2));
+        // A dirty line evicted from L1 is written to L2, as well as the new block being read
+        caches.store(64, 0);
+        caches.load(0, 0);
+        let l2 = caches.l2.as_ref().unwrap().stats;
+        assert_eq!((l2.reads, l2.writes, l2.hits), (5, 1, 3));
+        // Instruction fetches go through their own L1
+        caches.fetch(0x00400000, 0x00400000);
+        caches.fetch(0x00400004, 0x00400004);
+        assert_eq!(caches.l1i.as_ref().unwrap().stats.hits, 1);
+        assert_eq!(caches.l2.as_ref().unwrap().stats.reads, 6);
+    }
+}
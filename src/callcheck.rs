@@ -0,0 +1,114 @@
+// Checks that running code follows the o32 calling convention. Calls made with jal or jalr are
+// matched with the jr $ra that returns from them, reporting callees that don't restore the
+// callee-saved registers or return somewhere other than after the call, calls made with $sp not
+// 8-byte aligned, and functions reading $t registers they haven't written, which only hold
+// whatever the caller left there.
+use crate::asm::Program;
+use crate::cpu::CPU;
+use crate::diagnostics::Diagnostics;
+use crate::isa::{reg_as_str, Instr};
+
+const SP: u32 = 29;
+const RA: u32 = 31;
+// $s0-$s7, $sp and $fp, which a callee must leave as it found them
+const CALLEE_SAVED: [u32; 10] = [16, 17, 18, 19, 20, 21, 22, 23, 29, 30];
+
+fn is_temporary(reg: u32) -> bool {
+    matches!(reg, 8..=15 | 24 | 25)
+}
+
+struct Frame {
+    // Address of the jal or jalr and of the function it called
+    call_site: u32,
+    entry: u32,
+    // Where the function should return to
+    return_addr: u32,
+    // Callee-saved registers on entry, in CALLEE_SAVED order
+    saved: [u32; 10],
+    // $t registers the function has written, by bit
+    written: u32,
+}
+
+#[derive(Default)]
+pub struct CallChecker {
+    frames: Vec<Frame>,
+    pub violations: Diagnostics,
+    // $sp when the program started. MARS starts it at 0x7fffeffc, so alignment is judged by how
+    // far it has moved from there.
+    initial_sp: Option<u32>,
+}
+impl CallChecker {
+    pub fn new() -> CallChecker {
+        CallChecker::default()
+    }
+}
+
+// A function's label, or its address if it has none
+fn function_name(program: &Program, addr: u32) -> String {
+    program.label_at(addr).map(str::to_string).unwrap_or(format!("0x{:08x}", addr))
+}
+
+fn line(program: &Program, pc: u32) -> String {
+    program.lines.get(&pc).map(|pos| pos.to_string()).unwrap_or(format!("0x{:08x}", pc))
+}
+
+impl CPU {
+    // Checks an instruction's register reads against the current function, before it executes
+    pub(crate) fn check_reads(&mut self, instr: &Instr, pc: u32, program: &Program) {
+        let Some(checker) = &mut self.call_checker else {
+            return;
+        };
+        checker.initial_sp.get_or_insert(self.reg[SP as usize]);
+        let Some(frame) = checker.frames.last_mut() else {
+            return;
+        };
+        let unset: Vec<u32> = instr.reads().into_iter().filter(|reg| is_temporary(*reg) && frame.written & (1 << reg) == 0).collect();
+        if let Some(reg) = instr.writes().filter(|reg| is_temporary(*reg)) {
+            frame.written |= 1 << reg;
+        }
+        let name = function_name(program, frame.entry);
+        for reg in unset {
+            checker.violations.report(pc, format!("read {}", reg), format!("{} reads {} before writing it, the caller could not have set it", name, reg_as_str(&reg)));
+        }
+    }
+    // Tracks calls and returns once a jump and its delay slot have executed, pc is the jump's address
+    pub(crate) fn check_call(&mut self, instr: &Instr, pc: u32, program: &Program) {
+        let Some(checker) = &mut self.call_checker else {
+            return;
+        };
+        match instr {
+            Instr::Jal{..} | Instr::Jalr{rd: 1.., ..} => {
+                let sp = self.reg[SP as usize];
+                if !sp.wrapping_sub(checker.initial_sp.unwrap_or(sp)).is_multiple_of(8) {
+                    checker.violations.report(pc, "sp".to_string(), format!("call to {} with $sp 0x{:08x} not 8-byte aligned", function_name(program, self.pc), sp));
+                }
+                checker.frames.push(Frame {
+                    call_site: pc,
+                    entry: self.pc,
+                    return_addr: pc.wrapping_add(8),
+                    saved: CALLEE_SAVED.map(|reg| self.reg[reg as usize]),
+                    written: 0,
+                });
+            }
+            Instr::Jr{rd: RA} => {
+                let Some(frame) = checker.frames.pop() else {
+                    return;
+                };
+                let name = function_name(program, frame.entry);
+                let call = line(program, frame.call_site);
+                if self.pc != frame.return_addr {
+                    checker.violations.report(pc, "ra".to_string(), format!("{} returns to 0x{:08x} instead of 0x{:08x}, after its call at {}, $ra was not restored",
+                        name, self.pc, frame.return_addr, call));
+                }
+                for (reg, saved) in CALLEE_SAVED.iter().zip(frame.saved) {
+                    let value = self.reg[*reg as usize];
+                    if value != saved {
+                        checker.violations.report(pc, format!("restore {}", reg), format!("{} did not restore {} (0x{:08x} on entry from {}, 0x{:08x} on return)",
+                            name, reg_as_str(reg), saved, call, value));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,105 @@
+// The chain of calls the program is in, followed from jal, jalr and the and-link branches and the
+// jr $ra that returns from each, so faults can show how the program got there
+use crate::asm::Program;
+use crate::cpu::CPU;
+use crate::isa::Instr;
+
+const SP: usize = 29;
+const RA: u32 = 31;
+
+pub struct Frame {
+    // Address of the call and of the function it called
+    pub call_site: u32,
+    pub entry: u32,
+    // $sp at the call
+    pub sp: u32,
+    // Instructions retired when the call was made
+    pub instructions: u64,
+}
+
+// A frame as seen from inside it, for backtraces and moving between frames
+pub struct StackFrame {
+    // The instruction the frame is at: the current one for the innermost frame, else the call
+    pub pc: u32,
+    // Start of the function the frame is in, if known
+    pub function: Option<u32>,
+    pub sp: u32,
+}
+
+#[derive(Default)]
+pub struct CallStack {
+    // Outermost call first
+    frames: Vec<Frame>,
+    // Where the program started, the function outside any call
+    start: Option<u32>,
+}
+impl CallStack {
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+}
+
+fn describe(program: &Program, frame: &StackFrame) -> String {
+    let name = frame.function.and_then(|addr| program.label_at(addr)).unwrap_or("??");
+    let line = program.source.get(&frame.pc).map(|(location, _)| format!(" ({})", location)).unwrap_or_default();
+    format!("0x{:08x} in {}{}, $sp 0x{:08x}", frame.pc, name, line, frame.sp)
+}
+
+impl CPU {
+    // Follows calls and returns once a jump and its delay slot have executed, pc is the jump's address
+    pub(crate) fn track_call(&mut self, instr: &Instr, pc: u32) {
+        let stack = &mut self.call_stack;
+        let instructions = self.limits.instructions;
+        match instr {
+            // jalr $0 discards the link, so it is a jump rather than a call
+            Instr::Jal{..} | Instr::Jalr{rd: 1.., ..} | Instr::Bgezal{..} | Instr::Bltzal{..} => {
+                stack.frames.push(Frame { call_site: pc, entry: self.pc, sp: self.reg[SP], instructions });
+                if let Some(profiler) = &mut self.profiler {
+                    profiler.enter(self.pc);
+                }
+            }
+            // Returns unwind to the call they return from, so frames left by functions that
+            // never returned are dropped. Other uses of jr $ra aren't returns.
+            Instr::Jr{rd: RA} => {
+                if let Some(depth) = stack.frames.iter().rposition(|frame| frame.call_site.wrapping_add(8) == self.pc) {
+                    let returned = stack.frames.split_off(depth);
+                    if let Some(profiler) = &mut self.profiler {
+                        for frame in returned.iter().rev() {
+                            profiler.leave(frame, instructions);
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    // Remembers where the program started, before its first instruction
+    pub(crate) fn track_start(&mut self) {
+        self.call_stack.start.get_or_insert(self.pc);
+    }
+    // The frames of the current call chain, innermost first, the last being where the program started
+    pub fn stack_frames(&self) -> Vec<StackFrame> {
+        let stack = &self.call_stack;
+        let mut frames = vec![];
+        let mut pc = self.pc;
+        let mut sp = self.reg[SP];
+        for frame in stack.frames.iter().rev() {
+            frames.push(StackFrame { pc, function: Some(frame.entry), sp });
+            pc = frame.call_site;
+            sp = frame.sp;
+        }
+        frames.push(StackFrame { pc, function: stack.start, sp });
+        frames
+    }
+    // The call chain, a line per frame with its pc, function, source line and $sp
+    pub fn backtrace(&self, program: &Program) -> String {
+        let mut out = String::from("Backtrace:");
+        for (depth, frame) in self.stack_frames().iter().enumerate() {
+            out.push_str(&format!("\n  #{} {}", depth, describe(program, frame)));
+        }
+        out
+    }
+}
@@ -0,0 +1,115 @@
+// Coprocessor 0 register numbers
+pub const BADVADDR: u32 = 8;
+pub const COUNT: u32 = 9;
+pub const COMPARE: u32 = 11;
+pub const STATUS: u32 = 12;
+pub const CAUSE: u32 = 13;
+pub const EPC: u32 = 14;
+
+// Status register bits
+pub const STATUS_IE: u32 = 1 << 0;
+pub const STATUS_EXL: u32 = 1 << 1;
+// Cause register bits
+pub const CAUSE_BD: u32 = 1 << 31;
+// Interrupt lines 0-1 are software interrupts, 2-7 hardware interrupts. Each has a pending bit
+// (IP) in Cause and a mask bit (IM) in Status, at bit 8 + line.
+pub const INTERRUPT_SHIFT: u32 = 8;
+pub const TIMER_INTERRUPT: u32 = 7;
+
+// Exception codes as stored in Cause.ExcCode. Not all of them can be raised yet, but handlers
+// decode the full set.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExceptionCode {
+    Interrupt = 0,
+    AddressLoad = 4,
+    AddressStore = 5,
+    Syscall = 8,
+    Breakpoint = 9,
+    ReservedInstruction = 10,
+    Overflow = 12,
+    Trap = 13,
+}
+
+pub struct CP0 {
+    pub bad_vaddr: u32,
+    pub count: u32,
+    pub compare: u32,
+    pub status: u32,
+    pub cause: u32,
+    pub epc: u32,
+}
+impl CP0 {
+    pub fn new() -> CP0 {
+        CP0 {
+            bad_vaddr: 0,
+            count: 0,
+            compare: 0,
+            // All interrupts unmasked, user mode, interrupts enabled (as MARS starts up)
+            status: 0x0000ff11,
+            cause: 0,
+            epc: 0,
+        }
+    }
+    pub fn get(&self, index: u32) -> Result<u32, String> {
+        match index {
+            BADVADDR => Ok(self.bad_vaddr),
+            COUNT => Ok(self.count),
+            COMPARE => Ok(self.compare),
+            STATUS => Ok(self.status),
+            CAUSE => Ok(self.cause),
+            EPC => Ok(self.epc),
+            _ => Err(format!("Unimplemented coprocessor 0 register ${}", index)),
+        }
+    }
+    pub fn set(&mut self, index: u32, value: u32) -> Result<(), String> {
+        match index {
+            // BadVAddr is read only
+            BADVADDR => {}
+            COUNT => self.count = value,
+            // Writing Compare acknowledges the timer interrupt
+            COMPARE => {
+                self.compare = value;
+                self.clear_interrupt(TIMER_INTERRUPT);
+            }
+            STATUS => self.status = value,
+            // Only the software interrupt bits of Cause are writable
+            CAUSE => self.cause = (self.cause & !0x300) | (value & 0x300),
+            EPC => self.epc = value,
+            _ => return Err(format!("Unimplemented coprocessor 0 register ${}", index)),
+        }
+        Ok(())
+    }
+    pub fn raise_interrupt(&mut self, line: u32) {
+        self.cause |= 1 << (INTERRUPT_SHIFT + line);
+    }
+    pub fn clear_interrupt(&mut self, line: u32) {
+        self.cause &= !(1 << (INTERRUPT_SHIFT + line));
+    }
+    // True when an unmasked interrupt is pending and interrupts are enabled
+    pub fn interrupt_ready(&self) -> bool {
+        let pending = self.cause & self.status & (0xff << INTERRUPT_SHIFT);
+        pending != 0 && self.status & STATUS_IE != 0 && self.status & STATUS_EXL == 0
+    }
+    // Advances Count by one retired instruction, raising the timer interrupt when it reaches Compare
+    pub fn tick(&mut self) {
+        self.count = self.count.wrapping_add(1);
+        if self.count == self.compare {
+            self.raise_interrupt(TIMER_INTERRUPT);
+        }
+    }
+    // Records an exception: sets the cause, and unless already handling one, EPC and EXL
+    pub fn enter_exception(&mut self, code: ExceptionCode, epc: u32, in_delay_slot: bool, bad_vaddr: Option<u32>) {
+        self.cause = (self.cause & !(CAUSE_BD | 0x7c)) | ((code as u32) << 2);
+        if self.status & STATUS_EXL == 0 {
+            self.epc = epc;
+            if in_delay_slot {
+                self.cause |= CAUSE_BD;
+            }
+        }
+        if let Some(addr) = bad_vaddr {
+            self.bad_vaddr = addr;
+        }
+        self.status |= STATUS_EXL;
+    }
+}
@@ -0,0 +1,114 @@
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FpFmt {
+    Single,
+    Double,
+    // 32 bit integer, only used as a conversion source or destination
+    Word,
+}
+impl FpFmt {
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            FpFmt::Single => "s",
+            FpFmt::Double => "d",
+            FpFmt::Word => "w",
+        }
+    }
+    pub fn from_suffix(s: &str) -> Result<FpFmt, String> {
+        match s {
+            "s" => Ok(FpFmt::Single),
+            "d" => Ok(FpFmt::Double),
+            "w" => Ok(FpFmt::Word),
+            _ => Err(format!("Unknown floating point format .{}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FpCond {
+    Eq,
+    Lt,
+    Le,
+}
+
+pub fn freg_as_str(reg_id: &u32) -> String {
+    format!("$f{}", reg_id)
+}
+pub fn parse_freg(streg: &str) -> Result<u32, String> {
+    let s = streg.replace(&['$',',',][..], "");
+    match s.strip_prefix('f').map(|n| n.parse::<u32>()) {
+        Some(Ok(n)) if n < 32 => Ok(n),
+        _ => Err(format!("Cannot parse floating point register {}", s)),
+    }
+}
+
+// Floating point coprocessor: 32 single precision registers, which pair up as 16 doubles
+// (low word in the even register), and 8 condition flags
+pub struct CP1 {
+    pub regs: [u32; 32],
+    pub fcc: u8,
+}
+impl CP1 {
+    pub fn new() -> CP1 {
+        CP1 { regs: [0; 32], fcc: 0 }
+    }
+    pub fn get_single(&self, index: u32) -> f32 {
+        f32::from_bits(self.regs[index as usize])
+    }
+    pub fn set_single(&mut self, index: u32, value: f32) {
+        self.regs[index as usize] = value.to_bits();
+    }
+    pub fn get_double(&self, index: u32) -> Result<f64, String> {
+        if !index.is_multiple_of(2) {
+            return Err(format!("Double precision operand $f{} must be an even register", index));
+        }
+        let bits = ((self.regs[index as usize + 1] as u64) << 32) | self.regs[index as usize] as u64;
+        Ok(f64::from_bits(bits))
+    }
+    pub fn set_double(&mut self, index: u32, value: f64) -> Result<(), String> {
+        if !index.is_multiple_of(2) {
+            return Err(format!("Double precision operand $f{} must be an even register", index));
+        }
+        let bits = value.to_bits();
+        self.regs[index as usize] = bits as u32;
+        self.regs[index as usize + 1] = (bits >> 32) as u32;
+        Ok(())
+    }
+    // Reads a register in the given format, widened to f64 (exact for all three formats)
+    pub fn get(&self, fmt: FpFmt, index: u32) -> Result<f64, String> {
+        match fmt {
+            FpFmt::Single => Ok(self.get_single(index) as f64),
+            FpFmt::Double => self.get_double(index),
+            FpFmt::Word => Ok(self.regs[index as usize] as i32 as f64),
+        }
+    }
+    // Writes a register in the given format. Rounding to single precision from the exact f64
+    // result of a single precision operation gives the correctly rounded single result. Words
+    // round to nearest, as with the default FCSR rounding mode.
+    pub fn set(&mut self, fmt: FpFmt, index: u32, value: f64) -> Result<(), String> {
+        match fmt {
+            FpFmt::Single => self.set_single(index, value as f32),
+            FpFmt::Double => self.set_double(index, value)?,
+            FpFmt::Word => {
+                let rounded = value.round_ties_even();
+                // Out of range and NaN inputs produce the MIPS default result, 2^31 - 1
+                let word = if rounded.is_nan() || rounded < i32::MIN as f64 || rounded > i32::MAX as f64 {
+                    i32::MAX
+                } else {
+                    rounded as i32
+                };
+                self.regs[index as usize] = word as u32;
+            }
+        }
+        Ok(())
+    }
+    pub fn condition(&self, cc: u32) -> bool {
+        self.fcc & (1 << cc) != 0
+    }
+    pub fn set_condition(&mut self, cc: u32, value: bool) {
+        if value {
+            self.fcc |= 1 << cc;
+        } else {
+            self.fcc &= !(1 << cc);
+        }
+    }
+}
@@ -1,20 +1,109 @@
 use crate::isa::Instr;
+use crate::memory::{Memory, TEXT_BASE, GP_INIT, SP_INIT, HEAP_BASE, EXCEPTION_HANDLER};
+use crate::cache::{Cache, CacheConfig, CacheHierarchy};
+use crate::branch::BranchUnit;
+use crate::cp0::{CP0, ExceptionCode, STATUS_EXL};
+use crate::cp1::{CP1, FpCond, FpFmt};
+use crate::asm::Program;
+use crate::device::{Bus, Device};
+use crate::bitmap::Bitmap;
+use crate::callcheck::CallChecker;
+use crate::callstack::CallStack;
+use crate::limits::{Limits, Termination};
+use crate::linux::Linux;
+use crate::profile::Profiler;
+use crate::stdio::Stdio;
+use crate::uninit::UninitChecker;
+use crate::vfs::Vfs;
 
 pub struct CPU{
-    pub pc:usize,
+    pub pc:u32,
     pub hi:u32,
     pub lo:u32,
     pub reg:[u32;32],
-    pub delay_slot_ready: bool,
+    pub cp0: CP0,
+    pub cp1: CP1,
+    pub mem: Memory,
+    pub caches: CacheHierarchy,
+    // Branch predictors evaluated side by side on every branch
+    pub predictors: Vec<BranchUnit>,
+    // Memory mapped devices
+    pub bus: Bus,
+    // Framebuffer captured to image files, when enabled
+    pub bitmap: Option<Bitmap>,
+    // Standard streams used by the syscalls
+    pub stdio: Stdio,
+    // Filesystem seen by the file syscalls
+    pub vfs: Vfs,
+    // Linux process state, when emulating Linux syscalls instead of MARS's
+    pub linux: Option<Linux>,
+    // Target of a taken branch or jump, applied once the delay slot instruction has executed
+    pub branch_target: Option<u32>,
+    // Exception raised by the instruction currently executing, delivered by step()
+    pending_exception: Option<(ExceptionCode, Option<u32>)>,
+    // Current program break, moved by the sbrk syscall
+    pub heap_end: u32,
+    // Load linked bit, set by ll and cleared by exceptions so a following sc fails
+    pub ll_bit: bool,
+    // Set once the program exits through a syscall
+    pub exit_code: Option<i32>,
+    pub limits: Limits,
+    // Set when the program is stopped for exceeding a limit
+    pub termination: Option<Termination>,
+    // Calling convention checks, when enabled
+    pub call_checker: Option<CallChecker>,
+    // Shadow state for finding uses of uninitialized values, when enabled
+    pub uninit: Option<UninitChecker>,
+    // Calls the program is in, for backtraces
+    pub call_stack: CallStack,
+    // Whether loads and stores are checked against the memory layout
+    pub check_memory: bool,
+    // Instruction and call counts, when profiling
+    pub profiler: Option<Profiler>,
 }
 impl CPU {
+    pub fn builder() -> CPUBuilder {
+        CPUBuilder { cpu: CPU::new(), error: None }
+    }
     pub fn new() -> CPU {
+        let mut reg = [0; 32];
+        reg[28] = GP_INIT;
+        reg[29] = SP_INIT;
         CPU {
-            pc:0,
+            pc:TEXT_BASE,
             hi:0,
             lo:0,
-            reg:[0; 32],
-            delay_slot_ready:false,
+            reg,
+            cp0: CP0::new(),
+            cp1: CP1::new(),
+            mem: Memory::new(),
+            caches: CacheHierarchy::default(),
+            predictors: Vec::new(),
+            bus: Bus::default(),
+            bitmap: None,
+            stdio: Stdio::default(),
+            vfs: Vfs::current_dir(),
+            linux: None,
+            branch_target: None,
+            pending_exception: None,
+            heap_end: HEAP_BASE,
+            ll_bit: false,
+            exit_code: None,
+            limits: Limits::default(),
+            termination: None,
+            call_checker: None,
+            uninit: None,
+            call_stack: CallStack::default(),
+            check_memory: false,
+            profiler: None,
+        }
+    }
+    // Copies the program's initial data into memory
+    pub fn load_program(&mut self, program: &Program) {
+        for (addr, bytes, _) in &program.data {
+            for (i, byte) in bytes.iter().enumerate() {
+                self.mem.store_byte(addr + i as u32, *byte);
+            }
         }
     }
     pub fn get_reg(&self, index: u32) -> Result<u32, String> {
@@ -27,67 +116,732 @@ impl CPU {
 
     pub fn set_reg(&mut self, index: u32, value: u32) -> Result<(), String> {
         if index < self.reg.len() as u32 {
+            // $0 is hardwired to zero, writes to it are discarded as on hardware
             if index == 0 {
-                return Err("Invalid setting of $0 register".to_string());
+                return Ok(());
             }
             self.reg[index as usize] = value;
+            if let Some(checker) = &mut self.uninit {
+                checker.set_defined(index, true);
+            }
             return Ok(());
         } else {
             return Err("Out of bounds register set".to_string());
         }
     }
+    // Flags an exception for delivery and returns the message to report if nothing handles it
+    pub fn raise(&mut self, code: ExceptionCode, bad_vaddr: Option<u32>, message: String) -> String {
+        self.pending_exception = Some((code, bad_vaddr));
+        message
+    }
+    // Transfers control to the kernel exception handler if the program has one, otherwise the
+    // error is fatal
+    fn deliver(&mut self, program: &Program, message: String, epc: u32, in_delay_slot: bool) -> Result<(), String> {
+        let (code, bad_vaddr) = match self.pending_exception.take() {
+            Some(exception) => exception,
+            None => return Err(message),
+        };
+        if !program.has_exception_handler() {
+            return Err(message);
+        }
+        self.cp0.enter_exception(code, epc, in_delay_slot, bad_vaddr);
+        self.ll_bit = false;
+        self.branch_target = None;
+        self.pc = EXCEPTION_HANDLER;
+        Ok(())
+    }
+    // Fetches the instruction at pc, or None once pc has left the program
+    pub fn fetch(&mut self, program: &Program) -> Result<Option<Instr>, String> {
+        if !self.pc.is_multiple_of(4) {
+            return Err(self.raise(ExceptionCode::AddressLoad, Some(self.pc),
+                format!("Unaligned instruction fetch from 0x{:08x}", self.pc)));
+        }
+        let instr = match program.fetch(self.pc) {
+            Some(instr) => instr,
+            None => return Ok(None),
+        };
+        self.caches.fetch(self.pc, self.pc);
+        Ok(Some(instr))
+    }
+    // Executes one instruction, and if it was a taken branch or jump, its delay slot. Returns the
+    // instruction executed, or None once the program has exited, been stopped by a limit or run off the
+    // end of its text.
+    pub fn step(&mut self, program: &Program) -> Result<Option<Instr>, String> {
+        self.check_limits();
+        if self.exit_code.is_some() || self.termination.is_some() {
+            return Ok(None);
+        }
+        // Interrupts are taken between instructions, EPC points at the instruction that has not run yet
+        if self.cp0.interrupt_ready() && program.has_exception_handler() {
+            self.cp0.enter_exception(ExceptionCode::Interrupt, self.pc, false, None);
+            self.ll_bit = false;
+            self.pc = EXCEPTION_HANDLER;
+        }
+        self.track_start();
+        let pc = self.pc;
+        let instr = match self.fetch(program) {
+            Ok(Some(instr)) => instr,
+            Ok(None) => return Ok(None),
+            Err(s) => return self.deliver(program, s, pc, false).map(|_| None),
+        };
+        self.tick()?;
+        self.check_reads(&instr, pc, program);
+        let effect = self.check_uninit(&instr, pc, program)?;
+        if let Err(s) = self.execute_checked(&instr, program) {
+            self.deliver(program, s, pc, false)?;
+            return Ok(Some(instr));
+        }
+        self.apply_uninit(&instr, effect);
+        // eret has set pc, and pc is left on the syscall a program exits through, for backtraces
+        if let Instr::Eret = instr {
+            return Ok(Some(instr));
+        }
+        if self.exit_code.is_some() {
+            return Ok(Some(instr));
+        }
+        self.pc += 4;
+        if Instr::is_delay_instruction(&instr) {
+            if let Some(target) = self.branch_target.take() {
+                let delay_instr = match self.fetch(program) {
+                    Ok(delay_instr) => delay_instr,
+                    Err(s) => return self.deliver(program, s, pc, true).map(|_| Some(instr)),
+                };
+                if let Some(delay_instr) = delay_instr {
+                    self.tick()?;
+                    self.check_reads(&delay_instr, pc + 4, program);
+                    let effect = self.check_uninit(&delay_instr, pc + 4, program)?;
+                    if let Err(s) = self.execute_checked(&delay_instr, program) {
+                        self.deliver(program, s, pc, true)?;
+                        return Ok(Some(instr));
+                    }
+                    self.apply_uninit(&delay_instr, effect);
+                }
+                self.pc = target;
+                self.check_call(&instr, pc, program);
+                self.track_call(&instr, pc);
+            }
+        }
+        Ok(Some(instr))
+    }
+    // Executes an instruction, faulting loads and stores outside the memory layout when checking it
+    fn execute_checked(&mut self, instr: &Instr, program: &Program) -> Result<(), String> {
+        self.check_access(instr, program)?;
+        self.execute(instr)?;
+        self.check_stack_pointer(instr)
+    }
+    // Advances the timer and any devices by one retired instruction
+    fn tick(&mut self) -> Result<(), String> {
+        self.limits.instructions += 1;
+        // pc is still on the instruction retiring, in a delay slot too
+        if let Some(profiler) = &mut self.profiler {
+            profiler.count(self.pc);
+        }
+        self.cp0.tick();
+        self.bus.tick(&mut self.cp0);
+        self.flush_device_output();
+        if let Some(bitmap) = &mut self.bitmap {
+            bitmap.tick(&self.mem)?;
+        }
+        Ok(())
+    }
+    // Passes what devices have printed to the program's stdout, so it is captured and counted
+    // against the output limit like syscall output
+    fn flush_device_output(&mut self) {
+        let output = self.bus.take_output();
+        if !output.is_empty() {
+            let _ = self.write_output(1, &output);
+        }
+    }
+    fn load_word(&mut self, addr: u32) -> Result<u32, String> {
+        if !addr.is_multiple_of(4) {
+            return Err(self.raise(ExceptionCode::AddressLoad, Some(addr), format!("Unaligned word load from 0x{:08x}", addr)));
+        }
+        if let Some((device, offset)) = self.bus.device(addr) {
+            return Ok(device.read(offset));
+        }
+        let value = self.mem.load_word(addr)?;
+        self.caches.load(addr, self.pc);
+        Ok(value)
+    }
+    fn store_word(&mut self, addr: u32, value: u32) -> Result<(), String> {
+        if !addr.is_multiple_of(4) {
+            return Err(self.raise(ExceptionCode::AddressStore, Some(addr), format!("Unaligned word store to 0x{:08x}", addr)));
+        }
+        if let Some((device, offset)) = self.bus.device(addr) {
+            device.write(offset, value);
+            self.flush_device_output();
+            return Ok(());
+        }
+        self.mem.store_word(addr, value)?;
+        self.caches.store(addr, self.pc);
+        Ok(())
+    }
+    fn load_half(&mut self, addr: u32) -> Result<u16, String> {
+        if !addr.is_multiple_of(2) {
+            return Err(self.raise(ExceptionCode::AddressLoad, Some(addr), format!("Unaligned halfword load from 0x{:08x}", addr)));
+        }
+        if let Some((device, offset)) = self.bus.device(addr) {
+            return Ok((device.read(offset) >> (8 * (offset & 2))) as u16);
+        }
+        let value = self.mem.load_half(addr)?;
+        self.caches.load(addr, self.pc);
+        Ok(value)
+    }
+    fn store_half(&mut self, addr: u32, value: u16) -> Result<(), String> {
+        if !addr.is_multiple_of(2) {
+            return Err(self.raise(ExceptionCode::AddressStore, Some(addr), format!("Unaligned halfword store to 0x{:08x}", addr)));
+        }
+        if let Some((device, offset)) = self.bus.device(addr) {
+            device.write(offset, value as u32);
+            self.flush_device_output();
+            return Ok(());
+        }
+        self.mem.store_half(addr, value)?;
+        self.caches.store(addr, self.pc);
+        Ok(())
+    }
+    fn load_byte(&mut self, addr: u32) -> u8 {
+        if let Some((device, offset)) = self.bus.device(addr) {
+            return (device.read(offset) >> (8 * (offset & 3))) as u8;
+        }
+        self.caches.load(addr, self.pc);
+        self.mem.load_byte(addr)
+    }
+    fn store_byte(&mut self, addr: u32, value: u8) {
+        if let Some((device, offset)) = self.bus.device(addr) {
+            device.write(offset, value as u32);
+            return self.flush_device_output();
+        }
+        self.caches.store(addr, self.pc);
+        self.mem.store_byte(addr, value)
+    }
+    // Adds (or subtracts) a 64 bit product to HI/LO for the madd/msub family
+    fn accumulate(&mut self, product: i64, subtract: bool) {
+        let acc = ((self.hi as u64) << 32 | self.lo as u64) as i64;
+        let result = if subtract { acc.wrapping_sub(product) } else { acc.wrapping_add(product) };
+        self.hi = (result >> 32) as u32;
+        self.lo = result as u32;
+    }
+    fn trap(&mut self, condition: bool) -> Result<(), String> {
+        if condition {
+            return Err(self.raise(ExceptionCode::Trap, None, "Trap".to_string()));
+        }
+        Ok(())
+    }
+    // Doubleword accesses for ldc1/sdc1, low word first
+    fn load_double(&mut self, addr: u32) -> Result<(u32, u32), String> {
+        if !addr.is_multiple_of(8) {
+            return Err(self.raise(ExceptionCode::AddressLoad, Some(addr), format!("Unaligned doubleword load from 0x{:08x}", addr)));
+        }
+        Ok((self.load_word(addr)?, self.load_word(addr.wrapping_add(4))?))
+    }
+    fn store_double(&mut self, addr: u32, low: u32, high: u32) -> Result<(), String> {
+        if !addr.is_multiple_of(8) {
+            return Err(self.raise(ExceptionCode::AddressStore, Some(addr), format!("Unaligned doubleword store to 0x{:08x}", addr)));
+        }
+        self.store_word(addr, low)?;
+        self.store_word(addr.wrapping_add(4), high)
+    }
+    fn branch(&mut self, taken: bool, rel_addr: i32) {
+        // Offsets are in words, relative to the delay slot instruction
+        let target = self.pc.wrapping_add(4).wrapping_add((rel_addr * 4) as u32);
+        for unit in &mut self.predictors {
+            unit.record_branch(self.pc, target, taken);
+        }
+        if taken {
+            self.branch_target = Some(target);
+        }
+    }
+    fn jump(&mut self, target: u32) {
+        for unit in &mut self.predictors {
+            unit.record_jump(self.pc, target);
+        }
+        self.branch_target = Some(target);
+    }
     pub fn execute(&mut self, instr : &Instr) -> Result<(), String>{
         match instr{
             Instr::Add{rd, rs, rt} => {
-                self.set_reg(*rd, self.get_reg(*rs)?+self.get_reg(*rt)?)
+                let value = (self.get_reg(*rs)? as i32).checked_add(self.get_reg(*rt)? as i32)
+                    .ok_or_else(|| self.raise(ExceptionCode::Overflow, None, "Arithmetic overflow".to_string()))?;
+                self.set_reg(*rd, value as u32)
             }
             Instr::Sub{rd, rs, rt} => {
-                self.set_reg(*rd, self.get_reg(*rs)?-self.get_reg(*rt)?)
+                let value = (self.get_reg(*rs)? as i32).checked_sub(self.get_reg(*rt)? as i32)
+                    .ok_or_else(|| self.raise(ExceptionCode::Overflow, None, "Arithmetic overflow".to_string()))?;
+                self.set_reg(*rd, value as u32)
             }
             Instr::Addu{rd, rs, rt} => {
+                self.set_reg(*rd, self.get_reg(*rs)?.wrapping_add(self.get_reg(*rt)?))
+            }
+            Instr::Subu{rd, rs, rt} => {
+                self.set_reg(*rd, self.get_reg(*rs)?.wrapping_sub(self.get_reg(*rt)?))
+            }
+            Instr::Addi{rt, rs, immd} => {
+                let value = (self.get_reg(*rs)? as i32).checked_add(*immd as i32)
+                    .ok_or_else(|| self.raise(ExceptionCode::Overflow, None, "Arithmetic overflow".to_string()))?;
+                self.set_reg(*rt, value as u32)
+            }
+            Instr::Addiu{rt, rs, immd} => {
+                self.set_reg(*rt, self.get_reg(*rs)?.wrapping_add(*immd))
+            }
+            Instr::Mul{rd, rs, rt} => {
+                // Only the low word is kept, HI and LO are left alone
+                self.set_reg(*rd, (self.get_reg(*rs)? as i32).wrapping_mul(self.get_reg(*rt)? as i32) as u32)
+            }
+            Instr::Madd{rs, rt} => {
+                let product = (self.get_reg(*rs)? as i32 as i64) * (self.get_reg(*rt)? as i32 as i64);
+                self.accumulate(product, false);
+                Ok(())
+            }
+            Instr::Maddu{rs, rt} => {
+                let product = (self.get_reg(*rs)? as u64 * self.get_reg(*rt)? as u64) as i64;
+                self.accumulate(product, false);
+                Ok(())
+            }
+            Instr::Msub{rs, rt} => {
+                let product = (self.get_reg(*rs)? as i32 as i64) * (self.get_reg(*rt)? as i32 as i64);
+                self.accumulate(product, true);
+                Ok(())
+            }
+            Instr::Msubu{rs, rt} => {
+                let product = (self.get_reg(*rs)? as u64 * self.get_reg(*rt)? as u64) as i64;
+                self.accumulate(product, true);
+                Ok(())
+            }
+            Instr::Clz{rd, rs} => {
+                self.set_reg(*rd, self.get_reg(*rs)?.leading_zeros())
+            }
+            Instr::Clo{rd, rs} => {
+                self.set_reg(*rd, self.get_reg(*rs)?.leading_ones())
+            }
+            Instr::Seb{rd, rt} => {
+                self.set_reg(*rd, self.get_reg(*rt)? as i8 as u32)
+            }
+            Instr::Seh{rd, rt} => {
+                self.set_reg(*rd, self.get_reg(*rt)? as i16 as u32)
+            }
+            Instr::Wsbh{rd, rt} => {
+                let value = self.get_reg(*rt)?;
+                self.set_reg(*rd, ((value & 0x00ff00ff) << 8) | ((value & 0xff00ff00) >> 8))
+            }
+            Instr::Ext{rt, rs, pos, size} => {
+                let mask = u32::MAX >> (32 - size);
+                self.set_reg(*rt, (self.get_reg(*rs)? >> pos) & mask)
+            }
+            Instr::Ins{rt, rs, pos, size} => {
+                let mask = (u32::MAX >> (32 - size)) << pos;
+                let value = (self.get_reg(*rt)? & !mask) | ((self.get_reg(*rs)? << pos) & mask);
+                self.set_reg(*rt, value)
+            }
+            Instr::Movn{rd, rs, rt} => {
+                if self.get_reg(*rt)? != 0 {
+                    self.set_reg(*rd, self.get_reg(*rs)?)?;
+                }
+                Ok(())
+            }
+            Instr::Movz{rd, rs, rt} => {
+                if self.get_reg(*rt)? == 0 {
+                    self.set_reg(*rd, self.get_reg(*rs)?)?;
+                }
+                Ok(())
+            }
+            Instr::Mult{rs, rt} => {
+                let product = (self.get_reg(*rs)? as i32 as i64) * (self.get_reg(*rt)? as i32 as i64);
+                self.hi = (product >> 32) as u32;
+                self.lo = product as u32;
+                Ok(())
+            }
+            Instr::Multu{rs, rt} => {
+                let product = self.get_reg(*rs)? as u64 * self.get_reg(*rt)? as u64;
+                self.hi = (product >> 32) as u32;
+                self.lo = product as u32;
+                Ok(())
+            }
+            Instr::Divu{rs, rt} => {
+                let (dividend, divisor) = (self.get_reg(*rs)?, self.get_reg(*rt)?);
+                // Division by zero leaves HI and LO untouched
+                if let (Some(quotient), Some(remainder)) = (dividend.checked_div(divisor), dividend.checked_rem(divisor)) {
+                    self.lo = quotient;
+                    self.hi = remainder;
+                }
+                Ok(())
+            }
+            Instr::Div{rs, rt} => {
+                let divisor = self.get_reg(*rt)? as i32;
+                // Division by zero leaves HI and LO untouched
+                if divisor != 0 {
+                    let dividend = self.get_reg(*rs)? as i32;
+                    self.lo = dividend.wrapping_div(divisor) as u32;
+                    self.hi = dividend.wrapping_rem(divisor) as u32;
+                }
+                Ok(())
+            }
+            Instr::And{rd, rs, rt} => {
+                self.set_reg(*rd, self.get_reg(*rs)? & self.get_reg(*rt)?)
+            }
+            Instr::Or{rd, rs, rt} => {
+                self.set_reg(*rd, self.get_reg(*rs)? | self.get_reg(*rt)?)
+            }
+            Instr::Nor{rd, rs, rt} => {
+                self.set_reg(*rd, !(self.get_reg(*rs)? | self.get_reg(*rt)?))
+            }
+            Instr::Xor{rd, rs, rt} => {
+                self.set_reg(*rd, self.get_reg(*rs)? ^ self.get_reg(*rt)?)
+            }
+            Instr::Xori{rt, rs, immd} => {
+                self.set_reg(*rt, self.get_reg(*rs)? ^ (*immd & 0xffff))
+            }
+            Instr::Andi{rt, rs, immd} => {
+                self.set_reg(*rt, self.get_reg(*rs)? & (*immd & 0xffff))
+            }
+            Instr::Ori{rt, rs, immd} => {
+                self.set_reg(*rt, self.get_reg(*rs)? | (*immd & 0xffff))
+            }
+            Instr::Sll{rd, rs, shamt} => {
+                self.set_reg(*rd, self.get_reg(*rs)? << (*shamt & 0x1f))
+            }
+            Instr::Srl{rd, rs, shamt} => {
+                self.set_reg(*rd, self.get_reg(*rs)? >> (*shamt & 0x1f))
+            }
+            Instr::Sra{rd, rs, shamt} => {
+                self.set_reg(*rd, ((self.get_reg(*rs)? as i32) >> (*shamt & 0x1f)) as u32)
+            }
+            Instr::Rotr{rd, rs, shamt} => {
+                self.set_reg(*rd, self.get_reg(*rs)?.rotate_right(*shamt & 0x1f))
+            }
+            Instr::Rotrv{rd, rt, rs} => {
+                self.set_reg(*rd, self.get_reg(*rt)?.rotate_right(self.get_reg(*rs)? & 0x1f))
+            }
+            Instr::Sllv{rd, rt, rs} => {
+                self.set_reg(*rd, self.get_reg(*rt)? << (self.get_reg(*rs)? & 0x1f))
+            }
+            Instr::Srlv{rd, rt, rs} => {
+                self.set_reg(*rd, self.get_reg(*rt)? >> (self.get_reg(*rs)? & 0x1f))
+            }
+            Instr::Srav{rd, rt, rs} => {
+                self.set_reg(*rd, ((self.get_reg(*rt)? as i32) >> (self.get_reg(*rs)? & 0x1f)) as u32)
+            }
+            Instr::Lb{rt, rs, immd} => {
+                let value = self.load_byte(self.get_reg(*rs)?.wrapping_add(*immd));
+                self.set_reg(*rt, value as i8 as u32)
+            }
+            Instr::Lbu{rt, rs, immd} => {
+                let value = self.load_byte(self.get_reg(*rs)?.wrapping_add(*immd));
+                self.set_reg(*rt, value as u32)
+            }
+            Instr::Lh{rt, rs, immd} => {
+                let value = self.load_half(self.get_reg(*rs)?.wrapping_add(*immd))?;
+                self.set_reg(*rt, value as i16 as u32)
+            }
+            Instr::Lhu{rt, rs, immd} => {
+                let value = self.load_half(self.get_reg(*rs)?.wrapping_add(*immd))?;
+                self.set_reg(*rt, value as u32)
+            }
+            Instr::Sb{rt, rs, immd} => {
+                self.store_byte(self.get_reg(*rs)?.wrapping_add(*immd), self.get_reg(*rt)? as u8);
                 Ok(())
             }
-            Instr::Subu{rd, rs, rt} => {Ok(())}
-            Instr::Addi{rt, rs, immd} => {Ok(())}
-            Instr::Addiu{rt, rs, immd} => {Ok(())}
-            Instr::Mul{rd, rs, rt} => {Ok(())}
-            Instr::Mult{rs, rt} => {Ok(())}
-            Instr::Div{rs, rt} => {Ok(())}
-            Instr::And{rd, rs, rt} => {Ok(())}
-            Instr::Or{rd, rs, rt} => {Ok(())}
-            Instr::Andi{rt, rs, immd} => {Ok(())}
-            Instr::Ori{rt, rs, immd} => {Ok(())}
-            Instr::Sll{rd, rs, shamt} => {Ok(())}
-            Instr::Srl{rd, rs, shamt} => {Ok(())}
-            Instr::Lw{rt, rs, immd} => {Ok(())}
-            Instr::Sw{rt, rs, immd} => {Ok(())}
-            Instr::Lui{rt, immd} => {Ok(())}
-            Instr::La{rt, addr} => {Ok(())}
-            Instr::Li{rt, immd} => {Ok(())}
-            Instr::Mfhi{rd} => {Ok(())}
-            Instr::Mflo{rd} => {Ok(())}
-            Instr::Move{rs, rt} => {Ok(())}
-            Instr::Beq{rt, rs, rel_addr} => {Ok(())}
-            Instr::Bne{rt, rs, rel_addr} => {Ok(())}
-            Instr::Bgt{rt, rs, rel_addr} => {Ok(())}
-            Instr::Bge{rt, rs, rel_addr} => {Ok(())}
-            Instr::Blt{rt, rs, rel_addr} => {Ok(())}
-            Instr::Ble{rt, rs, rel_addr} => {Ok(())}
-            Instr::Slt{rd, rs, rt} => {Ok(())}
-            Instr::Slti{rt, rs, immd} => {Ok(())}
-            Instr::Sltiu{rt, rs, immd} => {Ok(())}
+            Instr::Sh{rt, rs, immd} => {
+                self.store_half(self.get_reg(*rs)?.wrapping_add(*immd), self.get_reg(*rt)? as u16)
+            }
+            Instr::Ll{rt, rs, immd} => {
+                let value = self.load_word(self.get_reg(*rs)?.wrapping_add(*immd))?;
+                self.ll_bit = true;
+                self.set_reg(*rt, value)
+            }
+            Instr::Sc{rt, rs, immd} => {
+                // The store only happens if nothing broke the link since the ll
+                if self.ll_bit {
+                    self.store_word(self.get_reg(*rs)?.wrapping_add(*immd), self.get_reg(*rt)?)?;
+                }
+                let success = self.ll_bit;
+                self.ll_bit = false;
+                self.set_reg(*rt, success as u32)
+            }
+            Instr::Lw{rt, rs, immd} => {
+                let value = self.load_word(self.get_reg(*rs)?.wrapping_add(*immd))?;
+                self.set_reg(*rt, value)
+            }
+            Instr::Sw{rt, rs, immd} => {
+                self.store_word(self.get_reg(*rs)?.wrapping_add(*immd), self.get_reg(*rt)?)
+            }
+            Instr::Lui{rt, immd} => {
+                self.set_reg(*rt, *immd << 16)
+            }
+            Instr::Mfhi{rd} => {
+                self.set_reg(*rd, self.hi)
+            }
+            Instr::Mflo{rd} => {
+                self.set_reg(*rd, self.lo)
+            }
+            Instr::Mthi{rs} => {
+                self.hi = self.get_reg(*rs)?;
+                Ok(())
+            }
+            Instr::Mtlo{rs} => {
+                self.lo = self.get_reg(*rs)?;
+                Ok(())
+            }
+            Instr::Beq{rt, rs, rel_addr} => {
+                self.branch(self.get_reg(*rt)? == self.get_reg(*rs)?, *rel_addr);
+                Ok(())
+            }
+            Instr::Bne{rt, rs, rel_addr} => {
+                self.branch(self.get_reg(*rt)? != self.get_reg(*rs)?, *rel_addr);
+                Ok(())
+            }
+            Instr::Bgez{rs, rel_addr} => {
+                self.branch(self.get_reg(*rs)? as i32 >= 0, *rel_addr);
+                Ok(())
+            }
+            Instr::Bgtz{rs, rel_addr} => {
+                self.branch(self.get_reg(*rs)? as i32 > 0, *rel_addr);
+                Ok(())
+            }
+            Instr::Blez{rs, rel_addr} => {
+                self.branch(self.get_reg(*rs)? as i32 <= 0, *rel_addr);
+                Ok(())
+            }
+            Instr::Bltz{rs, rel_addr} => {
+                self.branch((self.get_reg(*rs)? as i32) < 0, *rel_addr);
+                Ok(())
+            }
+            // The and-link branches write $ra whether or not the branch is taken
+            Instr::Bgezal{rs, rel_addr} => {
+                let taken = self.get_reg(*rs)? as i32 >= 0;
+                self.set_reg(31, self.pc+8)?;
+                self.branch(taken, *rel_addr);
+                Ok(())
+            }
+            Instr::Bltzal{rs, rel_addr} => {
+                let taken = (self.get_reg(*rs)? as i32) < 0;
+                self.set_reg(31, self.pc+8)?;
+                self.branch(taken, *rel_addr);
+                Ok(())
+            }
+            Instr::Sltu{rd, rs, rt} => {
+                self.set_reg(*rd, (self.get_reg(*rs)? < self.get_reg(*rt)?) as u32)
+            }
+            Instr::Slt{rd, rs, rt} => {
+                self.set_reg(*rd, ((self.get_reg(*rs)? as i32) < self.get_reg(*rt)? as i32) as u32)
+            }
+            Instr::Slti{rt, rs, immd} => {
+                self.set_reg(*rt, ((self.get_reg(*rs)? as i32) < *immd as i32) as u32)
+            }
+            Instr::Sltiu{rt, rs, immd} => {
+                self.set_reg(*rt, (self.get_reg(*rs)? < *immd) as u32)
+            }
             Instr::Jump{addr} => {
-                self.pc = *addr as usize;
-                self.delay_slot_ready = true;
+                self.jump(*addr);
+                Ok(())
+            }
+            Instr::Jr{rd} => {
+                self.jump(self.get_reg(*rd)?);
                 Ok(())
             }
-            Instr::Jr{rd} => {Ok(())}
             Instr::Jal{addr} => {
-                self.set_reg(31, self.pc as u32+8)?;
-                self.pc = *addr as usize;
-                self.delay_slot_ready = true;
+                self.set_reg(31, self.pc+8)?;
+                self.jump(*addr);
+                Ok(())
+            }
+            Instr::Jalr{rd, rs} => {
+                let target = self.get_reg(*rs)?;
+                self.set_reg(*rd, self.pc+8)?;
+                self.jump(target);
+                Ok(())
+            }
+            Instr::Nop => {
                 Ok(())
             }
+            Instr::Teq{rs, rt} => self.trap(self.get_reg(*rs)? == self.get_reg(*rt)?),
+            Instr::Tne{rs, rt} => self.trap(self.get_reg(*rs)? != self.get_reg(*rt)?),
+            Instr::Tge{rs, rt} => self.trap(self.get_reg(*rs)? as i32 >= self.get_reg(*rt)? as i32),
+            Instr::Tgeu{rs, rt} => self.trap(self.get_reg(*rs)? >= self.get_reg(*rt)?),
+            Instr::Tlt{rs, rt} => self.trap((self.get_reg(*rs)? as i32) < self.get_reg(*rt)? as i32),
+            Instr::Tltu{rs, rt} => self.trap(self.get_reg(*rs)? < self.get_reg(*rt)?),
+            Instr::Teqi{rs, immd} => self.trap(self.get_reg(*rs)? == *immd),
+            Instr::Tnei{rs, immd} => self.trap(self.get_reg(*rs)? != *immd),
+            Instr::Tgei{rs, immd} => self.trap(self.get_reg(*rs)? as i32 >= *immd as i32),
+            Instr::Tgeiu{rs, immd} => self.trap(self.get_reg(*rs)? >= *immd),
+            Instr::Tlti{rs, immd} => self.trap((self.get_reg(*rs)? as i32) < *immd as i32),
+            Instr::Tltiu{rs, immd} => self.trap(self.get_reg(*rs)? < *immd),
+            Instr::Syscall => {
+                self.syscall()
+            }
+            Instr::Break{code} => {
+                Err(self.raise(ExceptionCode::Breakpoint, None, format!("Break instruction (code {})", code)))
+            }
+            Instr::Mfc0{rt, rd} => {
+                self.set_reg(*rt, self.cp0.get(*rd)?)
+            }
+            Instr::Mtc0{rt, rd} => {
+                self.cp0.set(*rd, self.get_reg(*rt)?)
+            }
+            Instr::Eret => {
+                self.cp0.status &= !STATUS_EXL;
+                self.ll_bit = false;
+                self.pc = self.cp0.epc;
+                Ok(())
+            }
+            Instr::AddF{fmt, fd, fs, ft} => {
+                self.cp1.set(*fmt, *fd, self.cp1.get(*fmt, *fs)? + self.cp1.get(*fmt, *ft)?)
+            }
+            Instr::SubF{fmt, fd, fs, ft} => {
+                self.cp1.set(*fmt, *fd, self.cp1.get(*fmt, *fs)? - self.cp1.get(*fmt, *ft)?)
+            }
+            Instr::MulF{fmt, fd, fs, ft} => {
+                self.cp1.set(*fmt, *fd, self.cp1.get(*fmt, *fs)? * self.cp1.get(*fmt, *ft)?)
+            }
+            Instr::DivF{fmt, fd, fs, ft} => {
+                self.cp1.set(*fmt, *fd, self.cp1.get(*fmt, *fs)? / self.cp1.get(*fmt, *ft)?)
+            }
+            Instr::SqrtF{fmt, fd, fs} => {
+                self.cp1.set(*fmt, *fd, self.cp1.get(*fmt, *fs)?.sqrt())
+            }
+            Instr::AbsF{fmt, fd, fs} => {
+                self.cp1.set(*fmt, *fd, self.cp1.get(*fmt, *fs)?.abs())
+            }
+            Instr::NegF{fmt, fd, fs} => {
+                self.cp1.set(*fmt, *fd, -self.cp1.get(*fmt, *fs)?)
+            }
+            Instr::MovF{fmt, fd, fs} => {
+                // Copies the raw register contents, so NaN payloads survive
+                self.cp1.regs[*fd as usize] = self.cp1.regs[*fs as usize];
+                if *fmt == FpFmt::Double {
+                    self.cp1.regs[*fd as usize + 1] = self.cp1.regs[*fs as usize + 1];
+                }
+                Ok(())
+            }
+            Instr::Cvt{to, from, fd, fs} => {
+                self.cp1.set(*to, *fd, self.cp1.get(*from, *fs)?)
+            }
+            Instr::CmpF{cond, fmt, cc, fs, ft} => {
+                let (a, b) = (self.cp1.get(*fmt, *fs)?, self.cp1.get(*fmt, *ft)?);
+                // Comparisons involving NaN are false
+                let result = match cond {
+                    FpCond::Eq => a == b,
+                    FpCond::Lt => a < b,
+                    FpCond::Le => a <= b,
+                };
+                self.cp1.set_condition(*cc, result);
+                Ok(())
+            }
+            Instr::Bc1t{cc, rel_addr} => {
+                self.branch(self.cp1.condition(*cc), *rel_addr);
+                Ok(())
+            }
+            Instr::Bc1f{cc, rel_addr} => {
+                self.branch(!self.cp1.condition(*cc), *rel_addr);
+                Ok(())
+            }
+            Instr::Mtc1{rt, fs} => {
+                self.cp1.regs[*fs as usize] = self.get_reg(*rt)?;
+                Ok(())
+            }
+            Instr::Mfc1{rt, fs} => {
+                self.set_reg(*rt, self.cp1.regs[*fs as usize])
+            }
+            Instr::Lwc1{ft, rs, immd} => {
+                self.cp1.regs[*ft as usize] = self.load_word(self.get_reg(*rs)?.wrapping_add(*immd))?;
+                Ok(())
+            }
+            Instr::Swc1{ft, rs, immd} => {
+                self.store_word(self.get_reg(*rs)?.wrapping_add(*immd), self.cp1.regs[*ft as usize])
+            }
+            Instr::Ldc1{ft, rs, immd} => {
+                let (low, high) = self.load_double(self.get_reg(*rs)?.wrapping_add(*immd))?;
+                self.cp1.regs[*ft as usize] = low;
+                self.cp1.regs[*ft as usize + 1] = high;
+                Ok(())
+            }
+            Instr::Sdc1{ft, rs, immd} => {
+                let (low, high) = (self.cp1.regs[*ft as usize], self.cp1.regs[*ft as usize + 1]);
+                self.store_double(self.get_reg(*rs)?.wrapping_add(*immd), low, high)
+            }
+        }
+    }
+}
+
+// Sets up a CPU with caches, branch predictors and devices, e.g.
+// CPU::builder().l1d(config).device(0xffff0010, Box::new(Leds::new())).build()
+pub struct CPUBuilder {
+    cpu: CPU,
+    // The first device that could not be mapped
+    error: Option<String>,
+}
+impl CPUBuilder {
+    pub fn l1i(mut self, config: CacheConfig) -> CPUBuilder {
+        self.cpu.caches.l1i = Some(Cache::new("L1I", config));
+        self
+    }
+    pub fn l1d(mut self, config: CacheConfig) -> CPUBuilder {
+        self.cpu.caches.l1d = Some(Cache::new("L1D", config));
+        self
+    }
+    pub fn l2(mut self, config: CacheConfig) -> CPUBuilder {
+        self.cpu.caches.l2 = Some(Cache::new("L2", config));
+        self
+    }
+    pub fn predictor(mut self, unit: BranchUnit) -> CPUBuilder {
+        self.cpu.predictors.push(unit);
+        self
+    }
+    pub fn bitmap(mut self, bitmap: Bitmap) -> CPUBuilder {
+        self.cpu.bitmap = Some(bitmap);
+        self
+    }
+    pub fn stdio(mut self, stdio: Stdio) -> CPUBuilder {
+        self.cpu.stdio = stdio;
+        self
+    }
+    // Serves the file syscalls from the given filesystem instead of the current directory
+    pub fn vfs(mut self, vfs: Vfs) -> CPUBuilder {
+        self.cpu.vfs = vfs;
+        self
+    }
+    pub fn limits(mut self, limits: Limits) -> CPUBuilder {
+        self.cpu.limits = limits;
+        self
+    }
+    // Checks that calls follow the calling convention, see CPU::call_checker
+    pub fn check_calls(mut self) -> CPUBuilder {
+        self.cpu.call_checker = Some(CallChecker::new());
+        self
+    }
+    // Reports uses of uninitialized registers and memory, as errors if strict, see CPU::uninit
+    pub fn check_uninit(mut self, strict: bool) -> CPUBuilder {
+        self.cpu.uninit = Some(UninitChecker::new(strict));
+        self.cpu.mem.track_defined();
+        self
+    }
+    // Faults stack overflows and underflows, writes to code and accesses to unmapped memory
+    pub fn check_memory(mut self) -> CPUBuilder {
+        self.cpu.check_memory = true;
+        self
+    }
+    // Counts instructions run per instruction, source line and function, see CPU::profile
+    pub fn profile(mut self) -> CPUBuilder {
+        self.cpu.profiler = Some(Profiler::default());
+        self
+    }
+    // Emulates Linux o32 syscalls, starting the program with the given arguments and environment
+    pub fn linux(mut self, args: &[String], env: &[String]) -> CPUBuilder {
+        self.cpu.linux = Some(Linux::new());
+        self.cpu.setup_linux_stack(args, env);
+        self
+    }
+    // Maps a device's registers at base
+    pub fn device(mut self, base: u32, device: Box<dyn Device>) -> CPUBuilder {
+        if let Err(s) = self.cpu.bus.map(base, device) {
+            self.error.get_or_insert(s);
+        }
+        self
+    }
+    // The CPU, or an error if devices overlap
+    pub fn build(self) -> Result<CPU, String> {
+        match self.error {
+            Some(s) => Err(s),
+            None => Ok(self.cpu),
         }
     }
 }
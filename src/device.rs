@@ -0,0 +1,85 @@
+// Memory mapped peripherals. Devices claim a range of addresses on the bus, and loads and stores
+// there go to the device instead of RAM, bypassing the caches.
+use crate::cp0::CP0;
+
+// Lets a device raise and clear the interrupt lines in Cause, without the rest of CP0
+pub struct Interrupts<'a> {
+    cp0: &'a mut CP0,
+}
+impl Interrupts<'_> {
+    // Lines 0 to 7, shown in Cause bits 8 to 15. Line 7 is also used by the timer.
+    pub fn raise(&mut self, line: u32) {
+        self.cp0.raise_interrupt(line);
+    }
+    pub fn clear(&mut self, line: u32) {
+        self.cp0.clear_interrupt(line);
+    }
+}
+
+pub trait Device {
+    // Bytes of address space the device's registers take up
+    fn size(&self) -> u32;
+    // Reads the word containing the byte at offset, which is relative to the device's base address.
+    // Byte and halfword loads pick their part out of the word.
+    fn read(&mut self, offset: u32) -> u32;
+    // Stores a value at offset. Byte and halfword stores pass the value stored, zero extended.
+    fn write(&mut self, offset: u32, value: u32);
+    // Advances the device by one instruction
+    fn tick(&mut self, _interrupts: &mut Interrupts) {}
+    // Takes what the device has printed since last asked, which goes to the program's stdout
+    fn take_output(&mut self) -> Vec<u8> {
+        Vec::new()
+    }
+}
+
+struct Mapping {
+    base: u32,
+    device: Box<dyn Device>,
+}
+impl Mapping {
+    fn contains(&self, addr: u32) -> bool {
+        addr.wrapping_sub(self.base) < self.device.size()
+    }
+}
+
+// The devices mapped alongside RAM
+#[derive(Default)]
+pub struct Bus {
+    mappings: Vec<Mapping>,
+}
+impl Bus {
+    // Maps a device at base, which must not overlap any device already mapped
+    pub fn map(&mut self, base: u32, device: Box<dyn Device>) -> Result<(), String> {
+        let size = device.size();
+        if size == 0 || base.checked_add(size - 1).is_none() {
+            return Err(format!("Device at 0x{:08x} does not fit in the address space", base));
+        }
+        let new = Mapping { base, device };
+        if let Some(other) = self.mappings.iter().find(|m| m.contains(base) || new.contains(m.base)) {
+            return Err(format!("Device at 0x{:08x} overlaps the device at 0x{:08x}", base, other.base));
+        }
+        self.mappings.push(new);
+        Ok(())
+    }
+    // The device mapped at addr and the offset of addr within it, if any
+    pub fn device(&mut self, addr: u32) -> Option<(&mut dyn Device, u32)> {
+        let mapping = self.mappings.iter_mut().find(|m| m.contains(addr))?;
+        Some((mapping.device.as_mut(), addr - mapping.base))
+    }
+    pub fn is_mapped(&self, addr: u32) -> bool {
+        self.mappings.iter().any(|m| m.contains(addr))
+    }
+    pub fn take_output(&mut self) -> Vec<u8> {
+        let mut output = vec![];
+        for mapping in &mut self.mappings {
+            output.extend(mapping.device.take_output());
+        }
+        output
+    }
+    pub fn tick(&mut self, cp0: &mut CP0) {
+        let mut interrupts = Interrupts { cp0 };
+        for mapping in &mut self.mappings {
+            mapping.device.tick(&mut interrupts);
+        }
+    }
+}
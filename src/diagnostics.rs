@@ -0,0 +1,52 @@
+// Problems the runtime checkers find in a program, reported once per instruction and kind of
+// problem however often it happens, so a loop doesn't bury the report
+use crate::asm::Program;
+use std::collections::HashMap;
+
+pub struct Diagnostic {
+    pub pc: u32,
+    pub message: String,
+    // Times it happened
+    pub count: u64,
+}
+
+#[derive(Default)]
+pub struct Diagnostics {
+    items: Vec<Diagnostic>,
+    // Index into items by pc and what went wrong, ignoring the values involved
+    seen: HashMap<(u32, String), usize>,
+}
+impl Diagnostics {
+    // Records a problem at pc. Only the first message of each kind at an instruction is kept.
+    pub fn report(&mut self, pc: u32, kind: String, message: String) {
+        match self.seen.get(&(pc, kind.clone())) {
+            Some(&index) => self.items[index].count += 1,
+            None => {
+                self.seen.insert((pc, kind), self.items.len());
+                self.items.push(Diagnostic { pc, message, count: 1 });
+            }
+        }
+    }
+    pub fn items(&self) -> &[Diagnostic] {
+        &self.items
+    }
+    // A problem and the location of the instruction it happened at
+    pub fn describe(&self, item: &Diagnostic, program: &Program) -> String {
+        let location = program.source.get(&item.pc).map(|(location, _)| location.clone())
+            .unwrap_or(format!("0x{:08x}", item.pc));
+        let times = if item.count > 1 { format!(" ({} times)", item.count) } else { String::new() };
+        format!("{}: {}{}", location, item.message, times)
+    }
+    // Each problem followed by the source line it happened on
+    pub fn summary(&self, program: &Program) -> String {
+        let mut out = String::new();
+        for item in &self.items {
+            out.push_str(&self.describe(item, program));
+            out.push('\n');
+            if let Some((_, text)) = program.source.get(&item.pc) {
+                out.push_str(&format!("    {}\n", text.trim()));
+            }
+        }
+        out
+    }
+}
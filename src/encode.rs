@@ -0,0 +1,250 @@
+// Binary encoding of instructions, as the MIPS32 hardware would fetch them
+use crate::cp1::{FpCond, FpFmt};
+use crate::isa::Instr;
+
+// Major opcodes that select a second table through the function field
+const SPECIAL: u32 = 0x00;
+const REGIMM: u32 = 0x01;
+const COP0: u32 = 0x10;
+const COP1: u32 = 0x11;
+const SPECIAL2: u32 = 0x1c;
+const SPECIAL3: u32 = 0x1f;
+
+fn r_type(op: u32, rs: u32, rt: u32, rd: u32, shamt: u32, funct: u32) -> u32 {
+    (op << 26) | ((rs & 0x1f) << 21) | ((rt & 0x1f) << 16) | ((rd & 0x1f) << 11) | ((shamt & 0x1f) << 6) | (funct & 0x3f)
+}
+fn i_type(op: u32, rs: u32, rt: u32, immd: u32) -> u32 {
+    (op << 26) | ((rs & 0x1f) << 21) | ((rt & 0x1f) << 16) | (immd & 0xffff)
+}
+fn j_type(op: u32, addr: u32) -> u32 {
+    (op << 26) | ((addr >> 2) & 0x03ff_ffff)
+}
+fn fmt_field(fmt: FpFmt) -> u32 {
+    match fmt {
+        FpFmt::Single => 0x10,
+        FpFmt::Double => 0x11,
+        FpFmt::Word => 0x14,
+    }
+}
+
+impl Instr {
+    // Checks that the instruction at pc fits its encoding: branch offsets are signed 16 bit word
+    // counts from the delay slot, and jumps only replace the low 28 bits of the delay slot address
+    pub fn check_encodable(&self, pc: u32) -> Result<(), String> {
+        let delay_slot = pc.wrapping_add(4);
+        match *self {
+            Instr::Beq{rel_addr, ..} | Instr::Bne{rel_addr, ..} | Instr::Bgez{rel_addr, ..} | Instr::Bgtz{rel_addr, ..} |
+            Instr::Blez{rel_addr, ..} | Instr::Bltz{rel_addr, ..} | Instr::Bgezal{rel_addr, ..} | Instr::Bltzal{rel_addr, ..} |
+            Instr::Bc1t{rel_addr, ..} | Instr::Bc1f{rel_addr, ..} if i16::try_from(rel_addr).is_err() => {
+                let target = delay_slot.wrapping_add((rel_addr as u32).wrapping_mul(4));
+                Err(format!("Branch to 0x{:08x} is out of range, {} words from the delay slot, outside -32768 to 32767", target, rel_addr))
+            }
+            Instr::Jump{addr} | Instr::Jal{addr} if !addr.is_multiple_of(4) => {
+                Err(format!("Jump to 0x{:08x} is not word aligned", addr))
+            }
+            Instr::Jump{addr} | Instr::Jal{addr} if addr & 0xf000_0000 != delay_slot & 0xf000_0000 => {
+                Err(format!("Jump to 0x{:08x} is out of range, outside the 256 MB region of its delay slot at 0x{:08x}", addr, delay_slot))
+            }
+            _ => Ok(()),
+        }
+    }
+    // The 32 bit machine word for this instruction. Branch offsets are already relative to the
+    // delay slot in words, and jump targets are absolute, so no address is needed.
+    pub fn encode(&self) -> u32 {
+        let special = |rs, rt, rd, shamt, funct| r_type(SPECIAL, rs, rt, rd, shamt, funct);
+        let special2 = |rs, rt, rd, funct| r_type(SPECIAL2, rs, rt, rd, 0, funct);
+        let branch = |op, rs, rt, rel_addr: i32| i_type(op, rs, rt, rel_addr as u32);
+        let fp = |fmt, ft, fs, fd, funct| r_type(COP1, fmt_field(fmt), ft, fs, fd, funct);
+        match *self {
+            Instr::Sll{rd, rs, shamt} => special(0, rs, rd, shamt, 0x00),
+            Instr::Srl{rd, rs, shamt} => special(0, rs, rd, shamt, 0x02),
+            // rotr and rotrv are srl and srlv with an otherwise unused bit set
+            Instr::Rotr{rd, rs, shamt} => special(1, rs, rd, shamt, 0x02),
+            Instr::Sra{rd, rs, shamt} => special(0, rs, rd, shamt, 0x03),
+            Instr::Sllv{rd, rt, rs} => special(rs, rt, rd, 0, 0x04),
+            Instr::Srlv{rd, rt, rs} => special(rs, rt, rd, 0, 0x06),
+            Instr::Rotrv{rd, rt, rs} => special(rs, rt, rd, 1, 0x06),
+            Instr::Srav{rd, rt, rs} => special(rs, rt, rd, 0, 0x07),
+            Instr::Jr{rd} => special(rd, 0, 0, 0, 0x08),
+            Instr::Jalr{rd, rs} => special(rs, 0, rd, 0, 0x09),
+            Instr::Movz{rd, rs, rt} => special(rs, rt, rd, 0, 0x0a),
+            Instr::Movn{rd, rs, rt} => special(rs, rt, rd, 0, 0x0b),
+            Instr::Syscall => special(0, 0, 0, 0, 0x0c),
+            Instr::Break{code} => ((code & 0xfffff) << 6) | 0x0d,
+            Instr::Mfhi{rd} => special(0, 0, rd, 0, 0x10),
+            Instr::Mthi{rs} => special(rs, 0, 0, 0, 0x11),
+            Instr::Mflo{rd} => special(0, 0, rd, 0, 0x12),
+            Instr::Mtlo{rs} => special(rs, 0, 0, 0, 0x13),
+            Instr::Mult{rs, rt} => special(rs, rt, 0, 0, 0x18),
+            Instr::Multu{rs, rt} => special(rs, rt, 0, 0, 0x19),
+            Instr::Div{rs, rt} => special(rs, rt, 0, 0, 0x1a),
+            Instr::Divu{rs, rt} => special(rs, rt, 0, 0, 0x1b),
+            Instr::Add{rd, rs, rt} => special(rs, rt, rd, 0, 0x20),
+            Instr::Addu{rd, rs, rt} => special(rs, rt, rd, 0, 0x21),
+            Instr::Sub{rd, rs, rt} => special(rs, rt, rd, 0, 0x22),
+            Instr::Subu{rd, rs, rt} => special(rs, rt, rd, 0, 0x23),
+            Instr::And{rd, rs, rt} => special(rs, rt, rd, 0, 0x24),
+            Instr::Or{rd, rs, rt} => special(rs, rt, rd, 0, 0x25),
+            Instr::Xor{rd, rs, rt} => special(rs, rt, rd, 0, 0x26),
+            Instr::Nor{rd, rs, rt} => special(rs, rt, rd, 0, 0x27),
+            Instr::Slt{rd, rs, rt} => special(rs, rt, rd, 0, 0x2a),
+            Instr::Sltu{rd, rs, rt} => special(rs, rt, rd, 0, 0x2b),
+            Instr::Tge{rs, rt} => special(rs, rt, 0, 0, 0x30),
+            Instr::Tgeu{rs, rt} => special(rs, rt, 0, 0, 0x31),
+            Instr::Tlt{rs, rt} => special(rs, rt, 0, 0, 0x32),
+            Instr::Tltu{rs, rt} => special(rs, rt, 0, 0, 0x33),
+            Instr::Teq{rs, rt} => special(rs, rt, 0, 0, 0x34),
+            Instr::Tne{rs, rt} => special(rs, rt, 0, 0, 0x36),
+            Instr::Nop => 0,
+            Instr::Madd{rs, rt} => special2(rs, rt, 0, 0x00),
+            Instr::Maddu{rs, rt} => special2(rs, rt, 0, 0x01),
+            Instr::Mul{rd, rs, rt} => special2(rs, rt, rd, 0x02),
+            Instr::Msub{rs, rt} => special2(rs, rt, 0, 0x04),
+            Instr::Msubu{rs, rt} => special2(rs, rt, 0, 0x05),
+            // clz and clo repeat the destination in the rt field
+            Instr::Clz{rd, rs} => special2(rs, rd, rd, 0x20),
+            Instr::Clo{rd, rs} => special2(rs, rd, rd, 0x21),
+            // The field size is stored as the index of the last bit: size - 1 for ext, the
+            // most significant bit written for ins
+            Instr::Ext{rt, rs, pos, size} => r_type(SPECIAL3, rs, rt, size - 1, pos, 0x00),
+            Instr::Ins{rt, rs, pos, size} => r_type(SPECIAL3, rs, rt, pos + size - 1, pos, 0x04),
+            Instr::Wsbh{rd, rt} => r_type(SPECIAL3, 0, rt, rd, 0x02, 0x20),
+            Instr::Seb{rd, rt} => r_type(SPECIAL3, 0, rt, rd, 0x10, 0x20),
+            Instr::Seh{rd, rt} => r_type(SPECIAL3, 0, rt, rd, 0x18, 0x20),
+            Instr::Bltz{rs, rel_addr} => branch(REGIMM, rs, 0x00, rel_addr),
+            Instr::Bgez{rs, rel_addr} => branch(REGIMM, rs, 0x01, rel_addr),
+            Instr::Tgei{rs, immd} => i_type(REGIMM, rs, 0x08, immd),
+            Instr::Tgeiu{rs, immd} => i_type(REGIMM, rs, 0x09, immd),
+            Instr::Tlti{rs, immd} => i_type(REGIMM, rs, 0x0a, immd),
+            Instr::Tltiu{rs, immd} => i_type(REGIMM, rs, 0x0b, immd),
+            Instr::Teqi{rs, immd} => i_type(REGIMM, rs, 0x0c, immd),
+            Instr::Tnei{rs, immd} => i_type(REGIMM, rs, 0x0e, immd),
+            Instr::Bltzal{rs, rel_addr} => branch(REGIMM, rs, 0x10, rel_addr),
+            Instr::Bgezal{rs, rel_addr} => branch(REGIMM, rs, 0x11, rel_addr),
+            Instr::Jump{addr} => j_type(0x02, addr),
+            Instr::Jal{addr} => j_type(0x03, addr),
+            // The first operand of beq and bne is held in rt here, but encoded in the rs field
+            Instr::Beq{rt, rs, rel_addr} => branch(0x04, rt, rs, rel_addr),
+            Instr::Bne{rt, rs, rel_addr} => branch(0x05, rt, rs, rel_addr),
+            Instr::Blez{rs, rel_addr} => branch(0x06, rs, 0, rel_addr),
+            Instr::Bgtz{rs, rel_addr} => branch(0x07, rs, 0, rel_addr),
+            Instr::Addi{rt, rs, immd} => i_type(0x08, rs, rt, immd),
+            Instr::Addiu{rt, rs, immd} => i_type(0x09, rs, rt, immd),
+            Instr::Slti{rt, rs, immd} => i_type(0x0a, rs, rt, immd),
+            Instr::Sltiu{rt, rs, immd} => i_type(0x0b, rs, rt, immd),
+            Instr::Andi{rt, rs, immd} => i_type(0x0c, rs, rt, immd),
+            Instr::Ori{rt, rs, immd} => i_type(0x0d, rs, rt, immd),
+            Instr::Xori{rt, rs, immd} => i_type(0x0e, rs, rt, immd),
+            Instr::Lui{rt, immd} => i_type(0x0f, 0, rt, immd),
+            Instr::Lb{rt, rs, immd} => i_type(0x20, rs, rt, immd),
+            Instr::Lh{rt, rs, immd} => i_type(0x21, rs, rt, immd),
+            Instr::Lw{rt, rs, immd} => i_type(0x23, rs, rt, immd),
+            Instr::Lbu{rt, rs, immd} => i_type(0x24, rs, rt, immd),
+            Instr::Lhu{rt, rs, immd} => i_type(0x25, rs, rt, immd),
+            Instr::Sb{rt, rs, immd} => i_type(0x28, rs, rt, immd),
+            Instr::Sh{rt, rs, immd} => i_type(0x29, rs, rt, immd),
+            Instr::Sw{rt, rs, immd} => i_type(0x2b, rs, rt, immd),
+            Instr::Ll{rt, rs, immd} => i_type(0x30, rs, rt, immd),
+            Instr::Lwc1{ft, rs, immd} => i_type(0x31, rs, ft, immd),
+            Instr::Ldc1{ft, rs, immd} => i_type(0x35, rs, ft, immd),
+            Instr::Sc{rt, rs, immd} => i_type(0x38, rs, rt, immd),
+            Instr::Swc1{ft, rs, immd} => i_type(0x39, rs, ft, immd),
+            Instr::Sdc1{ft, rs, immd} => i_type(0x3d, rs, ft, immd),
+            Instr::Mfc0{rt, rd} => r_type(COP0, 0x00, rt, rd, 0, 0),
+            Instr::Mtc0{rt, rd} => r_type(COP0, 0x04, rt, rd, 0, 0),
+            Instr::Eret => r_type(COP0, 0x10, 0, 0, 0, 0x18),
+            Instr::Mfc1{rt, fs} => r_type(COP1, 0x00, rt, fs, 0, 0),
+            Instr::Mtc1{rt, fs} => r_type(COP1, 0x04, rt, fs, 0, 0),
+            // The rt field of a condition branch holds the flag number and whether to branch on true
+            Instr::Bc1f{cc, rel_addr} => branch(COP1, 0x08, cc << 2, rel_addr),
+            Instr::Bc1t{cc, rel_addr} => branch(COP1, 0x08, (cc << 2) | 1, rel_addr),
+            Instr::AddF{fmt, fd, fs, ft} => fp(fmt, ft, fs, fd, 0x00),
+            Instr::SubF{fmt, fd, fs, ft} => fp(fmt, ft, fs, fd, 0x01),
+            Instr::MulF{fmt, fd, fs, ft} => fp(fmt, ft, fs, fd, 0x02),
+            Instr::DivF{fmt, fd, fs, ft} => fp(fmt, ft, fs, fd, 0x03),
+            Instr::SqrtF{fmt, fd, fs} => fp(fmt, 0, fs, fd, 0x04),
+            Instr::AbsF{fmt, fd, fs} => fp(fmt, 0, fs, fd, 0x05),
+            Instr::MovF{fmt, fd, fs} => fp(fmt, 0, fs, fd, 0x06),
+            Instr::NegF{fmt, fd, fs} => fp(fmt, 0, fs, fd, 0x07),
+            // Conversions are encoded in the source format, with the target in the function
+            Instr::Cvt{to, from, fd, fs} => fp(from, 0, fs, fd, match to {
+                FpFmt::Single => 0x20,
+                FpFmt::Double => 0x21,
+                FpFmt::Word => 0x24,
+            }),
+            // The flag number sits in the top bits of the fd field
+            Instr::CmpF{cond, fmt, cc, fs, ft} => fp(fmt, ft, fs, cc << 2, match cond {
+                FpCond::Eq => 0x32,
+                FpCond::Lt => 0x3c,
+                FpCond::Le => 0x3e,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm::{assemble, Program};
+    use crate::isa::IsaLevel;
+    use std::path::Path;
+
+    fn assemble_str(source: &str) -> Result<Program, String> {
+        assemble(source, Path::new("test.s"), IsaLevel::Mips32r2)
+    }
+
+    // Machine words of a program's text, in address order
+    fn words(source: &str) -> Vec<u32> {
+        assemble_str(source).unwrap().text.values().map(|instr| instr.encode()).collect()
+    }
+
+    #[test]
+    fn r_type() {
+        assert_eq!(words("addu $t2, $t0, $t1\nsll $t0, $t1, 4\njr $ra"), vec![0x01095021, 0x00094100, 0x03e00008]);
+    }
+
+    #[test]
+    fn i_type() {
+        assert_eq!(words("addiu $sp, $sp, -8\nlw $ra, 4($sp)\nlui $at, 0x1001"), vec![0x27bdfff8, 0x8fbf0004, 0x3c011001]);
+    }
+
+    #[test]
+    fn j_type() {
+        assert_eq!(words("jal f\nnop\nf: j f"), vec![0x0c100002, 0x00000000, 0x08100002]);
+    }
+
+    #[test]
+    fn branches() {
+        assert_eq!(words("l: beq $t0, $t1, l\nbgez $t0, m\nnop\nnop\nm: nop"), vec![0x1109ffff, 0x05010002, 0, 0, 0]);
+    }
+
+    #[test]
+    fn special2_and_special3() {
+        assert_eq!(words("mul $t2, $t0, $t1\next $t0, $t1, 4, 8"), vec![0x71095002, 0x7d283900]);
+    }
+
+    #[test]
+    fn coprocessors() {
+        assert_eq!(words("eret\nadd.s $f0, $f1, $f2\nl: bc1t l"), vec![0x42000018, 0x46020800, 0x4501ffff]);
+    }
+
+    #[test]
+    fn branch_range() {
+        // 0x20000 bytes past the delay slot is 32768 words, one more than fits
+        let far = ".text\nbeq $t0, $t1, far\n.text 0x00420004\nfar: nop";
+        assert!(assemble_str(far).err().expect("assembled").contains("Branch to 0x00420004 is out of range"));
+        let near = ".text\nbeq $t0, $t1, far\n.text 0x00420000\nfar: nop";
+        assert_eq!(assemble_str(near).unwrap().text[&0x00400000].encode(), 0x11097fff);
+        let back = ".text 0x0041fffc\nbne $t0, $t1, back\n.text 0x00400000\nback: nop";
+        assert_eq!(assemble_str(back).unwrap().text[&0x0041fffc].encode(), 0x15098000);
+        let pseudo = ".text\nblt $t0, 5, far\n.text 0x00440000\nfar: nop";
+        assert!(assemble_str(pseudo).err().expect("assembled").contains("out of range"));
+    }
+
+    #[test]
+    fn jump_range() {
+        let far = ".text\nj handler\n.ktext\nhandler: eret";
+        assert!(assemble_str(far).err().expect("assembled").contains("outside the 256 MB region"));
+        let near = ".text\nj f\n.text 0x0ffffff0\nf: nop";
+        assert_eq!(assemble_str(near).unwrap().text[&0x00400000].encode(), 0x0bfffffc);
+    }
+}
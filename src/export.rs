@@ -0,0 +1,212 @@
+// Memory images of an assembled program, for loading into hardware designs and simulators
+use crate::asm::Program;
+use crate::memory::KTEXT_BASE;
+use std::fmt::Write;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImageFormat {
+    // The bytes alone
+    Binary,
+    IntelHex,
+    // One 32 bit word per line in hex, for Verilog's $readmemh
+    Readmemh,
+    // Logisim's "v2.0 raw" memory contents, one 32 bit word per entry
+    Logisim,
+}
+impl ImageFormat {
+    pub fn from_str(s: &str) -> Result<ImageFormat, String> {
+        match s {
+            "bin" => Ok(ImageFormat::Binary),
+            "ihex" => Ok(ImageFormat::IntelHex),
+            "readmemh" => Ok(ImageFormat::Readmemh),
+            "logisim" => Ok(ImageFormat::Logisim),
+            _ => Err(format!("Unknown image format {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Endian {
+    Little,
+    Big,
+}
+impl Endian {
+    pub fn from_str(s: &str) -> Result<Endian, String> {
+        match s {
+            "little" => Ok(Endian::Little),
+            "big" => Ok(Endian::Big),
+            _ => Err(format!("Unknown byte order {}, expected little or big", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Segment {
+    Text,
+    Data,
+}
+
+// The bytes of a user segment from its lowest to its highest address, with any gaps zero filled.
+// Kernel sections are left out. Returns the start address along with the bytes.
+fn segment_bytes(program: &Program, segment: Segment, endian: Endian) -> (u32, Vec<u8>) {
+    // Items of the given size, stored little-endian, in the requested byte order
+    let ordered = |bytes: &[u8], size: u32| -> Vec<u8> {
+        match endian {
+            Endian::Little => bytes.to_vec(),
+            Endian::Big => bytes.chunks(size.max(1) as usize).flat_map(|item| item.iter().rev().copied()).collect(),
+        }
+    };
+    let chunks: Vec<(u32, Vec<u8>)> = match segment {
+        Segment::Text => program.text.iter().filter(|(addr, _)| **addr < KTEXT_BASE)
+            .map(|(addr, instr)| (*addr, ordered(&instr.encode().to_le_bytes(), 4))).collect(),
+        Segment::Data => program.data.iter().filter(|(addr, bytes, _)| *addr < KTEXT_BASE && !bytes.is_empty())
+            .map(|(addr, bytes, size)| (*addr, ordered(bytes, *size))).collect(),
+    };
+    let Some(start) = chunks.iter().map(|(addr, _)| *addr).min() else {
+        return (0, vec![]);
+    };
+    let end = chunks.iter().map(|(addr, bytes)| addr + bytes.len() as u32).max().unwrap();
+    let mut image = vec![0; (end - start) as usize];
+    for (addr, bytes) in chunks {
+        let offset = (addr - start) as usize;
+        image[offset..offset + bytes.len()].copy_from_slice(&bytes);
+    }
+    (start, image)
+}
+
+fn intel_hex(offset: u32, bytes: &[u8]) -> String {
+    let mut out = String::new();
+    let mut record = |kind: u8, addr: u16, data: &[u8]| {
+        let mut fields = vec![data.len() as u8, (addr >> 8) as u8, addr as u8, kind];
+        fields.extend_from_slice(data);
+        let checksum = fields.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)).wrapping_neg();
+        fields.push(checksum);
+        let hex: String = fields.iter().map(|b| format!("{:02X}", b)).collect();
+        let _ = writeln!(out, ":{}", hex);
+    };
+    // Records hold 16 bit addresses, the upper half comes from an extended linear address record.
+    // Rows are aligned to 16 bytes so none crosses into the next 64K.
+    let mut upper = None;
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let addr = offset + pos as u32;
+        let len = (16 - addr as usize % 16).min(bytes.len() - pos);
+        if upper != Some(addr >> 16) {
+            upper = Some(addr >> 16);
+            record(0x04, 0, &((addr >> 16) as u16).to_be_bytes());
+        }
+        record(0x00, addr as u16, &bytes[pos..pos + len]);
+        pos += len;
+    }
+    record(0x01, 0, &[]);
+    out
+}
+
+// Groups bytes into 32 bit words in the given byte order, padding the last word with zeros
+fn words(bytes: &[u8], endian: Endian) -> Vec<u32> {
+    bytes.chunks(4).map(|chunk| {
+        let mut word = [0; 4];
+        word[..chunk.len()].copy_from_slice(chunk);
+        match endian {
+            Endian::Little => u32::from_le_bytes(word),
+            Endian::Big => u32::from_be_bytes(word),
+        }
+    }).collect()
+}
+
+// Writes an image of a segment. `base` is the address at the start of the image, which defaults
+// to the start of the segment; any space between the two is zero filled.
+pub fn export_image(program: &Program, segment: Segment, format: ImageFormat, endian: Endian, base: Option<u32>) -> Result<Vec<u8>, String> {
+    let (start, bytes) = segment_bytes(program, segment, endian);
+    let base = base.unwrap_or(start);
+    if !bytes.is_empty() && base > start {
+        return Err(format!("Image base 0x{:08x} is above the start of the segment at 0x{:08x}", base, start));
+    }
+    if format != ImageFormat::Binary && format != ImageFormat::IntelHex && !base.is_multiple_of(4) {
+        return Err(format!("Image base 0x{:08x} must be word aligned", base));
+    }
+    let offset = if bytes.is_empty() { 0 } else { start - base };
+    let mut padded = vec![0; offset as usize];
+    padded.extend_from_slice(&bytes);
+    Ok(match format {
+        ImageFormat::Binary => padded,
+        ImageFormat::IntelHex => intel_hex(offset, &bytes).into_bytes(),
+        ImageFormat::Readmemh => {
+            let mut out = format!("// {} image from 0x{:08x}\n", if segment == Segment::Text { ".text" } else { ".data" }, base);
+            for word in words(&padded, endian) {
+                let _ = writeln!(out, "{:08x}", word);
+            }
+            out.into_bytes()
+        }
+        ImageFormat::Logisim => {
+            // Runs of the same word are written as count*word, as Logisim saves them
+            let mut entries = vec![];
+            let words = words(&padded, endian);
+            let mut i = 0;
+            while i < words.len() {
+                let run = words[i..].iter().take_while(|w| **w == words[i]).count();
+                if run >= 4 {
+                    entries.push(format!("{}*{:x}", run, words[i]));
+                    i += run;
+                } else {
+                    entries.push(format!("{:x}", words[i]));
+                    i += 1;
+                }
+            }
+            let mut out = "v2.0 raw\n".to_string();
+            for line in entries.chunks(8) {
+                let _ = writeln!(out, "{}", line.join(" "));
+            }
+            out.into_bytes()
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::isa::IsaLevel;
+    use std::path::Path;
+
+    fn image(source: &str, segment: Segment, format: ImageFormat, endian: Endian) -> Vec<u8> {
+        let program = assemble(source, Path::new("test.s"), IsaLevel::Mips32r2).unwrap();
+        export_image(&program, segment, format, endian, None).unwrap()
+    }
+
+    #[test]
+    fn binary() {
+        let source = "addiu $sp, $sp, -8\njr $ra";
+        assert_eq!(image(source, Segment::Text, ImageFormat::Binary, Endian::Little), [0xf8, 0xff, 0xbd, 0x27, 0x08, 0x00, 0xe0, 0x03]);
+        assert_eq!(image(source, Segment::Text, ImageFormat::Binary, Endian::Big), [0x27, 0xbd, 0xff, 0xf8, 0x03, 0xe0, 0x00, 0x08]);
+        // Data items are swapped by their own size
+        assert_eq!(image(".data\n.half 0x1234\n.byte 1", Segment::Data, ImageFormat::Binary, Endian::Big), [0x12, 0x34, 0x01]);
+    }
+
+    #[test]
+    fn intel_hex() {
+        let hex = image(".data\n.byte 1, 2, 3", Segment::Data, ImageFormat::IntelHex, Endian::Little);
+        assert_eq!(String::from_utf8(hex).unwrap(), ":020000040000FA\n:03000000010203F7\n:00000001FF\n");
+    }
+
+    #[test]
+    fn readmemh() {
+        let hex = image("addiu $sp, $sp, -8\njr $ra", Segment::Text, ImageFormat::Readmemh, Endian::Little);
+        assert_eq!(String::from_utf8(hex).unwrap(), "// .text image from 0x00400000\n27bdfff8\n03e00008\n");
+    }
+
+    #[test]
+    fn logisim() {
+        let raw = image("nop\nnop\nnop\nnop\nnop\naddiu $sp, $sp, -8", Segment::Text, ImageFormat::Logisim, Endian::Little);
+        assert_eq!(String::from_utf8(raw).unwrap(), "v2.0 raw\n5*0 27bdfff8\n");
+    }
+
+    #[test]
+    fn far_branch() {
+        // The furthest forward branch is encoded exactly, one word further can't be assembled
+        let hex = image(".text\nbeq $t0, $t1, far\n.text 0x00420000\nfar: nop", Segment::Text, ImageFormat::Readmemh, Endian::Little);
+        assert_eq!(String::from_utf8(hex).unwrap().lines().nth(1), Some("11097fff"));
+        let far = ".text\nbeq $t0, $t1, far\n.text 0x00420004\nfar: nop";
+        assert!(assemble(far, Path::new("test.s"), IsaLevel::Mips32r2).is_err());
+    }
+}
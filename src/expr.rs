@@ -0,0 +1,241 @@
+// Assembly-time constant expressions, e.g. BUF_SIZE*4+1, table+12 or %hi(buffer)
+
+pub enum ExprError {
+    // A symbol that is not (yet) defined, which may be a label resolved in a later pass
+    Unknown(String),
+    Invalid(String),
+}
+impl ExprError {
+    pub fn message(self) -> String {
+        match self {
+            ExprError::Unknown(name) => format!("Unknown label {}", name),
+            ExprError::Invalid(message) => message,
+        }
+    }
+}
+
+struct Parser<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    lookup: &'a dyn Fn(&str) -> Option<u32>,
+}
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.get(self.pos).is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.chars.get(self.pos).copied()
+    }
+    // Consumes the given operator if it comes next
+    fn eat(&mut self, op: &str) -> bool {
+        self.skip_whitespace();
+        let op: Vec<char> = op.chars().collect();
+        if self.chars[self.pos..].starts_with(&op) {
+            self.pos += op.len();
+            true
+        } else {
+            false
+        }
+    }
+    fn invalid(&self, message: &str) -> ExprError {
+        ExprError::Invalid(format!("{} in expression {}", message, self.chars.iter().collect::<String>()))
+    }
+    // Binary operators from loosest to tightest binding, as in C
+    fn binary(&mut self, level: usize) -> Result<i64, ExprError> {
+        const LEVELS: [&[&str]; 6] = [&["|"], &["^"], &["&"], &["<<", ">>"], &["+", "-"], &["*", "/", "%"]];
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut value = self.binary(level + 1)?;
+        'outer: loop {
+            for op in LEVELS[level] {
+                if self.eat(op) {
+                    let rhs = self.binary(level + 1)?;
+                    value = match *op {
+                        "|" => value | rhs,
+                        "^" => value ^ rhs,
+                        "&" => value & rhs,
+                        "<<" => ((value as u32) << (rhs & 0x1f)) as i64,
+                        ">>" => ((value as u32) >> (rhs & 0x1f)) as i64,
+                        "+" => value.wrapping_add(rhs),
+                        "-" => value.wrapping_sub(rhs),
+                        "*" => (value as i32).wrapping_mul(rhs as i32) as i64,
+                        "/" | "%" if rhs as i32 == 0 => return Err(self.invalid("Division by zero")),
+                        "/" => (value as i32).wrapping_div(rhs as i32) as i64,
+                        _ => (value as i32).wrapping_rem(rhs as i32) as i64,
+                    };
+                    continue 'outer;
+                }
+            }
+            return Ok(value);
+        }
+    }
+    fn unary(&mut self) -> Result<i64, ExprError> {
+        if self.eat("-") {
+            return Ok(self.unary()?.wrapping_neg());
+        }
+        if self.eat("~") {
+            return Ok(!self.unary()?);
+        }
+        if self.eat("+") {
+            return self.unary();
+        }
+        self.primary()
+    }
+    fn primary(&mut self) -> Result<i64, ExprError> {
+        // %hi and %lo split an address for a lui followed by an instruction that sign extends its
+        // immediate, so %hi is rounded up when the low half is negative
+        if self.eat("%hi(") || self.eat("%lo(") {
+            let hi = self.chars[self.pos - 2] == 'i';
+            let value = self.binary(0)? as u32;
+            if !self.eat(")") {
+                return Err(self.invalid("Missing )"));
+            }
+            return Ok(if hi { (value.wrapping_add(0x8000) >> 16) as i64 } else { value as u16 as i16 as i64 });
+        }
+        if self.eat("(") {
+            let value = self.binary(0)?;
+            if !self.eat(")") {
+                return Err(self.invalid("Missing )"));
+            }
+            return Ok(value);
+        }
+        let start = self.pos;
+        match self.peek() {
+            Some('\'') => {
+                self.pos += 1;
+                let value = match self.chars.get(self.pos) {
+                    Some('\\') => {
+                        self.pos += 1;
+                        match self.chars.get(self.pos) {
+                            Some('n') => '\n',
+                            Some('t') => '\t',
+                            Some('r') => '\r',
+                            Some('0') => '\0',
+                            Some(c @ ('\\' | '\'' | '"')) => *c,
+                            _ => return Err(self.invalid("Invalid escape sequence")),
+                        }
+                    }
+                    Some(c) => *c,
+                    None => return Err(self.invalid("Unterminated character")),
+                };
+                if self.chars.get(self.pos + 1) != Some(&'\'') {
+                    return Err(self.invalid("Unterminated character"));
+                }
+                self.pos += 2;
+                Ok(value as i64)
+            }
+            Some(c) if c.is_ascii_digit() => {
+                while self.chars.get(self.pos).is_some_and(|c| c.is_ascii_alphanumeric()) {
+                    self.pos += 1;
+                }
+                let text: String = self.chars[start..self.pos].iter().collect();
+                let parsed = match text.strip_prefix("0x").or(text.strip_prefix("0X")) {
+                    Some(hex) => i64::from_str_radix(hex, 16),
+                    None => text.parse::<i64>(),
+                };
+                match parsed {
+                    Ok(value) if value <= u32::MAX as i64 => Ok(value),
+                    _ => Err(self.invalid(&format!("Invalid number {}", text))),
+                }
+            }
+            Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '.' => {
+                while self.chars.get(self.pos).is_some_and(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '.') {
+                    self.pos += 1;
+                }
+                let name: String = self.chars[start..self.pos].iter().collect();
+                match (self.lookup)(&name) {
+                    Some(value) => Ok(value as i64),
+                    None => Err(ExprError::Unknown(name)),
+                }
+            }
+            Some(c) => Err(self.invalid(&format!("Unexpected {}", c))),
+            None => Err(self.invalid("Missing operand")),
+        }
+    }
+}
+
+// Evaluates an expression to its 32 bit value, looking up symbols with the given function
+pub fn eval(expr: &str, lookup: &dyn Fn(&str) -> Option<u32>) -> Result<u32, ExprError> {
+    let mut parser = Parser { chars: expr.chars().collect(), pos: 0, lookup };
+    let value = parser.binary(0)?;
+    if parser.peek().is_some() {
+        return Err(parser.invalid("Unexpected trailing input"));
+    }
+    Ok(value as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(expr: &str) -> u32 {
+        let lookup = |name: &str| match name {
+            "SIZE" => Some(16),
+            "buffer" => Some(0x1001_8004),
+            _ => None,
+        };
+        match eval(expr, &lookup) {
+            Ok(value) => value,
+            Err(e) => panic!("{}: {}", expr, e.message()),
+        }
+    }
+
+    fn error(expr: &str) -> String {
+        match eval(expr, &|_| None) {
+            Ok(value) => panic!("{} evaluated to {}", expr, value),
+            Err(e) => e.message(),
+        }
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(value("SIZE*4+1"), 65);
+        assert_eq!(value("1 + 2 * 3"), 7);
+        assert_eq!(value("(1 + 2) * 3"), 9);
+        assert_eq!(value("1 << 2 + 1"), 8);
+        assert_eq!(value("6 & 3 | 8 ^ 12"), 6);
+        assert_eq!(value("-7 / 2"), -3i32 as u32);
+        assert_eq!(value("-7 % 2"), -1i32 as u32);
+        assert_eq!(value("~0 >> 28"), 0xf);
+        assert_eq!(value("'a' + '\\n'"), 107);
+    }
+
+    #[test]
+    fn wraps_to_32_bits() {
+        assert_eq!(value("0xffffffff + 1"), 0);
+        assert_eq!(value("0x80000000 * 2"), 0);
+        assert_eq!(value("-0x80000000 / -1"), 0x8000_0000);
+        assert_eq!(value("1 << 33"), 2);
+    }
+
+    #[test]
+    fn hi_lo_rebuild_the_address() {
+        // The low half of buffer is positive, of buffer+0x4000 negative
+        for expr in ["buffer", "buffer+0x4000"] {
+            let hi = value(&format!("%hi({})", expr));
+            let lo = value(&format!("%lo({})", expr));
+            assert_eq!((hi << 16).wrapping_add(lo), value(expr));
+        }
+        assert_eq!(value("%hi(buffer+0x4000)"), 0x1002);
+        assert_eq!(value("%lo(buffer+0x4000)"), 0xffff_c004);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(matches!(eval("later+4", &|_| None), Err(ExprError::Unknown(name)) if name == "later"));
+        assert!(error("1/0").starts_with("Division by zero"));
+        assert!(error("5 % (2-2)").starts_with("Division by zero"));
+        assert!(error("(1+2").starts_with("Missing )"));
+        assert!(error("%hi(4").starts_with("Missing )"));
+        assert!(error("1+").starts_with("Missing operand"));
+        assert!(error("1 2").starts_with("Unexpected trailing input"));
+        assert!(error("0x100000000").starts_with("Invalid number"));
+        assert!(error("12ab").starts_with("Invalid number"));
+        assert!(error("'ab'").starts_with("Unterminated character"));
+        assert!(error("'\\q'").starts_with("Invalid escape sequence"));
+    }
+}
@@ -0,0 +1,522 @@
+// Runs the test cases of a TOML spec against assembled programs, for grading. Each case gives
+// the program's input and starting state and what it should leave behind:
+//
+//   program = "sum.s"            # relative to the spec, keys at the top level are case defaults
+//   max_instructions = 100000
+//   check_calls = true           # calling convention violations fail the case
+//   check_uninit = true          # so do uses of uninitialized registers or memory
+//   check_memory = true          # stray loads and stores fault
+//   keyboard = "q"               # maps the MMIO console, typing this; what it displays is stdout
+//
+//   [[case]]
+//   name = "adds"
+//   stdin = "3\n4\n"
+//   entry = "main"               # label to start at, by default the start of .text
+//   registers = { a0 = 5 }       # values may be integers or expressions using labels
+//   memory = [{ address = "table", words = [1, 2, 3] }]
+//   [case.expect]
+//   stdout = "7\n"
+//   exit_code = 0
+//   registers = { v0 = 7 }
+//   memory = [{ address = "result", words = [7] }]
+//
+// Memory regions hold one of words, halves, bytes or a string. Programs read from the case's stdin,
+// their output is captured, and file syscalls use an empty in-memory filesystem.
+use crate::asm::{assemble, Program};
+use crate::cpu::CPU;
+use crate::expr;
+use crate::isa::{parse_reg, IsaLevel};
+use crate::limits::Limits;
+use crate::mmio::{Console, Keyboard, DEFAULT_DISPLAY_DELAY, MMIO_BASE};
+use crate::stdio::Stdio;
+use crate::vfs::Vfs;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use toml::{Table, Value};
+
+// Used when a spec doesn't set max_instructions, so a case stuck in a loop still ends
+const DEFAULT_MAX_INSTRUCTIONS: u64 = 10_000_000;
+// Register numbers for HI and LO, after the general purpose registers
+const HI: u32 = 32;
+const LO: u32 = 33;
+
+// A value in a spec: a number, or an expression that may use the program's labels
+#[derive(Clone)]
+enum Word {
+    Const(u32),
+    Expr(String),
+}
+impl Word {
+    fn parse(value: &Value) -> Result<Word, String> {
+        match value {
+            Value::Integer(i) => Ok(Word::Const(*i as u32)),
+            Value::String(s) => Ok(Word::Expr(s.clone())),
+            _ => Err(format!("Expected a number or expression, found {}", value)),
+        }
+    }
+    fn resolve(&self, program: &Program) -> Result<u32, String> {
+        match self {
+            Word::Const(value) => Ok(*value),
+            Word::Expr(s) => expr::eval(s, &|name| program.symbol(name)).map_err(|e| e.message()),
+        }
+    }
+}
+
+// Bytes at an address, given as words, halves, bytes or a string
+struct Region {
+    address: Word,
+    // Item size in bytes and the items, so memory can be compared item by item
+    size: u32,
+    items: Vec<Word>,
+}
+impl Region {
+    fn parse(value: &Value) -> Result<Region, String> {
+        let table = value.as_table().ok_or("Memory regions must be tables")?;
+        let address = Word::parse(table.get("address").ok_or("Memory region without an address")?)?;
+        let items = |key: &str| -> Result<Option<Vec<Word>>, String> {
+            match table.get(key) {
+                Some(Value::Array(items)) => Ok(Some(items.iter().map(Word::parse).collect::<Result<_, _>>()?)),
+                Some(_) => Err(format!("Memory region {} must be an array", key)),
+                None => Ok(None),
+            }
+        };
+        let (size, items) = if let Some(words) = items("words")? {
+            (4, words)
+        } else if let Some(halves) = items("halves")? {
+            (2, halves)
+        } else if let Some(bytes) = items("bytes")? {
+            (1, bytes)
+        } else if let Some(Value::String(s)) = table.get("string") {
+            (1, s.bytes().map(|b| Word::Const(b as u32)).collect())
+        } else {
+            return Err("Memory region needs words, halves, bytes or a string".to_string());
+        };
+        Ok(Region { address, size, items })
+    }
+}
+
+fn parse_registers(value: Option<&Value>) -> Result<Vec<(String, u32, Word)>, String> {
+    let Some(value) = value else {
+        return Ok(vec![]);
+    };
+    let table = value.as_table().ok_or("registers must be a table")?;
+    table.iter().map(|(name, value)| {
+        let reg = match name.as_str() {
+            "hi" => HI,
+            "lo" => LO,
+            _ => parse_reg(name)?,
+        };
+        Ok((name.clone(), reg, Word::parse(value)?))
+    }).collect()
+}
+
+fn parse_regions(value: Option<&Value>) -> Result<Vec<Region>, String> {
+    match value {
+        Some(Value::Array(regions)) => regions.iter().map(Region::parse).collect(),
+        Some(_) => Err("memory must be an array of regions".to_string()),
+        None => Ok(vec![]),
+    }
+}
+
+struct Case {
+    name: String,
+    program: PathBuf,
+    stdin: String,
+    entry: Option<String>,
+    max_instructions: u64,
+    timeout: Option<Duration>,
+    check_calls: bool,
+    check_uninit: bool,
+    check_memory: bool,
+    // Text typed on the MMIO keyboard, if the console is mapped
+    keyboard: Option<String>,
+    registers: Vec<(String, u32, Word)>,
+    memory: Vec<Region>,
+    stdout: Option<String>,
+    exit_code: Option<i32>,
+    expect_registers: Vec<(String, u32, Word)>,
+    expect_memory: Vec<Region>,
+}
+impl Case {
+    // Reads a case, falling back to the top level of the spec for any key it doesn't set
+    fn parse(case: &Table, spec: &Table, index: usize, dir: &Path) -> Result<Case, String> {
+        let get = |key: &str| case.get(key).or(spec.get(key));
+        let string = |key: &str| match get(key) {
+            Some(Value::String(s)) => Ok(Some(s.clone())),
+            Some(_) => Err(format!("{} must be a string", key)),
+            None => Ok(None),
+        };
+        let name = match case.get("name") {
+            Some(Value::String(name)) => name.clone(),
+            Some(_) => return Err("name must be a string".to_string()),
+            None => format!("case {}", index + 1),
+        };
+        let program = string("program")?.ok_or(format!("No program given for {}", name))?;
+        let max_instructions = match get("max_instructions") {
+            Some(Value::Integer(n)) if *n > 0 => *n as u64,
+            Some(_) => return Err("max_instructions must be a positive integer".to_string()),
+            None => DEFAULT_MAX_INSTRUCTIONS,
+        };
+        let timeout = match get("timeout") {
+            Some(Value::Float(s)) => Some(Duration::try_from_secs_f64(*s).map_err(|_| "Invalid timeout")?),
+            Some(Value::Integer(s)) if *s >= 0 => Some(Duration::from_secs(*s as u64)),
+            Some(_) => return Err("timeout must be a number of seconds".to_string()),
+            None => None,
+        };
+        let flag = |key: &str| match get(key) {
+            Some(Value::Boolean(value)) => Ok(*value),
+            Some(_) => Err(format!("{} must be true or false", key)),
+            None => Ok(false),
+        };
+        let empty = Table::new();
+        let expect = match case.get("expect") {
+            Some(Value::Table(expect)) => expect,
+            Some(_) => return Err("expect must be a table".to_string()),
+            None => &empty,
+        };
+        let exit_code = match expect.get("exit_code") {
+            Some(Value::Integer(code)) => Some(*code as i32),
+            Some(_) => return Err("exit_code must be an integer".to_string()),
+            None => None,
+        };
+        let stdout = match expect.get("stdout") {
+            Some(Value::String(s)) => Some(s.clone()),
+            Some(_) => return Err("stdout must be a string".to_string()),
+            None => None,
+        };
+        Ok(Case {
+            program: dir.join(program),
+            stdin: string("stdin")?.unwrap_or_default(),
+            entry: string("entry")?,
+            max_instructions,
+            timeout,
+            check_calls: flag("check_calls")?,
+            check_uninit: flag("check_uninit")?,
+            check_memory: flag("check_memory")?,
+            keyboard: string("keyboard")?,
+            registers: parse_registers(get("registers"))?,
+            memory: parse_regions(get("memory"))?,
+            stdout,
+            exit_code,
+            expect_registers: parse_registers(expect.get("registers"))?,
+            expect_memory: parse_regions(expect.get("memory"))?,
+            name,
+        })
+    }
+}
+
+pub struct CaseResult {
+    pub name: String,
+    // Why the case could not be run, or why the program stopped with a fault
+    pub error: Option<String>,
+    // Each expectation that was not met, with a diff or the expected and actual values
+    pub failures: Vec<String>,
+    pub instructions: u64,
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub time: Duration,
+}
+impl CaseResult {
+    pub fn passed(&self) -> bool {
+        self.error.is_none() && self.failures.is_empty()
+    }
+}
+
+pub struct Report {
+    pub spec: String,
+    pub cases: Vec<CaseResult>,
+}
+
+// A line diff of expected and actual text, from their longest common subsequence of lines
+fn diff(expected: &str, actual: &str) -> String {
+    let a: Vec<&str> = expected.split_inclusive('\n').collect();
+    let b: Vec<&str> = actual.split_inclusive('\n').collect();
+    // lcs[i][j] is the length of the LCS of a[i..] and b[j..]
+    let mut lcs = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] { lcs[i + 1][j + 1] + 1 } else { lcs[i + 1][j].max(lcs[i][j + 1]) };
+        }
+    }
+    let mut out = String::new();
+    let mut line = |sign: char, text: &str| {
+        // Show where a missing newline at the end makes the difference
+        let text = match text.strip_suffix('\n') {
+            Some(text) => text.to_string(),
+            None => format!("{}\\ (no newline)", text),
+        };
+        let _ = writeln!(out, "{}{}", sign, text);
+    };
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            line(' ', a[i]);
+            i += 1;
+            j += 1;
+        } else if i < a.len() && (j == b.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            line('-', a[i]);
+            i += 1;
+        } else {
+            line('+', b[j]);
+            j += 1;
+        }
+    }
+    out
+}
+
+fn read_item(cpu: &CPU, addr: u32, size: u32) -> u32 {
+    (0..size).fold(0, |value, i| value | (cpu.mem.load_byte(addr.wrapping_add(i)) as u32) << (8 * i))
+}
+
+fn run_case(case: &Case, program: &Program) -> Result<CaseResult, String> {
+    let mut limits = Limits::default();
+    limits.max_instructions = Some(case.max_instructions);
+    limits.timeout = case.timeout;
+    let mut builder = CPU::builder().limits(limits).stdio(Stdio::captured(case.stdin.as_bytes())).vfs(Vfs::memory());
+    if case.check_calls {
+        builder = builder.check_calls();
+    }
+    if case.check_memory {
+        builder = builder.check_memory();
+    }
+    if case.check_uninit {
+        builder = builder.check_uninit(false);
+    }
+    if let Some(text) = &case.keyboard {
+        let keyboard = Keyboard::Script(text.bytes().collect());
+        builder = builder.device(MMIO_BASE, Box::new(Console::new(keyboard, DEFAULT_DISPLAY_DELAY)));
+    }
+    let mut cpu = builder.build()?;
+    cpu.load_program(program);
+    if let Some(entry) = &case.entry {
+        cpu.pc = program.symbol(entry).ok_or(format!("Unknown entry label {}", entry))?;
+    }
+    for (_, reg, value) in &case.registers {
+        let value = value.resolve(program)?;
+        match *reg {
+            HI => cpu.hi = value,
+            LO => cpu.lo = value,
+            reg => cpu.set_reg(reg, value)?,
+        }
+    }
+    for region in &case.memory {
+        let addr = region.address.resolve(program)?;
+        for (i, item) in region.items.iter().enumerate() {
+            let value = item.resolve(program)?;
+            for byte in 0..region.size {
+                cpu.mem.store_byte(addr.wrapping_add(i as u32 * region.size + byte), (value >> (8 * byte)) as u8);
+            }
+        }
+    }
+    let start = Instant::now();
+    let mut error = None;
+    loop {
+        match cpu.step(program) {
+            Ok(Some(_)) => {}
+            Ok(None) => break,
+            Err(s) => {
+                error = Some(format!("Could not execute! {}\n{}", s, cpu.backtrace(program)));
+                break;
+            }
+        }
+    }
+    let time = start.elapsed();
+    // The exit code the program would have from the command line, a fault has none
+    let exit_code = match (&error, cpu.termination, cpu.exit_code) {
+        (Some(_), _, _) => None,
+        (None, Some(reason), _) => Some(reason.exit_code()),
+        (None, None, code) => Some(code.unwrap_or(0)),
+    };
+    let stdout = String::from_utf8_lossy(cpu.stdio.stdout()).into_owned();
+    let mut failures = vec![];
+    if let Some(reason) = cpu.termination {
+        if case.exit_code != Some(reason.exit_code()) {
+            failures.push(format!("{} after {} instructions", reason.message(), cpu.limits.instructions()));
+        }
+    }
+    if let (Some(expected), Some(actual)) = (case.exit_code, exit_code) {
+        if expected != actual {
+            failures.push(format!("exit code: expected {}, got {}", expected, actual));
+        }
+    }
+    if let Some(expected) = &case.stdout {
+        if *expected != stdout {
+            failures.push(format!("stdout differs (-expected +actual):\n{}", diff(expected, &stdout).trim_end()));
+        }
+    }
+    for (name, reg, expected) in &case.expect_registers {
+        let expected = expected.resolve(program)?;
+        let actual = match *reg {
+            HI => cpu.hi,
+            LO => cpu.lo,
+            reg => cpu.get_reg(reg)?,
+        };
+        if expected != actual {
+            failures.push(format!("register {}: expected {} (0x{:08x}), got {} (0x{:08x})", name, expected as i32, expected, actual as i32, actual));
+        }
+    }
+    for region in &case.expect_memory {
+        let addr = region.address.resolve(program)?;
+        for (i, item) in region.items.iter().enumerate() {
+            let expected = item.resolve(program)?;
+            let item_addr = addr.wrapping_add(i as u32 * region.size);
+            let actual = read_item(&cpu, item_addr, region.size);
+            let mask = if region.size == 4 { !0 } else { (1 << (8 * region.size)) - 1 };
+            if expected & mask != actual {
+                let width = 2 * region.size as usize;
+                failures.push(format!("memory at 0x{:08x}: expected 0x{:0w$x}, got 0x{:0w$x}", item_addr, expected & mask, actual, w = width));
+            }
+        }
+    }
+    if let Some(checker) = &cpu.call_checker {
+        let violations = &checker.violations;
+        failures.extend(violations.items().iter().map(|item| format!("calling convention: {}", violations.describe(item, program))));
+    }
+    if let Some(checker) = &cpu.uninit {
+        let warnings = &checker.warnings;
+        failures.extend(warnings.items().iter().map(|item| format!("uninitialized: {}", warnings.describe(item, program))));
+    }
+    Ok(CaseResult { name: case.name.clone(), error, failures, instructions: cpu.limits.instructions(), exit_code, stdout, time })
+}
+
+// Runs every case of the spec at path. Errors are for a spec that can't be read; problems with
+// a single case, including its program failing to assemble, are reported against that case.
+pub fn run_spec(path: &Path) -> Result<Report, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    let spec: Table = text.parse().map_err(|e| format!("Invalid test spec {}: {}", path.display(), e))?;
+    let isa = match spec.get("isa") {
+        Some(Value::String(s)) => IsaLevel::from_str(s)?,
+        Some(_) => return Err("isa must be a string".to_string()),
+        None => IsaLevel::Mips32r2,
+    };
+    let cases = match spec.get("case") {
+        Some(Value::Array(cases)) => cases,
+        _ => return Err(format!("{} has no [[case]] tables", path.display())),
+    };
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut programs: HashMap<PathBuf, Result<Program, String>> = HashMap::new();
+    let mut results = vec![];
+    for (index, case) in cases.iter().enumerate() {
+        let result = case.as_table().ok_or("[[case]] entries must be tables".to_string())
+            .and_then(|case| Case::parse(case, &spec, index, dir))
+            .and_then(|case| {
+                let program = programs.entry(case.program.clone()).or_insert_with(|| {
+                    let source = std::fs::read_to_string(&case.program).map_err(|e| format!("Cannot read {}: {}", case.program.display(), e))?;
+                    assemble(&source, &case.program, isa).map_err(|s| format!("Could not parse instruction! {}", s))
+                });
+                run_case(&case, program.as_ref().map_err(|s| s.clone())?)
+            });
+        results.push(result.unwrap_or_else(|error| CaseResult {
+            name: match case.get("name") {
+                Some(Value::String(name)) => name.clone(),
+                _ => format!("case {}", index + 1),
+            },
+            error: Some(error),
+            failures: vec![],
+            instructions: 0,
+            exit_code: None,
+            stdout: String::new(),
+            time: Duration::ZERO,
+        }));
+    }
+    Ok(Report { spec: path.display().to_string(), cases: results })
+}
+
+fn xml_escape(s: &str) -> String {
+    s.chars().map(|c| match c {
+        '&' => "&amp;".to_string(),
+        '<' => "&lt;".to_string(),
+        '>' => "&gt;".to_string(),
+        '"' => "&quot;".to_string(),
+        // Control characters other than whitespace aren't allowed in XML at all
+        c if (c as u32) < 0x20 && !matches!(c, '\t' | '\n' | '\r') => "\u{fffd}".to_string(),
+        c => c.to_string(),
+    }).collect()
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = "\"".to_string();
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl Report {
+    pub fn passed(&self) -> usize {
+        self.cases.iter().filter(|case| case.passed()).count()
+    }
+    // A line per case, with the reasons for any failures, and the totals
+    pub fn summary(&self) -> String {
+        let mut out = String::new();
+        for case in &self.cases {
+            let _ = writeln!(out, "{} {}", if case.passed() { "PASS" } else { "FAIL" }, case.name);
+            for problem in case.error.iter().chain(&case.failures) {
+                for line in problem.lines() {
+                    let _ = writeln!(out, "    {}", line);
+                }
+            }
+        }
+        let _ = writeln!(out, "{} passed, {} failed", self.passed(), self.cases.len() - self.passed());
+        out
+    }
+    pub fn junit(&self) -> String {
+        let total: Duration = self.cases.iter().map(|case| case.time).sum();
+        let errors = self.cases.iter().filter(|case| case.error.is_some()).count();
+        let failures = self.cases.iter().filter(|case| case.error.is_none() && !case.failures.is_empty()).count();
+        let mut out = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n".to_string();
+        let _ = writeln!(out, "<testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.6}\">",
+            xml_escape(&self.spec), self.cases.len(), failures, errors, total.as_secs_f64());
+        for case in &self.cases {
+            let _ = write!(out, "  <testcase name=\"{}\" classname=\"{}\" time=\"{:.6}\"", xml_escape(&case.name), xml_escape(&self.spec), case.time.as_secs_f64());
+            if case.passed() {
+                out.push_str("/>\n");
+                continue;
+            }
+            out.push_str(">\n");
+            if let Some(error) = &case.error {
+                let _ = writeln!(out, "    <error message=\"{}\"/>", xml_escape(error.lines().next().unwrap_or("")));
+            }
+            if !case.failures.is_empty() {
+                let message = case.failures[0].lines().next().unwrap_or("");
+                let _ = writeln!(out, "    <failure message=\"{}\">{}</failure>", xml_escape(message), xml_escape(&case.failures.join("\n")));
+            }
+            let _ = writeln!(out, "    <system-out>{}</system-out>", xml_escape(&case.stdout));
+            out.push_str("  </testcase>\n");
+        }
+        out.push_str("</testsuite>\n");
+        out
+    }
+    pub fn json(&self) -> String {
+        let mut out = format!("{{\n  \"spec\": {},\n  \"passed\": {},\n  \"failed\": {},\n  \"cases\": [",
+            json_string(&self.spec), self.passed(), self.cases.len() - self.passed());
+        for (i, case) in self.cases.iter().enumerate() {
+            let failures: Vec<String> = case.failures.iter().map(|f| json_string(f)).collect();
+            let _ = write!(out, "{}\n    {{\"name\": {}, \"passed\": {}, \"error\": {}, \"failures\": [{}], \"instructions\": {}, \"exit_code\": {}, \"stdout\": {}, \"time\": {:.6}}}",
+                if i == 0 { "" } else { "," },
+                json_string(&case.name),
+                case.passed(),
+                case.error.as_deref().map(json_string).unwrap_or("null".to_string()),
+                failures.join(", "),
+                case.instructions,
+                case.exit_code.map(|code| code.to_string()).unwrap_or("null".to_string()),
+                json_string(&case.stdout),
+                case.time.as_secs_f64());
+        }
+        out.push_str("\n  ]\n}\n");
+        out
+    }
+}
@@ -1,5 +1,8 @@
-use regex::Regex;
 use std::fmt;
+use crate::cp1::{FpFmt, FpCond, freg_as_str};
+use crate::expr::{eval, ExprError};
+use crate::parser::{self, ParseError, Span, Statement};
+use crate::pseudo::{Pseudo, Operand, Cond, AT};
 pub fn reg_as_str(reg_id: &u32) -> String{
     // Register names to map
     let reg_names = vec![
@@ -53,6 +56,31 @@ pub fn parse_reg(streg : &str) -> Result<u32, String>{
         _ => Err(format!("Cannot parse register {}",s)),
     }
 }
+// Architecture revisions, in order. Instructions introduced by a later revision are rejected
+// when assembling for an earlier one.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum IsaLevel {
+    Mips1,
+    Mips32r1,
+    Mips32r2,
+}
+impl IsaLevel {
+    pub fn from_str(s: &str) -> Result<IsaLevel, String> {
+        match s {
+            "mips1" => Ok(IsaLevel::Mips1),
+            "mips32r1" | "mips32" => Ok(IsaLevel::Mips32r1),
+            "mips32r2" => Ok(IsaLevel::Mips32r2),
+            _ => Err(format!("Unknown ISA level {}", s)),
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            IsaLevel::Mips1 => "MIPS I",
+            IsaLevel::Mips32r1 => "MIPS32r1",
+            IsaLevel::Mips32r2 => "MIPS32r2",
+        }
+    }
+}
 #[derive(Clone, Copy)]
 pub enum Instr{
     // Arithmetic instructions
@@ -65,49 +93,125 @@ pub enum Instr{
     // Multiplication/Division instructions
     Mul{rd: u32, rs: u32, rt: u32},
     Mult{rs: u32, rt: u32},
+    Multu{rs: u32, rt: u32},
     Div{rs: u32, rt: u32},
+    Divu{rs: u32, rt: u32},
+    Madd{rs: u32, rt: u32},
+    Maddu{rs: u32, rt: u32},
+    Msub{rs: u32, rt: u32},
+    Msubu{rs: u32, rt: u32},
+    // Bit manipulation
+    Clz{rd: u32, rs: u32},
+    Clo{rd: u32, rs: u32},
+    Seb{rd: u32, rt: u32},
+    Seh{rd: u32, rt: u32},
+    Wsbh{rd: u32, rt: u32},
+    Ext{rt: u32, rs: u32, pos: u32, size: u32},
+    Ins{rt: u32, rs: u32, pos: u32, size: u32},
+    // Conditional moves
+    Movn{rd: u32, rs: u32, rt: u32},
+    Movz{rd: u32, rs: u32, rt: u32},
     // Logical instructions
     And{rd: u32, rs: u32, rt: u32},
     Or{rd: u32, rs: u32, rt: u32},
+    Nor{rd: u32, rs: u32, rt: u32},
+    Xor{rd: u32, rs: u32, rt: u32},
     Andi{rt: u32, rs: u32, immd: u32},
     Ori{rt: u32, rs: u32, immd: u32},
+    Xori{rt: u32, rs: u32, immd: u32},
     // Shift instructions
     Sll{rd: u32, rs: u32, shamt: u32},
     Srl{rd: u32, rs: u32, shamt: u32},
+    Sra{rd: u32, rs: u32, shamt: u32},
+    Sllv{rd: u32, rt: u32, rs: u32},
+    Srlv{rd: u32, rt: u32, rs: u32},
+    Srav{rd: u32, rt: u32, rs: u32},
+    Rotr{rd: u32, rs: u32, shamt: u32},
+    Rotrv{rd: u32, rt: u32, rs: u32},
     // Data Transfer
     Lw{rt: u32, rs: u32, immd: u32},
     Sw{rt: u32, rs: u32, immd: u32},
+    Lb{rt: u32, rs: u32, immd: u32},
+    Lbu{rt: u32, rs: u32, immd: u32},
+    Lh{rt: u32, rs: u32, immd: u32},
+    Lhu{rt: u32, rs: u32, immd: u32},
+    Sb{rt: u32, rs: u32, immd: u32},
+    Sh{rt: u32, rs: u32, immd: u32},
+    Ll{rt: u32, rs: u32, immd: u32},
+    Sc{rt: u32, rs: u32, immd: u32},
     Lui{rt: u32, immd: u32},
-    La{rt: u32, addr: u32}, // Pseudo-instruction
-    Li{rt: u32, immd: u32}, // Pseudo-instruction
     Mfhi{rd: u32},
     Mflo{rd: u32},
-    Move{rs: u32, rt: u32}, // Pseudo-instruction
+    Mthi{rs: u32},
+    Mtlo{rs: u32},
     // Conditional Branch
     Beq{rt: u32, rs: u32, rel_addr: i32},
     Bne{rt: u32, rs: u32, rel_addr: i32},
-    Bgt{rt: u32, rs: u32, rel_addr: i32}, // Pseudo-instruction
-    Bge{rt: u32, rs: u32, rel_addr: i32}, // Pseudo-instruction
-    Blt{rt: u32, rs: u32, rel_addr: i32}, // Pseudo-instruction
-    Ble{rt: u32, rs: u32, rel_addr: i32}, // Pseudo-instruction
+    Bgez{rs: u32, rel_addr: i32},
+    Bgtz{rs: u32, rel_addr: i32},
+    Blez{rs: u32, rel_addr: i32},
+    Bltz{rs: u32, rel_addr: i32},
+    Bgezal{rs: u32, rel_addr: i32},
+    Bltzal{rs: u32, rel_addr: i32},
     // Comparison
     Slt{rd: u32, rs: u32, rt: u32},
     Slti{rt: u32, rs: u32, immd: u32},
     Sltiu{rt: u32, rs: u32, immd: u32},
+    Sltu{rd: u32, rs: u32, rt: u32},
     // Unconditional Jump
     Jump{addr: u32},
     Jr{rd: u32},
     Jal{addr: u32},
+    Jalr{rd: u32, rs: u32},
+    Nop,
+    // Traps
+    Teq{rs: u32, rt: u32},
+    Tne{rs: u32, rt: u32},
+    Tge{rs: u32, rt: u32},
+    Tgeu{rs: u32, rt: u32},
+    Tlt{rs: u32, rt: u32},
+    Tltu{rs: u32, rt: u32},
+    Teqi{rs: u32, immd: u32},
+    Tnei{rs: u32, immd: u32},
+    Tgei{rs: u32, immd: u32},
+    Tgeiu{rs: u32, immd: u32},
+    Tlti{rs: u32, immd: u32},
+    Tltiu{rs: u32, immd: u32},
+    // Exceptions and coprocessor 0
+    Syscall,
+    Break{code: u32},
+    Mfc0{rt: u32, rd: u32},
+    Mtc0{rt: u32, rd: u32},
+    Eret,
+    // Floating point (coprocessor 1)
+    AddF{fmt: FpFmt, fd: u32, fs: u32, ft: u32},
+    SubF{fmt: FpFmt, fd: u32, fs: u32, ft: u32},
+    MulF{fmt: FpFmt, fd: u32, fs: u32, ft: u32},
+    DivF{fmt: FpFmt, fd: u32, fs: u32, ft: u32},
+    SqrtF{fmt: FpFmt, fd: u32, fs: u32},
+    AbsF{fmt: FpFmt, fd: u32, fs: u32},
+    NegF{fmt: FpFmt, fd: u32, fs: u32},
+    MovF{fmt: FpFmt, fd: u32, fs: u32},
+    Cvt{to: FpFmt, from: FpFmt, fd: u32, fs: u32},
+    CmpF{cond: FpCond, fmt: FpFmt, cc: u32, fs: u32, ft: u32},
+    Bc1t{cc: u32, rel_addr: i32},
+    Bc1f{cc: u32, rel_addr: i32},
+    Mtc1{rt: u32, fs: u32},
+    Mfc1{rt: u32, fs: u32},
+    Lwc1{ft: u32, rs: u32, immd: u32},
+    Swc1{ft: u32, rs: u32, immd: u32},
+    Ldc1{ft: u32, rs: u32, immd: u32},
+    Sdc1{ft: u32, rs: u32, immd: u32},
 }
 impl fmt::Debug for Instr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Instr::Add{rd, rs, rt} => write!(f, "add {}, {}, {}",  reg_as_str(rd), reg_as_str(rs), reg_as_str(rt)),
             Instr::Sub{rd, rs, rt} => write!(f, "sub {}, {}, {}",  reg_as_str(rd), reg_as_str(rs), reg_as_str(rt)),
-            Instr::Addi{rt, rs, immd} => write!(f, "addi {}, {}, {}",  reg_as_str(rt), reg_as_str(rs), immd),
+            Instr::Addi{rt, rs, immd} => write!(f, "addi {}, {}, {}",  reg_as_str(rt), reg_as_str(rs), *immd as i32),
             Instr::Addu{rd, rs, rt} => write!(f, "addu {}, {}, {}",  reg_as_str(rd), reg_as_str(rs), reg_as_str(rt)),
             Instr::Subu{rd, rs, rt} => write!(f, "subu {}, {}, {}",  reg_as_str(rd), reg_as_str(rs), reg_as_str(rt)),
-            Instr::Addiu{rt, rs, immd} => write!(f, "addiu {}, {}, {}",  reg_as_str(rt), reg_as_str(rs), immd),
+            Instr::Addiu{rt, rs, immd} => write!(f, "addiu {}, {}, {}",  reg_as_str(rt), reg_as_str(rs), *immd as i32),
             Instr::Mul{rd, rs, rt} => write!(f, "mul {}, {}, {}",  reg_as_str(rd), reg_as_str(rs), reg_as_str(rt)),
             Instr::Mult{rs, rt} => write!(f, "mult {}, {}",  reg_as_str(rs), reg_as_str(rt)),
             Instr::Div{rs, rt} => write!(f, "div {}, {}",  reg_as_str(rs), reg_as_str(rt)),
@@ -117,58 +221,437 @@ impl fmt::Debug for Instr {
             Instr::Ori{rt, rs, immd} => write!(f, "ori {}, {}, {}",  reg_as_str(rt), reg_as_str(rs), immd),
             Instr::Sll{rd, rs, shamt} => write!(f, "sll {}, {}, {}",  reg_as_str(rd), reg_as_str(rs), shamt),
             Instr::Srl{rd, rs, shamt} => write!(f, "srl {}, {}, {}",  reg_as_str(rd), reg_as_str(rs), shamt),
-            Instr::Lw{rt, rs, immd} => write!(f, "lw {}, {}({})",  reg_as_str(rt), immd, reg_as_str(rs)),
-            Instr::Sw{rt, rs, immd} => write!(f, "sw {}, {}({})",  reg_as_str(rt), immd, reg_as_str(rs)),
+            Instr::Lw{rt, rs, immd} => write!(f, "lw {}, {}({})",  reg_as_str(rt), *immd as i32, reg_as_str(rs)),
+            Instr::Sw{rt, rs, immd} => write!(f, "sw {}, {}({})",  reg_as_str(rt), *immd as i32, reg_as_str(rs)),
             Instr::Lui{rt, immd} => write!(f, "lui {}, {}",  reg_as_str(rt), immd),
-            Instr::La{rt, addr} => write!(f, "la {}, {}",  reg_as_str(rt), addr),
-            Instr::Li{rt, immd} => write!(f, "li {}, {}",  reg_as_str(rt), immd),
             Instr::Mfhi{rd} => write!(f, "mfhi {}",  reg_as_str(rd)),
             Instr::Mflo{rd} => write!(f, "mflo {}",  reg_as_str(rd)),
-            Instr::Move{rs, rt} => write!(f, "move {}, {}",  reg_as_str(rs), reg_as_str(rt)),
             Instr::Beq{rt, rs, rel_addr} => write!(f, "beq {}, {}, {}",  reg_as_str(rt), reg_as_str(rs), rel_addr),
             Instr::Bne{rt, rs, rel_addr} => write!(f, "bne {}, {}, {}",  reg_as_str(rt), reg_as_str(rs), rel_addr),
-            Instr::Bgt{rt, rs, rel_addr} => write!(f, "bgt {}, {}, {}",  reg_as_str(rt), reg_as_str(rs), rel_addr),
-            Instr::Bge{rt, rs, rel_addr} => write!(f, "bge {}, {}, {}",  reg_as_str(rt), reg_as_str(rs), rel_addr),
-            Instr::Blt{rt, rs, rel_addr} => write!(f, "blt {}, {}, {}",  reg_as_str(rt), reg_as_str(rs), rel_addr),
-            Instr::Ble{rt, rs, rel_addr} => write!(f, "ble {}, {}, {}",  reg_as_str(rt), reg_as_str(rs), rel_addr),
             Instr::Slt{rd, rs, rt} => write!(f, "slt {}, {}, {}",  reg_as_str(rd), reg_as_str(rs), reg_as_str(rt)),
-            Instr::Slti{rt, rs, immd} => write!(f, "slti {}, {}, {}",  reg_as_str(rt), reg_as_str(rs), immd),
-            Instr::Sltiu{rt, rs, immd} => write!(f, "slti {}, {}, {}",  reg_as_str(rt), reg_as_str(rs), immd),
+            Instr::Slti{rt, rs, immd} => write!(f, "slti {}, {}, {}",  reg_as_str(rt), reg_as_str(rs), *immd as i32),
+            Instr::Sltiu{rt, rs, immd} => write!(f, "sltiu {}, {}, {}",  reg_as_str(rt), reg_as_str(rs), *immd as i32),
             Instr::Jump{addr} => write!(f, "j {}",  addr),
             Instr::Jr{rd} => write!(f, "jr {}",  reg_as_str(rd)),
             Instr::Jal{addr} => write!(f, "jal {}",  addr),
+            Instr::Multu{rs, rt} => write!(f, "multu {}, {}",  reg_as_str(rs), reg_as_str(rt)),
+            Instr::Divu{rs, rt} => write!(f, "divu {}, {}",  reg_as_str(rs), reg_as_str(rt)),
+            Instr::Nor{rd, rs, rt} => write!(f, "nor {}, {}, {}",  reg_as_str(rd), reg_as_str(rs), reg_as_str(rt)),
+            Instr::Xor{rd, rs, rt} => write!(f, "xor {}, {}, {}",  reg_as_str(rd), reg_as_str(rs), reg_as_str(rt)),
+            Instr::Xori{rt, rs, immd} => write!(f, "xori {}, {}, {}",  reg_as_str(rt), reg_as_str(rs), immd),
+            Instr::Sra{rd, rs, shamt} => write!(f, "sra {}, {}, {}",  reg_as_str(rd), reg_as_str(rs), shamt),
+            Instr::Sllv{rd, rt, rs} => write!(f, "sllv {}, {}, {}",  reg_as_str(rd), reg_as_str(rt), reg_as_str(rs)),
+            Instr::Srlv{rd, rt, rs} => write!(f, "srlv {}, {}, {}",  reg_as_str(rd), reg_as_str(rt), reg_as_str(rs)),
+            Instr::Srav{rd, rt, rs} => write!(f, "srav {}, {}, {}",  reg_as_str(rd), reg_as_str(rt), reg_as_str(rs)),
+            Instr::Lb{rt, rs, immd} => write!(f, "lb {}, {}({})",  reg_as_str(rt), *immd as i32, reg_as_str(rs)),
+            Instr::Lbu{rt, rs, immd} => write!(f, "lbu {}, {}({})",  reg_as_str(rt), *immd as i32, reg_as_str(rs)),
+            Instr::Lh{rt, rs, immd} => write!(f, "lh {}, {}({})",  reg_as_str(rt), *immd as i32, reg_as_str(rs)),
+            Instr::Lhu{rt, rs, immd} => write!(f, "lhu {}, {}({})",  reg_as_str(rt), *immd as i32, reg_as_str(rs)),
+            Instr::Sb{rt, rs, immd} => write!(f, "sb {}, {}({})",  reg_as_str(rt), *immd as i32, reg_as_str(rs)),
+            Instr::Sh{rt, rs, immd} => write!(f, "sh {}, {}({})",  reg_as_str(rt), *immd as i32, reg_as_str(rs)),
+            Instr::Mthi{rs} => write!(f, "mthi {}",  reg_as_str(rs)),
+            Instr::Mtlo{rs} => write!(f, "mtlo {}",  reg_as_str(rs)),
+            Instr::Bgez{rs, rel_addr} => write!(f, "bgez {}, {}",  reg_as_str(rs), rel_addr),
+            Instr::Bgtz{rs, rel_addr} => write!(f, "bgtz {}, {}",  reg_as_str(rs), rel_addr),
+            Instr::Blez{rs, rel_addr} => write!(f, "blez {}, {}",  reg_as_str(rs), rel_addr),
+            Instr::Bltz{rs, rel_addr} => write!(f, "bltz {}, {}",  reg_as_str(rs), rel_addr),
+            Instr::Bgezal{rs, rel_addr} => write!(f, "bgezal {}, {}",  reg_as_str(rs), rel_addr),
+            Instr::Bltzal{rs, rel_addr} => write!(f, "bltzal {}, {}",  reg_as_str(rs), rel_addr),
+            Instr::Sltu{rd, rs, rt} => write!(f, "sltu {}, {}, {}",  reg_as_str(rd), reg_as_str(rs), reg_as_str(rt)),
+            Instr::Jalr{rd, rs} => write!(f, "jalr {}, {}",  reg_as_str(rd), reg_as_str(rs)),
+            Instr::Nop => write!(f, "nop"),
+            Instr::Madd{rs, rt} => write!(f, "madd {}, {}",  reg_as_str(rs), reg_as_str(rt)),
+            Instr::Maddu{rs, rt} => write!(f, "maddu {}, {}",  reg_as_str(rs), reg_as_str(rt)),
+            Instr::Msub{rs, rt} => write!(f, "msub {}, {}",  reg_as_str(rs), reg_as_str(rt)),
+            Instr::Msubu{rs, rt} => write!(f, "msubu {}, {}",  reg_as_str(rs), reg_as_str(rt)),
+            Instr::Clz{rd, rs} => write!(f, "clz {}, {}",  reg_as_str(rd), reg_as_str(rs)),
+            Instr::Clo{rd, rs} => write!(f, "clo {}, {}",  reg_as_str(rd), reg_as_str(rs)),
+            Instr::Seb{rd, rt} => write!(f, "seb {}, {}",  reg_as_str(rd), reg_as_str(rt)),
+            Instr::Seh{rd, rt} => write!(f, "seh {}, {}",  reg_as_str(rd), reg_as_str(rt)),
+            Instr::Wsbh{rd, rt} => write!(f, "wsbh {}, {}",  reg_as_str(rd), reg_as_str(rt)),
+            Instr::Ext{rt, rs, pos, size} => write!(f, "ext {}, {}, {}, {}",  reg_as_str(rt), reg_as_str(rs), pos, size),
+            Instr::Ins{rt, rs, pos, size} => write!(f, "ins {}, {}, {}, {}",  reg_as_str(rt), reg_as_str(rs), pos, size),
+            Instr::Movn{rd, rs, rt} => write!(f, "movn {}, {}, {}",  reg_as_str(rd), reg_as_str(rs), reg_as_str(rt)),
+            Instr::Movz{rd, rs, rt} => write!(f, "movz {}, {}, {}",  reg_as_str(rd), reg_as_str(rs), reg_as_str(rt)),
+            Instr::Rotr{rd, rs, shamt} => write!(f, "rotr {}, {}, {}",  reg_as_str(rd), reg_as_str(rs), shamt),
+            Instr::Rotrv{rd, rt, rs} => write!(f, "rotrv {}, {}, {}",  reg_as_str(rd), reg_as_str(rt), reg_as_str(rs)),
+            Instr::Ll{rt, rs, immd} => write!(f, "ll {}, {}({})",  reg_as_str(rt), *immd as i32, reg_as_str(rs)),
+            Instr::Sc{rt, rs, immd} => write!(f, "sc {}, {}({})",  reg_as_str(rt), *immd as i32, reg_as_str(rs)),
+            Instr::Teq{rs, rt} => write!(f, "teq {}, {}",  reg_as_str(rs), reg_as_str(rt)),
+            Instr::Tne{rs, rt} => write!(f, "tne {}, {}",  reg_as_str(rs), reg_as_str(rt)),
+            Instr::Tge{rs, rt} => write!(f, "tge {}, {}",  reg_as_str(rs), reg_as_str(rt)),
+            Instr::Tgeu{rs, rt} => write!(f, "tgeu {}, {}",  reg_as_str(rs), reg_as_str(rt)),
+            Instr::Tlt{rs, rt} => write!(f, "tlt {}, {}",  reg_as_str(rs), reg_as_str(rt)),
+            Instr::Tltu{rs, rt} => write!(f, "tltu {}, {}",  reg_as_str(rs), reg_as_str(rt)),
+            Instr::Teqi{rs, immd} => write!(f, "teqi {}, {}",  reg_as_str(rs), *immd as i32),
+            Instr::Tnei{rs, immd} => write!(f, "tnei {}, {}",  reg_as_str(rs), *immd as i32),
+            Instr::Tgei{rs, immd} => write!(f, "tgei {}, {}",  reg_as_str(rs), *immd as i32),
+            Instr::Tgeiu{rs, immd} => write!(f, "tgeiu {}, {}",  reg_as_str(rs), *immd as i32),
+            Instr::Tlti{rs, immd} => write!(f, "tlti {}, {}",  reg_as_str(rs), *immd as i32),
+            Instr::Tltiu{rs, immd} => write!(f, "tltiu {}, {}",  reg_as_str(rs), *immd as i32),
+            Instr::Syscall => write!(f, "syscall"),
+            Instr::Break{code} => write!(f, "break {}",  code),
+            Instr::Mfc0{rt, rd} => write!(f, "mfc0 {}, ${}",  reg_as_str(rt), rd),
+            Instr::Mtc0{rt, rd} => write!(f, "mtc0 {}, ${}",  reg_as_str(rt), rd),
+            Instr::Eret => write!(f, "eret"),
+            Instr::AddF{fmt, fd, fs, ft} => write!(f, "add.{} {}, {}, {}",  fmt.suffix(), freg_as_str(fd), freg_as_str(fs), freg_as_str(ft)),
+            Instr::SubF{fmt, fd, fs, ft} => write!(f, "sub.{} {}, {}, {}",  fmt.suffix(), freg_as_str(fd), freg_as_str(fs), freg_as_str(ft)),
+            Instr::MulF{fmt, fd, fs, ft} => write!(f, "mul.{} {}, {}, {}",  fmt.suffix(), freg_as_str(fd), freg_as_str(fs), freg_as_str(ft)),
+            Instr::DivF{fmt, fd, fs, ft} => write!(f, "div.{} {}, {}, {}",  fmt.suffix(), freg_as_str(fd), freg_as_str(fs), freg_as_str(ft)),
+            Instr::SqrtF{fmt, fd, fs} => write!(f, "sqrt.{} {}, {}",  fmt.suffix(), freg_as_str(fd), freg_as_str(fs)),
+            Instr::AbsF{fmt, fd, fs} => write!(f, "abs.{} {}, {}",  fmt.suffix(), freg_as_str(fd), freg_as_str(fs)),
+            Instr::NegF{fmt, fd, fs} => write!(f, "neg.{} {}, {}",  fmt.suffix(), freg_as_str(fd), freg_as_str(fs)),
+            Instr::MovF{fmt, fd, fs} => write!(f, "mov.{} {}, {}",  fmt.suffix(), freg_as_str(fd), freg_as_str(fs)),
+            Instr::Cvt{to, from, fd, fs} => write!(f, "cvt.{}.{} {}, {}",  to.suffix(), from.suffix(), freg_as_str(fd), freg_as_str(fs)),
+            Instr::CmpF{cond, fmt, cc, fs, ft} => write!(f, "c.{}.{} {}, {}, {}",  format!("{:?}", cond).to_lowercase(), fmt.suffix(), cc, freg_as_str(fs), freg_as_str(ft)),
+            Instr::Bc1t{cc, rel_addr} => write!(f, "bc1t {}, {}",  cc, rel_addr),
+            Instr::Bc1f{cc, rel_addr} => write!(f, "bc1f {}, {}",  cc, rel_addr),
+            Instr::Mtc1{rt, fs} => write!(f, "mtc1 {}, {}",  reg_as_str(rt), freg_as_str(fs)),
+            Instr::Mfc1{rt, fs} => write!(f, "mfc1 {}, {}",  reg_as_str(rt), freg_as_str(fs)),
+            Instr::Lwc1{ft, rs, immd} => write!(f, "lwc1 {}, {}({})",  freg_as_str(ft), *immd as i32, reg_as_str(rs)),
+            Instr::Swc1{ft, rs, immd} => write!(f, "swc1 {}, {}({})",  freg_as_str(ft), *immd as i32, reg_as_str(rs)),
+            Instr::Ldc1{ft, rs, immd} => write!(f, "ldc1 {}, {}({})",  freg_as_str(ft), *immd as i32, reg_as_str(rs)),
+            Instr::Sdc1{ft, rs, immd} => write!(f, "sdc1 {}, {}({})",  freg_as_str(ft), *immd as i32, reg_as_str(rs)),
         }
     }
 }
 impl Instr {
+    // The earliest architecture revision that has this instruction
+    pub fn isa_level(&self) -> IsaLevel {
+        match self {
+            Instr::Seb{..} | Instr::Seh{..} | Instr::Wsbh{..} | Instr::Ext{..} | Instr::Ins{..} |
+            Instr::Rotr{..} | Instr::Rotrv{..} => IsaLevel::Mips32r2,
+            Instr::Mul{..} | Instr::Madd{..} | Instr::Maddu{..} | Instr::Msub{..} | Instr::Msubu{..} |
+            Instr::Clz{..} | Instr::Clo{..} | Instr::Movn{..} | Instr::Movz{..} | Instr::Ll{..} | Instr::Sc{..} |
+            Instr::Teq{..} | Instr::Tne{..} | Instr::Tge{..} | Instr::Tgeu{..} | Instr::Tlt{..} | Instr::Tltu{..} |
+            Instr::Teqi{..} | Instr::Tnei{..} | Instr::Tgei{..} | Instr::Tgeiu{..} | Instr::Tlti{..} | Instr::Tltiu{..} |
+            Instr::Ldc1{..} | Instr::Sdc1{..} | Instr::SqrtF{..} | Instr::Eret => IsaLevel::Mips32r1,
+            _ => IsaLevel::Mips1,
+        }
+    }
     pub fn is_delay_instruction(instr :&Instr) -> bool{
-        match instr{
-            Instr::Beq{..} | Instr::Bne{..} | Instr::Bgt{..} | Instr::Bge{..} | Instr::Blt{..} | Instr::Ble{..} | Instr::Jump{..} | Instr::Jal{..}=> true,
-            _ => false,
+        matches!(instr,
+            Instr::Beq{..} | Instr::Bne{..} |
+            Instr::Bgez{..} | Instr::Bgtz{..} | Instr::Blez{..} | Instr::Bltz{..} | Instr::Bgezal{..} | Instr::Bltzal{..} |
+            Instr::Jump{..} | Instr::Jr{..} | Instr::Jal{..} | Instr::Jalr{..} | Instr::Bc1t{..} | Instr::Bc1f{..})
+    }
+    // Whether the immediate fits the 16 bit field (or shift amount the 5 bit field) of its encoding
+    pub fn immediate_fits(&self) -> bool {
+        let signed = |immd: &u32| (*immd as i32) >= i16::MIN as i32 && (*immd as i32) <= i16::MAX as i32;
+        match self {
+            Instr::Andi{immd, ..} | Instr::Ori{immd, ..} | Instr::Xori{immd, ..} | Instr::Lui{immd, ..} => *immd <= 0xffff,
+            Instr::Addi{immd, ..} | Instr::Addiu{immd, ..} | Instr::Slti{immd, ..} | Instr::Sltiu{immd, ..} |
+            Instr::Teqi{immd, ..} | Instr::Tnei{immd, ..} | Instr::Tgei{immd, ..} | Instr::Tgeiu{immd, ..} |
+            Instr::Tlti{immd, ..} | Instr::Tltiu{immd, ..} => signed(immd),
+            Instr::Sll{shamt, ..} | Instr::Srl{shamt, ..} | Instr::Sra{shamt, ..} | Instr::Rotr{shamt, ..} => *shamt < 32,
+            _ => match self.memory_operand() {
+                Some((_, immd)) => signed(&immd),
+                None => true,
+            },
+        }
+    }
+    // Base register and offset of a load or store
+    pub fn memory_operand(&self) -> Option<(u32, u32)> {
+        match self {
+            Instr::Lw{rs, immd, ..} | Instr::Sw{rs, immd, ..} | Instr::Lb{rs, immd, ..} | Instr::Lbu{rs, immd, ..} |
+            Instr::Lh{rs, immd, ..} | Instr::Lhu{rs, immd, ..} | Instr::Sb{rs, immd, ..} | Instr::Sh{rs, immd, ..} |
+            Instr::Ll{rs, immd, ..} | Instr::Sc{rs, immd, ..} | Instr::Lwc1{rs, immd, ..} | Instr::Swc1{rs, immd, ..} |
+            Instr::Ldc1{rs, immd, ..} | Instr::Sdc1{rs, immd, ..} => Some((*rs, *immd)),
+            _ => None,
         }
     }
-    pub fn from_str(line: &String) -> Result<Instr, String> {
-        let tokens: Vec<&str> = regex::Regex::new(r"([\s()$,]+|#.*)").unwrap()
-        .split(line).filter(|s| !s.is_empty()).collect();
-        let parse_immediate = |s: &str| s.parse::<u32>().unwrap();
-        
-        let parse_two_regs = |tokens: &[&str]| -> Result<(u32, u32), String> {
-            Ok((parse_reg(tokens[1])?, parse_reg(tokens[2])?))
+    // General purpose registers the instruction reads, not counting those a syscall reads
+    pub fn reads(&self) -> Vec<u32> {
+        match *self {
+            Instr::Add{rs, rt, ..} | Instr::Sub{rs, rt, ..} | Instr::Addu{rs, rt, ..} | Instr::Subu{rs, rt, ..} |
+            Instr::Mul{rs, rt, ..} | Instr::Mult{rs, rt} | Instr::Multu{rs, rt} | Instr::Div{rs, rt} | Instr::Divu{rs, rt} |
+            Instr::Madd{rs, rt} | Instr::Maddu{rs, rt} | Instr::Msub{rs, rt} | Instr::Msubu{rs, rt} |
+            Instr::Movn{rs, rt, ..} | Instr::Movz{rs, rt, ..} | Instr::Ins{rs, rt, ..} |
+            Instr::And{rs, rt, ..} | Instr::Or{rs, rt, ..} | Instr::Nor{rs, rt, ..} | Instr::Xor{rs, rt, ..} |
+            Instr::Sllv{rs, rt, ..} | Instr::Srlv{rs, rt, ..} | Instr::Srav{rs, rt, ..} | Instr::Rotrv{rs, rt, ..} |
+            Instr::Sw{rs, rt, ..} | Instr::Sb{rs, rt, ..} | Instr::Sh{rs, rt, ..} | Instr::Sc{rs, rt, ..} |
+            Instr::Beq{rs, rt, ..} | Instr::Bne{rs, rt, ..} | Instr::Slt{rs, rt, ..} | Instr::Sltu{rs, rt, ..} |
+            Instr::Teq{rs, rt} | Instr::Tne{rs, rt} | Instr::Tge{rs, rt} | Instr::Tgeu{rs, rt} | Instr::Tlt{rs, rt} | Instr::Tltu{rs, rt} => vec![rs, rt],
+            Instr::Addi{rs, ..} | Instr::Addiu{rs, ..} | Instr::Clz{rs, ..} | Instr::Clo{rs, ..} | Instr::Ext{rs, ..} |
+            Instr::Andi{rs, ..} | Instr::Ori{rs, ..} | Instr::Xori{rs, ..} |
+            Instr::Sll{rs, ..} | Instr::Srl{rs, ..} | Instr::Sra{rs, ..} | Instr::Rotr{rs, ..} |
+            Instr::Lw{rs, ..} | Instr::Lb{rs, ..} | Instr::Lbu{rs, ..} | Instr::Lh{rs, ..} | Instr::Lhu{rs, ..} | Instr::Ll{rs, ..} |
+            Instr::Lwc1{rs, ..} | Instr::Swc1{rs, ..} | Instr::Ldc1{rs, ..} | Instr::Sdc1{rs, ..} |
+            Instr::Mthi{rs} | Instr::Mtlo{rs} | Instr::Bgez{rs, ..} | Instr::Bgtz{rs, ..} | Instr::Blez{rs, ..} | Instr::Bltz{rs, ..} |
+            Instr::Bgezal{rs, ..} | Instr::Bltzal{rs, ..} | Instr::Slti{rs, ..} | Instr::Sltiu{rs, ..} | Instr::Jalr{rs, ..} |
+            Instr::Teqi{rs, ..} | Instr::Tnei{rs, ..} | Instr::Tgei{rs, ..} | Instr::Tgeiu{rs, ..} | Instr::Tlti{rs, ..} | Instr::Tltiu{rs, ..} => vec![rs],
+            Instr::Seb{rt, ..} | Instr::Seh{rt, ..} | Instr::Wsbh{rt, ..} | Instr::Mtc0{rt, ..} | Instr::Mtc1{rt, ..} => vec![rt],
+            Instr::Jr{rd} => vec![rd],
+            _ => vec![],
+        }
+    }
+    // General purpose register the instruction writes, if any
+    pub fn writes(&self) -> Option<u32> {
+        let reg = match *self {
+            Instr::Add{rd, ..} | Instr::Sub{rd, ..} | Instr::Addu{rd, ..} | Instr::Subu{rd, ..} | Instr::Mul{rd, ..} |
+            Instr::Clz{rd, ..} | Instr::Clo{rd, ..} | Instr::Seb{rd, ..} | Instr::Seh{rd, ..} | Instr::Wsbh{rd, ..} |
+            Instr::Movn{rd, ..} | Instr::Movz{rd, ..} | Instr::And{rd, ..} | Instr::Or{rd, ..} | Instr::Nor{rd, ..} | Instr::Xor{rd, ..} |
+            Instr::Sll{rd, ..} | Instr::Srl{rd, ..} | Instr::Sra{rd, ..} | Instr::Sllv{rd, ..} | Instr::Srlv{rd, ..} | Instr::Srav{rd, ..} |
+            Instr::Rotr{rd, ..} | Instr::Rotrv{rd, ..} | Instr::Mfhi{rd} | Instr::Mflo{rd} | Instr::Slt{rd, ..} | Instr::Sltu{rd, ..} |
+            Instr::Jalr{rd, ..} => rd,
+            Instr::Addi{rt, ..} | Instr::Addiu{rt, ..} | Instr::Ext{rt, ..} | Instr::Ins{rt, ..} |
+            Instr::Andi{rt, ..} | Instr::Ori{rt, ..} | Instr::Xori{rt, ..} | Instr::Slti{rt, ..} | Instr::Sltiu{rt, ..} |
+            Instr::Lw{rt, ..} | Instr::Lb{rt, ..} | Instr::Lbu{rt, ..} | Instr::Lh{rt, ..} | Instr::Lhu{rt, ..} | Instr::Ll{rt, ..} |
+            Instr::Sc{rt, ..} | Instr::Lui{rt, ..} | Instr::Mfc0{rt, ..} | Instr::Mfc1{rt, ..} => rt,
+            Instr::Jal{..} | Instr::Bgezal{..} | Instr::Bltzal{..} => 31,
+            _ => return None,
         };
+        Some(reg).filter(|reg| *reg != 0)
+    }
+    // Bytes a load or store accesses and whether it stores
+    pub fn memory_access(&self) -> Option<(u32, bool)> {
+        match self {
+            Instr::Lw{..} | Instr::Ll{..} | Instr::Lwc1{..} => Some((4, false)),
+            Instr::Lh{..} | Instr::Lhu{..} => Some((2, false)),
+            Instr::Lb{..} | Instr::Lbu{..} => Some((1, false)),
+            Instr::Ldc1{..} => Some((8, false)),
+            Instr::Sw{..} | Instr::Sc{..} | Instr::Swc1{..} => Some((4, true)),
+            Instr::Sh{..} => Some((2, true)),
+            Instr::Sb{..} => Some((1, true)),
+            Instr::Sdc1{..} => Some((8, true)),
+            _ => None,
+        }
+    }
+    // The same load or store with a different base register and offset
+    pub fn with_address(&self, base: u32, offset: u32) -> Instr {
+        let (rs, immd) = (base, offset);
+        match *self {
+            Instr::Lw{rt, ..} => Instr::Lw{rt, rs, immd},
+            Instr::Sw{rt, ..} => Instr::Sw{rt, rs, immd},
+            Instr::Lb{rt, ..} => Instr::Lb{rt, rs, immd},
+            Instr::Lbu{rt, ..} => Instr::Lbu{rt, rs, immd},
+            Instr::Lh{rt, ..} => Instr::Lh{rt, rs, immd},
+            Instr::Lhu{rt, ..} => Instr::Lhu{rt, rs, immd},
+            Instr::Sb{rt, ..} => Instr::Sb{rt, rs, immd},
+            Instr::Sh{rt, ..} => Instr::Sh{rt, rs, immd},
+            Instr::Ll{rt, ..} => Instr::Ll{rt, rs, immd},
+            Instr::Sc{rt, ..} => Instr::Sc{rt, rs, immd},
+            Instr::Lwc1{ft, ..} => Instr::Lwc1{ft, rs, immd},
+            Instr::Swc1{ft, ..} => Instr::Swc1{ft, rs, immd},
+            Instr::Ldc1{ft, ..} => Instr::Ldc1{ft, rs, immd},
+            Instr::Sdc1{ft, ..} => Instr::Sdc1{ft, rs, immd},
+            other => other,
+        }
+    }
+    // The same instruction with a different immediate, or memory offset for loads and stores
+    pub fn with_immediate(&self, immd: u32) -> Instr {
+        match *self {
+            Instr::Addi{rt, rs, ..} => Instr::Addi{rt, rs, immd},
+            Instr::Addiu{rt, rs, ..} => Instr::Addiu{rt, rs, immd},
+            Instr::Andi{rt, rs, ..} => Instr::Andi{rt, rs, immd},
+            Instr::Ori{rt, rs, ..} => Instr::Ori{rt, rs, immd},
+            Instr::Xori{rt, rs, ..} => Instr::Xori{rt, rs, immd},
+            Instr::Slti{rt, rs, ..} => Instr::Slti{rt, rs, immd},
+            Instr::Sltiu{rt, rs, ..} => Instr::Sltiu{rt, rs, immd},
+            Instr::Lui{rt, ..} => Instr::Lui{rt, immd},
+            Instr::Teqi{rs, ..} => Instr::Teqi{rs, immd},
+            Instr::Tnei{rs, ..} => Instr::Tnei{rs, immd},
+            Instr::Tgei{rs, ..} => Instr::Tgei{rs, immd},
+            Instr::Tgeiu{rs, ..} => Instr::Tgeiu{rs, immd},
+            Instr::Tlti{rs, ..} => Instr::Tlti{rs, immd},
+            Instr::Tltiu{rs, ..} => Instr::Tltiu{rs, immd},
+            other => match other.memory_operand() {
+                Some((rs, _)) => other.with_address(rs, immd),
+                None => other,
+            },
+        }
+    }
+    // The register form of an immediate arithmetic or logical instruction, taking the
+    // immediate from the given register instead
+    pub fn with_register_operand(&self, reg: u32) -> Option<Instr> {
+        let rt = reg;
+        Some(match *self {
+            Instr::Addi{rt: rd, rs, ..} => Instr::Add{rd, rs, rt},
+            Instr::Addiu{rt: rd, rs, ..} => Instr::Addu{rd, rs, rt},
+            Instr::Andi{rt: rd, rs, ..} => Instr::And{rd, rs, rt},
+            Instr::Ori{rt: rd, rs, ..} => Instr::Or{rd, rs, rt},
+            Instr::Xori{rt: rd, rs, ..} => Instr::Xor{rd, rs, rt},
+            Instr::Slti{rt: rd, rs, ..} => Instr::Slt{rd, rs, rt},
+            Instr::Sltiu{rt: rd, rs, ..} => Instr::Sltu{rd, rs, rt},
+            _ => return None,
+        })
+    }
+}
+#[derive(Debug)]
+pub enum ParsedInstr {
+    I(Instr),
+    // Conditional Branch
+    Beq{rt: u32, rs: u32, label: String},
+    Bne{rt: u32, rs: u32, label: String},
+    Bgez{rs: u32, label: String},
+    Bgtz{rs: u32, label: String},
+    Blez{rs: u32, label: String},
+    Bltz{rs: u32, label: String},
+    Bgezal{rs: u32, label: String},
+    Bltzal{rs: u32, label: String},
+    // Unconditional Jump
+    Jump{label: String},
+    Jal{label: String},
+    // Floating point condition branches
+    Bc1t{cc: u32, label: String},
+    Bc1f{cc: u32, label: String},
+    // Expands to one or more real instructions
+    Pseudo(Pseudo),
+}
 
-        let parse_three_regs = |tokens: &[&str]| -> Result<(u32, u32, u32), String> {
-            Ok((parse_reg(tokens[1])?, parse_reg(tokens[2])?, parse_reg(tokens[3])?))
+// The operands of a statement, read according to the shape its mnemonic expects
+struct Operands<'a> {
+    statement: &'a Statement,
+    // The line the statement was parsed from, for quoting operands in errors
+    text: &'a str,
+    constants: &'a dyn Fn(&str) -> Option<u32>,
+    // An immediate naming a label, which is filled in once labels are known
+    deferred: Option<String>,
+}
+impl Operands<'_> {
+    fn span(&self, i: usize) -> Span {
+        self.statement.operands[i].1
+    }
+    fn expected(&self, i: usize, what: &str) -> ParseError {
+        let span = self.span(i);
+        ParseError::new(format!("Expected {}, found {}", what, &self.text[span.start..span.end]), span)
+    }
+    // Checks the number of operands is one of those allowed, returning it
+    fn count(&self, allowed: &[usize]) -> Result<usize, ParseError> {
+        let n = self.statement.operands.len();
+        if allowed.contains(&n) {
+            return Ok(n);
+        }
+        let counts: Vec<String> = allowed.iter().map(|n| n.to_string()).collect();
+        let message = format!("{} takes {} operand{}, found {}", self.statement.mnemonic, counts.join(" or "),
+            if allowed == [1] { "" } else { "s" }, n);
+        // Point at the first operand too many, or the end of the statement if some are missing
+        let max = *allowed.iter().max().unwrap();
+        let span = if n > max {
+            self.span(max)
+        } else {
+            Span { start: self.statement.span.end, end: self.statement.span.end }
         };
-        if tokens.len() == 0{
-            return Err("No Tokens to parse!".to_string());
+        Err(ParseError::new(message, span))
+    }
+    fn reg(&self, i: usize) -> Result<u32, ParseError> {
+        match self.statement.operands[i].0 {
+            parser::Operand::Reg(reg) => Ok(reg),
+            _ => Err(self.expected(i, "a register")),
         }
-        match tokens[0]{
-            "add" | "addu" | "sub" | "subu" | "mul" | "and" | "or" | "slt" =>{
-                if tokens.len() < 4{
-                    return Err("Cannot parse 3 register R instruction!".to_string());
-                }
-                let (rd, rs, rt) = parse_three_regs(&tokens)?;
-                Ok(match tokens[0] {
+    }
+    // A floating point register holding a value of the given format. Double precision values
+    // live in register pairs, so must be in an even register.
+    fn freg(&self, i: usize, fmt: FpFmt) -> Result<u32, ParseError> {
+        let parser::Operand::FReg(reg) = self.statement.operands[i].0 else {
+            return Err(self.expected(i, "a floating point register"));
+        };
+        if fmt == FpFmt::Double && reg % 2 != 0 {
+            return Err(ParseError::new(format!("Double precision operand $f{} must be an even register", reg), self.span(i)));
+        }
+        Ok(reg)
+    }
+    // Evaluates an expression against the constants defined so far, giving None if it names a label
+    fn evaluate(&self, expr: &str, span: Span) -> Result<Option<u32>, ParseError> {
+        match eval(expr, self.constants) {
+            Ok(value) => Ok(Some(value)),
+            Err(ExprError::Unknown(_)) => Ok(None),
+            Err(ExprError::Invalid(message)) => Err(ParseError::new(message, span)),
+        }
+    }
+    fn defer(&mut self, expr: &str, span: Span) -> Result<(), ParseError> {
+        if self.deferred.is_some() {
+            return Err(ParseError::new("Only one operand may refer to a label".to_string(), span));
+        }
+        self.deferred = Some(expr.to_string());
+        Ok(())
+    }
+    fn constant(&self, i: usize) -> Result<u32, ParseError> {
+        match &self.statement.operands[i].0 {
+            parser::Operand::Expr(expr) => self.evaluate(expr, self.span(i))?.ok_or(self.expected(i, "a constant")),
+            _ => Err(self.expected(i, "a constant")),
+        }
+    }
+    // An immediate, which may name a label to be filled in after the first pass
+    fn imm(&mut self, i: usize) -> Result<u32, ParseError> {
+        let parser::Operand::Expr(expr) = &self.statement.operands[i].0 else {
+            return Err(self.expected(i, "an immediate"));
+        };
+        match self.evaluate(expr, self.span(i))? {
+            Some(value) => Ok(value),
+            None => {
+                self.defer(&expr.clone(), self.span(i))?;
+                Ok(0)
+            }
+        }
+    }
+    // A branch or jump target, resolved once labels are known
+    fn label(&self, i: usize) -> Result<String, ParseError> {
+        match &self.statement.operands[i].0 {
+            parser::Operand::Expr(expr) => Ok(expr.clone()),
+            _ => Err(self.expected(i, "a label")),
+        }
+    }
+    // Second operand of a branch, which may be compared against a register or a constant
+    fn condition_flag(&self, i: usize) -> Result<u32, ParseError> {
+        match self.constant(i)? {
+            cc if cc > 7 => Err(ParseError::new(format!("Invalid condition flag {}", cc), self.span(i))),
+            cc => Ok(cc),
+        }
+    }
+    fn reg_or_constant(&self, i: usize) -> Result<Operand, ParseError> {
+        match self.statement.operands[i].0 {
+            parser::Operand::Reg(reg) => Ok(Operand::Reg(reg)),
+            parser::Operand::Expr(_) => Ok(Operand::Imm(self.constant(i)?)),
+            _ => Err(self.expected(i, "a register or constant")),
+        }
+    }
+    // Fills in the address of a load or store, from offset($rs), ($rs), a constant address, or
+    // a label with an optional base register, e.g. table+8($t1)
+    fn address(&mut self, i: usize, instr: Instr) -> Result<ParsedInstr, ParseError> {
+        let span = self.span(i);
+        let (offset, base) = match &self.statement.operands[i].0 {
+            parser::Operand::Mem(offset, base) => (offset.clone(), *base),
+            parser::Operand::Expr(expr) => (Some(expr.clone()), 0),
+            _ => return Err(self.expected(i, "an address")),
+        };
+        let instr = instr.with_address(base, 0);
+        let Some(offset) = offset else {
+            return Ok(ParsedInstr::I(instr));
+        };
+        match self.evaluate(&offset, span)? {
+            Some(value) => ParsedInstr::from_instr(instr.with_address(base, value)).map_err(|e| ParseError::new(e, span)),
+            // %lo(label) fits the offset field, so is filled in later like any other immediate
+            None if offset.starts_with('%') => {
+                self.defer(&offset, span)?;
+                Ok(ParsedInstr::I(instr))
+            }
+            None => Ok(ParsedInstr::Pseudo(Pseudo::LabelAccess{instr, label: offset})),
+        }
+    }
+}
+
+impl ParsedInstr {
+    // Number of instructions this takes up in the text section
+    pub fn len(&self) -> u32 {
+        match self {
+            ParsedInstr::Pseudo(pseudo) => pseudo.len(),
+            _ => 1,
+        }
+    }
+    // Reads a statement as an instruction, checking its operands have the shape its mnemonic
+    // expects. `text` is the line the statement was parsed from and `constants` looks up the
+    // constants defined so far. Also returns any immediate expression that names a label, which
+    // is filled in by the second assembler pass.
+    pub fn from_statement(statement: &Statement, text: &str, constants: &dyn Fn(&str) -> Option<u32>) -> Result<(ParsedInstr, Option<String>), ParseError> {
+        let mut o = Operands { statement, text, constants, deferred: None };
+        let mnemonic = statement.mnemonic.as_str();
+        let real = |instr: Instr| ParsedInstr::from_instr(instr).map_err(|e| ParseError::new(e, statement.span));
+        let parsed = match mnemonic {
+            "add" | "addu" | "sub" | "subu" | "mul" | "and" | "or" | "nor" | "xor" | "slt" | "sltu" | "movn" | "movz" => {
+                o.count(&[3])?;
+                let (rd, rs, rt) = (o.reg(0)?, o.reg(1)?, o.reg(2)?);
+                ParsedInstr::I(match mnemonic {
                     "add" => Instr::Add{rd, rs, rt},
                     "addu" => Instr::Addu{rd, rs, rt},
                     "sub" => Instr::Sub{rd, rs, rt},
@@ -176,170 +659,402 @@ impl Instr {
                     "mul" => Instr::Mul{rd, rs, rt},
                     "and" => Instr::And{rd, rs, rt},
                     "or" => Instr::Or{rd, rs, rt},
+                    "nor" => Instr::Nor{rd, rs, rt},
+                    "xor" => Instr::Xor{rd, rs, rt},
                     "slt" => Instr::Slt{rd, rs, rt},
+                    "sltu" => Instr::Sltu{rd, rs, rt},
+                    "movn" => Instr::Movn{rd, rs, rt},
+                    "movz" => Instr::Movz{rd, rs, rt},
                     _=> unreachable!()
                 })
             }
-            "addi" | "addiu" | "andi" | "ori" | "slti" | "sltiu" =>{
-                if tokens.len() < 4{
-                    return Err("Cannot parse 2 register, 1 immediate I instruction!".to_string());
-                }
-                let (rt, rs) = parse_two_regs(&tokens)?;
-                let immd = parse_immediate(tokens[3]);
-                Ok(match tokens[0] {
+            "addi" | "addiu" | "andi" | "ori" | "xori" | "slti" | "sltiu" => {
+                o.count(&[3])?;
+                let (rt, rs, immd) = (o.reg(0)?, o.reg(1)?, o.imm(2)?);
+                real(match mnemonic {
                     "addi" => Instr::Addi{rt, rs, immd},
                     "addiu" => Instr::Addiu{rt, rs, immd},
                     "andi" => Instr::Andi{rt, rs, immd},
                     "ori" => Instr::Ori{rt, rs, immd},
+                    "xori" => Instr::Xori{rt, rs, immd},
                     "slti" => Instr::Slti{rt, rs, immd},
                     "sltiu" => Instr::Sltiu{rt, rs, immd},
                     _=> unreachable!()
-                })
+                })?
             }
-            "lw" | "sw" =>{
-                if tokens.len() < 4{
-                    return Err("Cannot parse load and store instr!".to_string());
-                }
-                let rt = parse_reg(tokens[1])?;
-                let immd = parse_immediate(tokens[2]);
-                let rs = parse_reg(tokens[3])?;
-                Ok(match tokens[0] {
-                    "addi" => Instr::Addi{rt, rs, immd},
-                    "addiu" => Instr::Addiu{rt, rs, immd},
-                    "andi" => Instr::Andi{rt, rs, immd},
-                    "ori" => Instr::Ori{rt, rs, immd},
-                    "lw" => Instr::Lw{rt, rs, immd},
-                    "sw" => Instr::Sw{rt, rs, immd},
-                    "slti" => Instr::Slti{rt, rs, immd},
-                    "sltiu" => Instr::Sltiu{rt, rs, immd},
+            "lw" | "sw" | "lb" | "lbu" | "lh" | "lhu" | "sb" | "sh" | "ll" | "sc" |
+            "lwc1" | "swc1" | "ldc1" | "sdc1" | "l.s" | "s.s" | "l.d" | "s.d" => {
+                o.count(&[2])?;
+                let (rs, immd) = (0, 0);
+                let instr = match mnemonic {
+                    "lwc1" | "l.s" => Instr::Lwc1{ft: o.freg(0, FpFmt::Single)?, rs, immd},
+                    "swc1" | "s.s" => Instr::Swc1{ft: o.freg(0, FpFmt::Single)?, rs, immd},
+                    "ldc1" | "l.d" => Instr::Ldc1{ft: o.freg(0, FpFmt::Double)?, rs, immd},
+                    "sdc1" | "s.d" => Instr::Sdc1{ft: o.freg(0, FpFmt::Double)?, rs, immd},
+                    _ => {
+                        let rt = o.reg(0)?;
+                        match mnemonic {
+                            "lw" => Instr::Lw{rt, rs, immd},
+                            "sw" => Instr::Sw{rt, rs, immd},
+                            "lb" => Instr::Lb{rt, rs, immd},
+                            "lbu" => Instr::Lbu{rt, rs, immd},
+                            "lh" => Instr::Lh{rt, rs, immd},
+                            "lhu" => Instr::Lhu{rt, rs, immd},
+                            "sb" => Instr::Sb{rt, rs, immd},
+                            "sh" => Instr::Sh{rt, rs, immd},
+                            "ll" => Instr::Ll{rt, rs, immd},
+                            "sc" => Instr::Sc{rt, rs, immd},
+                            _=> unreachable!()
+                        }
+                    }
+                };
+                o.address(1, instr)?
+            }
+            // div and divu with three operands are pseudo-instructions, with two they are real
+            "div" | "divu" if statement.operands.len() == 3 => {
+                let (rd, rs, rt) = (o.reg(0)?, o.reg(1)?, o.reg(2)?);
+                ParsedInstr::Pseudo(match mnemonic {
+                    "div" => Pseudo::Div{rd, rs, rt},
+                    "divu" => Pseudo::Divu{rd, rs, rt},
                     _=> unreachable!()
                 })
             }
-            "mult" | "div" | "move" => {
-                if tokens.len() < 3{
-                    return Err("Cannot parse 2 register instruction!".to_string());
+            "mult" | "multu" | "div" | "divu" | "madd" | "maddu" | "msub" | "msubu" | "teq" | "tne" | "tge" | "tgeu" | "tlt" | "tltu" => {
+                if matches!(mnemonic, "div" | "divu") {
+                    o.count(&[2, 3])?;
+                } else {
+                    o.count(&[2])?;
                 }
-                let (rs, rt) = parse_two_regs(&tokens)?;
-                Ok(match tokens[0] {
+                let (rs, rt) = (o.reg(0)?, o.reg(1)?);
+                ParsedInstr::I(match mnemonic {
                     "mult" => Instr::Mult{rs, rt},
+                    "multu" => Instr::Multu{rs, rt},
                     "div" => Instr::Div{rs, rt},
-                    "move" => Instr::Move{rs, rt},
+                    "divu" => Instr::Divu{rs, rt},
+                    "madd" => Instr::Madd{rs, rt},
+                    "maddu" => Instr::Maddu{rs, rt},
+                    "msub" => Instr::Msub{rs, rt},
+                    "msubu" => Instr::Msubu{rs, rt},
+                    "teq" => Instr::Teq{rs, rt},
+                    "tne" => Instr::Tne{rs, rt},
+                    "tge" => Instr::Tge{rs, rt},
+                    "tgeu" => Instr::Tgeu{rs, rt},
+                    "tlt" => Instr::Tlt{rs, rt},
+                    "tltu" => Instr::Tltu{rs, rt},
                     _=> unreachable!()
                 })
             }
-            "sll" | "srl" => {
-                if tokens.len() < 4{
-                    return Err("Cannot parse 2 register, 1 shamt shift instruction!".to_string());
-                }
-                let (rd, rs) = parse_two_regs(&tokens)?;
-                let shamt = parse_immediate(tokens[3]);
-                Ok(match tokens[0] {
+            "sll" | "srl" | "sra" | "rotr" => {
+                o.count(&[3])?;
+                let (rd, rs, shamt) = (o.reg(0)?, o.reg(1)?, o.constant(2)?);
+                real(match mnemonic {
                     "sll" => Instr::Sll{rd, rs, shamt},
                     "srl" => Instr::Srl{rd, rs, shamt},
+                    "sra" => Instr::Sra{rd, rs, shamt},
+                    "rotr" => Instr::Rotr{rd, rs, shamt},
+                    _=> unreachable!()
+                })?
+            }
+            "sllv" | "srlv" | "srav" | "rotrv" => {
+                o.count(&[3])?;
+                let (rd, rt, rs) = (o.reg(0)?, o.reg(1)?, o.reg(2)?);
+                ParsedInstr::I(match mnemonic {
+                    "sllv" => Instr::Sllv{rd, rt, rs},
+                    "srlv" => Instr::Srlv{rd, rt, rs},
+                    "srav" => Instr::Srav{rd, rt, rs},
+                    "rotrv" => Instr::Rotrv{rd, rt, rs},
                     _=> unreachable!()
                 })
             }
-            "lui" | "li" => {
-                if tokens.len() < 3{
-                    return Err("Cannot parse 1 register, 1 immediate instruction!".to_string());
-                }
-                let rt = parse_reg(tokens[1])?;
-                let immd = parse_immediate(tokens[2]);
-                Ok(match tokens[0] {
-                    "lui" => Instr::Lui{rt, immd},
-                    "li" => Instr::Li{rt, immd},
+            "teqi" | "tnei" | "tgei" | "tgeiu" | "tlti" | "tltiu" => {
+                o.count(&[2])?;
+                let (rs, immd) = (o.reg(0)?, o.imm(1)?);
+                real(match mnemonic {
+                    "teqi" => Instr::Teqi{rs, immd},
+                    "tnei" => Instr::Tnei{rs, immd},
+                    "tgei" => Instr::Tgei{rs, immd},
+                    "tgeiu" => Instr::Tgeiu{rs, immd},
+                    "tlti" => Instr::Tlti{rs, immd},
+                    "tltiu" => Instr::Tltiu{rs, immd},
+                    _=> unreachable!()
+                })?
+            }
+            "clz" | "clo" | "seb" | "seh" | "wsbh" => {
+                o.count(&[2])?;
+                let (rd, rs) = (o.reg(0)?, o.reg(1)?);
+                ParsedInstr::I(match mnemonic {
+                    "clz" => Instr::Clz{rd, rs},
+                    "clo" => Instr::Clo{rd, rs},
+                    "seb" => Instr::Seb{rd, rt: rs},
+                    "seh" => Instr::Seh{rd, rt: rs},
+                    "wsbh" => Instr::Wsbh{rd, rt: rs},
                     _=> unreachable!()
                 })
             }
-            "mfhi" | "mflo" | "jr" => {
-                if tokens.len() < 2{
-                    return Err("Cannot parse 1 register instruction!".to_string());
+            "ext" | "ins" => {
+                o.count(&[4])?;
+                let (rt, rs, pos, size) = (o.reg(0)?, o.reg(1)?, o.constant(2)?, o.constant(3)?);
+                if pos > 31 || size == 0 || pos + size > 32 {
+                    return Err(ParseError::new(format!("Invalid bit field position {} and size {}", pos, size), statement.span));
                 }
-                let rd = parse_reg(tokens[1])?;
-                Ok(match tokens[0] {
+                ParsedInstr::I(match mnemonic {
+                    "ext" => Instr::Ext{rt, rs, pos, size},
+                    "ins" => Instr::Ins{rt, rs, pos, size},
+                    _=> unreachable!()
+                })
+            }
+            // jalr $rs links through $ra, jalr $rd, $rs through $rd
+            "jalr" => match o.count(&[1, 2])? {
+                1 => ParsedInstr::I(Instr::Jalr{rd: 31, rs: o.reg(0)?}),
+                _ => ParsedInstr::I(Instr::Jalr{rd: o.reg(0)?, rs: o.reg(1)?}),
+            },
+            "lui" => {
+                o.count(&[2])?;
+                let (rt, immd) = (o.reg(0)?, o.imm(1)?);
+                real(Instr::Lui{rt, immd})?
+            }
+            "mfhi" | "mflo" | "mthi" | "mtlo" | "jr" => {
+                o.count(&[1])?;
+                let rd = o.reg(0)?;
+                ParsedInstr::I(match mnemonic {
                     "mfhi" => Instr::Mfhi{rd},
                     "mflo" => Instr::Mflo{rd},
+                    "mthi" => Instr::Mthi{rs: rd},
+                    "mtlo" => Instr::Mtlo{rs: rd},
                     "jr" => Instr::Jr{rd},
                     _=> unreachable!()
                 })
             }
-            _ => {return Err(format!("Could Not parse {}",line))}
-        }
-    }
-}
-#[derive(Debug)]
-pub enum ParsedInstr {
-    Label(String),
-    I(Instr),
-    Empty,
-    // Conditional Branch
-    Beq{rt: u32, rs: u32, label: String},
-    Bne{rt: u32, rs: u32, label: String},
-    Bgt{rt: u32, rs: u32, label: String},
-    Bge{rt: u32, rs: u32, label: String},
-    Blt{rt: u32, rs: u32, label: String},
-    Ble{rt: u32, rs: u32, label: String},
-    // Unconditional Jump
-    Jump{label: String},
-    Jal{label: String},
-    // Load address
-    La{rt: u32, label: String},
-}
-
-impl ParsedInstr {
-    pub fn from_str(line: &String) -> Result<ParsedInstr, String> {
-        let tokens: Vec<&str> = regex::Regex::new(r"([\s()$,]+|#.*)").unwrap()
-        .split(line).filter(|s| !s.is_empty()).collect();
-        let parse_two_regs = |tokens: &[&str]| -> Result<(u32, u32), String> {
-            Ok((parse_reg(tokens[1])?, parse_reg(tokens[2])?))
-        };
-        // Skip blank lines
-        if tokens.len() == 0{
-            return Ok(ParsedInstr::Empty);
-        }
-        match tokens[0]{
-            "beq" | "bne" | "bgt" | "bge" | "blt" | "ble" =>{
-                if tokens.len() < 4{
-                    return Err("Cannot parse branch instruction!".to_string());
-                }
-                let (rt, rs) = parse_two_regs(&tokens)?;
-                Ok(match tokens[0] {
-                    "beq" => ParsedInstr::Beq{rt, rs, label: tokens[3].to_string()},
-                    "bne" => ParsedInstr::Bne{rt, rs, label: tokens[3].to_string()},
-                    "bgt" => ParsedInstr::Bgt{rt, rs, label: tokens[3].to_string()},
-                    "bge" => ParsedInstr::Bge{rt, rs, label: tokens[3].to_string()},
-                    "blt" => ParsedInstr::Blt{rt, rs, label: tokens[3].to_string()},
-                    "ble" => ParsedInstr::Ble{rt, rs, label: tokens[3].to_string()},
+            // The coprocessor 0 register is written by number, e.g. mfc0 $k0, $13
+            "mfc0" | "mtc0" => {
+                o.count(&[2])?;
+                let rt = o.reg(0)?;
+                let rd = match statement.operands[1].0 {
+                    parser::Operand::Reg(rd) => rd,
+                    _ => o.constant(1)?,
+                };
+                ParsedInstr::I(match mnemonic {
+                    "mfc0" => Instr::Mfc0{rt, rd},
+                    "mtc0" => Instr::Mtc0{rt, rd},
                     _=> unreachable!()
                 })
             }
-            "j" | "jal" => {
-                Ok(match tokens[0] {
-                    "j" => ParsedInstr::Jump{label: tokens[1].to_string()},
-                    "jal" => ParsedInstr::Jal{label: tokens[1].to_string()},
+            "break" => {
+                let code = match o.count(&[0, 1])? {
+                    0 => 0,
+                    _ => o.constant(0)?,
+                };
+                ParsedInstr::I(Instr::Break{code})
+            }
+            "mtc1" | "mfc1" => {
+                o.count(&[2])?;
+                let (rt, fs) = (o.reg(0)?, o.freg(1, FpFmt::Single)?);
+                ParsedInstr::I(match mnemonic {
+                    "mtc1" => Instr::Mtc1{rt, fs},
+                    "mfc1" => Instr::Mfc1{rt, fs},
                     _=> unreachable!()
                 })
             }
-            "la" => {
-                if tokens.len() < 3{
-                    return Err("Cannot parse LA instruction!".to_string());
+            "syscall" | "nop" | "eret" => {
+                o.count(&[0])?;
+                ParsedInstr::I(match mnemonic {
+                    "syscall" => Instr::Syscall,
+                    "nop" => Instr::Nop,
+                    "eret" => Instr::Eret,
+                    _=> unreachable!()
+                })
+            }
+            "beq" | "bne" | "bgt" | "bge" | "blt" | "ble" | "bgtu" | "bgeu" | "bltu" | "bleu" => {
+                o.count(&[3])?;
+                let (rs, label) = (o.reg(0)?, o.label(2)?);
+                let rt = match o.reg_or_constant(1)? {
+                    // beq and bne against a register are real instructions
+                    Operand::Reg(rt) if mnemonic == "beq" => return Ok((ParsedInstr::Beq{rt: rs, rs: rt, label}, None)),
+                    Operand::Reg(rt) if mnemonic == "bne" => return Ok((ParsedInstr::Bne{rt: rs, rs: rt, label}, None)),
+                    rt => rt,
+                };
+                let cond = match mnemonic {
+                    "beq" => Cond::Eq,
+                    "bne" => Cond::Ne,
+                    "bgt" => Cond::Gt,
+                    "bge" => Cond::Ge,
+                    "blt" => Cond::Lt,
+                    "ble" => Cond::Le,
+                    "bgtu" => Cond::Gtu,
+                    "bgeu" => Cond::Geu,
+                    "bltu" => Cond::Ltu,
+                    "bleu" => Cond::Leu,
+                    _=> unreachable!()
+                };
+                ParsedInstr::Pseudo(Pseudo::Branch{cond, rs, rt, label})
+            }
+            "beqz" | "bnez" => {
+                o.count(&[2])?;
+                let (rs, label) = (o.reg(0)?, o.label(1)?);
+                match mnemonic {
+                    "beqz" => ParsedInstr::Beq{rt: rs, rs: 0, label},
+                    "bnez" => ParsedInstr::Bne{rt: rs, rs: 0, label},
+                    _=> unreachable!()
                 }
-                let rt = parse_reg(tokens[1])?;
-                Ok(match tokens[0] {
-                    "la" => ParsedInstr::La{rt, label: tokens[2].to_string()},
+            }
+            "b" | "bal" | "j" | "jal" => {
+                o.count(&[1])?;
+                let label = o.label(0)?;
+                match mnemonic {
+                    "b" => ParsedInstr::Beq{rt: 0, rs: 0, label},
+                    "bal" => ParsedInstr::Pseudo(Pseudo::Bal{label}),
+                    "j" => ParsedInstr::Jump{label},
+                    "jal" => ParsedInstr::Jal{label},
+                    _=> unreachable!()
+                }
+            }
+            "bgez" | "bgtz" | "blez" | "bltz" | "bgezal" | "bltzal" => {
+                o.count(&[2])?;
+                let (rs, label) = (o.reg(0)?, o.label(1)?);
+                match mnemonic {
+                    "bgez" => ParsedInstr::Bgez{rs, label},
+                    "bgtz" => ParsedInstr::Bgtz{rs, label},
+                    "blez" => ParsedInstr::Blez{rs, label},
+                    "bltz" => ParsedInstr::Bltz{rs, label},
+                    "bgezal" => ParsedInstr::Bgezal{rs, label},
+                    "bltzal" => ParsedInstr::Bltzal{rs, label},
+                    _=> unreachable!()
+                }
+            }
+            "bc1t" | "bc1f" => {
+                // The condition flag is optional and defaults to 0
+                let (cc, label) = match o.count(&[1, 2])? {
+                    1 => (0, o.label(0)?),
+                    _ => (o.condition_flag(0)?, o.label(1)?),
+                };
+                match mnemonic {
+                    "bc1t" => ParsedInstr::Bc1t{cc, label},
+                    "bc1f" => ParsedInstr::Bc1f{cc, label},
+                    _=> unreachable!()
+                }
+            }
+            "la" => {
+                o.count(&[2])?;
+                ParsedInstr::Pseudo(Pseudo::La{rt: o.reg(0)?, label: o.label(1)?})
+            }
+            // The length of li depends on its value, so it must be known in the first pass
+            "li" => {
+                o.count(&[2])?;
+                ParsedInstr::Pseudo(Pseudo::Li{rt: o.reg(0)?, immd: o.constant(1)?})
+            }
+            "move" | "not" | "neg" | "negu" | "abs" => {
+                o.count(&[2])?;
+                let (rd, rs) = (o.reg(0)?, o.reg(1)?);
+                ParsedInstr::Pseudo(match mnemonic {
+                    "move" => Pseudo::Move{rd, rs},
+                    "not" => Pseudo::Not{rd, rs},
+                    "neg" => Pseudo::Neg{rd, rs},
+                    "negu" => Pseudo::Negu{rd, rs},
+                    "abs" => Pseudo::Abs{rd, rs},
+                    _=> unreachable!()
+                })
+            }
+            "rem" | "remu" | "mulo" | "mulou" | "seq" | "sne" | "sgt" | "sgtu" | "sge" | "sgeu" | "sle" | "sleu" => {
+                o.count(&[3])?;
+                let (rd, rs, rt) = (o.reg(0)?, o.reg(1)?, o.reg(2)?);
+                let set = |cond| Pseudo::Set{cond, rd, rs, rt};
+                ParsedInstr::Pseudo(match mnemonic {
+                    "rem" => Pseudo::Rem{rd, rs, rt},
+                    "remu" => Pseudo::Remu{rd, rs, rt},
+                    "mulo" => Pseudo::Mulo{rd, rs, rt},
+                    "mulou" => Pseudo::Mulou{rd, rs, rt},
+                    "seq" => set(Cond::Eq),
+                    "sne" => set(Cond::Ne),
+                    "sgt" => set(Cond::Gt),
+                    "sgtu" => set(Cond::Gtu),
+                    "sge" => set(Cond::Ge),
+                    "sgeu" => set(Cond::Geu),
+                    "sle" => set(Cond::Le),
+                    "sleu" => set(Cond::Leu),
+                    _=> unreachable!()
+                })
+            }
+            op if op.contains('.') => ParsedInstr::I(ParsedInstr::fp_instr(&o)?),
+            _ => return Err(ParseError::new(format!("Unknown instruction {}", mnemonic), statement.mnemonic_span)),
+        };
+        Ok((parsed, o.deferred))
+    }
+    // Reads the floating point arithmetic, conversion and comparison instructions, whose
+    // mnemonics carry their formats, e.g. add.s, cvt.d.w, c.lt.d
+    fn fp_instr(o: &Operands) -> Result<Instr, ParseError> {
+        let mnemonic = o.statement.mnemonic.as_str();
+        let span = o.statement.mnemonic_span;
+        let parts: Vec<&str> = mnemonic.split('.').collect();
+        let format = |suffix: &str| FpFmt::from_suffix(suffix).map_err(|e| ParseError::new(e, span));
+        // Only conversions take words, there is no word arithmetic
+        let float_format = |suffix: &str| match format(suffix)? {
+            FpFmt::Word => Err(ParseError::new(format!("{} has no word format, use .s or .d", parts[0]), span)),
+            fmt => Ok(fmt),
+        };
+        match parts.as_slice() {
+            ["add" | "sub" | "mul" | "div", suffix] => {
+                let fmt = float_format(suffix)?;
+                o.count(&[3])?;
+                let (fd, fs, ft) = (o.freg(0, fmt)?, o.freg(1, fmt)?, o.freg(2, fmt)?);
+                Ok(match parts[0] {
+                    "add" => Instr::AddF{fmt, fd, fs, ft},
+                    "sub" => Instr::SubF{fmt, fd, fs, ft},
+                    "mul" => Instr::MulF{fmt, fd, fs, ft},
+                    "div" => Instr::DivF{fmt, fd, fs, ft},
+                    _=> unreachable!()
+                })
+            }
+            ["sqrt" | "abs" | "neg" | "mov", suffix] => {
+                let fmt = float_format(suffix)?;
+                o.count(&[2])?;
+                let (fd, fs) = (o.freg(0, fmt)?, o.freg(1, fmt)?);
+                Ok(match parts[0] {
+                    "sqrt" => Instr::SqrtF{fmt, fd, fs},
+                    "abs" => Instr::AbsF{fmt, fd, fs},
+                    "neg" => Instr::NegF{fmt, fd, fs},
+                    "mov" => Instr::MovF{fmt, fd, fs},
                     _=> unreachable!()
                 })
             }
-            // If not one of the clearly understood label-based instructions its a label or another command
-            _ => {
-                let label_regex = regex::Regex::new(r"^[A-Za-z0-9_]+:$").unwrap();
-                if label_regex.is_match(tokens[0]){
-                    let label = tokens[0].replace(":", "");
-                    return Ok(ParsedInstr::Label(label));
+            ["cvt", to, from] => {
+                let (to, from) = (format(to)?, format(from)?);
+                if to == from {
+                    return Err(ParseError::new(format!("Cannot convert between identical formats in {}", mnemonic), span));
                 }
-                let instr = Instr::from_str(line)?;
-                return Ok(ParsedInstr::I(instr));
+                o.count(&[2])?;
+                Ok(Instr::Cvt{to, from, fd: o.freg(0, to)?, fs: o.freg(1, from)?})
             }
+            ["c", cond, suffix] => {
+                let cond = match *cond {
+                    "eq" => FpCond::Eq,
+                    "lt" => FpCond::Lt,
+                    "le" => FpCond::Le,
+                    _ => return Err(ParseError::new(format!("Unknown floating point comparison {}", mnemonic), span)),
+                };
+                let fmt = format(suffix)?;
+                if fmt == FpFmt::Word {
+                    return Err(ParseError::new(format!("Cannot compare words with {}", mnemonic), span));
+                }
+                // The condition flag is optional and defaults to 0
+                let (cc, first) = match o.count(&[2, 3])? {
+                    2 => (0, 0),
+                    _ => (o.condition_flag(0)?, 1),
+                };
+                Ok(Instr::CmpF{cond, fmt, cc, fs: o.freg(first, fmt)?, ft: o.freg(first + 1, fmt)?})
+            }
+            _ => Err(ParseError::new(format!("Unknown instruction {}", mnemonic), span)),
+        }
+    }
+    // Wraps a real instruction, expanding it if its immediate is too wide to encode
+    fn from_instr(instr: Instr) -> Result<ParsedInstr, String> {
+        if instr.immediate_fits() {
+            Ok(ParsedInstr::I(instr))
+        } else if instr.memory_operand().is_some() {
+            Ok(ParsedInstr::Pseudo(Pseudo::WideOffset{instr}))
+        } else if instr.with_register_operand(AT).is_some() {
+            Ok(ParsedInstr::Pseudo(Pseudo::WideImmediate{instr}))
+        } else {
+            Err(format!("Immediate out of range in {:?}", instr))
         }
     }
 }
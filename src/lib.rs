@@ -0,0 +1,32 @@
+#![allow(clippy::needless_return, clippy::upper_case_acronyms, clippy::new_without_default, clippy::should_implement_trait, clippy::len_without_is_empty)]
+// MIPS32 assembler and emulator. The mipsemu binary drives it from the command line; embedders can
+// build a CPU with their own memory mapped devices, see device::Device and cpu::CPUBuilder.
+pub mod branch;
+pub mod asm;
+pub mod bitmap;
+pub mod cache;
+pub mod callcheck;
+pub mod callstack;
+pub mod cp0;
+pub mod cp1;
+pub mod cpu;
+pub mod device;
+pub mod diagnostics;
+pub mod encode;
+pub mod export;
+pub mod harness;
+pub mod expr;
+pub mod isa;
+pub mod limits;
+pub mod linux;
+pub mod macros;
+pub mod memcheck;
+pub mod memory;
+pub mod mmio;
+pub mod parser;
+pub mod profile;
+pub mod pseudo;
+pub mod stdio;
+pub mod syscall;
+pub mod uninit;
+pub mod vfs;
@@ -0,0 +1,210 @@
+// Limits on what a program may use, so runaway programs are stopped with a reason an autograder
+// can tell apart: retired instructions, wall-clock time, heap and stack size and bytes of output
+use crate::cpu::CPU;
+use crate::memory::{HEAP_BASE, SP_INIT};
+use crate::vfs::VfsError;
+use std::io;
+use std::time::{Duration, Instant};
+
+const SP: usize = 29;
+// Instructions between checks of the clock
+const CLOCK_INTERVAL: u64 = 1024;
+// Most bytes a read or write syscall copies at a time, so a huge length can't exhaust host memory
+pub(crate) const IO_CHUNK: u32 = 1 << 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Termination {
+    InstructionLimit,
+    Timeout,
+    HeapLimit,
+    StackLimit,
+    OutputLimit,
+}
+impl Termination {
+    pub fn message(&self) -> &'static str {
+        match self {
+            Termination::InstructionLimit => "Instruction limit reached",
+            Termination::Timeout => "Time limit reached",
+            Termination::HeapLimit => "Heap size limit reached",
+            Termination::StackLimit => "Stack size limit reached",
+            Termination::OutputLimit => "Output limit reached",
+        }
+    }
+    // Process exit code, 124 for a timeout as with timeout(1) and counting down from there
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Termination::Timeout => 124,
+            Termination::InstructionLimit => 123,
+            Termination::HeapLimit => 122,
+            Termination::StackLimit => 121,
+            Termination::OutputLimit => 120,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Limits {
+    pub max_instructions: Option<u64>,
+    pub timeout: Option<Duration>,
+    // Bytes the heap may grow by from its base, including any Linux memory mappings
+    pub max_heap: Option<u32>,
+    // Bytes $sp may move below its initial value
+    pub max_stack: Option<u32>,
+    // Bytes written to stdout and stderr by syscalls
+    pub max_output: Option<u64>,
+    // Instructions retired so far, including delay slots
+    pub(crate) instructions: u64,
+    output: u64,
+    started: Option<Instant>,
+    next_clock_check: u64,
+}
+impl Limits {
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+}
+
+impl CPU {
+    // Stops the program for exceeding a limit, step() returns None from then on
+    pub fn terminate(&mut self, reason: Termination) {
+        self.termination.get_or_insert(reason);
+    }
+    // Checks the limits before the next instruction: the instruction budget, the clock every so
+    // often, and how far the stack has grown
+    pub(crate) fn check_limits(&mut self) {
+        let limits = &mut self.limits;
+        if limits.max_instructions.is_some_and(|max| limits.instructions >= max) {
+            return self.terminate(Termination::InstructionLimit);
+        }
+        if let Some(timeout) = limits.timeout {
+            let started = *limits.started.get_or_insert_with(Instant::now);
+            if limits.instructions >= limits.next_clock_check {
+                limits.next_clock_check = limits.instructions + CLOCK_INTERVAL;
+                if started.elapsed() > timeout {
+                    return self.terminate(Termination::Timeout);
+                }
+            }
+        }
+        if limits.max_stack.is_some_and(|max| self.reg[SP] < SP_INIT.saturating_sub(max)) {
+            self.terminate(Termination::StackLimit);
+        }
+    }
+    // Whether the heap may grow to the given size in bytes, stopping the program if not
+    pub(crate) fn heap_allowed(&mut self, size: u32) -> bool {
+        if self.limits.max_heap.is_some_and(|max| size > max) {
+            self.terminate(Termination::HeapLimit);
+            return false;
+        }
+        true
+    }
+    // Bytes between the heap base and the program break
+    pub(crate) fn heap_size(&self) -> u32 {
+        self.heap_end.saturating_sub(HEAP_BASE)
+    }
+    // Writes program output to stdout (fd 1) or stderr (fd 2). Output beyond the limit is dropped
+    // and the program stopped.
+    pub fn write_output(&mut self, fd: u32, bytes: &[u8]) -> io::Result<()> {
+        let allowed = match self.limits.max_output {
+            Some(max) => (max.saturating_sub(self.limits.output) as usize).min(bytes.len()),
+            None => bytes.len(),
+        };
+        self.limits.output += allowed as u64;
+        if allowed < bytes.len() {
+            self.terminate(Termination::OutputLimit);
+        }
+        self.stdio.write(fd, &bytes[..allowed])
+    }
+    // Writes len bytes of memory at addr to a descriptor a chunk at a time, stopping early once the
+    // program is stopped for too much output. Returns the bytes written.
+    pub(crate) fn write_from_memory(&mut self, fd: u32, addr: u32, len: u32) -> Result<u32, VfsError> {
+        let mut written = 0;
+        loop {
+            let chunk = (len - written).min(IO_CHUNK);
+            let bytes: Vec<u8> = (0..chunk).map(|i| self.mem.load_byte(addr.wrapping_add(written + i))).collect();
+            let result = match fd {
+                1 | 2 => self.write_output(fd, &bytes).map_err(|e| VfsError::from_io(&e)),
+                _ => self.vfs.write(fd, &bytes).map(|_| ()),
+            };
+            match result {
+                Err(e) if written == 0 => return Err(e),
+                Err(_) => return Ok(written),
+                Ok(()) => written += chunk,
+            }
+            if written == len || self.termination.is_some() {
+                return Ok(written);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::isa::IsaLevel;
+    use crate::stdio::Stdio;
+    use std::path::Path;
+
+    fn run(source: &str, limits: Limits) -> CPU {
+        let program = assemble(source, Path::new("test.s"), IsaLevel::Mips32r2).expect("assembled");
+        let mut cpu = CPU::builder().limits(limits).stdio(Stdio::captured(b"")).build().unwrap();
+        cpu.load_program(&program);
+        while cpu.step(&program).unwrap().is_some() {}
+        cpu
+    }
+
+    fn exit_code(source: &str, limits: Limits) -> Option<i32> {
+        run(source, limits).termination.map(|reason| reason.exit_code())
+    }
+
+    const LOOP: &str = "loop: j loop";
+
+    #[test]
+    fn instruction_limit() {
+        let limits = Limits { max_instructions: Some(100), ..Limits::default() };
+        let cpu = run(LOOP, limits);
+        assert_eq!(cpu.termination, Some(Termination::InstructionLimit));
+        assert_eq!(cpu.limits.instructions(), 100);
+        assert_eq!(Termination::InstructionLimit.exit_code(), 123);
+    }
+
+    #[test]
+    fn timeout() {
+        let limits = Limits { timeout: Some(Duration::from_millis(1)), ..Limits::default() };
+        assert_eq!(exit_code(LOOP, limits), Some(124));
+    }
+
+    #[test]
+    fn heap_limit() {
+        let source = "li $a0, 4096\nli $v0, 9\nsyscall\nli $v0, 9\nsyscall\nli $v0, 10\nsyscall";
+        let limits = Limits { max_heap: Some(4096), ..Limits::default() };
+        assert_eq!(exit_code(source, limits), Some(122));
+        let limits = Limits { max_heap: Some(8192), ..Limits::default() };
+        assert_eq!(exit_code(source, limits), None);
+    }
+
+    #[test]
+    fn stack_limit() {
+        let limits = Limits { max_stack: Some(64), ..Limits::default() };
+        assert_eq!(exit_code("addiu $sp, $sp, -64\naddiu $sp, $sp, -4\nnop\nli $v0, 10\nsyscall", limits), Some(121));
+    }
+
+    #[test]
+    fn output_limit() {
+        let source = ".data\ns: .asciiz \"hello\"\n.text\nla $a0, s\nli $v0, 4\nsyscall\nli $v0, 10\nsyscall";
+        let limits = Limits { max_output: Some(3), ..Limits::default() };
+        let cpu = run(source, limits);
+        assert_eq!(cpu.stdio.stdout(), b"hel");
+        assert_eq!(cpu.termination.map(|reason| reason.exit_code()), Some(120));
+    }
+
+    #[test]
+    fn huge_write_is_copied_in_chunks() {
+        // A write of nearly 4 GB stops at the output limit without copying the rest
+        let source = "li $a0, 1\nli $a1, 0x10010000\nli $a2, 0xfffffff0\nli $v0, 15\nsyscall\nli $v0, 10\nsyscall";
+        let limits = Limits { max_output: Some(10), ..Limits::default() };
+        let cpu = run(source, limits);
+        assert_eq!(cpu.stdio.stdout().len(), 10);
+        assert_eq!(cpu.termination, Some(Termination::OutputLimit));
+    }
+}
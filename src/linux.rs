@@ -0,0 +1,443 @@
+// Linux user mode emulation with the o32 ABI, as qemu-mips does it: the program starts with
+// argv, envp and the auxiliary vector on the stack, and syscalls take their number from $v0
+// (4000 and up), arguments from $a0-$a3 then the stack, and return a value in $v0 with $a3 set
+// when it is an errno. Programs are assembled from source as with MARS's syscalls; there is no
+// ELF loader, so compiled binaries can't be run and the auxiliary vector has no program headers.
+use crate::cpu::CPU;
+use crate::limits::IO_CHUNK;
+use crate::memory::{HEAP_BASE, SP_INIT, TEXT_BASE};
+use crate::vfs::{OpenFlags, VfsError};
+use std::collections::HashSet;
+use std::ops::Range;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+const V0: u32 = 2;
+const A0: u32 = 4;
+const A3: u32 = 7;
+const SP: u32 = 29;
+
+const SYS_EXIT: u32 = 4001;
+const SYS_READ: u32 = 4003;
+const SYS_WRITE: u32 = 4004;
+const SYS_OPEN: u32 = 4005;
+const SYS_CLOSE: u32 = 4006;
+const SYS_BRK: u32 = 4045;
+const SYS_IOCTL: u32 = 4054;
+const SYS_MMAP: u32 = 4090;
+const SYS_MUNMAP: u32 = 4091;
+const SYS_FSTAT: u32 = 4108;
+const SYS_UNAME: u32 = 4122;
+const SYS_MMAP2: u32 = 4210;
+const SYS_FSTAT64: u32 = 4215;
+const SYS_EXIT_GROUP: u32 = 4246;
+const SYS_CLOCK_GETTIME: u32 = 4263;
+const SYS_SET_THREAD_AREA: u32 = 4283;
+
+// errno values, which differ from other architectures on MIPS
+const ENOENT: u32 = 2;
+const EIO: u32 = 5;
+const EBADF: u32 = 9;
+const ENOMEM: u32 = 12;
+const EACCES: u32 = 13;
+const EEXIST: u32 = 17;
+const EISDIR: u32 = 21;
+const EINVAL: u32 = 22;
+const EMFILE: u32 = 24;
+const ENOTTY: u32 = 25;
+const ENOSPC: u32 = 28;
+const ENOSYS: u32 = 89;
+
+// open flags
+const O_ACCMODE: u32 = 0x3;
+const O_WRONLY: u32 = 0x1;
+const O_RDWR: u32 = 0x2;
+const O_APPEND: u32 = 0x8;
+const O_CREAT: u32 = 0x100;
+const O_TRUNC: u32 = 0x200;
+const O_EXCL: u32 = 0x400;
+
+const MAP_ANONYMOUS: u32 = 0x800;
+const PAGE_SIZE: u32 = 4096;
+// Mappings are handed out downwards from here, well below the stack
+const MMAP_TOP: u32 = 0x77000000;
+
+// Auxiliary vector entry types
+const AT_NULL: u32 = 0;
+const AT_PAGESZ: u32 = 6;
+const AT_ENTRY: u32 = 9;
+const AT_UID: u32 = 11;
+const AT_EUID: u32 = 12;
+const AT_GID: u32 = 13;
+const AT_EGID: u32 = 14;
+const AT_SECURE: u32 = 23;
+const AT_RANDOM: u32 = 25;
+const AT_EXECFN: u32 = 31;
+
+// Files are opened through the CPU's virtual filesystem
+pub struct Linux {
+    // Lowest address of the mappings made so far
+    mmap_bottom: u32,
+    // Set by set_thread_area, where the C library keeps thread local storage
+    pub thread_pointer: u32,
+    start: Instant,
+    // Unsupported syscalls already warned about
+    warned: HashSet<u32>,
+}
+impl Linux {
+    pub fn new() -> Linux {
+        Linux { mmap_bottom: MMAP_TOP, thread_pointer: 0, start: Instant::now(), warned: HashSet::new() }
+    }
+    // Addresses of the memory mapped so far
+    pub fn mapped(&self) -> Range<u32> {
+        self.mmap_bottom..MMAP_TOP
+    }
+}
+
+// What a syscall returns: a value, or an errno
+type SysResult = Result<u32, u32>;
+
+fn errno(e: VfsError) -> u32 {
+    match e {
+        VfsError::NotFound => ENOENT,
+        VfsError::PermissionDenied => EACCES,
+        VfsError::AlreadyExists => EEXIST,
+        VfsError::IsADirectory => EISDIR,
+        VfsError::BadDescriptor => EBADF,
+        VfsError::TooManyFiles => EMFILE,
+        VfsError::QuotaExceeded => ENOSPC,
+        VfsError::Io => EIO,
+    }
+}
+
+impl CPU {
+    fn write_bytes(&mut self, addr: u32, bytes: &[u8]) {
+        for (i, byte) in bytes.iter().enumerate() {
+            self.mem.store_byte(addr.wrapping_add(i as u32), *byte);
+        }
+    }
+    fn read_c_string(&self, mut addr: u32) -> String {
+        let mut bytes = vec![];
+        loop {
+            let byte = self.mem.load_byte(addr);
+            if byte == 0 {
+                return String::from_utf8_lossy(&bytes).into_owned();
+            }
+            bytes.push(byte);
+            addr = addr.wrapping_add(1);
+        }
+    }
+    // Lays out the initial stack the way the kernel does for a new process: argc, the argv and
+    // envp pointer arrays and the auxiliary vector, with the strings they point to above them
+    pub fn setup_linux_stack(&mut self, args: &[String], env: &[String]) {
+        let mut top = SP_INIT;
+        let mut push_bytes = |cpu: &mut CPU, bytes: &[u8]| {
+            top -= bytes.len() as u32;
+            cpu.write_bytes(top, bytes);
+            top
+        };
+        let random = push_bytes(self, &[0x5a; 16]);
+        let mut strings = |cpu: &mut CPU, list: &[String]| -> Vec<u32> {
+            list.iter().map(|s| push_bytes(cpu, &[s.as_bytes(), &[0]].concat())).collect()
+        };
+        let env_ptrs = strings(self, env);
+        let arg_ptrs = strings(self, args);
+        let execfn = arg_ptrs.first().copied().unwrap_or(0);
+        let auxv = [
+            (AT_PAGESZ, PAGE_SIZE), (AT_ENTRY, TEXT_BASE), (AT_UID, 0), (AT_EUID, 0), (AT_GID, 0),
+            (AT_EGID, 0), (AT_SECURE, 0), (AT_RANDOM, random), (AT_EXECFN, execfn), (AT_NULL, 0),
+        ];
+        let mut words = vec![args.len() as u32];
+        words.extend(&arg_ptrs);
+        words.push(0);
+        words.extend(&env_ptrs);
+        words.push(0);
+        words.extend(auxv.iter().flat_map(|(key, value)| [*key, *value]));
+        let sp = (top - 4 * words.len() as u32) & !0xf;
+        for (i, word) in words.iter().enumerate() {
+            self.mem.store_word(sp + 4 * i as u32, *word).unwrap();
+        }
+        self.reg[SP as usize] = sp;
+    }
+    // Services a syscall using the Linux o32 numbering
+    pub fn linux_syscall(&mut self) -> Result<(), String> {
+        let number = self.get_reg(V0)?;
+        let mut arg = [0; 6];
+        for (i, value) in arg.iter_mut().enumerate() {
+            // Arguments after the fourth are passed on the stack, above the space reserved for $a0-$a3
+            *value = if i < 4 { self.get_reg(A0 + i as u32)? } else { self.mem.load_word(self.get_reg(SP)?.wrapping_add(4 * i as u32))? };
+        }
+        let result = match number {
+            SYS_EXIT | SYS_EXIT_GROUP => {
+                self.exit_code = Some(arg[0] as i32);
+                return Ok(());
+            }
+            SYS_READ => self.sys_read(arg[0], arg[1], arg[2]),
+            SYS_WRITE => self.sys_write(arg[0], arg[1], arg[2]),
+            SYS_OPEN => self.sys_open(arg[0], arg[1], arg[2]),
+            SYS_CLOSE => self.sys_close(arg[0]),
+            SYS_BRK => {
+                // Fails by returning the old break, never with an errno
+                let mapped = MMAP_TOP - self.linux_state().mmap_bottom;
+                if arg[0] >= HEAP_BASE && arg[0] < self.linux_state().mmap_bottom && self.heap_allowed((arg[0] - HEAP_BASE).saturating_add(mapped)) {
+                    self.heap_end = arg[0];
+                }
+                Ok(self.heap_end)
+            }
+            // Terminal control isn't emulated, so no descriptor is a terminal
+            SYS_IOCTL => Err(if arg[0] <= 2 || self.vfs.is_open(arg[0]) { ENOTTY } else { EBADF }),
+            SYS_MMAP => self.sys_mmap(arg[1], arg[3], arg[4], arg[5]),
+            SYS_MMAP2 => self.sys_mmap(arg[1], arg[3], arg[4], arg[5].wrapping_mul(PAGE_SIZE)),
+            // Unmapped memory is not reused, so there is nothing to do
+            SYS_MUNMAP => Ok(0),
+            SYS_FSTAT => self.sys_fstat(arg[0], arg[1], false),
+            SYS_FSTAT64 => self.sys_fstat(arg[0], arg[1], true),
+            SYS_UNAME => {
+                // Six fields of 65 bytes each
+                for (i, field) in ["Linux", "mipsemu", "5.10.0", "#1", "mips", "(none)"].iter().enumerate() {
+                    let mut bytes = field.as_bytes().to_vec();
+                    bytes.resize(65, 0);
+                    self.write_bytes(arg[0].wrapping_add(65 * i as u32), &bytes);
+                }
+                Ok(0)
+            }
+            SYS_SET_THREAD_AREA => {
+                self.linux_state().thread_pointer = arg[0];
+                Ok(0)
+            }
+            SYS_CLOCK_GETTIME => {
+                let time = match arg[0] {
+                    // CLOCK_REALTIME
+                    0 => SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default(),
+                    // CLOCK_MONOTONIC and the process and thread CPU time clocks
+                    1..=3 => self.linux_state().start.elapsed(),
+                    _ => return self.syscall_return(Err(EINVAL)),
+                };
+                self.mem.store_word(arg[1], time.as_secs() as u32)?;
+                self.mem.store_word(arg[1].wrapping_add(4), time.subsec_nanos())?;
+                Ok(0)
+            }
+            _ => {
+                if self.linux_state().warned.insert(number) {
+                    eprintln!("Warning: unsupported Linux syscall {} at 0x{:08x}", number, self.pc);
+                }
+                Err(ENOSYS)
+            }
+        };
+        self.syscall_return(result)
+    }
+    fn syscall_return(&mut self, result: SysResult) -> Result<(), String> {
+        let (value, error) = match result {
+            Ok(value) => (value, 0),
+            Err(errno) => (errno, 1),
+        };
+        self.set_reg(V0, value)?;
+        self.set_reg(A3, error)
+    }
+    fn linux_state(&mut self) -> &mut Linux {
+        self.linux.as_mut().expect("Linux syscall without Linux emulation")
+    }
+    fn sys_read(&mut self, fd: u32, buf: u32, count: u32) -> SysResult {
+        // Reads may be short, so huge counts are read a chunk at a time
+        let mut bytes = vec![0; count.min(IO_CHUNK) as usize];
+        let read = match fd {
+            0 => self.stdio.read(&mut bytes).map_err(|_| EIO)?,
+            _ => self.vfs.read(fd, &mut bytes).map_err(errno)?,
+        };
+        self.write_bytes(buf, &bytes[..read]);
+        Ok(read as u32)
+    }
+    fn sys_write(&mut self, fd: u32, buf: u32, count: u32) -> SysResult {
+        self.write_from_memory(fd, buf, count).map_err(errno)
+    }
+    fn sys_open(&mut self, path: u32, flags: u32, _mode: u32) -> SysResult {
+        let path = self.read_c_string(path);
+        let access = flags & O_ACCMODE;
+        let flags = OpenFlags {
+            read: access != O_WRONLY,
+            write: access == O_WRONLY || access == O_RDWR,
+            append: flags & O_APPEND != 0,
+            create: flags & O_CREAT != 0,
+            truncate: flags & O_TRUNC != 0,
+            exclusive: flags & O_EXCL != 0,
+        };
+        self.vfs.open(&path, flags).map_err(errno)
+    }
+    fn sys_close(&mut self, fd: u32) -> SysResult {
+        match fd {
+            0..=2 => Ok(0),
+            _ => self.vfs.close(fd).map(|_| 0).map_err(errno),
+        }
+    }
+    // Anonymous mappings are zero filled, file mappings are private copies of the file
+    fn sys_mmap(&mut self, len: u32, flags: u32, fd: u32, offset: u32) -> SysResult {
+        if len == 0 {
+            return Err(EINVAL);
+        }
+        let size = len.checked_next_multiple_of(PAGE_SIZE).ok_or(ENOMEM)?;
+        let heap_end = self.heap_end;
+        let bottom = self.linux_state().mmap_bottom;
+        let addr = bottom.checked_sub(size).filter(|addr| *addr >= heap_end).ok_or(ENOMEM)?;
+        if !self.heap_allowed(self.heap_size().saturating_add(MMAP_TOP - addr)) {
+            return Err(ENOMEM);
+        }
+        let contents = if flags & MAP_ANONYMOUS != 0 {
+            vec![]
+        } else {
+            self.vfs.read_at(fd, offset as u64, len).map_err(errno)?
+        };
+        self.linux_state().mmap_bottom = addr;
+        let mut bytes = vec![0; size as usize];
+        bytes[..contents.len()].copy_from_slice(&contents);
+        self.write_bytes(addr, &bytes);
+        Ok(addr)
+    }
+    // Fills in the o32 struct stat, or struct stat64, which differ in field sizes and offsets
+    fn sys_fstat(&mut self, fd: u32, buf: u32, stat64: bool) -> SysResult {
+        // Standard streams look like a terminal's character device, files like regular files
+        let (mode, size, modified) = match fd {
+            0..=2 => (0o020620, 0, 0),
+            _ => {
+                let metadata = self.vfs.metadata(fd).map_err(errno)?;
+                (if metadata.is_dir { 0o040755 } else { 0o100644 }, metadata.size, metadata.modified)
+            }
+        };
+        let blocks = size.div_ceil(512);
+        let mut stat = vec![0u8; if stat64 { 104 } else { 144 }];
+        let mut put = |offset: usize, value: u32| stat[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        if stat64 {
+            put(16, fd + 1);
+            put(24, mode);
+            put(28, 1);
+            put(56, size as u32);
+            put(60, (size >> 32) as u32);
+            for offset in [64, 72, 80] {
+                put(offset, modified);
+            }
+            put(88, PAGE_SIZE);
+            put(96, blocks as u32);
+            put(100, (blocks >> 32) as u32);
+        } else {
+            put(16, fd + 1);
+            put(20, mode);
+            put(24, 1);
+            put(48, size as u32);
+            for offset in [56, 64, 72] {
+                put(offset, modified);
+            }
+            put(80, PAGE_SIZE);
+            put(84, blocks as u32);
+        }
+        self.write_bytes(buf, &stat);
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::limits::{Limits, Termination};
+    use crate::stdio::Stdio;
+    use crate::vfs::Vfs;
+
+    fn linux(args: &[&str], env: &[&str], limits: Limits) -> CPU {
+        let strings = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        CPU::builder().limits(limits).stdio(Stdio::captured(b"")).vfs(Vfs::memory())
+            .linux(&strings(args), &strings(env)).build().unwrap()
+    }
+
+    fn syscall(cpu: &mut CPU, number: u32, args: &[u32]) -> SysResult {
+        cpu.set_reg(V0, number).unwrap();
+        for (i, value) in args.iter().enumerate() {
+            cpu.set_reg(A0 + i as u32, *value).unwrap();
+        }
+        cpu.linux_syscall().unwrap();
+        let value = cpu.get_reg(V0).unwrap();
+        if cpu.get_reg(A3).unwrap() == 0 { Ok(value) } else { Err(value) }
+    }
+
+    fn word(cpu: &CPU, addr: u32) -> u32 {
+        cpu.mem.load_word(addr).unwrap()
+    }
+
+    #[test]
+    fn initial_stack() {
+        let cpu = linux(&["prog", "-v"], &["HOME=/"], Limits::default());
+        let sp = cpu.get_reg(SP).unwrap();
+        assert_eq!(sp % 16, 0);
+        assert_eq!(word(&cpu, sp), 2);
+        assert_eq!(cpu.read_c_string(word(&cpu, sp + 4)), "prog");
+        assert_eq!(cpu.read_c_string(word(&cpu, sp + 8)), "-v");
+        assert_eq!(word(&cpu, sp + 12), 0);
+        assert_eq!(cpu.read_c_string(word(&cpu, sp + 16)), "HOME=/");
+        assert_eq!(word(&cpu, sp + 20), 0);
+        let auxv: Vec<(u32, u32)> = (0..).map(|i| (word(&cpu, sp + 24 + 8 * i), word(&cpu, sp + 28 + 8 * i)))
+            .take_while(|(key, _)| *key != AT_NULL).collect();
+        let aux = |key| auxv.iter().find(|(k, _)| *k == key).map(|(_, value)| *value);
+        assert_eq!(aux(AT_PAGESZ), Some(PAGE_SIZE));
+        assert_eq!(aux(AT_ENTRY), Some(TEXT_BASE));
+        assert_eq!(aux(AT_EXECFN), Some(word(&cpu, sp + 4)));
+        // The random bytes and strings sit above the pointers, below the initial stack pointer
+        let random = aux(AT_RANDOM).unwrap();
+        assert!(random > sp + 24 + 8 * auxv.len() as u32 && random + 16 <= SP_INIT);
+    }
+
+    #[test]
+    fn brk_bounds() {
+        let mut limits = Limits::default();
+        limits.max_heap = Some(2 * PAGE_SIZE);
+        let mut cpu = linux(&["prog"], &[], limits);
+        assert_eq!(syscall(&mut cpu, SYS_BRK, &[0]), Ok(HEAP_BASE));
+        assert_eq!(syscall(&mut cpu, SYS_BRK, &[HEAP_BASE + PAGE_SIZE]), Ok(HEAP_BASE + PAGE_SIZE));
+        // Below the heap or into the mappings, the break stays where it was
+        assert_eq!(syscall(&mut cpu, SYS_BRK, &[HEAP_BASE - 4]), Ok(HEAP_BASE + PAGE_SIZE));
+        assert_eq!(syscall(&mut cpu, SYS_BRK, &[MMAP_TOP]), Ok(HEAP_BASE + PAGE_SIZE));
+        assert!(cpu.termination.is_none());
+        assert_eq!(syscall(&mut cpu, SYS_BRK, &[HEAP_BASE + 3 * PAGE_SIZE]), Ok(HEAP_BASE + PAGE_SIZE));
+        assert_eq!(cpu.termination, Some(Termination::HeapLimit));
+    }
+
+    #[test]
+    fn mmap_bounds() {
+        let mut cpu = linux(&["prog"], &[], Limits::default());
+        let anonymous = |len| [0, len, 3, MAP_ANONYMOUS, -1i32 as u32, 0];
+        // Arguments after the fourth are read from the stack
+        let mmap = |cpu: &mut CPU, args: [u32; 6]| {
+            let sp = cpu.get_reg(SP).unwrap();
+            cpu.mem.store_word(sp + 16, args[4]).unwrap();
+            cpu.mem.store_word(sp + 20, args[5]).unwrap();
+            syscall(cpu, SYS_MMAP2, &args[..4])
+        };
+        assert_eq!(mmap(&mut cpu, anonymous(100)), Ok(MMAP_TOP - PAGE_SIZE));
+        assert_eq!(mmap(&mut cpu, anonymous(PAGE_SIZE + 1)), Ok(MMAP_TOP - 3 * PAGE_SIZE));
+        assert_eq!(cpu.linux.as_ref().unwrap().mapped(), MMAP_TOP - 3 * PAGE_SIZE..MMAP_TOP);
+        assert_eq!(mmap(&mut cpu, anonymous(0)), Err(EINVAL));
+        assert_eq!(mmap(&mut cpu, anonymous(u32::MAX)), Err(ENOMEM));
+        // Mappings may not reach down into the heap
+        assert_eq!(mmap(&mut cpu, anonymous(MMAP_TOP - 3 * PAGE_SIZE - HEAP_BASE + 1)), Err(ENOMEM));
+        assert_eq!(syscall(&mut cpu, SYS_BRK, &[MMAP_TOP - 3 * PAGE_SIZE + 4]), Ok(HEAP_BASE));
+    }
+
+    #[test]
+    fn fstat_layouts() {
+        let mut cpu = linux(&["prog"], &[], Limits::default());
+        let flags = OpenFlags { read: true, write: true, append: false, create: true, truncate: false, exclusive: false };
+        let fd = cpu.vfs.open("/f", flags).unwrap();
+        cpu.vfs.write(fd, &[7; 1000]).unwrap();
+        let buf = HEAP_BASE;
+        assert_eq!(syscall(&mut cpu, SYS_FSTAT, &[fd, buf]), Ok(0));
+        assert_eq!(word(&cpu, buf + 20), 0o100644);
+        assert_eq!(word(&cpu, buf + 24), 1);
+        assert_eq!(word(&cpu, buf + 48), 1000);
+        assert_eq!(word(&cpu, buf + 80), PAGE_SIZE);
+        assert_eq!(word(&cpu, buf + 84), 2);
+        assert_eq!(syscall(&mut cpu, SYS_FSTAT64, &[fd, buf]), Ok(0));
+        assert_eq!(word(&cpu, buf + 24), 0o100644);
+        assert_eq!(word(&cpu, buf + 28), 1);
+        assert_eq!((word(&cpu, buf + 56), word(&cpu, buf + 60)), (1000, 0));
+        assert_eq!(word(&cpu, buf + 88), PAGE_SIZE);
+        assert_eq!((word(&cpu, buf + 96), word(&cpu, buf + 100)), (2, 0));
+        assert_eq!(syscall(&mut cpu, SYS_FSTAT64, &[1, buf]), Ok(0));
+        assert_eq!(word(&cpu, buf + 24), 0o020620);
+        assert_eq!(syscall(&mut cpu, SYS_FSTAT, &[42, buf]), Err(EBADF));
+    }
+}
@@ -0,0 +1,249 @@
+use crate::parser::rename_labels;
+use regex::Regex;
+use std::collections::HashMap;
+use std::fmt;
+
+// Deepest nesting of macro calls allowed, which catches macros that call themselves
+const MAX_DEPTH: usize = 64;
+
+// A line in the program source. Lines of the file being assembled have no file name, lines
+// pulled in by .include carry the name they were included by.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Position {
+    pub file: Option<String>,
+    pub line: usize,
+}
+impl Position {
+    // The line number, prefixed with the file for included lines, e.g. 12 or lib.s:12
+    pub fn short(&self) -> String {
+        match &self.file {
+            Some(file) => format!("{}:{}", file, self.line),
+            None => self.line.to_string(),
+        }
+    }
+}
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{} line {}", file, self.line),
+            None => write!(f, "line {}", self.line),
+        }
+    }
+}
+
+// A source line after macro expansion, along with where it came from for error messages
+#[derive(Clone)]
+pub struct SourceLine {
+    pub text: String,
+    // For expanded lines this is the line in the macro body
+    pub pos: Position,
+    // Macro calls this line was expanded through, outermost first: (macro name, call site)
+    pub expansions: Vec<(String, Position)>,
+}
+impl SourceLine {
+    // Describes the line for error messages, e.g. "line 4, in macro print called from line 20"
+    pub fn location(&self) -> String {
+        self.describe(self.pos.to_string())
+    }
+    // The same, pointing at the character at a byte offset into the text, e.g. "line 4, column 9"
+    pub fn location_at(&self, offset: usize) -> String {
+        let column = self.text[..offset.min(self.text.len())].chars().count() + 1;
+        self.describe(format!("{}, column {}", self.pos, column))
+    }
+    fn describe(&self, mut location: String) -> String {
+        for (name, call) in self.expansions.iter().rev() {
+            location += &format!(", in macro {} called from {}", name, call);
+        }
+        location
+    }
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<SourceLine>,
+    // Labels defined in the body, renamed on each expansion so they are local to it
+    labels: Vec<String>,
+    pos: Position,
+}
+
+// Splits arguments on commas outside of parentheses and quotes, e.g. 4($sp), ','
+pub fn split_args(args: &str) -> Vec<String> {
+    let mut parts = vec![];
+    let mut depth = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    let mut current = String::new();
+    for c in args.chars() {
+        match (quote, c) {
+            (Some(_), '\\') if !escaped => {
+                escaped = true;
+                current.push(c);
+                continue;
+            }
+            (Some(q), _) if c == q && !escaped => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') => depth -= 1,
+            (None, ',') if depth == 0 => {
+                parts.push(current.trim().to_string());
+                current.clear();
+                continue;
+            }
+            _ => {}
+        }
+        escaped = false;
+        current.push(c);
+    }
+    if !current.trim().is_empty() || !parts.is_empty() {
+        parts.push(current.trim().to_string());
+    }
+    parts
+}
+
+struct Expander {
+    macros: HashMap<(String, usize), Macro>,
+    label_regex: Regex,
+    param_regex: Regex,
+    // Number of expansions so far, used to make local labels unique
+    expansions: usize,
+    out: Vec<SourceLine>,
+}
+impl Expander {
+    // Parses a line as a call to a defined macro, returning its name and arguments
+    fn parse_call(&self, text: &str) -> Option<(String, Vec<String>)> {
+        let text = text.trim();
+        let end = text.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(text.len());
+        let (name, rest) = text.split_at(end);
+        if name.is_empty() || !self.macros.keys().any(|(n, _)| n == name) {
+            return None;
+        }
+        let rest = rest.trim();
+        let args = match rest.strip_prefix('(').and_then(|r| r.strip_suffix(')')) {
+            Some(inner) => split_args(inner),
+            None => split_args(rest),
+        };
+        Some((name.to_string(), args))
+    }
+    // Emits a line, expanding it first if it calls a macro
+    fn line(&mut self, source: SourceLine) -> Result<(), String> {
+        let location = source.location();
+        let SourceLine { text, pos, expansions } = source;
+        // A label in front of a call stays on its own line, ahead of the expansion
+        let (label, rest) = match self.label_regex.captures(&text) {
+            Some(caps) => (Some(caps.get(1).unwrap().as_str().to_string()), caps.get(2).unwrap().as_str().to_string()),
+            None => (None, text.clone()),
+        };
+        let Some((name, args)) = self.parse_call(&rest) else {
+            self.out.push(SourceLine { text, pos, expansions });
+            return Ok(());
+        };
+        if let Some(label) = label {
+            self.out.push(SourceLine { text: format!("{}:", label), pos: pos.clone(), expansions: expansions.clone() });
+        }
+        if expansions.len() >= MAX_DEPTH {
+            // The full chain of calls would be unreadable, so point at the outermost one
+            return Err(format!("{}: Macro {} nested more than {} deep, does it call itself?", expansions[0].1, name, MAX_DEPTH));
+        }
+        let Some(mac) = self.macros.get(&(name.clone(), args.len())) else {
+            let mut arities: Vec<String> = self.macros.iter().filter(|((n, _), _)| *n == name)
+                .map(|((_, arity), m)| format!("{} ({})", arity, m.pos)).collect();
+            arities.sort();
+            return Err(format!("{}: Macro {} does not take {} arguments, it is defined with {}",
+                location, name, args.len(), arities.join(", ")));
+        };
+        self.expansions += 1;
+        let suffix = format!("_M{}", self.expansions);
+        let substitutions: HashMap<&str, &str> = mac.params.iter().map(|p| p.as_str()).zip(args.iter().map(|a| a.as_str())).collect();
+        let renamed: HashMap<String, String> = mac.labels.iter().map(|l| (l.clone(), format!("{}{}", l, suffix))).collect();
+        let mut body = vec![];
+        for body_line in &mac.body {
+            // Unknown % names are left alone, they may be operators such as %hi
+            let text = self.param_regex.replace_all(&body_line.text, |caps: &regex::Captures| {
+                substitutions.get(&caps[0]).map(|s| s.to_string()).unwrap_or(caps[0].to_string())
+            });
+            let text = rename_labels(&text, &renamed);
+            let mut inner = expansions.clone();
+            inner.push((name.clone(), pos.clone()));
+            body.push(SourceLine { text, pos: body_line.pos.clone(), expansions: inner });
+        }
+        for line in body {
+            self.line(line)?;
+        }
+        Ok(())
+    }
+}
+
+// Expands .macro definitions and their calls, given source lines with comments already stripped.
+// Macros must be defined before they are called, and are told apart by name and number of arguments.
+pub fn expand_macros(lines: Vec<SourceLine>) -> Result<Vec<SourceLine>, String> {
+    let mut expander = Expander {
+        macros: HashMap::new(),
+        label_regex: Regex::new(r"^\s*([A-Za-z0-9_]+):(.*)$").unwrap(),
+        param_regex: Regex::new(r"%[A-Za-z0-9_]+").unwrap(),
+        expansions: 0,
+        out: vec![],
+    };
+    let mut lines = lines.into_iter();
+    while let Some(source) = lines.next() {
+        let trimmed = source.text.trim();
+        let pos = source.pos.clone();
+        if trimmed == ".end_macro" {
+            return Err(format!("{}: .end_macro without .macro", pos));
+        }
+        let Some(header) = trimmed.strip_prefix(".macro") else {
+            expander.line(source)?;
+            continue;
+        };
+        // .macro name, .macro name(%a, %b) or .macro name %a, %b
+        let header = header.trim();
+        let end = header.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(header.len());
+        let (name, params) = header.split_at(end);
+        if name.is_empty() {
+            return Err(format!("{}: Missing macro name", pos));
+        }
+        let params = params.trim();
+        let params = params.strip_prefix('(').and_then(|p| p.strip_suffix(')')).unwrap_or(params);
+        let params = split_args(params);
+        let valid = |p: &String| p.len() > 1 && p.starts_with('%') && p[1..].chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if let Some(param) = params.iter().find(|p| !valid(p)) {
+            return Err(format!("{}: Macro parameter {} must start with %", pos, param));
+        }
+        let mut body = vec![];
+        loop {
+            match lines.next() {
+                Some(line) if line.text.trim() == ".end_macro" => break,
+                Some(line) if line.text.trim().starts_with(".macro") => {
+                    return Err(format!("{}: Macro definitions cannot be nested (inside macro {} from {})", line.pos, name, pos));
+                }
+                Some(line) => body.push(line),
+                None => return Err(format!("{}: Macro {} has no .end_macro", pos, name)),
+            }
+        }
+        let labels = body.iter().filter_map(|line| expander.label_regex.captures(&line.text))
+            .map(|caps| caps.get(1).unwrap().as_str().to_string()).collect();
+        let key = (name.to_string(), params.len());
+        if let Some(existing) = expander.macros.get(&key) {
+            return Err(format!("{}: Macro {} with {} arguments is already defined at {}", pos, name, params.len(), existing.pos));
+        }
+        expander.macros.insert(key, Macro { params, body, labels, pos });
+    }
+    Ok(expander.out)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm::assemble;
+    use crate::isa::IsaLevel;
+    use std::path::Path;
+
+    #[test]
+    fn local_labels_named_like_registers() {
+        let source = ".macro count(%n)\nli $t0, %n\nt0: addiu $t0, $t0, -1\nbne $t0, $zero, t0\nnop\n.end_macro\ncount(3)\ncount(4)\n";
+        let program = assemble(source, Path::new("test.s"), IsaLevel::Mips32r2).unwrap();
+        assert_eq!(program.symbol("t0_M1"), Some(0x00400004));
+        assert_eq!(program.symbol("t0_M2"), Some(0x00400014));
+        let words: Vec<u32> = program.text.values().map(|instr| instr.encode()).collect();
+        assert_eq!(words[..4], [0x24080003, 0x2508ffff, 0x1500fffe, 0]);
+    }
+}
@@ -1,128 +1,377 @@
-mod cpu;
-use cpu::CPU;
-mod isa;
-use isa::*;
+use mipsemu::branch::BranchUnit;
+use mipsemu::asm::assemble;
+use mipsemu::bitmap::{Bitmap, BitmapConfig};
+use mipsemu::cache::CacheConfig;
+use mipsemu::cpu::CPU;
+use mipsemu::export::{export_image, Endian, ImageFormat, Segment};
+use mipsemu::expr;
+use mipsemu::harness::run_spec;
+use mipsemu::isa::IsaLevel;
+use mipsemu::limits::Limits;
+use mipsemu::mmio::{Console, Keyboard, DEFAULT_DISPLAY_DELAY, MMIO_BASE};
+use mipsemu::vfs::Vfs;
 use std::env;
-use std::io::{self, BufRead, BufReader};
-use std::io::{Error,ErrorKind};
-use std::fs::File;
-use std::collections::HashMap;
+use std::io::{self, Error};
+use std::time::Duration;
+
+const USAGE: &str = "Usage: program [options] <input MIPS script> [program arguments]
+       program asm [--isa <level>] [--listing <file>] [image options] <input MIPS script>
+       program test [--junit <file>] [--json <file>] <spec.toml>
+The asm command only assembles the script, checking it for errors and writing any outputs asked for.
+The test command runs the cases of a TOML test spec, printing a summary and writing JUnit XML and JSON
+reports if asked. It exits with 0 if every case passes and 1 otherwise.
+Image options, for asm:
+  --text <file>      write an image of the .text segment
+  --data <file>      write an image of the .data segment
+  --format <format>  image format: bin (the default), ihex, readmemh or logisim
+  --endian <order>   byte order of the images: little (the default, as emulated) or big
+  --text-base <addr> address at the start of the .text image, by default the first instruction's
+  --data-base <addr> address at the start of the .data image, by default the first item's
+Options:
+  --trace            print each instruction as it executes
+  --backtrace        print the call stack when the program exits, as is always done when it faults
+  --listing          print the assembler listing before running, or with asm, write it to a file
+  --check-calls      report calls that break the calling convention: callee-saved registers or $ra
+                     not restored, $sp not 8-byte aligned at a call, or $t registers read before written
+  --check-uninit <mode> report uninitialized registers and memory used as a branch condition, jump
+                     target, address or syscall argument: warn lists them when the program ends,
+                     strict stops the program at the first
+  --check-memory     fault loads and stores outside the memory layout: stack overflow into the heap,
+                     stack underflow above the initial $sp, writes to code and unmapped addresses
+  --profile          print a flat profile when the program ends: instructions run in each function,
+                     exclusive and inclusive of its calls, and the hottest source lines
+  --profile-listing <file> write the program listing annotated with how often each instruction ran
+  --callgrind <file> write a profile in callgrind format, for KCachegrind or callgrind_annotate
+  --isa <level>      reject instructions newer than mips1, mips32r1 or mips32r2 (the default)
+  --linux            emulate Linux o32 syscalls instead of MARS's, passing the program its arguments
+                     on the stack. Arguments starting with - can be given after --. The program is
+                     still assembly source, ELF binaries can't be loaded
+  --env <var=value>  add a variable to the environment of a --linux program, may be given more than once
+  --fs <config>      filesystem for the file syscalls, by default the current directory, which the
+                     program cannot leave
+  --max-instructions <n> stop the program after n instructions, with exit code 123
+  --timeout <seconds> stop the program after this much time, with exit code 124
+  --max-heap <bytes> stop the program if its heap grows beyond this size, with exit code 122
+  --max-stack <bytes> stop the program if $sp moves this far below its initial value, with exit code 121
+  --max-output <bytes> stop the program once it has written this much to stdout and stderr, with exit code 120
+  --mmio             map the MARS keyboard and display registers at 0xffff0000, using stdin and stdout
+  --keyboard <text>  type the given text on the MMIO keyboard instead of reading stdin, \\n for newlines
+  --display-delay <n> instructions the MMIO display takes to print each character (default 5)
+  --bitmap <config>  emulate a bitmap display, writing frames to image files when the program exits
+                     and on syscall 100
+  --bitmap-out <file> file to write bitmap frames to, {} is replaced by the frame number. Files
+                     ending in .ppm are written as PPM, others as PNG (default bitmap{}.png)
+  --bitmap-every <n> also write a frame every n instructions
+  --l1i <config>     simulate an L1 instruction cache
+  --l1d <config>     simulate an L1 data cache
+  --l2 <config>      simulate a unified L2 cache
+  --bp <config>      evaluate a branch predictor, may be given more than once to compare predictors
+Cache configs are comma separated key=value pairs, e.g. size=4096,assoc=2,block=16,repl=lru,write=back,alloc=yes
+  repl: lru | fifo | random, write: back | through, alloc: yes | no
+Bitmap configs are key=value pairs too, e.g. width=256,height=256,unit=8,base=gp
+  width/height: display size in pixels, at most 8192, unit: pixels per side of each word's unit (default 512x256, 1)
+  base: address of the framebuffer or global | gp | data (the default) | heap | mmio
+Filesystem configs are one of memory, dir=<path> or tar=<file>, followed by optional key=value limits,
+  e.g. dir=sandbox,ro,files=16,written=65536
+  memory: empty and in memory, dir: a host directory, ro to make it read-only, tar: in memory from an archive
+  files: maximum number of open files, written: maximum number of bytes written to files
+Branch predictor configs name the predictor followed by optional key=value pairs, e.g. gshare,bits=10,history=8,btb=6
+  predictors: not-taken | btfnt | 1bit | 2bit | gshare | tournament, bits/btb: log2 of table entries";
+
+struct Options {
+    input: String,
+    // Assemble without running, for the asm command
+    assemble_only: bool,
+    trace: bool,
+    backtrace: bool,
+    check_calls: bool,
+    // Some(strict) to check for uses of uninitialized values
+    check_uninit: Option<bool>,
+    check_memory: bool,
+    profile: bool,
+    profile_listing: Option<String>,
+    callgrind: Option<String>,
+    listing: bool,
+    listing_file: Option<String>,
+    // Memory images to write with asm: (segment, file)
+    images: Vec<(Segment, String)>,
+    format: ImageFormat,
+    endian: Endian,
+    text_base: Option<u32>,
+    data_base: Option<u32>,
+    isa: IsaLevel,
+    linux: bool,
+    // Program arguments and environment for --linux
+    args: Vec<String>,
+    env: Vec<String>,
+    fs: Option<Vfs>,
+    limits: Limits,
+    mmio: bool,
+    keyboard: Option<String>,
+    display_delay: u32,
+    bitmap: Option<BitmapConfig>,
+    bitmap_out: String,
+    bitmap_every: Option<u64>,
+    l1i: Option<CacheConfig>,
+    l1d: Option<CacheConfig>,
+    l2: Option<CacheConfig>,
+    predictors: Vec<BranchUnit>,
+}
+
+fn parse_address(s: &str) -> Result<u32, String> {
+    expr::eval(s, &|_| None).map_err(|_| format!("Cannot parse address {}", s))
+}
+
+fn parse_limit(s: &str) -> Result<u64, String> {
+    s.parse().map_err(|_| format!("Cannot parse limit {}", s))
+}
+
+// A memory size in bytes, which may be written as an expression such as 1<<20
+fn parse_size(s: &str) -> Result<u32, String> {
+    expr::eval(s, &|_| None).map_err(|_| format!("Cannot parse size {}", s))
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut input = None;
+    let assemble_only = args.get(1).is_some_and(|arg| arg == "asm");
+    let mut options = Options { input: String::new(), assemble_only, trace: false, backtrace: false, check_calls: false, check_uninit: None, check_memory: false, profile: false, profile_listing: None, callgrind: None, listing: false, listing_file: None, images: Vec::new(), format: ImageFormat::Binary, endian: Endian::Little, text_base: None, data_base: None, isa: IsaLevel::Mips32r2, linux: false, args: Vec::new(), env: Vec::new(), fs: None, limits: Limits::default(), mmio: false, keyboard: None, display_delay: DEFAULT_DISPLAY_DELAY, bitmap: None, bitmap_out: "bitmap{}.png".to_string(), bitmap_every: None, l1i: None, l1d: None, l2: None, predictors: Vec::new() };
+    let mut iter = args.iter().skip(if assemble_only { 2 } else { 1 });
+    while let Some(arg) = iter.next() {
+        let mut value = || iter.next().ok_or(format!("Missing value for {}", arg));
+        match arg.as_str() {
+            "--trace" => options.trace = true,
+            "--backtrace" => options.backtrace = true,
+            "--check-calls" => options.check_calls = true,
+            "--check-memory" => options.check_memory = true,
+            "--profile" => options.profile = true,
+            "--profile-listing" => options.profile_listing = Some(value()?.clone()),
+            "--callgrind" => options.callgrind = Some(value()?.clone()),
+            "--check-uninit" => options.check_uninit = Some(match value()?.as_str() {
+                "warn" => false,
+                "strict" => true,
+                mode => return Err(format!("Unknown uninitialized value check {}, expected warn or strict", mode)),
+            }),
+            "--listing" if assemble_only => options.listing_file = Some(value()?.clone()),
+            "--listing" => options.listing = true,
+            "--text" if assemble_only => options.images.push((Segment::Text, value()?.clone())),
+            "--data" if assemble_only => options.images.push((Segment::Data, value()?.clone())),
+            "--format" if assemble_only => options.format = ImageFormat::from_str(value()?)?,
+            "--endian" if assemble_only => options.endian = Endian::from_str(value()?)?,
+            "--text-base" if assemble_only => options.text_base = Some(parse_address(value()?)?),
+            "--data-base" if assemble_only => options.data_base = Some(parse_address(value()?)?),
+            "--isa" => options.isa = IsaLevel::from_str(value()?)?,
+            "--linux" => options.linux = true,
+            "--env" => options.env.push(value()?.clone()),
+            "--" if input.is_some() => options.args.extend(iter.by_ref().cloned()),
+            "--fs" => options.fs = Some(Vfs::from_str(value()?)?),
+            "--max-instructions" => options.limits.max_instructions = Some(parse_limit(value()?)?),
+            "--timeout" => {
+                let seconds = value()?;
+                let timeout = seconds.parse().ok().and_then(|s| Duration::try_from_secs_f64(s).ok());
+                options.limits.timeout = Some(timeout.ok_or(format!("Cannot parse timeout {}", seconds))?);
+            }
+            "--max-heap" => options.limits.max_heap = Some(parse_size(value()?)?),
+            "--max-stack" => options.limits.max_stack = Some(parse_size(value()?)?),
+            "--max-output" => options.limits.max_output = Some(parse_limit(value()?)?),
+            "--mmio" => options.mmio = true,
+            "--keyboard" => options.keyboard = Some(value()?.replace("\\n", "\n")),
+            "--display-delay" => {
+                let delay = value()?;
+                options.display_delay = delay.parse().map_err(|_| format!("Cannot parse display delay {}", delay))?;
+            }
+            "--bitmap" => options.bitmap = Some(BitmapConfig::from_str(value()?)?),
+            "--bitmap-out" => options.bitmap_out = value()?.clone(),
+            "--bitmap-every" => {
+                let every = value()?;
+                options.bitmap_every = Some(every.parse().ok().filter(|n| *n > 0).ok_or(format!("Cannot parse frame interval {}", every))?);
+            }
+            "--l1i" => options.l1i = Some(CacheConfig::from_str(value()?)?),
+            "--l1d" => options.l1d = Some(CacheConfig::from_str(value()?)?),
+            "--l2" => options.l2 = Some(CacheConfig::from_str(value()?)?),
+            "--bp" => options.predictors.push(BranchUnit::from_str(value()?)?),
+            _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+            _ if input.is_none() => input = Some(arg.clone()),
+            _ => options.args.push(arg.clone()),
+        }
+    }
+    options.input = input.ok_or("No input file given".to_string())?;
+    if !options.args.is_empty() && !options.linux {
+        return Err("Only one input file may be given, program arguments need --linux".to_string());
+    }
+    // argv[0] is the program itself
+    options.args.insert(0, options.input.clone());
+    Ok(options)
+}
+
+// The test command: runs a spec and writes the reports asked for
+fn run_tests(args: &[String]) -> Result<bool, String> {
+    let mut spec = None;
+    let mut junit = None;
+    let mut json = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--junit" => junit = Some(iter.next().ok_or("--junit needs a file")?),
+            "--json" => json = Some(iter.next().ok_or("--json needs a file")?),
+            _ if arg.starts_with('-') => return Err(format!("Unknown option {}", arg)),
+            _ if spec.is_none() => spec = Some(arg),
+            _ => return Err(format!("Unexpected argument {}", arg)),
+        }
+    }
+    let spec = spec.ok_or("No test spec given")?;
+    let report = run_spec(std::path::Path::new(spec))?;
+    print!("{}", report.summary());
+    if let Some(file) = junit {
+        std::fs::write(file, report.junit()).map_err(|e| format!("Cannot write {}: {}", file, e))?;
+    }
+    if let Some(file) = json {
+        std::fs::write(file, report.json()).map_err(|e| format!("Cannot write {}: {}", file, e))?;
+    }
+    Ok(report.passed() == report.cases.len())
+}
 
 fn main() -> io::Result<()> {
     // Get arguments
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2{
-        println!("Usage: program <input MIPS script>");
-        std::process::exit(-1);
-    }
-    // Open file for reading
-    let file = File::open(&args[1])?;
-    let reader = BufReader::new(file);
-    // Stores parsed instructions
-    let mut p_instrs:Vec<ParsedInstr> = vec![];
-    // Stores label mappings
-    let mut labels: HashMap<String, u32> = HashMap::new();
-    // Stores current instruction number
-    let mut instr_number: u32 = 0;
-    // For each line
-    for line in reader.lines() {
-        match line{
-            Ok(l) => {
-                // Attempt to parse line as ParsedInstr type
-                let line_parsed = ParsedInstr::from_str(&l);
-                // Skip any NOPs
-                match line_parsed{
-                    // Skip all empty lines
-                    Ok(ParsedInstr::Empty) => {}
-                    // Map labels to the current instruction location(in words)
-                    Ok(ParsedInstr::Label(s)) => {
-                        labels.insert(s, instr_number);
-                    }
-                    // If its a usual instruction, add the instruction and increment the instruction number
-                    Ok(p_instr) => {
-                        p_instrs.push(p_instr);
-                        instr_number += 1;
-                    },
-                    Err(s) => {
-                        return Err(Error::new(ErrorKind::Other,format!("Could not parse instruction! {}",s)))
-                    },
-                }
+    if args.get(1).is_some_and(|arg| arg == "test") {
+        match run_tests(&args[2..]) {
+            Ok(passed) => std::process::exit(if passed { 0 } else { 1 }),
+            Err(s) => {
+                println!("{}\n{}", s, USAGE);
+                std::process::exit(-1);
             }
-            Err(_) => continue,
         }
     }
-    // Map parsed instructions to instructions ready to execute
-    let mut instructions: Vec<Instr> = Vec::new();
-    let mut instr_number: i32 = 0;
-    for inst in p_instrs{
-        match &inst{
-            // Typical instructions can just be pulled out of any parsed instructions
-            ParsedInstr::I(inner) => instructions.push(inner.clone()),
-            // Handle labelled instructions
-            // Beq, Bne, etc use relative addresses in words
-            // J, and Jal use absolute addresses in words
-            ParsedInstr::Beq{rt, rs, label} | ParsedInstr::Bne{rt, rs, label} | ParsedInstr::Bgt{rt, rs, label} |
-            ParsedInstr::Bge{rt, rs, label} | ParsedInstr::Blt{rt, rs, label} | ParsedInstr::Ble{rt, rs, label} => {
-                let addr: u32 = labels.get(label).unwrap().clone();
-                let rel_addr: i32 = (addr as i32 - (instr_number as i32 + 1)).try_into().unwrap();
-                let instr = match &inst {
-                    ParsedInstr::Beq{..} => Instr::Beq{rt: *rt, rs: *rs, rel_addr},
-                    ParsedInstr::Bne{..} => Instr::Bne{rt: *rt, rs: *rs, rel_addr},
-                    ParsedInstr::Bgt{..} => Instr::Bgt{rt: *rt, rs: *rs, rel_addr},
-                    ParsedInstr::Bge{..} => Instr::Bge{rt: *rt, rs: *rs, rel_addr},
-                    ParsedInstr::Blt{..} => Instr::Blt{rt: *rt, rs: *rs, rel_addr},
-                    ParsedInstr::Ble{..} => Instr::Ble{rt: *rt, rs: *rs, rel_addr},
-                    _ => unreachable!(),
-                };
-                instructions.push(instr);
-            }
-            ParsedInstr::Jump{label} | ParsedInstr::Jal{label} => {
-                let addr: u32 = labels.get(label).unwrap().clone();
-                let instr = match &inst {
-                    ParsedInstr::Jump{..} => Instr::Jump{addr},
-                    ParsedInstr::Jal{..} => Instr::Jal{addr},
-                    _ => unreachable!(),
-                };
-                instructions.push(instr);
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(s) => {
+            println!("{}\n{}", s, USAGE);
+            std::process::exit(-1);
+        }
+    };
+    // Read and assemble the program
+    let source = std::fs::read_to_string(&options.input)?;
+    let program = assemble(&source, std::path::Path::new(&options.input), options.isa).map_err(|s| Error::other(format!("Could not parse instruction! {}", s)))?;
+    if options.assemble_only {
+        if let Some(file) = &options.listing_file {
+            std::fs::write(file, program.listing())?;
+        }
+        for (segment, file) in &options.images {
+            let base = if *segment == Segment::Text { options.text_base } else { options.data_base };
+            let image = export_image(&program, *segment, options.format, options.endian, base).map_err(Error::other)?;
+            std::fs::write(file, image)?;
+        }
+        return Ok(());
+    }
+    if options.listing {
+        print!("{}", program.listing());
+    }
+    // Now that we have a set of instructions, execute them
+    let mut builder = CPU::builder();
+    if let Some(config) = options.l1i {
+        builder = builder.l1i(config);
+    }
+    if let Some(config) = options.l1d {
+        builder = builder.l1d(config);
+    }
+    if let Some(config) = options.l2 {
+        builder = builder.l2(config);
+    }
+    for unit in options.predictors {
+        builder = builder.predictor(unit);
+    }
+    let keyboard = match options.keyboard {
+        Some(text) => Some(Keyboard::Script(text.into_bytes().into())),
+        None if options.mmio => Some(Keyboard::stdin()),
+        None => None,
+    };
+    if let Some(keyboard) = keyboard {
+        builder = builder.device(MMIO_BASE, Box::new(Console::new(keyboard, options.display_delay)));
+    }
+    if let Some(config) = options.bitmap {
+        builder = builder.bitmap(Bitmap::new(config, options.bitmap_out, options.bitmap_every));
+    }
+    if let Some(vfs) = options.fs {
+        builder = builder.vfs(vfs);
+    }
+    builder = builder.limits(options.limits);
+    if options.linux {
+        builder = builder.linux(&options.args, &options.env);
+    }
+    if options.check_calls {
+        builder = builder.check_calls();
+    }
+    if options.check_memory {
+        builder = builder.check_memory();
+    }
+    if let Some(strict) = options.check_uninit {
+        builder = builder.check_uninit(strict);
+    }
+    if options.profile || options.profile_listing.is_some() || options.callgrind.is_some() {
+        builder = builder.profile();
+    }
+    let mut cpu = builder.build().map_err(Error::other)?;
+    cpu.load_program(&program);
+    let mut fault = None;
+    loop {
+        let pc = cpu.pc;
+        // Fetch, decode and execute, including any delay slot
+        match cpu.step(&program) {
+            Ok(Some(instr)) => {
+                if options.trace {
+                    println!("0x{:08x}: {:?}", pc, instr);
+                }
             }
-            ParsedInstr::La{rt, label} => {
-                let addr: u32 = labels.get(label).unwrap().clone();
-                let instr = match &inst {
-                    ParsedInstr::La{..} => Instr::La{rt: *rt, addr},
-                    _ => unreachable!(),
-                };
-                instructions.push(instr);
+            Ok(None) => break,
+            Err(s) => {
+                fault = Some(s);
+                break;
             }
-            // Handle anything like labels or NOPs that slipped through(shouldn't happen)
-            _ => {}
         }
-        instr_number += 1;
     }
-    // Now that we have a set of instructions, execute them
-    let mut cpu = CPU::new();
-    let debug: bool = true;
-    while cpu.pc < instructions.len(){
-        // Fetch
-        let fetch = instructions[cpu.pc];
-        println!("{:?}",fetch);
-        // Decode and Execute
-        cpu.execute(&fetch).map_err(|s| {
-            Error::new(ErrorKind::Other, format!("Could not execute! {}", s))
-        })?;
-        // If applicable, execute delay slot instruction
-        if Instr::is_delay_instruction(&fetch) && cpu.delay_slot_ready{
-            let delay_instr = instructions[cpu.pc];
-            println!("{:?}",delay_instr);
-            cpu.execute(&delay_instr).map_err(|s| {
-                Error::new(ErrorKind::Other, format!("Could not execute! {}", s))
-            })?;
-            cpu.delay_slot_ready = false;
+    if let Some(checker) = &cpu.call_checker {
+        eprint!("{}", checker.violations.summary(&program));
+    }
+    if let Some(checker) = &cpu.uninit {
+        eprint!("{}", checker.warnings.summary(&program));
+    }
+    if let Some(s) = &fault {
+        // Printed as is rather than returned, as faults may span several lines
+        eprintln!("Could not execute! {}\n{}", s, cpu.backtrace(&program));
+    } else if options.backtrace {
+        eprintln!("{}", cpu.backtrace(&program));
+    }
+    // The frames and reports are written for a program that faulted too, to see how it got there
+    if let Some(bitmap) = &mut cpu.bitmap {
+        bitmap.dump(&cpu.mem).map_err(Error::other)?;
+    }
+    if cpu.caches.is_enabled() {
+        print!("{}", cpu.caches.report(&program.lines));
+    }
+    for unit in &cpu.predictors {
+        print!("{}", unit.report(&program.lines));
+    }
+    if let Some(profile) = cpu.profile(&program) {
+        if options.profile {
+            print!("{}", profile.flat(&program));
+        }
+        if let Some(file) = &options.profile_listing {
+            std::fs::write(file, profile.annotated_listing(&program))?;
         }
-        
-        // Increment PC
-        cpu.pc += 1;
+        if let Some(file) = &options.callgrind {
+            std::fs::write(file, profile.callgrind(&program, &options.input))?;
+        }
+    }
+    if fault.is_some() {
+        std::process::exit(1);
     }
-    
-    
+    if let Some(reason) = cpu.termination {
+        eprintln!("{} after {} instructions, at 0x{:08x}", reason.message(), cpu.limits.instructions(), cpu.pc);
+        std::process::exit(reason.exit_code());
+    }
+    if let Some(code) = cpu.exit_code {
+        std::process::exit(code);
+    }
+
     Ok(())
 }
@@ -0,0 +1,95 @@
+// Checks loads and stores against the memory layout, so stray pointers fault where they go wrong
+// instead of quietly reading zeros or corrupting memory. The stack runs from $sp up to the initial
+// stack pointer and may grow down to the end of the heap; text may be read but not written; data,
+// the heap, Linux memory mappings, kernel data and memory mapped devices may be read and written.
+// Anything else is unmapped.
+use crate::asm::Program;
+use crate::cp0::ExceptionCode;
+use crate::cpu::CPU;
+use crate::isa::Instr;
+use crate::memory::{HEAP_BASE, KDATA_BASE, KTEXT_BASE, SP_INIT, TEXT_BASE};
+
+const SP: usize = 29;
+// Start of the data segment, where MARS places .extern data before .data
+const DATA_SEGMENT_BASE: u32 = 0x10000000;
+// Just past the word at the initial stack pointer, the top of the stack
+const STACK_TOP: u32 = SP_INIT + 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MemoryFault {
+    StackOverflow,
+    StackUnderflow,
+    TextWrite,
+    Unmapped,
+}
+impl MemoryFault {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MemoryFault::StackOverflow => "Stack overflow",
+            MemoryFault::StackUnderflow => "Stack underflow",
+            MemoryFault::TextWrite => "Write to the text segment",
+            MemoryFault::Unmapped => "Access to unmapped memory",
+        }
+    }
+}
+
+impl CPU {
+    // The end of a segment of code, the address after its last instruction
+    fn text_end(program: &Program, base: u32) -> u32 {
+        program.text.range(base..base.saturating_add(0x10000000)).next_back().map(|(addr, _)| addr + 4).unwrap_or(base)
+    }
+    // Why an access to addr is not allowed, if it isn't
+    fn classify_access(&self, addr: u32, store: bool, program: &Program) -> Option<(MemoryFault, String)> {
+        let sp = self.reg[SP];
+        if self.bus.is_mapped(addr) {
+            return None;
+        }
+        let mapped = self.linux.as_ref().is_some_and(|linux| linux.mapped().contains(&addr));
+        match addr {
+            _ if mapped => None,
+            TEXT_BASE..DATA_SEGMENT_BASE | KTEXT_BASE..KDATA_BASE if store => {
+                Some((MemoryFault::TextWrite, "code cannot be modified".to_string()))
+            }
+            TEXT_BASE..DATA_SEGMENT_BASE | KTEXT_BASE..KDATA_BASE => {
+                let end = CPU::text_end(program, if addr < KTEXT_BASE { TEXT_BASE } else { KTEXT_BASE });
+                (addr >= end).then(|| (MemoryFault::Unmapped, format!("past the last instruction at 0x{:08x}", end - 4)))
+            }
+            DATA_SEGMENT_BASE..HEAP_BASE => None,
+            _ if (HEAP_BASE..self.heap_end).contains(&addr) => None,
+            _ if (sp..STACK_TOP).contains(&addr) => None,
+            _ if (self.heap_end..sp).contains(&addr) => {
+                Some((MemoryFault::Unmapped, format!("between the end of the heap at 0x{:08x} and $sp 0x{:08x}", self.heap_end, sp)))
+            }
+            STACK_TOP..KTEXT_BASE => Some((MemoryFault::StackUnderflow, format!("above the top of the stack at 0x{:08x}", SP_INIT))),
+            KDATA_BASE.. => None,
+            _ => Some((MemoryFault::Unmapped, "outside every segment".to_string())),
+        }
+    }
+    // Faults a load or store about to run if it would leave the memory it may use
+    pub(crate) fn check_access(&mut self, instr: &Instr, program: &Program) -> Result<(), String> {
+        if !self.check_memory {
+            return Ok(());
+        }
+        let (Some((_, store)), Some((base, offset))) = (instr.memory_access(), instr.memory_operand()) else {
+            return Ok(());
+        };
+        let addr = self.reg[base as usize].wrapping_add(offset);
+        let Some((fault, reason)) = self.classify_access(addr, store, program) else {
+            return Ok(());
+        };
+        let message = format!("{} at 0x{:08x}: {} 0x{:08x}, {}", fault.name(), self.pc,
+            if store { "store to" } else { "load from" }, addr, reason);
+        let code = if store { ExceptionCode::AddressStore } else { ExceptionCode::AddressLoad };
+        Err(self.raise(code, Some(addr), message))
+    }
+    // Faults an instruction that has just moved $sp into the heap
+    pub(crate) fn check_stack_pointer(&mut self, instr: &Instr) -> Result<(), String> {
+        let sp = self.reg[SP];
+        if !self.check_memory || instr.writes() != Some(SP as u32) || sp >= self.heap_end {
+            return Ok(());
+        }
+        let message = format!("{} at 0x{:08x}: $sp 0x{:08x} is below the end of the heap at 0x{:08x}",
+            MemoryFault::StackOverflow.name(), self.pc, sp, self.heap_end);
+        Err(self.raise(ExceptionCode::AddressStore, Some(sp), message))
+    }
+}
@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+
+// Default segment layout (matches the MARS "default" memory configuration)
+pub const TEXT_BASE: u32 = 0x00400000;
+pub const DATA_BASE: u32 = 0x10010000;
+pub const HEAP_BASE: u32 = 0x10040000;
+pub const GP_INIT: u32 = 0x10008000;
+pub const SP_INIT: u32 = 0x7fffeffc;
+pub const KTEXT_BASE: u32 = 0x80000000;
+pub const KDATA_BASE: u32 = 0x90000000;
+pub const EXCEPTION_HANDLER: u32 = 0x80000180;
+
+const PAGE_BITS: u32 = 12;
+const PAGE_SIZE: usize = 1 << PAGE_BITS;
+
+// Sparse, byte addressable, little-endian memory. Pages are only allocated once written to,
+// reads from untouched pages return 0.
+pub struct Memory {
+    pages: HashMap<u32, Box<[u8; PAGE_SIZE]>>,
+    // A bit per byte, set once the byte has been written, when tracking which bytes are defined
+    defined: Option<HashMap<u32, Box<[u64; PAGE_SIZE / 64]>>>,
+}
+impl Memory {
+    pub fn new() -> Memory {
+        Memory {
+            pages: HashMap::new(),
+            defined: None,
+        }
+    }
+    // Starts tracking which bytes have been written, counting everything written so far
+    pub fn track_defined(&mut self) {
+        self.defined = Some(self.pages.keys().map(|page| (*page, Box::new([!0; PAGE_SIZE / 64]))).collect());
+    }
+    // Whether the byte has been written, always true when not tracking
+    pub fn is_defined(&self, addr: u32) -> bool {
+        let Some(defined) = &self.defined else {
+            return true;
+        };
+        let offset = (addr as usize) & (PAGE_SIZE - 1);
+        defined.get(&(addr >> PAGE_BITS)).is_some_and(|bits| bits[offset / 64] & (1 << (offset % 64)) != 0)
+    }
+    // Marks a byte as holding a defined value or not, e.g. after storing an undefined register
+    pub fn set_defined(&mut self, addr: u32, value: bool) {
+        let Some(defined) = &mut self.defined else {
+            return;
+        };
+        let offset = (addr as usize) & (PAGE_SIZE - 1);
+        let bits = defined.entry(addr >> PAGE_BITS).or_insert_with(|| Box::new([0; PAGE_SIZE / 64]));
+        if value {
+            bits[offset / 64] |= 1 << (offset % 64);
+        } else {
+            bits[offset / 64] &= !(1 << (offset % 64));
+        }
+    }
+    pub fn load_byte(&self, addr: u32) -> u8 {
+        match self.pages.get(&(addr >> PAGE_BITS)) {
+            Some(page) => page[(addr as usize) & (PAGE_SIZE - 1)],
+            None => 0,
+        }
+    }
+    pub fn store_byte(&mut self, addr: u32, value: u8) {
+        let page = self.pages.entry(addr >> PAGE_BITS).or_insert_with(|| Box::new([0; PAGE_SIZE]));
+        page[(addr as usize) & (PAGE_SIZE - 1)] = value;
+        self.set_defined(addr, true);
+    }
+    pub fn load_half(&self, addr: u32) -> Result<u16, String> {
+        if !addr.is_multiple_of(2) {
+            return Err(format!("Unaligned halfword load from 0x{:08x}", addr));
+        }
+        Ok(u16::from_le_bytes([self.load_byte(addr), self.load_byte(addr + 1)]))
+    }
+    pub fn store_half(&mut self, addr: u32, value: u16) -> Result<(), String> {
+        if !addr.is_multiple_of(2) {
+            return Err(format!("Unaligned halfword store to 0x{:08x}", addr));
+        }
+        let bytes = value.to_le_bytes();
+        self.store_byte(addr, bytes[0]);
+        self.store_byte(addr + 1, bytes[1]);
+        Ok(())
+    }
+    pub fn load_word(&self, addr: u32) -> Result<u32, String> {
+        if !addr.is_multiple_of(4) {
+            return Err(format!("Unaligned word load from 0x{:08x}", addr));
+        }
+        let mut bytes = [0u8; 4];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = self.load_byte(addr + i as u32);
+        }
+        Ok(u32::from_le_bytes(bytes))
+    }
+    pub fn store_word(&mut self, addr: u32, value: u32) -> Result<(), String> {
+        if !addr.is_multiple_of(4) {
+            return Err(format!("Unaligned word store to 0x{:08x}", addr));
+        }
+        for (i, byte) in value.to_le_bytes().iter().enumerate() {
+            self.store_byte(addr + i as u32, *byte);
+        }
+        Ok(())
+    }
+}
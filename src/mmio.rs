@@ -0,0 +1,196 @@
+// Memory mapped keyboard and display, with the register layout of the MARS "Keyboard and Display
+// MMIO Simulator" tool
+use crate::device::{Device, Interrupts};
+use std::collections::VecDeque;
+use std::io::{self, Read};
+use std::sync::mpsc::{self, Receiver};
+
+// Where MARS maps the console, with the registers at these offsets
+pub const MMIO_BASE: u32 = 0xffff0000;
+const RECEIVER_CONTROL: u32 = 0x0;
+const RECEIVER_DATA: u32 = 0x4;
+const TRANSMITTER_CONTROL: u32 = 0x8;
+const TRANSMITTER_DATA: u32 = 0xc;
+
+// Control register bits
+const READY: u32 = 1 << 0;
+const INTERRUPT_ENABLE: u32 = 1 << 1;
+
+// Interrupt lines, which MARS shares with the software interrupts
+pub const KEYBOARD_INTERRUPT: u32 = 0;
+pub const DISPLAY_INTERRUPT: u32 = 1;
+
+// Instructions the display takes to print a character, as in MARS
+pub const DEFAULT_DISPLAY_DELAY: u32 = 5;
+
+// Where typed characters come from
+pub enum Keyboard {
+    // Read from stdin on a background thread, so the program does not block waiting for a key
+    Stdin(Receiver<u8>),
+    // Characters typed one after another, each as soon as the last has been read
+    Script(VecDeque<u8>),
+}
+impl Keyboard {
+    pub fn stdin() -> Keyboard {
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            for byte in io::stdin().lock().bytes() {
+                match byte {
+                    Ok(byte) if sender.send(byte).is_ok() => {}
+                    _ => break,
+                }
+            }
+        });
+        Keyboard::Stdin(receiver)
+    }
+    fn next(&mut self) -> Option<u8> {
+        match self {
+            Keyboard::Stdin(receiver) => receiver.try_recv().ok(),
+            Keyboard::Script(bytes) => bytes.pop_front(),
+        }
+    }
+}
+
+pub struct Console {
+    keyboard: Keyboard,
+    receiver_control: u32,
+    receiver_data: u32,
+    transmitter_control: u32,
+    // Characters printed and not yet passed on to stdout
+    output: Vec<u8>,
+    // Instructions until the display is ready for the next character
+    busy: u32,
+    delay: u32,
+    // Interrupt lines as last signalled, so changes can be passed on to Cause
+    signalled: [bool; 2],
+}
+impl Console {
+    pub fn new(keyboard: Keyboard, delay: u32) -> Console {
+        Console {
+            keyboard,
+            receiver_control: 0,
+            receiver_data: 0,
+            transmitter_control: READY,
+            output: vec![],
+            busy: 0,
+            delay,
+            signalled: [false; 2],
+        }
+    }
+}
+impl Device for Console {
+    fn size(&self) -> u32 {
+        0x10
+    }
+    // Reading the received character clears the ready bit
+    fn read(&mut self, offset: u32) -> u32 {
+        match offset & !3 {
+            RECEIVER_CONTROL => self.receiver_control,
+            RECEIVER_DATA => {
+                self.receiver_control &= !READY;
+                self.receiver_data
+            }
+            TRANSMITTER_CONTROL => self.transmitter_control,
+            _ => 0,
+        }
+    }
+    // Only the interrupt enable bits of the control registers are writable, and characters written
+    // while the display is busy are dropped
+    fn write(&mut self, offset: u32, value: u32) {
+        match offset & !3 {
+            RECEIVER_CONTROL => self.receiver_control = (self.receiver_control & !INTERRUPT_ENABLE) | (value & INTERRUPT_ENABLE),
+            TRANSMITTER_CONTROL => self.transmitter_control = (self.transmitter_control & !INTERRUPT_ENABLE) | (value & INTERRUPT_ENABLE),
+            TRANSMITTER_DATA if self.transmitter_control & READY != 0 => {
+                self.output.push(value as u8);
+                self.transmitter_control &= !READY;
+                self.busy = self.delay;
+            }
+            _ => {}
+        }
+    }
+    fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+    // Takes the next key once the last has been read, finishes printing, and raises or clears the
+    // interrupt lines to match the ready bits
+    fn tick(&mut self, interrupts: &mut Interrupts) {
+        if self.receiver_control & READY == 0 {
+            if let Some(byte) = self.keyboard.next() {
+                self.receiver_data = byte as u32;
+                self.receiver_control |= READY;
+            }
+        }
+        if self.busy > 0 {
+            self.busy -= 1;
+        }
+        if self.busy == 0 {
+            self.transmitter_control |= READY;
+        }
+        for (line, control) in [(KEYBOARD_INTERRUPT, self.receiver_control), (DISPLAY_INTERRUPT, self.transmitter_control)] {
+            let level = control & (READY | INTERRUPT_ENABLE) == READY | INTERRUPT_ENABLE;
+            if level != self.signalled[line as usize] {
+                self.signalled[line as usize] = level;
+                if level {
+                    interrupts.raise(line);
+                } else {
+                    interrupts.clear(line);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::cpu::CPU;
+    use crate::isa::IsaLevel;
+    use crate::limits::{Limits, Termination};
+    use crate::stdio::Stdio;
+    use std::path::Path;
+
+    // Echoes typed characters to the display until a q
+    const ECHO: &str = "
+        .text
+        main:   li $s0, 0xffff0000
+        wait:   lw $t0, 0($s0)
+                andi $t0, $t0, 1
+                beq $t0, $zero, wait
+                lw $t1, 4($s0)
+                li $t2, 'q'
+                beq $t1, $t2, done
+        out:    lw $t0, 8($s0)
+                andi $t0, $t0, 1
+                beq $t0, $zero, out
+                sw $t1, 12($s0)
+                j wait
+        done:   li $v0, 10
+                syscall
+    ";
+
+    fn run(typed: &str, limits: Limits) -> CPU {
+        let program = assemble(ECHO, Path::new("test.s"), IsaLevel::Mips32r2).expect("assembled");
+        let console = Console::new(Keyboard::Script(typed.bytes().collect()), DEFAULT_DISPLAY_DELAY);
+        let mut cpu = CPU::builder().limits(limits).stdio(Stdio::captured(b"")).device(MMIO_BASE, Box::new(console)).build().unwrap();
+        cpu.load_program(&program);
+        while cpu.step(&program).unwrap().is_some() {}
+        cpu
+    }
+
+    #[test]
+    fn display_writes_to_stdout() {
+        let cpu = run("hi!q", Limits::default());
+        assert_eq!(cpu.stdio.stdout(), b"hi!");
+        assert!(cpu.termination.is_none());
+    }
+
+    #[test]
+    fn display_counts_towards_output_limit() {
+        let mut limits = Limits::default();
+        limits.max_output = Some(5);
+        let cpu = run("hello worldq", limits);
+        assert_eq!(cpu.stdio.stdout(), b"hello");
+        assert!(matches!(cpu.termination, Some(Termination::OutputLimit)));
+    }
+}
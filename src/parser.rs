@@ -0,0 +1,391 @@
+// Lexer and parser for a single line of assembly source, producing labels and either a directive
+// or an instruction statement with typed operands. Spans are byte offsets into the line.
+use crate::cp1::parse_freg;
+use crate::isa::parse_reg;
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug)]
+pub struct ParseError {
+    pub message: String,
+    pub span: Span,
+}
+impl ParseError {
+    pub fn new(message: String, span: Span) -> ParseError {
+        ParseError { message, span }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum TokenKind {
+    // $ followed by a register name or number, without the $
+    Reg(String),
+    // Mnemonics, labels, directives and symbols. May contain dots, e.g. add.s or .word
+    Ident(String),
+    Int,
+    Char,
+    Str,
+    Colon,
+    Comma,
+    LParen,
+    RParen,
+    // Expression operators
+    Op,
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    kind: TokenKind,
+    span: Span,
+}
+
+fn lex(text: &str) -> Result<Vec<Token>, ParseError> {
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let end_of = |i: usize| chars.get(i).map(|(pos, _)| *pos).unwrap_or(text.len());
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let (start, c) = chars[i];
+        let word = |i: usize, extra: &[char]| {
+            let mut j = i;
+            while chars.get(j).is_some_and(|(_, c)| c.is_ascii_alphanumeric() || *c == '_' || extra.contains(c)) {
+                j += 1;
+            }
+            j
+        };
+        let (kind, next) = match c {
+            c if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            '$' => {
+                let j = word(i + 1, &[]);
+                if j == i + 1 {
+                    return Err(ParseError::new("Expected a register name after $".to_string(), Span { start, end: start + 1 }));
+                }
+                (TokenKind::Reg(text[end_of(i + 1)..end_of(j)].to_string()), j)
+            }
+            c if c.is_ascii_digit() => (TokenKind::Int, word(i, &[])),
+            c if c.is_ascii_alphabetic() || c == '_' || c == '.' => {
+                let j = word(i, &['.']);
+                (TokenKind::Ident(text[start..end_of(j)].to_string()), j)
+            }
+            '\'' | '"' => {
+                // Runs to the matching unescaped quote
+                let mut j = i + 1;
+                while j < chars.len() && chars[j].1 != c {
+                    j += if chars[j].1 == '\\' { 2 } else { 1 };
+                }
+                if j >= chars.len() {
+                    return Err(ParseError::new("Unterminated quote".to_string(), Span { start, end: text.len() }));
+                }
+                (if c == '"' { TokenKind::Str } else { TokenKind::Char }, j + 1)
+            }
+            ':' => (TokenKind::Colon, i + 1),
+            ',' => (TokenKind::Comma, i + 1),
+            '(' => (TokenKind::LParen, i + 1),
+            ')' => (TokenKind::RParen, i + 1),
+            '+' | '-' | '*' | '/' | '%' | '&' | '|' | '^' | '~' => (TokenKind::Op, i + 1),
+            '<' | '>' if chars.get(i + 1).map(|(_, n)| *n) == Some(c) => (TokenKind::Op, i + 2),
+            _ => return Err(ParseError::new(format!("Unexpected character {}", c), Span { start, end: end_of(i + 1) })),
+        };
+        tokens.push(Token { kind, span: Span { start, end: end_of(next) } });
+        i = next;
+    }
+    Ok(tokens)
+}
+
+// Replaces identifiers that have a value, such as .eqv symbols, leaving registers, strings and
+// character literals alone. Lines that don't lex are returned unchanged for parse_line to report.
+pub fn substitute(text: &str, values: &HashMap<String, String>) -> String {
+    replace_idents(text, values, false)
+}
+
+// Renames labels where the line defines them and where its operands refer to them, leaving the
+// mnemonic or directive and operator names such as %hi alone as well
+pub fn rename_labels(text: &str, names: &HashMap<String, String>) -> String {
+    replace_idents(text, names, true)
+}
+
+fn replace_idents(text: &str, values: &HashMap<String, String>, labels_only: bool) -> String {
+    let Ok(tokens) = lex(text) else {
+        return text.to_string();
+    };
+    // The mnemonic or directive is the first identifier that isn't a label definition
+    let mnemonic = (0..tokens.len()).find(|i| {
+        matches!(tokens[*i].kind, TokenKind::Ident(_)) && tokens.get(i + 1).map(|t| &t.kind) != Some(&TokenKind::Colon)
+    });
+    let mut out = String::new();
+    let mut last = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        let TokenKind::Ident(name) = &token.kind else {
+            continue;
+        };
+        let operator = i > 0 && &text[tokens[i - 1].span.start..tokens[i - 1].span.end] == "%" && tokens[i - 1].span.end == token.span.start;
+        if labels_only && (Some(i) == mnemonic || operator) {
+            continue;
+        }
+        if let Some(value) = values.get(name) {
+            out.push_str(&text[last..token.span.start]);
+            out.push_str(value);
+            last = token.span.end;
+        }
+    }
+    out.push_str(&text[last..]);
+    out
+}
+
+#[derive(Clone, Debug)]
+pub enum Operand {
+    Reg(u32),
+    FReg(u32),
+    // A constant or label expression, as written
+    Expr(String),
+    // offset($base), where the offset expression is optional
+    Mem(Option<String>, u32),
+}
+
+#[derive(Debug)]
+pub struct Statement {
+    pub mnemonic: String,
+    pub mnemonic_span: Span,
+    pub operands: Vec<(Operand, Span)>,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+pub enum Body {
+    // The directive and its arguments as written, e.g. .word 1, 2
+    Directive(String),
+    Instr(Statement),
+}
+
+#[derive(Debug)]
+pub struct Line {
+    pub labels: Vec<(String, Span)>,
+    pub body: Option<Body>,
+}
+
+fn register(name: &str, span: Span) -> Result<Operand, ParseError> {
+    if let Ok(reg) = parse_reg(name) {
+        return Ok(Operand::Reg(reg));
+    }
+    parse_freg(name).map(Operand::FReg).map_err(|_| ParseError::new(format!("Unknown register ${}", name), span))
+}
+
+// Classifies the tokens between two commas as a register, memory reference or expression
+fn operand(text: &str, tokens: &[Token], span: Span) -> Result<Operand, ParseError> {
+    match tokens {
+        [] => Err(ParseError::new("Missing operand".to_string(), span)),
+        [Token { kind: TokenKind::Reg(name), span }] => register(name, *span),
+        [Token { kind: TokenKind::Reg(name), .. }, next, ..] if !matches!(next.kind, TokenKind::Op) => {
+            Err(ParseError::new(format!("Expected , after ${}", name), next.span))
+        }
+        [offset @ .., Token { kind: TokenKind::LParen, .. }, Token { kind: TokenKind::Reg(name), span: reg_span }, Token { kind: TokenKind::RParen, .. }] => {
+            let Operand::Reg(base) = register(name, *reg_span)? else {
+                return Err(ParseError::new(format!("Base register must be a general purpose register, not ${}", name), *reg_span));
+            };
+            let offset = match offset {
+                [] => None,
+                [first, .., last] => Some(expression(text, offset, Span { start: first.span.start, end: last.span.end })?),
+                [only] => Some(expression(text, offset, only.span)?),
+            };
+            Ok(Operand::Mem(offset, base))
+        }
+        _ => Ok(Operand::Expr(expression(text, tokens, span)?)),
+    }
+}
+
+// Checks the tokens can form an expression and returns its text
+fn expression(text: &str, tokens: &[Token], span: Span) -> Result<String, ParseError> {
+    let mut depth = 0;
+    for token in tokens {
+        match &token.kind {
+            TokenKind::Reg(name) => return Err(ParseError::new(format!("Unexpected register ${}, expected an operator or ,", name), token.span)),
+            TokenKind::Str => return Err(ParseError::new("Strings are only allowed in directives".to_string(), token.span)),
+            TokenKind::Colon => return Err(ParseError::new("Unexpected :".to_string(), token.span)),
+            TokenKind::LParen => depth += 1,
+            TokenKind::RParen if depth == 0 => return Err(ParseError::new("Unmatched )".to_string(), token.span)),
+            TokenKind::RParen => depth -= 1,
+            _ => {}
+        }
+    }
+    // Two values in a row with nothing between them are most likely a missing comma. The only
+    // value that can be followed by ( is the name of the %hi or %lo operator.
+    let value = |t: &Token| matches!(t.kind, TokenKind::Int | TokenKind::Char | TokenKind::Ident(_));
+    for (k, pair) in tokens.windows(2).enumerate() {
+        let operator = k > 0 && text[tokens[k - 1].span.start..tokens[k - 1].span.end] == *"%";
+        let call = (value(&pair[0]) || pair[0].kind == TokenKind::RParen) && pair[1].kind == TokenKind::LParen;
+        if value(&pair[0]) && value(&pair[1]) || call && !operator {
+            return Err(ParseError::new("Expected , between operands".to_string(), pair[1].span));
+        }
+    }
+    if depth != 0 {
+        return Err(ParseError::new("Unmatched (".to_string(), span));
+    }
+    Ok(text[span.start..span.end].to_string())
+}
+
+pub fn parse_line(text: &str) -> Result<Line, ParseError> {
+    let tokens = lex(text)?;
+    let mut labels = vec![];
+    let mut i = 0;
+    // Any number of labels, each an identifier followed by :
+    while let [Token { kind: TokenKind::Ident(name), span }, Token { kind: TokenKind::Colon, .. }, ..] = &tokens[i..] {
+        if name.contains('.') {
+            return Err(ParseError::new(format!("Invalid label name {}", name), *span));
+        }
+        labels.push((name.clone(), *span));
+        i += 2;
+    }
+    let Some(first) = tokens.get(i) else {
+        return Ok(Line { labels, body: None });
+    };
+    let TokenKind::Ident(mnemonic) = &first.kind else {
+        return Err(ParseError::new("Expected an instruction or directive".to_string(), first.span));
+    };
+    if mnemonic.starts_with('.') {
+        return Ok(Line { labels, body: Some(Body::Directive(text[first.span.start..].trim_end().to_string())) });
+    }
+    let rest = &tokens[i + 1..];
+    let mut operands = vec![];
+    if !rest.is_empty() {
+        // Split on commas outside parentheses
+        let mut groups = vec![];
+        let mut depth = 0;
+        let mut start = 0;
+        for (j, token) in rest.iter().enumerate() {
+            match token.kind {
+                TokenKind::LParen => depth += 1,
+                TokenKind::RParen => depth -= 1,
+                TokenKind::Comma if depth == 0 => {
+                    groups.push(start..j);
+                    start = j + 1;
+                }
+                _ => {}
+            }
+        }
+        groups.push(start..rest.len());
+        for range in groups {
+            let group = &rest[range.clone()];
+            let span = match group {
+                [first, .., last] => Span { start: first.span.start, end: last.span.end },
+                [only] => only.span,
+                // An empty operand, point at the comma next to it
+                [] => rest.get(range.start).unwrap_or(&rest[range.start - 1]).span,
+            };
+            operands.push((operand(text, group, span)?, span));
+        }
+    }
+    let end = rest.last().map(|t| t.span.end).unwrap_or(first.span.end);
+    let statement = Statement {
+        mnemonic: mnemonic.clone(),
+        mnemonic_span: first.span,
+        operands,
+        span: Span { start: first.span.start, end },
+    };
+    Ok(Line { labels, body: Some(Body::Instr(statement)) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn statement(text: &str) -> Statement {
+        match parse_line(text).unwrap().body {
+            Some(Body::Instr(statement)) => statement,
+            body => panic!("{} parsed as {:?}", text, body),
+        }
+    }
+
+    // The message of the error for a line and the text it points at
+    fn error(text: &str) -> (String, &str) {
+        let e = parse_line(text).expect_err("parsed");
+        (e.message, &text[e.span.start..e.span.end])
+    }
+
+    fn names(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn memory_operands() {
+        let operands = statement("lw $t0, 4($sp)").operands;
+        assert!(matches!(&operands[0].0, Operand::Reg(8)));
+        assert!(matches!(&operands[1].0, Operand::Mem(Some(offset), 29) if offset == "4"));
+        assert!(matches!(&statement("lw $t0, ($sp)").operands[1].0, Operand::Mem(None, 29)));
+        let operands = statement("sw $t1, label+4($t0)").operands;
+        assert!(matches!(&operands[1].0, Operand::Mem(Some(offset), 8) if offset == "label+4"));
+        assert_eq!(operands[1].1, Span { start: 8, end: 20 });
+        assert!(matches!(&statement("lw $t0, -8 ( $sp )").operands[1].0, Operand::Mem(Some(offset), 29) if offset == "-8"));
+        assert_eq!(error("lw $t0, 4($f2)").0, "Base register must be a general purpose register, not $f2");
+    }
+
+    #[test]
+    fn missing_comma() {
+        assert_eq!(error("addu $t0 $t1, $t2"), ("Expected , after $t0".to_string(), "$t1"));
+        assert_eq!(error("li $t0, 4 5"), ("Expected , between operands".to_string(), "5"));
+        assert_eq!(error("addiu $t0, $t1, label(4)"), ("Expected , between operands".to_string(), "("));
+        assert_eq!(error("addu $t0, , $t2"), ("Missing operand".to_string(), ","));
+    }
+
+    #[test]
+    fn hi_lo_operators() {
+        let operands = statement("lui $at, %hi(label)").operands;
+        assert!(matches!(&operands[1].0, Operand::Expr(e) if e == "%hi(label)"));
+        let operands = statement("addiu $t0, $at, %lo(label+8)").operands;
+        assert!(matches!(&operands[2].0, Operand::Expr(e) if e == "%lo(label+8)"));
+        assert!(matches!(&statement("lw $t0, %lo(label)($at)").operands[1].0, Operand::Mem(Some(e), 1) if e == "%lo(label)"));
+        assert_eq!(error("li $t0, (4").0, "Unmatched (");
+        assert_eq!(error("li $t0, 4)"), ("Unmatched )".to_string(), ")"));
+    }
+
+    #[test]
+    fn quotes() {
+        assert_eq!(error(".asciiz \"abc"), ("Unterminated quote".to_string(), "\"abc"));
+        assert_eq!(error("li $t0, 'a"), ("Unterminated quote".to_string(), "'a"));
+        assert!(matches!(&statement("li $t0, '\\''").operands[1].0, Operand::Expr(e) if e == "'\\''"));
+        // Escaped quotes don't end a string, and directives keep their arguments as written
+        let text = ".asciiz \"a, \\\"b\\\"\"";
+        assert!(matches!(parse_line(text).unwrap().body, Some(Body::Directive(d)) if d == text));
+        assert_eq!(error("li $t0, \"a\"").0, "Strings are only allowed in directives");
+    }
+
+    #[test]
+    fn registers_in_expressions() {
+        assert_eq!(error("addiu $t0, $t1, 4+$t2"), ("Unexpected register $t2, expected an operator or ,".to_string(), "$t2"));
+        assert_eq!(error("addu $t0, $t1, $bogus"), ("Unknown register $bogus".to_string(), "$bogus"));
+        assert_eq!(error("li $, 4"), ("Expected a register name after $".to_string(), "$"));
+        assert!(matches!(&statement("add.s $f0, $f1, $f2").operands[2].0, Operand::FReg(2)));
+    }
+
+    #[test]
+    fn labels() {
+        let line = parse_line("loop:").unwrap();
+        assert_eq!(line.labels, [("loop".to_string(), Span { start: 0, end: 4 })]);
+        assert!(line.body.is_none());
+        let line = parse_line("a: b:  nop").unwrap();
+        assert_eq!(line.labels.iter().map(|l| l.0.as_str()).collect::<Vec<_>>(), ["a", "b"]);
+        assert!(matches!(line.body, Some(Body::Instr(s)) if s.mnemonic == "nop" && s.operands.is_empty()));
+        assert!(parse_line("   ").unwrap().body.is_none());
+        assert_eq!(error("a.b: nop"), ("Invalid label name a.b".to_string(), "a.b"));
+        assert_eq!(error("4: nop").0, "Expected an instruction or directive");
+    }
+
+    #[test]
+    fn rename_labels_leaves_mnemonics_and_operators() {
+        let renamed = names(&[("b", "b_1"), ("hi", "hi_1")]);
+        assert_eq!(rename_labels("b: b b", &renamed), "b_1: b b_1");
+        assert_eq!(rename_labels("lui $at, %hi(hi)", &renamed), "lui $at, %hi(hi_1)");
+        assert_eq!(rename_labels(".word hi, 'b'", &renamed), ".word hi_1, 'b'");
+        // Plain substitution replaces every identifier with a value, registers and strings aside
+        let values = names(&[("t0", "1"), ("N", "4"), ("nop", "sll")]);
+        assert_eq!(substitute("nop $t0, N", &values), "sll $t0, 4");
+        assert_eq!(substitute(".asciiz \"N\"", &values), ".asciiz \"N\"");
+    }
+}
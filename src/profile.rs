@@ -0,0 +1,224 @@
+// Instruction-level profiler. Counts how often each instruction runs and, following the shadow
+// call stack, how many instructions each call took with its callees. Counts are reported per
+// instruction, per source line and per function, a function running from a label that is called
+// (or where the program or exception handler starts) up to the next, with exclusive counts for
+// the function's own instructions and inclusive counts taking in everything it called.
+use crate::asm::Program;
+use crate::callstack::Frame;
+use crate::cpu::CPU;
+use crate::isa::Instr;
+use crate::memory::KTEXT_BASE;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write;
+use std::path::Path;
+
+// The hottest source lines shown in a flat profile
+const TOP_LINES: usize = 20;
+
+#[derive(Clone, Copy, Default)]
+struct Calls {
+    count: u64,
+    // Instructions the calls took, callees included
+    inclusive: u64,
+}
+
+#[derive(Clone, Default)]
+pub struct Profiler {
+    // Executions of each instruction
+    counts: HashMap<u32, u64>,
+    // Calls from each call site to each function
+    calls: HashMap<(u32, u32), Calls>,
+    // Instructions spent in each function, callees included. A recursive call is counted as part
+    // of the outermost call to the function, not again on its own.
+    inclusive: HashMap<u32, u64>,
+    // Calls to each function that haven't returned
+    active: HashMap<u32, u32>,
+}
+impl Profiler {
+    pub(crate) fn count(&mut self, pc: u32) {
+        *self.counts.entry(pc).or_default() += 1;
+    }
+    pub(crate) fn enter(&mut self, entry: u32) {
+        *self.active.entry(entry).or_default() += 1;
+    }
+    // Charges a call that has returned with the instructions run since it was made
+    pub(crate) fn leave(&mut self, frame: &Frame, instructions: u64) {
+        let elapsed = instructions - frame.instructions;
+        let calls = self.calls.entry((frame.call_site, frame.entry)).or_default();
+        calls.count += 1;
+        calls.inclusive += elapsed;
+        let active = self.active.entry(frame.entry).or_default();
+        *active = active.saturating_sub(1);
+        if *active == 0 {
+            *self.inclusive.entry(frame.entry).or_default() += elapsed;
+        }
+    }
+}
+
+struct Function {
+    name: String,
+    exclusive: u64,
+    inclusive: u64,
+    calls: u64,
+}
+
+// A finished profile, with calls still in progress charged up to now
+pub struct Profile {
+    total: u64,
+    counts: HashMap<u32, u64>,
+    calls: BTreeMap<(u32, u32), Calls>,
+    // By entry point
+    functions: BTreeMap<u32, Function>,
+}
+impl Profile {
+    // Start of the function an instruction belongs to
+    fn function_of(&self, pc: u32) -> Option<u32> {
+        self.functions.range(..=pc).next_back().map(|(entry, _)| *entry)
+    }
+    fn name(&self, entry: u32) -> &str {
+        &self.functions[&entry].name
+    }
+    fn percent(&self, count: u64) -> f64 {
+        if self.total == 0 { 0.0 } else { count as f64 * 100.0 / self.total as f64 }
+    }
+    // Functions by exclusive count, then the hottest source lines
+    pub fn flat(&self, program: &Program) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "Profile: {} instructions", self.total);
+        let mut functions: Vec<&Function> = self.functions.values().filter(|f| f.exclusive > 0 || f.inclusive > 0).collect();
+        functions.sort_by(|a, b| b.exclusive.cmp(&a.exclusive).then(b.inclusive.cmp(&a.inclusive)));
+        let _ = writeln!(out, "  {:>10} {:>7} {:>10} {:>7} {:>8}  function", "exclusive", "", "inclusive", "", "calls");
+        for f in functions {
+            let _ = writeln!(out, "  {:>10} {:>6.2}% {:>10} {:>6.2}% {:>8}  {}",
+                f.exclusive, self.percent(f.exclusive), f.inclusive, self.percent(f.inclusive), f.calls, f.name);
+        }
+        // Fold per pc counts into per source line counts, keeping the line's first address to sort ties
+        let mut per_line: HashMap<&str, (u64, u32, &str)> = HashMap::new();
+        for (pc, count) in &self.counts {
+            if let Some((location, text)) = program.source.get(pc) {
+                let line = per_line.entry(location.as_str()).or_insert((0, *pc, text.as_str()));
+                line.0 += count;
+                line.1 = line.1.min(*pc);
+            }
+        }
+        let mut lines: Vec<(&str, (u64, u32, &str))> = per_line.into_iter().collect();
+        lines.sort_by(|a, b| b.1.0.cmp(&a.1.0).then(a.1.1.cmp(&b.1.1)));
+        if !lines.is_empty() {
+            let _ = writeln!(out, "Hottest lines");
+            let _ = writeln!(out, "  {:>10} {:>7}  source", "count", "");
+            for (location, (count, _, text)) in lines.into_iter().take(TOP_LINES) {
+                let _ = writeln!(out, "  {:>10} {:>6.2}%  {}: {}", count, self.percent(count), location, text.trim());
+            }
+        }
+        out
+    }
+    // The program listing with how often each instruction ran, - for never, under a heading for
+    // each function
+    pub fn annotated_listing(&self, program: &Program) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "{:>10}  {:<12}{:<32}Source", "Count", "Address", "Basic");
+        let mut last = None;
+        for (pc, instr) in &program.text {
+            if let Some(f) = self.functions.get(pc) {
+                let _ = writeln!(out, "\n{}: {} exclusive, {} inclusive, {} calls", f.name, f.exclusive, f.inclusive, f.calls);
+            }
+            let count = self.counts.get(pc).map_or("-".to_string(), |count| count.to_string());
+            let source = &program.source[pc];
+            let text = if last != Some(source) {
+                format!("{}: {}", source.0, source.1.trim())
+            } else {
+                String::new()
+            };
+            last = Some(source);
+            let row = format!("{:>10}  0x{:08x}  {:<32}{}", count, pc, format!("{:?}", instr), text);
+            let _ = writeln!(out, "{}", row.trim_end());
+        }
+        out
+    }
+    // A profile in callgrind's format, for KCachegrind and callgrind_annotate, with costs by
+    // instruction address and line of file. path is the file assembled, included files are named
+    // relative to it.
+    pub fn callgrind(&self, program: &Program, path: &str) -> String {
+        let line = |pc: u32| program.lines.get(&pc).map_or(0, |pos| pos.line);
+        let file = |pc: u32| match program.lines.get(&pc).and_then(|pos| pos.file.as_ref()) {
+            Some(name) => Path::new(path).parent().unwrap_or(Path::new("")).join(name).display().to_string(),
+            None => path.to_string(),
+        };
+        let mut out = String::new();
+        let _ = writeln!(out, "# callgrind format\nversion: 1\ncreator: mipsemu\npositions: instr line\nevents: Ir");
+        let _ = writeln!(out, "summary: {}", self.total);
+        let mut current = None;
+        let mut current_file = String::new();
+        for pc in program.text.keys() {
+            let Some(entry) = self.function_of(*pc) else {
+                continue;
+            };
+            let count = self.counts.get(pc).copied().unwrap_or(0);
+            let calls: Vec<(&(u32, u32), &Calls)> = self.calls.range((*pc, 0)..=(*pc, u32::MAX)).collect();
+            if count == 0 && calls.is_empty() {
+                continue;
+            }
+            if current != Some(entry) {
+                current_file = file(entry);
+                let _ = writeln!(out, "\nfl={}\nfn={}", current_file, self.name(entry));
+                current = Some(entry);
+            }
+            // Functions may run into lines of another file, e.g. through a macro defined there
+            if file(*pc) != current_file {
+                current_file = file(*pc);
+                let _ = writeln!(out, "fi={}", current_file);
+            }
+            if count > 0 {
+                let _ = writeln!(out, "0x{:08x} {} {}", pc, line(*pc), count);
+            }
+            for ((_, callee), calls) in calls {
+                let _ = writeln!(out, "cfi={}\ncfn={}", file(*callee), self.name(*callee));
+                let _ = writeln!(out, "calls={} 0x{:08x} {}", calls.count, callee, line(*callee));
+                let _ = writeln!(out, "0x{:08x} {} {}", pc, line(*pc), calls.inclusive);
+            }
+        }
+        out
+    }
+}
+
+impl CPU {
+    // The profile so far, if profiling
+    pub fn profile(&self, program: &Program) -> Option<Profile> {
+        let mut profiler = self.profiler.clone()?;
+        let total = self.limits.instructions;
+        // Calls still in progress are charged as if they returned now, innermost first
+        for frame in self.call_stack.frames().iter().rev() {
+            profiler.leave(frame, total);
+        }
+        // Functions start where the program and exception handler start and at every call target
+        let mut entries: BTreeSet<u32> = profiler.calls.keys().map(|(_, callee)| *callee).collect();
+        entries.extend(program.text.values().filter_map(|instr| match instr {
+            Instr::Jal{addr} => Some(*addr),
+            _ => None,
+        }));
+        let start = self.stack_frames().last().and_then(|frame| frame.function);
+        entries.extend(start);
+        entries.extend(program.text.keys().next());
+        entries.extend(program.text.range(KTEXT_BASE..).next().map(|(pc, _)| *pc));
+        let mut functions: BTreeMap<u32, Function> = entries.into_iter().map(|entry| {
+            let name = program.label_at(entry).map_or(format!("0x{:08x}", entry), |name| name.to_string());
+            let inclusive = profiler.inclusive.get(&entry).copied().unwrap_or(0);
+            let calls = profiler.calls.iter().filter(|((_, callee), _)| *callee == entry).map(|(_, calls)| calls.count).sum();
+            (entry, Function { name, exclusive: 0, inclusive, calls })
+        }).collect();
+        // The function the program started in is running throughout
+        if let Some(f) = start.and_then(|start| functions.get_mut(&start)) {
+            f.inclusive = total;
+        }
+        for (pc, count) in &profiler.counts {
+            if let Some((_, f)) = functions.range_mut(..=*pc).next_back() {
+                f.exclusive += count;
+            }
+        }
+        // Code run from outside any call, such as the exception handler, is its own inclusive cost
+        for f in functions.values_mut() {
+            f.inclusive = f.inclusive.max(f.exclusive);
+        }
+        Some(Profile { total, counts: profiler.counts, calls: profiler.calls.into_iter().collect(), functions })
+    }
+}
@@ -0,0 +1,352 @@
+use crate::isa::*;
+
+// The assembler temporary, reserved for pseudo-instruction expansions
+pub const AT: u32 = 1;
+
+// Second operand of a pseudo branch, which may be a register or a constant
+#[derive(Clone, Copy, Debug)]
+pub enum Operand {
+    Reg(u32),
+    Imm(u32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cond {
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Gtu,
+    Geu,
+    Ltu,
+    Leu,
+}
+
+// Pseudo-instructions, each of which expands to a fixed number of real instructions so that
+// label addresses are known after the first assembler pass
+#[derive(Debug)]
+pub enum Pseudo {
+    Move{rd: u32, rs: u32},
+    Not{rd: u32, rs: u32},
+    Neg{rd: u32, rs: u32},
+    Negu{rd: u32, rs: u32},
+    Abs{rd: u32, rs: u32},
+    Li{rt: u32, immd: u32},
+    La{rt: u32, label: String},
+    // Three operand division and remainder
+    Div{rd: u32, rs: u32, rt: u32},
+    Divu{rd: u32, rs: u32, rt: u32},
+    Rem{rd: u32, rs: u32, rt: u32},
+    Remu{rd: u32, rs: u32, rt: u32},
+    // Multiplication that breaks on overflow
+    Mulo{rd: u32, rs: u32, rt: u32},
+    Mulou{rd: u32, rs: u32, rt: u32},
+    // Set rd to 1 if the comparison holds (seq, sne, sgt, sge, sle and unsigned versions)
+    Set{cond: Cond, rd: u32, rs: u32, rt: u32},
+    // Conditional branches the hardware lacks, including b, beqz and branches against constants
+    Branch{cond: Cond, rs: u32, rt: Operand, label: String},
+    Bal{label: String},
+    // Load or store addressed by a label, e.g. lw $t0, value, optionally plus the base register
+    // already in the instruction, e.g. lw $t0, table($t1)
+    LabelAccess{instr: Instr, label: String},
+    // Load or store whose offset does not fit in 16 bits
+    WideOffset{instr: Instr},
+    // Immediate arithmetic or logical instruction whose constant does not fit in 16 bits
+    WideImmediate{instr: Instr},
+}
+
+fn fits_signed(value: u32) -> bool {
+    (value as i32) >= i16::MIN as i32 && (value as i32) <= i16::MAX as i32
+}
+// Upper half of an address for use with a sign extended lower half, as in lui/lw pairs
+fn high_adjusted(value: u32) -> u32 {
+    value.wrapping_add(0x8000) >> 16
+}
+fn low_signed(value: u32) -> u32 {
+    value as u16 as i16 as u32
+}
+
+// Loads a 32 bit constant with as few instructions as possible
+fn load_immediate(rt: u32, value: u32) -> Vec<Instr> {
+    if fits_signed(value) {
+        vec![Instr::Addiu{rt, rs: 0, immd: value}]
+    } else if value <= 0xffff {
+        vec![Instr::Ori{rt, rs: 0, immd: value}]
+    } else {
+        vec![Instr::Lui{rt: AT, immd: value >> 16}, Instr::Ori{rt, rs: AT, immd: value & 0xffff}]
+    }
+}
+
+// Computes an ordering comparison into rd with slt/sltu. The flag says whether the result is
+// the inverse of the condition, which saves an instruction for ge and le.
+fn ordering(cond: Cond, rd: u32, rs: u32, rt: u32) -> (Instr, bool) {
+    match cond {
+        Cond::Gt => (Instr::Slt{rd, rs: rt, rt: rs}, false),
+        Cond::Lt => (Instr::Slt{rd, rs, rt}, false),
+        Cond::Ge => (Instr::Slt{rd, rs, rt}, true),
+        Cond::Le => (Instr::Slt{rd, rs: rt, rt: rs}, true),
+        Cond::Gtu => (Instr::Sltu{rd, rs: rt, rt: rs}, false),
+        Cond::Ltu => (Instr::Sltu{rd, rs, rt}, false),
+        Cond::Geu => (Instr::Sltu{rd, rs, rt}, true),
+        Cond::Leu => (Instr::Sltu{rd, rs: rt, rt: rs}, true),
+        Cond::Eq | Cond::Ne => unreachable!(),
+    }
+}
+
+impl Pseudo {
+    // Number of real instructions the expansion takes
+    pub fn len(&self) -> u32 {
+        match self {
+            Pseudo::Li{rt, immd} => load_immediate(*rt, *immd).len() as u32,
+            Pseudo::Branch{cond, rt, ..} => {
+                let load = match rt {
+                    Operand::Imm(value) => load_immediate(AT, *value).len() as u32,
+                    Operand::Reg(_) => 0,
+                };
+                let compare = match cond {
+                    Cond::Eq | Cond::Ne => 1,
+                    _ => 2,
+                };
+                load + compare
+            }
+            Pseudo::Move{..} | Pseudo::Not{..} | Pseudo::Neg{..} | Pseudo::Negu{..} | Pseudo::Bal{..} => 1,
+            Pseudo::Set{cond, ..} => match cond {
+                Cond::Gt | Cond::Lt | Cond::Gtu | Cond::Ltu => 1,
+                _ => 2,
+            },
+            Pseudo::LabelAccess{instr, ..} => match instr.memory_operand() {
+                Some((0, _)) => 2,
+                _ => 3,
+            },
+            Pseudo::La{..} | Pseudo::Div{..} | Pseudo::Divu{..} | Pseudo::Rem{..} | Pseudo::Remu{..} => 2,
+            Pseudo::Abs{..} | Pseudo::WideOffset{..} | Pseudo::WideImmediate{..} => 3,
+            Pseudo::Mulou{..} => 6,
+            Pseudo::Mulo{..} => 8,
+        }
+    }
+    // Expands into real instructions, the first of which is placed at pc
+    pub fn expand(&self, pc: u32, find_label: &dyn Fn(&String) -> Result<u32, String>) -> Result<Vec<Instr>, String> {
+        let expansion = match self {
+            Pseudo::Move{rd, rs} => vec![Instr::Addu{rd: *rd, rs: 0, rt: *rs}],
+            Pseudo::Not{rd, rs} => vec![Instr::Nor{rd: *rd, rs: *rs, rt: 0}],
+            Pseudo::Neg{rd, rs} => vec![Instr::Sub{rd: *rd, rs: 0, rt: *rs}],
+            Pseudo::Negu{rd, rs} => vec![Instr::Subu{rd: *rd, rs: 0, rt: *rs}],
+            Pseudo::Abs{rd, rs} => vec![
+                Instr::Sra{rd: AT, rs: *rs, shamt: 31},
+                Instr::Xor{rd: *rd, rs: AT, rt: *rs},
+                Instr::Subu{rd: *rd, rs: *rd, rt: AT},
+            ],
+            Pseudo::Li{rt, immd} => load_immediate(*rt, *immd),
+            Pseudo::La{rt, label} => {
+                let addr = find_label(label)?;
+                vec![Instr::Lui{rt: AT, immd: addr >> 16}, Instr::Ori{rt: *rt, rs: AT, immd: addr & 0xffff}]
+            }
+            Pseudo::Div{rd, rs, rt} => vec![Instr::Div{rs: *rs, rt: *rt}, Instr::Mflo{rd: *rd}],
+            Pseudo::Divu{rd, rs, rt} => vec![Instr::Divu{rs: *rs, rt: *rt}, Instr::Mflo{rd: *rd}],
+            Pseudo::Rem{rd, rs, rt} => vec![Instr::Div{rs: *rs, rt: *rt}, Instr::Mfhi{rd: *rd}],
+            Pseudo::Remu{rd, rs, rt} => vec![Instr::Divu{rs: *rs, rt: *rt}, Instr::Mfhi{rd: *rd}],
+            // The product fits if HI is the sign extension of LO. The branch skips its nop delay
+            // slot and the break.
+            Pseudo::Mulo{rd, rs, rt} => vec![
+                Instr::Mult{rs: *rs, rt: *rt},
+                Instr::Mfhi{rd: AT},
+                Instr::Mflo{rd: *rd},
+                Instr::Sra{rd: *rd, rs: *rd, shamt: 31},
+                Instr::Beq{rt: AT, rs: *rd, rel_addr: 2},
+                Instr::Nop,
+                Instr::Break{code: 0},
+                Instr::Mflo{rd: *rd},
+            ],
+            Pseudo::Mulou{rd, rs, rt} => vec![
+                Instr::Multu{rs: *rs, rt: *rt},
+                Instr::Mfhi{rd: AT},
+                Instr::Beq{rt: AT, rs: 0, rel_addr: 2},
+                Instr::Nop,
+                Instr::Break{code: 0},
+                Instr::Mflo{rd: *rd},
+            ],
+            Pseudo::Set{cond, rd, rs, rt} => match cond {
+                Cond::Eq => vec![Instr::Subu{rd: *rd, rs: *rs, rt: *rt}, Instr::Sltiu{rt: *rd, rs: *rd, immd: 1}],
+                Cond::Ne => vec![Instr::Subu{rd: *rd, rs: *rs, rt: *rt}, Instr::Sltu{rd: *rd, rs: 0, rt: *rd}],
+                _ => {
+                    let (compare, inverted) = ordering(*cond, *rd, *rs, *rt);
+                    let mut instrs = vec![compare];
+                    if inverted {
+                        instrs.push(Instr::Xori{rt: *rd, rs: *rd, immd: 1});
+                    }
+                    instrs
+                }
+            },
+            Pseudo::Branch{cond, rs, rt, label} => {
+                let mut instrs = vec![];
+                let rt = match rt {
+                    Operand::Reg(rt) => *rt,
+                    Operand::Imm(value) => {
+                        instrs.extend(load_immediate(AT, *value));
+                        AT
+                    }
+                };
+                // The branch is the last instruction, its offset is relative to its delay slot
+                let branch_pc = pc + 4 * (self.len() - 1);
+                let rel_addr = (find_label(label)?.wrapping_sub(branch_pc + 4) as i32) / 4;
+                match cond {
+                    Cond::Eq => instrs.push(Instr::Beq{rt: *rs, rs: rt, rel_addr}),
+                    Cond::Ne => instrs.push(Instr::Bne{rt: *rs, rs: rt, rel_addr}),
+                    _ => {
+                        let (compare, inverted) = ordering(*cond, AT, *rs, rt);
+                        instrs.push(compare);
+                        instrs.push(if inverted {
+                            Instr::Beq{rt: AT, rs: 0, rel_addr}
+                        } else {
+                            Instr::Bne{rt: AT, rs: 0, rel_addr}
+                        });
+                    }
+                }
+                instrs
+            }
+            Pseudo::Bal{label} => {
+                let rel_addr = (find_label(label)?.wrapping_sub(pc + 4) as i32) / 4;
+                vec![Instr::Bgezal{rs: 0, rel_addr}]
+            }
+            Pseudo::LabelAccess{instr, label} => {
+                let addr = find_label(label)?;
+                let mut instrs = vec![Instr::Lui{rt: AT, immd: high_adjusted(addr)}];
+                match instr.memory_operand() {
+                    Some((0, _)) | None => {}
+                    Some((rs, _)) => instrs.push(Instr::Addu{rd: AT, rs: AT, rt: rs}),
+                }
+                instrs.push(instr.with_address(AT, low_signed(addr)));
+                instrs
+            }
+            Pseudo::WideOffset{instr} => {
+                let (rs, offset) = instr.memory_operand().unwrap();
+                vec![
+                    Instr::Lui{rt: AT, immd: high_adjusted(offset)},
+                    Instr::Addu{rd: AT, rs: AT, rt: rs},
+                    instr.with_address(AT, low_signed(offset)),
+                ]
+            }
+            Pseudo::WideImmediate{instr} => {
+                let immd = match instr {
+                    Instr::Addi{immd, ..} | Instr::Addiu{immd, ..} | Instr::Andi{immd, ..} | Instr::Ori{immd, ..} |
+                    Instr::Xori{immd, ..} | Instr::Slti{immd, ..} | Instr::Sltiu{immd, ..} => *immd,
+                    _ => unreachable!(),
+                };
+                vec![
+                    Instr::Lui{rt: AT, immd: immd >> 16},
+                    Instr::Ori{rt: AT, rs: AT, immd: immd & 0xffff},
+                    instr.with_register_operand(AT).unwrap(),
+                ]
+            }
+        };
+        debug_assert_eq!(expansion.len() as u32, self.len());
+        Ok(expansion)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const T0: u32 = 8;
+    const T1: u32 = 9;
+    const T2: u32 = 10;
+
+    // Machine words of an expansion at 0x00400000, checking it is as long as len says
+    fn expand(pseudo: Pseudo) -> Vec<u32> {
+        let find_label = |label: &String| match label.as_str() {
+            "target" => Ok(0x0040_0020),
+            "value" => Ok(0x1001_8004),
+            "high" => Ok(0x1001_c004),
+            _ => Err(format!("Unknown label {}", label)),
+        };
+        let instrs = pseudo.expand(0x0040_0000, &find_label).unwrap();
+        assert_eq!(instrs.len() as u32, pseudo.len());
+        instrs.iter().map(|instr| instr.encode()).collect()
+    }
+
+    fn words(instrs: &[Instr]) -> Vec<u32> {
+        instrs.iter().map(|instr| instr.encode()).collect()
+    }
+
+    #[test]
+    fn li_takes_as_few_instructions_as_it_can() {
+        assert_eq!(expand(Pseudo::Li{rt: T0, immd: -5i32 as u32}), words(&[Instr::Addiu{rt: T0, rs: 0, immd: -5i32 as u32}]));
+        assert_eq!(expand(Pseudo::Li{rt: T0, immd: 0xbeef}), words(&[Instr::Ori{rt: T0, rs: 0, immd: 0xbeef}]));
+        assert_eq!(expand(Pseudo::Li{rt: T0, immd: 0xdead_beef}),
+            words(&[Instr::Lui{rt: AT, immd: 0xdead}, Instr::Ori{rt: T0, rs: AT, immd: 0xbeef}]));
+    }
+
+    #[test]
+    fn la() {
+        assert_eq!(expand(Pseudo::La{rt: T0, label: "value".to_string()}),
+            words(&[Instr::Lui{rt: AT, immd: 0x1001}, Instr::Ori{rt: T0, rs: AT, immd: 0x8004}]));
+    }
+
+    #[test]
+    fn label_access_adjusts_for_a_negative_low_half() {
+        let lw = Instr::Lw{rt: T0, rs: 0, immd: 0};
+        assert_eq!(expand(Pseudo::LabelAccess{instr: lw, label: "value".to_string()}),
+            words(&[Instr::Lui{rt: AT, immd: 0x1002}, Instr::Lw{rt: T0, rs: AT, immd: 0xffff_8004}]));
+        let sw = Instr::Sw{rt: T0, rs: T1, immd: 0};
+        assert_eq!(expand(Pseudo::LabelAccess{instr: sw, label: "high".to_string()}), words(&[
+            Instr::Lui{rt: AT, immd: 0x1002},
+            Instr::Addu{rd: AT, rs: AT, rt: T1},
+            Instr::Sw{rt: T0, rs: AT, immd: 0xffff_c004},
+        ]));
+    }
+
+    #[test]
+    fn wide_immediate_goes_through_at() {
+        let addiu = Instr::Addiu{rt: T0, rs: T1, immd: 0x12345};
+        assert_eq!(expand(Pseudo::WideImmediate{instr: addiu}), words(&[
+            Instr::Lui{rt: AT, immd: 1},
+            Instr::Ori{rt: AT, rs: AT, immd: 0x2345},
+            Instr::Addu{rd: T0, rs: T1, rt: AT},
+        ]));
+    }
+
+    #[test]
+    fn set() {
+        assert_eq!(expand(Pseudo::Set{cond: Cond::Eq, rd: T0, rs: T1, rt: T2}),
+            words(&[Instr::Subu{rd: T0, rs: T1, rt: T2}, Instr::Sltiu{rt: T0, rs: T0, immd: 1}]));
+        assert_eq!(expand(Pseudo::Set{cond: Cond::Gt, rd: T0, rs: T1, rt: T2}), words(&[Instr::Slt{rd: T0, rs: T2, rt: T1}]));
+        assert_eq!(expand(Pseudo::Set{cond: Cond::Geu, rd: T0, rs: T1, rt: T2}),
+            words(&[Instr::Sltu{rd: T0, rs: T1, rt: T2}, Instr::Xori{rt: T0, rs: T0, immd: 1}]));
+    }
+
+    #[test]
+    fn branch_offset_is_from_the_last_instruction() {
+        // blt $t0, 0x12345, target: lui, ori and slt come first, the bne is at 0x0040000c
+        let branch = Pseudo::Branch{cond: Cond::Lt, rs: T0, rt: Operand::Imm(0x12345), label: "target".to_string()};
+        assert_eq!(expand(branch), words(&[
+            Instr::Lui{rt: AT, immd: 1},
+            Instr::Ori{rt: AT, rs: AT, immd: 0x2345},
+            Instr::Slt{rd: AT, rs: T0, rt: AT},
+            Instr::Bne{rt: AT, rs: 0, rel_addr: 4},
+        ]));
+        let branch = Pseudo::Branch{cond: Cond::Le, rs: T0, rt: Operand::Reg(T1), label: "target".to_string()};
+        assert_eq!(expand(branch), words(&[Instr::Slt{rd: AT, rs: T1, rt: T0}, Instr::Beq{rt: AT, rs: 0, rel_addr: 6}]));
+        let branch = Pseudo::Branch{cond: Cond::Ne, rs: T0, rt: Operand::Imm(3), label: "target".to_string()};
+        assert_eq!(expand(branch), words(&[Instr::Addiu{rt: AT, rs: 0, immd: 3}, Instr::Bne{rt: T0, rs: AT, rel_addr: 6}]));
+    }
+
+    #[test]
+    fn overflow_checks_skip_the_break() {
+        let mulou = expand(Pseudo::Mulou{rd: T0, rs: T1, rt: T2});
+        assert_eq!(mulou[2], Instr::Beq{rt: AT, rs: 0, rel_addr: 2}.encode());
+        assert_eq!(mulou[4], Instr::Break{code: 0}.encode());
+        let mulo = expand(Pseudo::Mulo{rd: T0, rs: T1, rt: T2});
+        assert_eq!(mulo[6], Instr::Break{code: 0}.encode());
+        assert_eq!(mulo[7], Instr::Mflo{rd: T0}.encode());
+    }
+
+    #[test]
+    fn far_pseudo_branch_is_rejected() {
+        let source = "blt $t0, 5, far\n.text 0x00440000\nfar: nop";
+        let error = crate::asm::assemble(source, std::path::Path::new("test.s"), IsaLevel::Mips32r2).err().expect("assembled");
+        assert!(error.contains("out of range"), "{}", error);
+    }
+}
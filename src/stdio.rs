@@ -0,0 +1,60 @@
+// The program's standard streams: the host's, or for tests, input from a buffer with the output
+// captured
+use std::io::{self, BufRead, Cursor, Read, Write};
+
+#[derive(Default)]
+pub struct Stdio {
+    // Input to read instead of the host's stdin
+    input: Option<Cursor<Vec<u8>>>,
+    // stdout and stderr, when captured instead of written to the host's
+    captured: Option<[Vec<u8>; 2]>,
+}
+impl Stdio {
+    // Reads from the given input and captures everything written
+    pub fn captured(input: &[u8]) -> Stdio {
+        Stdio { input: Some(Cursor::new(input.to_vec())), captured: Some([vec![], vec![]]) }
+    }
+    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.input {
+            Some(input) => input.read(buf),
+            None => {
+                // Show any prompt before waiting for input
+                io::stdout().flush()?;
+                io::stdin().read(buf)
+            }
+        }
+    }
+    // Reads up to and including the next newline, or to the end of the input
+    pub fn read_line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        match &mut self.input {
+            Some(input) => input.read_line(&mut line)?,
+            None => {
+                io::stdout().flush()?;
+                io::stdin().lock().read_line(&mut line)?
+            }
+        };
+        Ok(line)
+    }
+    // Writes to stdout (fd 1) or stderr (fd 2)
+    pub fn write(&mut self, fd: u32, bytes: &[u8]) -> io::Result<()> {
+        match (&mut self.captured, fd) {
+            (Some([_, err]), 2) => err.extend_from_slice(bytes),
+            (Some([out, _]), _) => out.extend_from_slice(bytes),
+            (None, 2) => io::stderr().write_all(bytes)?,
+            (None, _) => {
+                let mut out = io::stdout();
+                out.write_all(bytes)?;
+                out.flush()?;
+            }
+        }
+        Ok(())
+    }
+    // Everything captured from stdout and stderr so far
+    pub fn stdout(&self) -> &[u8] {
+        self.captured.as_ref().map(|[out, _]| out.as_slice()).unwrap_or(&[])
+    }
+    pub fn stderr(&self) -> &[u8] {
+        self.captured.as_ref().map(|[_, err]| err.as_slice()).unwrap_or(&[])
+    }
+}
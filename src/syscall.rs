@@ -0,0 +1,147 @@
+use crate::bitmap::DUMP_FRAME_SERVICE;
+use crate::cpu::CPU;
+use crate::cp0::ExceptionCode;
+use crate::limits::IO_CHUNK;
+use crate::vfs::OpenFlags;
+
+// Register numbers used by the syscall calling convention
+const V0: u32 = 2;
+const A0: u32 = 4;
+const A1: u32 = 5;
+const A2: u32 = 6;
+
+impl CPU {
+    fn read_line(&mut self) -> Result<String, String> {
+        self.stdio.read_line().map_err(|e| format!("Could not read input: {}", e))
+    }
+    fn read_string(&self, mut addr: u32) -> Vec<u8> {
+        let mut bytes = vec![];
+        loop {
+            let byte = self.mem.load_byte(addr);
+            if byte == 0 {
+                return bytes;
+            }
+            bytes.push(byte);
+            addr = addr.wrapping_add(1);
+        }
+    }
+    // Services a syscall using the MARS service numbers in $v0
+    pub fn syscall(&mut self) -> Result<(), String> {
+        if self.linux.is_some() {
+            return self.linux_syscall();
+        }
+        let a0 = self.get_reg(A0)?;
+        match self.get_reg(V0)? {
+            // Print integer
+            1 => { let _ = self.write_output(1, (a0 as i32).to_string().as_bytes()); }
+            // Print float and double from $f12
+            2 => { let _ = self.write_output(1, format!("{:?}", self.cp1.get_single(12)).as_bytes()); }
+            3 => { let _ = self.write_output(1, format!("{:?}", self.cp1.get_double(12)?).as_bytes()); }
+            // Print string
+            4 => { let _ = self.write_output(1, &self.read_string(a0)); }
+            // Read integer
+            5 => {
+                let line = self.read_line()?;
+                let value = line.trim().parse::<i32>().map_err(|_| format!("Invalid integer input {}", line.trim()))?;
+                self.set_reg(V0, value as u32)?;
+            }
+            // Read float and double into $f0
+            6 => {
+                let line = self.read_line()?;
+                let value = line.trim().parse::<f32>().map_err(|_| format!("Invalid float input {}", line.trim()))?;
+                self.cp1.set_single(0, value);
+            }
+            7 => {
+                let line = self.read_line()?;
+                let value = line.trim().parse::<f64>().map_err(|_| format!("Invalid double input {}", line.trim()))?;
+                self.cp1.set_double(0, value)?;
+            }
+            // Read string into a buffer of $a1 bytes, including the terminating null
+            8 => {
+                let max = self.get_reg(A1)? as usize;
+                if max == 0 {
+                    return Ok(());
+                }
+                let line = self.read_line()?;
+                let bytes: Vec<u8> = line.bytes().take(max - 1).collect();
+                for (i, byte) in bytes.iter().chain(std::iter::once(&0)).enumerate() {
+                    self.mem.store_byte(a0.wrapping_add(i as u32), *byte);
+                }
+            }
+            // Allocate heap memory (sbrk), keeping the break word aligned
+            9 => {
+                let addr = self.heap_end;
+                if self.heap_allowed(self.heap_size().wrapping_add(a0)) {
+                    self.heap_end = self.heap_end.wrapping_add(a0).next_multiple_of(4);
+                }
+                self.set_reg(V0, addr)?;
+            }
+            // Exit
+            10 => self.exit_code = Some(0),
+            // Print character
+            11 => { let _ = self.write_output(1, &[a0 as u8]); }
+            // Read character
+            12 => {
+                let mut byte = [0u8; 1];
+                let value = match self.stdio.read(&mut byte) {
+                    Ok(1) => byte[0] as u32,
+                    _ => 0,
+                };
+                self.set_reg(V0, value)?;
+            }
+            // Open a file for reading (flags 0), writing (1) or appending (9). Returns the
+            // descriptor, or -1 if the file could not be opened.
+            13 => {
+                let path = String::from_utf8_lossy(&self.read_string(a0)).into_owned();
+                let flags = match self.get_reg(A1)? {
+                    0 => OpenFlags { read: true, ..OpenFlags::default() },
+                    1 => OpenFlags { write: true, create: true, truncate: true, ..OpenFlags::default() },
+                    9 => OpenFlags { write: true, create: true, append: true, ..OpenFlags::default() },
+                    _ => return self.set_reg(V0, -1i32 as u32),
+                };
+                let fd = self.vfs.open(&path, flags).map(|fd| fd as i32).unwrap_or(-1);
+                self.set_reg(V0, fd as u32)?;
+            }
+            // Read from and write to a file: $a1 is the buffer and $a2 the number of bytes. Returns
+            // the number of bytes read or written, 0 at the end of the file, or -1 on an error. Reads
+            // may return fewer bytes than asked for.
+            14 => {
+                let mut bytes = vec![0; self.get_reg(A2)?.min(IO_CHUNK) as usize];
+                let read = match a0 {
+                    0 => self.stdio.read(&mut bytes).map_err(|_| ()),
+                    _ => self.vfs.read(a0, &mut bytes).map_err(|_| ()),
+                };
+                if let Ok(count) = read {
+                    for (i, byte) in bytes[..count].iter().enumerate() {
+                        self.mem.store_byte(self.get_reg(A1)?.wrapping_add(i as u32), *byte);
+                    }
+                }
+                self.set_reg(V0, read.map(|count| count as i32).unwrap_or(-1) as u32)?;
+            }
+            15 => {
+                let written = self.write_from_memory(a0, self.get_reg(A1)?, self.get_reg(A2)?);
+                self.set_reg(V0, written.map(|count| count as i32).unwrap_or(-1) as u32)?;
+            }
+            // Close a file
+            16 => {
+                let _ = self.vfs.close(a0);
+            }
+            // Exit with value
+            17 => self.exit_code = Some(a0 as i32),
+            // Print integer in hex, binary and as unsigned
+            34 => { let _ = self.write_output(1, format!("0x{:08x}", a0).as_bytes()); }
+            35 => { let _ = self.write_output(1, format!("{:032b}", a0).as_bytes()); }
+            36 => { let _ = self.write_output(1, a0.to_string().as_bytes()); }
+            // Capture a frame of the bitmap display, if there is one
+            DUMP_FRAME_SERVICE => {
+                if let Some(bitmap) = &mut self.bitmap {
+                    bitmap.dump(&self.mem)?;
+                }
+            }
+            service => {
+                return Err(self.raise(ExceptionCode::Syscall, None, format!("Unknown syscall service {}", service)));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+// Detects uses of values that were never initialized. Registers start at 0 and untouched memory
+// reads as 0, which hides bugs, so a shadow bit per register, HI, LO and memory byte records
+// whether it holds a defined value: written by the program, its data segment, a syscall or the
+// loader. Results are defined when everything they were computed from was. Undefined values are
+// reported where they decide something, as a branch condition, jump target, load or store address,
+// or syscall argument; in strict mode these stop the program.
+use crate::asm::Program;
+use crate::cpu::CPU;
+use crate::diagnostics::Diagnostics;
+use crate::isa::{reg_as_str, Instr};
+
+const HI: u32 = 32;
+const LO: u32 = 33;
+const V0: u32 = 2;
+const A0: u32 = 4;
+
+pub struct UninitChecker {
+    // A bit per general purpose register, then HI and LO
+    defined: u64,
+    // Whether uses are errors rather than warnings
+    pub strict: bool,
+    pub warnings: Diagnostics,
+}
+impl UninitChecker {
+    pub fn new(strict: bool) -> UninitChecker {
+        // $zero, and $gp and $sp as set up by the loader
+        UninitChecker { defined: 1 | 1 << 28 | 1 << 29, strict, warnings: Diagnostics::default() }
+    }
+    pub fn is_defined(&self, reg: u32) -> bool {
+        reg == 0 || self.defined & (1 << reg) != 0
+    }
+    pub fn set_defined(&mut self, reg: u32, value: bool) {
+        if value {
+            self.defined |= 1 << reg;
+        } else {
+            self.defined &= !(1 << reg);
+        }
+    }
+}
+
+// Bytes accessed by a load or store into or from a general purpose register, and whether it stores
+fn access(instr: &Instr) -> Option<(u32, bool)> {
+    match instr {
+        Instr::Lw{..} | Instr::Ll{..} => Some((4, false)),
+        Instr::Lh{..} | Instr::Lhu{..} => Some((2, false)),
+        Instr::Lb{..} | Instr::Lbu{..} => Some((1, false)),
+        Instr::Sw{..} | Instr::Sc{..} => Some((4, true)),
+        Instr::Sh{..} => Some((2, true)),
+        Instr::Sb{..} => Some((1, true)),
+        _ => None,
+    }
+}
+
+// Argument registers read by each MARS syscall service
+fn syscall_args(service: u32) -> u32 {
+    match service {
+        1 | 4 | 9 | 11 | 16 | 17 | 32 | 34 | 35 | 36 => 1,
+        8 => 2,
+        13..=15 => 3,
+        _ => 0,
+    }
+}
+
+// What an instruction does to the shadow state, worked out before it runs
+pub(crate) struct Effect {
+    // Whether the register it writes will be defined
+    result: bool,
+    // New state of HI and LO, for instructions that write them
+    hi_lo: Option<(bool, bool)>,
+    // Bytes a store writes and whether the value stored is defined
+    store: Option<(u32, u32, bool)>,
+}
+
+impl CPU {
+    // Reports uses of undefined values by an instruction about to run, returning an error in strict
+    // mode, along with the effect it will have on the shadow state
+    pub(crate) fn check_uninit(&mut self, instr: &Instr, pc: u32, program: &Program) -> Result<Option<Effect>, String> {
+        let Some(checker) = &self.uninit else {
+            return Ok(None);
+        };
+        let defined = |reg: u32| checker.is_defined(reg);
+        let mut uses: Vec<(u32, String)> = vec![];
+        match instr {
+            Instr::Beq{..} | Instr::Bne{..} | Instr::Bgez{..} | Instr::Bgtz{..} | Instr::Blez{..} | Instr::Bltz{..} |
+            Instr::Bgezal{..} | Instr::Bltzal{..} => {
+                uses.extend(instr.reads().into_iter().map(|reg| (reg, "branch condition".to_string())));
+            }
+            Instr::Jr{rd} | Instr::Jalr{rs: rd, ..} => uses.push((*rd, "jump target".to_string())),
+            Instr::Syscall => {
+                let service = self.reg[V0 as usize];
+                uses.push((V0, "syscall number".to_string()));
+                // Linux syscalls take different arguments, only the number is checked
+                if self.linux.is_none() && defined(V0) {
+                    uses.extend((A0..A0 + syscall_args(service)).map(|reg| (reg, format!("syscall {} argument", service))));
+                }
+            }
+            _ => {}
+        }
+        if let Some((base, _)) = instr.memory_operand() {
+            uses.push((base, "address".to_string()));
+        }
+        let undefined: Vec<(u32, String)> = uses.into_iter().filter(|(reg, _)| !defined(*reg)).collect();
+        let sources = instr.reads().iter().all(|reg| defined(*reg));
+        let hi_lo = (defined(HI), defined(LO));
+        let mut effect = Effect { result: sources, hi_lo: None, store: None };
+        match *instr {
+            Instr::Mfhi{..} => effect.result = hi_lo.0,
+            Instr::Mflo{..} => effect.result = hi_lo.1,
+            Instr::Mult{..} | Instr::Multu{..} | Instr::Div{..} | Instr::Divu{..} => effect.hi_lo = Some((sources, sources)),
+            Instr::Madd{..} | Instr::Maddu{..} | Instr::Msub{..} | Instr::Msubu{..} => {
+                effect.hi_lo = Some((sources && hi_lo.0, sources && hi_lo.1));
+            }
+            Instr::Mthi{..} => effect.hi_lo = Some((sources, hi_lo.1)),
+            Instr::Mtlo{..} => effect.hi_lo = Some((hi_lo.0, sources)),
+            // Links, constants and coprocessor registers, which aren't tracked
+            Instr::Jal{..} | Instr::Jalr{..} | Instr::Bgezal{..} | Instr::Bltzal{..} | Instr::Lui{..} |
+            Instr::Mfc0{..} | Instr::Mfc1{..} | Instr::Sc{..} => effect.result = true,
+            // Clearing a register by combining it with itself
+            Instr::Xor{rs, rt, ..} | Instr::Subu{rs, rt, ..} | Instr::Sub{rs, rt, ..} if rs == rt => effect.result = true,
+            _ => {}
+        }
+        if let (Some((size, store)), Some((base, offset))) = (access(instr), instr.memory_operand()) {
+            let addr = self.reg[base as usize].wrapping_add(offset);
+            if store {
+                // Stores read their base register and then the register stored
+                effect.store = Some((addr, size, defined(instr.reads()[1])));
+            } else {
+                let bytes = (0..size).all(|i| self.mem.is_defined(addr.wrapping_add(i)) || self.bus.is_mapped(addr.wrapping_add(i)));
+                effect.result = defined(base) && bytes;
+            }
+        }
+        let Some(checker) = &mut self.uninit else { unreachable!() };
+        for (reg, what) in undefined {
+            let message = format!("{} uses uninitialized {}", what, reg_as_str(&reg));
+            if checker.strict {
+                let location = program.source.get(&pc).map(|(location, text)| format!("{}: {}", location, text.trim()))
+                    .unwrap_or(format!("0x{:08x}", pc));
+                return Err(format!("{} ({})", message, location));
+            }
+            checker.warnings.report(pc, format!("{} {}", what, reg), message);
+        }
+        Ok(Some(effect))
+    }
+    // Updates the shadow state once an instruction has run
+    pub(crate) fn apply_uninit(&mut self, instr: &Instr, effect: Option<Effect>) {
+        let (Some(effect), Some(checker)) = (effect, &mut self.uninit) else {
+            return;
+        };
+        if let Some(reg) = instr.writes() {
+            checker.set_defined(reg, effect.result);
+        }
+        if let Some((hi, lo)) = effect.hi_lo {
+            checker.set_defined(HI, hi);
+            checker.set_defined(LO, lo);
+        }
+        // A failed sc stores nothing
+        let stored = match *instr {
+            Instr::Sc{rt, ..} => self.reg[rt as usize] == 1,
+            _ => true,
+        };
+        if let Some((addr, size, value)) = effect.store.filter(|_| stored) {
+            for i in 0..size {
+                self.mem.set_defined(addr.wrapping_add(i), value);
+            }
+        }
+    }
+}
@@ -0,0 +1,372 @@
+// The filesystem programs see through the file syscalls, so untrusted programs can be kept away
+// from the host's files: an in-memory filesystem, optionally loaded from a tar archive, or a host
+// directory the program cannot leave, which may be read-only. Descriptors 0 to 2 are the standard
+// streams and are handled by the syscalls themselves, files opened here start at 3.
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VfsError {
+    NotFound,
+    PermissionDenied,
+    AlreadyExists,
+    IsADirectory,
+    BadDescriptor,
+    TooManyFiles,
+    // The limit on bytes written has been reached
+    QuotaExceeded,
+    Io,
+}
+impl VfsError {
+    pub(crate) fn from_io(e: &io::Error) -> VfsError {
+        match e.kind() {
+            io::ErrorKind::NotFound => VfsError::NotFound,
+            io::ErrorKind::PermissionDenied => VfsError::PermissionDenied,
+            io::ErrorKind::AlreadyExists => VfsError::AlreadyExists,
+            io::ErrorKind::IsADirectory => VfsError::IsADirectory,
+            _ => VfsError::Io,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OpenFlags {
+    pub read: bool,
+    pub write: bool,
+    pub append: bool,
+    pub create: bool,
+    pub truncate: bool,
+    // Fail if the file already exists, with create
+    pub exclusive: bool,
+}
+
+pub struct Metadata {
+    pub size: u64,
+    pub is_dir: bool,
+    // Seconds since the epoch
+    pub modified: u32,
+}
+
+enum Backend {
+    // Files by normalized absolute path
+    Memory(HashMap<String, Vec<u8>>),
+    Host { root: PathBuf, read_only: bool },
+}
+
+enum Handle {
+    Memory { path: String, pos: usize, flags: OpenFlags },
+    Host(File),
+}
+
+pub struct Vfs {
+    backend: Backend,
+    files: HashMap<u32, Handle>,
+    max_open: usize,
+    max_written: Option<u64>,
+    written: u64,
+}
+
+// Resolves a path against / without ever going above it, as chroot does
+fn normalize(path: &str) -> String {
+    let mut parts: Vec<&str> = vec![];
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_str().unwrap_or("")),
+            Component::ParentDir => {
+                parts.pop();
+            }
+            _ => {}
+        }
+    }
+    format!("/{}", parts.join("/"))
+}
+
+// Regular files of a tar archive, by normalized path
+fn read_tar(bytes: &[u8]) -> Result<HashMap<String, Vec<u8>>, String> {
+    let mut files = HashMap::new();
+    let mut pos = 0;
+    while pos + 512 <= bytes.len() {
+        let header = &bytes[pos..pos + 512];
+        // The archive ends with zero filled blocks
+        if header.iter().all(|b| *b == 0) {
+            break;
+        }
+        let field = |range: std::ops::Range<usize>| {
+            let raw = &header[range];
+            String::from_utf8_lossy(&raw[..raw.iter().position(|b| *b == 0).unwrap_or(raw.len())]).into_owned()
+        };
+        let size_field = field(124..136);
+        let size = usize::from_str_radix(size_field.trim(), 8).map_err(|_| format!("Invalid size {} in tar archive", size_field))?;
+        // ustar splits long names into a prefix and a name
+        let name = match field(345..500) {
+            prefix if !prefix.is_empty() && &header[257..262] == b"ustar" => format!("{}/{}", prefix, field(0..100)),
+            _ => field(0..100),
+        };
+        let start = pos + 512;
+        let end = start.checked_add(size).filter(|end| *end <= bytes.len()).ok_or("Truncated tar archive")?;
+        if matches!(header[156], b'0' | 0) {
+            files.insert(normalize(&name), bytes[start..end].to_vec());
+        }
+        pos = start + size.next_multiple_of(512);
+    }
+    Ok(files)
+}
+
+impl Vfs {
+    // Parses a filesystem spec: "memory", "dir=<path>" with ",ro" for read-only or "tar=<file>",
+    // followed by optional limits "files=<n>" on open files and "written=<bytes>" on bytes written
+    pub fn from_str(spec: &str) -> Result<Vfs, String> {
+        let mut fields = spec.split(',');
+        let kind = fields.next().unwrap_or("");
+        let backend = match kind.split_once('=') {
+            None if kind == "memory" => Backend::Memory(HashMap::new()),
+            Some(("dir", path)) => {
+                let root = std::fs::canonicalize(path).map_err(|e| format!("Cannot use {} as the filesystem root: {}", path, e))?;
+                Backend::Host { root, read_only: false }
+            }
+            Some(("tar", file)) => {
+                let bytes = std::fs::read(file).map_err(|e| format!("Cannot read tar archive {}: {}", file, e))?;
+                Backend::Memory(read_tar(&bytes)?)
+            }
+            _ => return Err(format!("Unknown filesystem {}, expected memory, dir=<path> or tar=<file>", spec)),
+        };
+        let mut vfs = Vfs::new(backend);
+        let parse_num = |s: &str| s.parse::<u64>().map_err(|_| format!("Invalid filesystem parameter value {}", s));
+        for field in fields {
+            match field.split_once('=') {
+                None if field == "ro" => match &mut vfs.backend {
+                    Backend::Host { read_only, .. } => *read_only = true,
+                    Backend::Memory(_) => return Err("Only dir filesystems can be read-only".to_string()),
+                },
+                Some(("files", value)) => vfs.max_open = parse_num(value)? as usize,
+                Some(("written", value)) => vfs.max_written = Some(parse_num(value)?),
+                _ => return Err(format!("Unknown filesystem parameter {}", field)),
+            }
+        }
+        Ok(vfs)
+    }
+    fn new(backend: Backend) -> Vfs {
+        Vfs { backend, files: HashMap::new(), max_open: 1024, max_written: None, written: 0 }
+    }
+    // An empty in-memory filesystem
+    pub fn memory() -> Vfs {
+        Vfs::new(Backend::Memory(HashMap::new()))
+    }
+    // The host's current directory, with the program kept inside it
+    pub fn current_dir() -> Vfs {
+        // Canonical, as the paths it is compared against are
+        let root = std::env::current_dir().and_then(std::fs::canonicalize).unwrap_or_default();
+        Vfs::new(Backend::Host { root, read_only: false })
+    }
+    // Where a path lives on the host, refusing any that a symlink takes out of the root
+    fn host_path(root: &Path, path: &str) -> Result<PathBuf, VfsError> {
+        let host = root.join(&normalize(path)[1..]);
+        // Files that don't exist yet are checked through the directory they would be created in
+        let existing = if host.exists() { host.clone() } else { host.parent().unwrap_or(root).to_path_buf() };
+        match std::fs::canonicalize(&existing) {
+            Ok(real) if real.starts_with(root) => Ok(host),
+            Ok(_) => Err(VfsError::PermissionDenied),
+            Err(_) => Err(VfsError::NotFound),
+        }
+    }
+    pub fn open(&mut self, path: &str, flags: OpenFlags) -> Result<u32, VfsError> {
+        if self.files.len() >= self.max_open {
+            return Err(VfsError::TooManyFiles);
+        }
+        let writes = flags.write || flags.append || flags.create || flags.truncate;
+        let handle = match &mut self.backend {
+            Backend::Memory(files) => {
+                let path = normalize(path);
+                match files.get_mut(&path) {
+                    Some(_) if flags.create && flags.exclusive => return Err(VfsError::AlreadyExists),
+                    Some(contents) if flags.truncate => contents.clear(),
+                    Some(_) => {}
+                    None if flags.create => {
+                        files.insert(path.clone(), vec![]);
+                    }
+                    None => return Err(VfsError::NotFound),
+                }
+                Handle::Memory { path, pos: 0, flags }
+            }
+            Backend::Host { read_only: true, .. } if writes => return Err(VfsError::PermissionDenied),
+            Backend::Host { root, .. } => {
+                let host = Vfs::host_path(root, path)?;
+                let mut options = OpenOptions::new();
+                options.read(flags.read).write(flags.write).append(flags.append).truncate(flags.truncate);
+                if flags.create && flags.exclusive {
+                    options.create_new(true);
+                } else {
+                    options.create(flags.create);
+                }
+                Handle::Host(options.open(host).map_err(|e| VfsError::from_io(&e))?)
+            }
+        };
+        let fd = (3..).find(|fd| !self.files.contains_key(fd)).unwrap();
+        self.files.insert(fd, handle);
+        Ok(fd)
+    }
+    pub fn close(&mut self, fd: u32) -> Result<(), VfsError> {
+        self.files.remove(&fd).map(|_| ()).ok_or(VfsError::BadDescriptor)
+    }
+    pub fn read(&mut self, fd: u32, buf: &mut [u8]) -> Result<usize, VfsError> {
+        match self.files.get_mut(&fd).ok_or(VfsError::BadDescriptor)? {
+            Handle::Memory { path, pos, flags } => {
+                if !flags.read {
+                    return Err(VfsError::BadDescriptor);
+                }
+                let Backend::Memory(files) = &self.backend else { unreachable!() };
+                let contents = &files[path.as_str()];
+                // The file may have been truncated below pos through another descriptor
+                let start = (*pos).min(contents.len());
+                let count = buf.len().min(contents.len() - start);
+                buf[..count].copy_from_slice(&contents[start..start + count]);
+                *pos += count;
+                Ok(count)
+            }
+            Handle::Host(file) => file.read(buf).map_err(|e| VfsError::from_io(&e)),
+        }
+    }
+    pub fn write(&mut self, fd: u32, bytes: &[u8]) -> Result<usize, VfsError> {
+        let handle = self.files.get_mut(&fd).ok_or(VfsError::BadDescriptor)?;
+        if self.max_written.is_some_and(|max| self.written + bytes.len() as u64 > max) {
+            return Err(VfsError::QuotaExceeded);
+        }
+        match handle {
+            Handle::Memory { path, pos, flags } => {
+                if !flags.write && !flags.append {
+                    return Err(VfsError::BadDescriptor);
+                }
+                let Backend::Memory(files) = &mut self.backend else { unreachable!() };
+                let contents = files.get_mut(path.as_str()).unwrap();
+                if flags.append {
+                    *pos = contents.len();
+                }
+                if contents.len() < *pos + bytes.len() {
+                    contents.resize(*pos + bytes.len(), 0);
+                }
+                contents[*pos..*pos + bytes.len()].copy_from_slice(bytes);
+                *pos += bytes.len();
+            }
+            Handle::Host(file) => file.write_all(bytes).map_err(|e| VfsError::from_io(&e))?,
+        }
+        self.written += bytes.len() as u64;
+        Ok(bytes.len())
+    }
+    // Up to len bytes from offset, without moving the file position, for mapping files into memory
+    pub fn read_at(&mut self, fd: u32, offset: u64, len: u32) -> Result<Vec<u8>, VfsError> {
+        match self.files.get_mut(&fd).ok_or(VfsError::BadDescriptor)? {
+            Handle::Memory { path, .. } => {
+                let Backend::Memory(files) = &self.backend else { unreachable!() };
+                let contents = &files[path.as_str()];
+                let start = (offset as usize).min(contents.len());
+                Ok(contents[start..(start + len as usize).min(contents.len())].to_vec())
+            }
+            Handle::Host(file) => {
+                let mut bytes = vec![];
+                let pos = file.stream_position().map_err(|e| VfsError::from_io(&e))?;
+                file.seek(SeekFrom::Start(offset))
+                    .and_then(|_| file.take(len as u64).read_to_end(&mut bytes))
+                    .and_then(|_| file.seek(SeekFrom::Start(pos)))
+                    .map_err(|e| VfsError::from_io(&e))?;
+                Ok(bytes)
+            }
+        }
+    }
+    pub fn metadata(&self, fd: u32) -> Result<Metadata, VfsError> {
+        match self.files.get(&fd).ok_or(VfsError::BadDescriptor)? {
+            Handle::Memory { path, .. } => {
+                let Backend::Memory(files) = &self.backend else { unreachable!() };
+                Ok(Metadata { size: files[path.as_str()].len() as u64, is_dir: false, modified: 0 })
+            }
+            Handle::Host(file) => {
+                let metadata = file.metadata().map_err(|e| VfsError::from_io(&e))?;
+                let modified = metadata.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs() as u32).unwrap_or(0);
+                Ok(Metadata { size: metadata.len(), is_dir: metadata.is_dir(), modified })
+            }
+        }
+    }
+    pub fn is_open(&self, fd: u32) -> bool {
+        self.files.contains_key(&fd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const READ: OpenFlags = OpenFlags { read: true, write: false, append: false, create: false, truncate: false, exclusive: false };
+    const CREATE: OpenFlags = OpenFlags { read: false, write: true, append: false, create: true, truncate: true, exclusive: false };
+
+    fn write_file(vfs: &mut Vfs, path: &str, contents: &[u8]) {
+        let fd = vfs.open(path, CREATE).unwrap();
+        vfs.write(fd, contents).unwrap();
+        vfs.close(fd).unwrap();
+    }
+
+    #[test]
+    fn read_after_truncate_through_another_descriptor() {
+        let mut vfs = Vfs::memory();
+        write_file(&mut vfs, "/f", b"hello");
+        let fd = vfs.open("/f", READ).unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(vfs.read(fd, &mut buf), Ok(5));
+        let truncated = vfs.open("/f", CREATE).unwrap();
+        assert_eq!(vfs.read(fd, &mut buf), Ok(0));
+        vfs.write(truncated, b"hi").unwrap();
+        assert_eq!(vfs.read(fd, &mut buf), Ok(0));
+        let again = vfs.open("/f", READ).unwrap();
+        assert_eq!(vfs.read(again, &mut buf), Ok(2));
+        assert_eq!(&buf[..2], b"hi");
+    }
+
+    #[test]
+    fn memory_paths_stay_under_root() {
+        let mut vfs = Vfs::memory();
+        write_file(&mut vfs, "../../outside", b"x");
+        assert!(vfs.open("/outside", READ).is_ok());
+        assert!(vfs.open("dir/../../outside", READ).is_ok());
+        assert_eq!(vfs.open("/etc/passwd", READ), Err(VfsError::NotFound));
+    }
+
+    #[test]
+    fn host_paths_stay_under_root() {
+        let base = std::env::temp_dir().join(format!("mipsemu-vfs-{}", std::process::id()));
+        let root = base.join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(base.join("secret"), b"secret").unwrap();
+        let mut vfs = Vfs::from_str(&format!("dir={}", root.display())).unwrap();
+        // .. can't climb out of the root, so this creates root/secret rather than reading base/secret
+        assert_eq!(vfs.open("../secret", READ), Err(VfsError::NotFound));
+        write_file(&mut vfs, "../secret", b"inside");
+        assert_eq!(std::fs::read(root.join("secret")).unwrap(), b"inside");
+        assert_eq!(std::fs::read(base.join("secret")).unwrap(), b"secret");
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&base, root.join("link")).unwrap();
+            assert_eq!(vfs.open("link/secret", READ), Err(VfsError::PermissionDenied));
+        }
+        let mut read_only = Vfs::from_str(&format!("dir={},ro", root.display())).unwrap();
+        assert_eq!(read_only.open("new", CREATE), Err(VfsError::PermissionDenied));
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn written_quota() {
+        let mut vfs = Vfs::from_str("memory,written=4").unwrap();
+        let fd = vfs.open("/f", CREATE).unwrap();
+        assert_eq!(vfs.write(fd, b"abc"), Ok(3));
+        assert_eq!(vfs.write(fd, b"de"), Err(VfsError::QuotaExceeded));
+    }
+
+    #[test]
+    fn open_file_limit() {
+        let mut vfs = Vfs::from_str("memory,files=1").unwrap();
+        write_file(&mut vfs, "/f", b"");
+        let fd = vfs.open("/f", READ).unwrap();
+        assert_eq!(fd, 3);
+        assert_eq!(vfs.open("/f", READ), Err(VfsError::TooManyFiles));
+    }
+}